@@ -0,0 +1,226 @@
+use std::collections::HashSet;
+
+use quicksilver::input::Key;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindingsError {
+    InvalidSyntax(String),
+    UnknownAction(String),
+    UnknownKey(String),
+    DuplicateKey(Key),
+    ReservedKey(Key),
+}
+
+/// Keys that toggle debugging features in the client, so that they cannot be
+/// bound to actions. Keep this in sync with the key handling in `lib.rs`.
+pub const DEBUG_KEYS: [Key; 6] = [Key::H, Key::K, Key::L, Key::M, Key::N, Key::P];
+
+/// Maps each action of `comn::Input` to a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyBindings {
+    pub move_left: Key,
+    pub move_right: Key,
+    pub move_up: Key,
+    pub move_down: Key,
+    pub dash: Key,
+    pub use_action: Key,
+    pub shoot: Key,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            move_left: Key::A,
+            move_right: Key::D,
+            move_up: Key::W,
+            move_down: Key::S,
+            dash: Key::Space,
+            use_action: Key::LShift,
+            shoot: Key::Q,
+        }
+    }
+}
+
+impl KeyBindings {
+    /// Parse bindings that override the defaults, given in the form
+    /// `move_left:Left,shoot:F`.
+    pub fn parse(spec: &str) -> Result<Self, BindingsError> {
+        let mut bindings = Self::default();
+
+        for binding in spec.split(',').filter(|binding| !binding.is_empty()) {
+            let mut parts = binding.splitn(2, ':');
+            let (action, key_name) = match (parts.next(), parts.next()) {
+                (Some(action), Some(key_name)) => (action, key_name),
+                _ => return Err(BindingsError::InvalidSyntax(binding.to_string())),
+            };
+
+            let key = parse_key(key_name)?;
+            *bindings
+                .action_mut(action)
+                .ok_or_else(|| BindingsError::UnknownAction(action.to_string()))? = key;
+        }
+
+        bindings.check_duplicates()?;
+
+        Ok(bindings)
+    }
+
+    pub fn keys(&self) -> [Key; 7] {
+        [
+            self.move_left,
+            self.move_right,
+            self.move_up,
+            self.move_down,
+            self.dash,
+            self.use_action,
+            self.shoot,
+        ]
+    }
+
+    pub fn current_input(&self, pressed_keys: &HashSet<Key>) -> comn::Input {
+        comn::Input {
+            move_left: pressed_keys.contains(&self.move_left),
+            move_right: pressed_keys.contains(&self.move_right),
+            move_up: pressed_keys.contains(&self.move_up),
+            move_down: pressed_keys.contains(&self.move_down),
+            dash: pressed_keys.contains(&self.dash),
+            use_action: pressed_keys.contains(&self.use_action),
+            shoot: pressed_keys.contains(&self.shoot),
+        }
+    }
+
+    fn action_mut(&mut self, action: &str) -> Option<&mut Key> {
+        match action {
+            "move_left" => Some(&mut self.move_left),
+            "move_right" => Some(&mut self.move_right),
+            "move_up" => Some(&mut self.move_up),
+            "move_down" => Some(&mut self.move_down),
+            "dash" => Some(&mut self.dash),
+            "use_action" => Some(&mut self.use_action),
+            "shoot" => Some(&mut self.shoot),
+            _ => None,
+        }
+    }
+
+    fn check_duplicates(&self) -> Result<(), BindingsError> {
+        let mut seen = HashSet::new();
+
+        for key in self.keys().iter() {
+            if !seen.insert(*key) {
+                return Err(BindingsError::DuplicateKey(*key));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_key(name: &str) -> Result<Key, BindingsError> {
+    let key = match name {
+        "A" => Key::A,
+        "B" => Key::B,
+        "C" => Key::C,
+        "D" => Key::D,
+        "E" => Key::E,
+        "F" => Key::F,
+        "G" => Key::G,
+        "H" => Key::H,
+        "I" => Key::I,
+        "J" => Key::J,
+        "K" => Key::K,
+        "L" => Key::L,
+        "M" => Key::M,
+        "N" => Key::N,
+        "O" => Key::O,
+        "P" => Key::P,
+        "Q" => Key::Q,
+        "R" => Key::R,
+        "S" => Key::S,
+        "T" => Key::T,
+        "U" => Key::U,
+        "V" => Key::V,
+        "W" => Key::W,
+        "X" => Key::X,
+        "Y" => Key::Y,
+        "Z" => Key::Z,
+        "Left" => Key::Left,
+        "Right" => Key::Right,
+        "Up" => Key::Up,
+        "Down" => Key::Down,
+        "Space" => Key::Space,
+        "LShift" => Key::LShift,
+        "RShift" => Key::RShift,
+        "LControl" => Key::LControl,
+        "RControl" => Key::RControl,
+        "LAlt" => Key::LAlt,
+        "Tab" => Key::Tab,
+        "Return" => Key::Return,
+        _ => return Err(BindingsError::UnknownKey(name.to_string())),
+    };
+
+    if DEBUG_KEYS.contains(&key) {
+        Err(BindingsError::ReservedKey(key))
+    } else {
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_spec_gives_defaults() {
+        assert_eq!(KeyBindings::parse(""), Ok(KeyBindings::default()));
+    }
+
+    #[test]
+    fn actions_are_remapped() {
+        let bindings = KeyBindings::parse("move_left:Left,shoot:F").unwrap();
+
+        assert_eq!(
+            bindings,
+            KeyBindings {
+                move_left: Key::Left,
+                shoot: Key::F,
+                ..KeyBindings::default()
+            }
+        );
+
+        let pressed_keys = vec![Key::Left, Key::A].into_iter().collect();
+        let input = bindings.current_input(&pressed_keys);
+        assert!(input.move_left);
+        assert!(!input.move_right);
+        assert!(!input.shoot);
+    }
+
+    #[test]
+    fn debug_keys_are_reserved() {
+        for (name, key) in ["H", "K", "L", "M", "N", "P"].iter().zip(DEBUG_KEYS.iter()) {
+            assert_eq!(
+                KeyBindings::parse(&format!("shoot:{}", name)),
+                Err(BindingsError::ReservedKey(*key))
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_specs_are_rejected() {
+        assert_eq!(
+            KeyBindings::parse("shoot:A"),
+            Err(BindingsError::DuplicateKey(Key::A))
+        );
+        assert_eq!(
+            KeyBindings::parse("jump:F"),
+            Err(BindingsError::UnknownAction("jump".to_string()))
+        );
+        assert_eq!(
+            KeyBindings::parse("shoot:F13"),
+            Err(BindingsError::UnknownKey("F13".to_string()))
+        );
+        assert_eq!(
+            KeyBindings::parse("shoot"),
+            Err(BindingsError::InvalidSyntax("shoot".to_string()))
+        );
+    }
+}
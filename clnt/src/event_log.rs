@@ -0,0 +1,116 @@
+use std::{collections::VecDeque, time::Duration};
+
+use instant::Instant;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Maximal number of events that we keep around.
+    pub max_len: usize,
+
+    /// Events older than this are dropped.
+    pub max_age: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_len: 32,
+            max_age: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Bounded log of recent game events, e.g. for showing a kill feed.
+#[derive(Debug, Clone)]
+pub struct EventLog {
+    config: Config,
+
+    /// Events together with the time at which we started them, sorted by time
+    /// ascending.
+    events: VecDeque<(Instant, comn::Event)>,
+}
+
+impl EventLog {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, now: Instant, event: comn::Event) {
+        self.events.push_back((now, event));
+
+        while self.events.len() > self.config.max_len {
+            self.events.pop_front();
+        }
+    }
+
+    pub fn prune(&mut self, now: Instant) {
+        while self.events.front().map_or(false, |(time, _)| {
+            now.duration_since(*time) > self.config.max_age
+        }) {
+            self.events.pop_front();
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(Instant, comn::Event)> {
+        self.events.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(player_id: u32) -> comn::Event {
+        comn::Event::NewCatcher {
+            player_id: comn::PlayerId(player_id),
+        }
+    }
+
+    fn player_ids(log: &EventLog) -> Vec<u32> {
+        log.iter()
+            .map(|(_, event)| match event {
+                comn::Event::NewCatcher { player_id } => player_id.0,
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn oldest_events_are_evicted_when_full() {
+        let mut log = EventLog::new(Config {
+            max_len: 3,
+            ..Config::default()
+        });
+        let now = Instant::now();
+
+        for player_id in 0..5 {
+            log.push(now, event(player_id));
+        }
+
+        assert_eq!(player_ids(&log), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn old_events_are_pruned() {
+        let mut log = EventLog::new(Config {
+            max_age: Duration::from_secs(5),
+            ..Config::default()
+        });
+        let start = Instant::now();
+
+        log.push(start, event(0));
+        log.push(start + Duration::from_secs(2), event(1));
+
+        log.prune(start + Duration::from_secs(5));
+        assert_eq!(player_ids(&log), vec![0, 1]);
+
+        log.prune(start + Duration::from_secs(6));
+        assert_eq!(player_ids(&log), vec![1]);
+
+        log.prune(start + Duration::from_secs(8));
+        assert!(player_ids(&log).is_empty());
+    }
+}
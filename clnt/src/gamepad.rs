@@ -0,0 +1,129 @@
+//! Input from gamepads via the web Gamepad API.
+//!
+//! We assume the standard mapping, see
+//! https://w3c.github.io/gamepad/#remapping.
+
+use wasm_bindgen::JsCast;
+
+/// Stick deflection below which we ignore an axis.
+pub const STICK_THRESHOLD: f32 = 0.35;
+
+const BUTTON_DASH: u32 = 0;
+const BUTTON_SHOOT: u32 = 1;
+const BUTTON_USE_ACTION: u32 = 2;
+const BUTTON_RIGHT_TRIGGER: u32 = 7;
+
+/// Returns the input of the first connected gamepad, if any.
+pub fn current_input() -> Option<comn::Input> {
+    let gamepads = web_sys::window()?.navigator().get_gamepads().ok()?;
+
+    let gamepad = gamepads
+        .iter()
+        .filter_map(|gamepad| gamepad.dyn_into::<web_sys::Gamepad>().ok())
+        .find(|gamepad| gamepad.connected())?;
+
+    let axes = gamepad.axes();
+    let axis = |i: u32| axes.get(i).as_f64().unwrap_or(0.0) as f32;
+
+    let buttons = gamepad.buttons();
+    let is_pressed = |i: u32| {
+        buttons
+            .get(i)
+            .dyn_into::<web_sys::GamepadButton>()
+            .map_or(false, |button| button.pressed())
+    };
+
+    let mut input = stick_to_input(axis(0), axis(1));
+    input.dash = is_pressed(BUTTON_DASH);
+    input.shoot = is_pressed(BUTTON_SHOOT) || is_pressed(BUTTON_RIGHT_TRIGGER);
+    input.use_action = is_pressed(BUTTON_USE_ACTION);
+
+    Some(input)
+}
+
+/// Map the left stick to movement. The y axis points down, as in the game.
+pub fn stick_to_input(x: f32, y: f32) -> comn::Input {
+    comn::Input {
+        move_left: x < -STICK_THRESHOLD,
+        move_right: x > STICK_THRESHOLD,
+        move_up: y < -STICK_THRESHOLD,
+        move_down: y > STICK_THRESHOLD,
+        ..comn::Input::default()
+    }
+}
+
+/// Combine keyboard and gamepad input, so that both can be used at the same
+/// time.
+pub fn combine_input(a: &comn::Input, b: &comn::Input) -> comn::Input {
+    comn::Input {
+        move_left: a.move_left || b.move_left,
+        move_right: a.move_right || b.move_right,
+        move_up: a.move_up || b.move_up,
+        move_down: a.move_down || b.move_down,
+        dash: a.dash || b.dash,
+        use_action: a.use_action || b.use_action,
+        shoot: a.shoot || b.shoot,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moves(input: &comn::Input) -> [bool; 4] {
+        [
+            input.move_left,
+            input.move_right,
+            input.move_up,
+            input.move_down,
+        ]
+    }
+
+    #[test]
+    fn small_stick_deflection_is_ignored() {
+        assert_eq!(moves(&stick_to_input(0.0, 0.0)), [false; 4]);
+        assert_eq!(
+            moves(&stick_to_input(STICK_THRESHOLD, -STICK_THRESHOLD)),
+            [false; 4]
+        );
+    }
+
+    #[test]
+    fn stick_deflection_moves() {
+        assert_eq!(
+            moves(&stick_to_input(-0.5, 0.0)),
+            [true, false, false, false]
+        );
+        assert_eq!(
+            moves(&stick_to_input(1.0, 0.2)),
+            [false, true, false, false]
+        );
+        assert_eq!(
+            moves(&stick_to_input(0.7, -0.7)),
+            [false, true, true, false]
+        );
+        assert_eq!(
+            moves(&stick_to_input(0.0, 0.9)),
+            [false, false, false, true]
+        );
+    }
+
+    #[test]
+    fn combined_input_presses_keys_of_both() {
+        let keyboard = comn::Input {
+            move_left: true,
+            ..comn::Input::default()
+        };
+        let gamepad = comn::Input {
+            dash: true,
+            ..stick_to_input(0.0, 1.0)
+        };
+
+        let input = combine_input(&keyboard, &gamepad);
+        assert!(input.move_left);
+        assert!(input.move_down);
+        assert!(input.dash);
+        assert!(!input.move_right);
+        assert!(!input.shoot);
+    }
+}
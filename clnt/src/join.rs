@@ -5,13 +5,58 @@ use wasm_bindgen_futures::JsFuture;
 
 use quicksilver::input::Input;
 
-use crate::{runner::Runner, webrtc};
+use crate::{prediction, runner::Runner, webrtc};
 
 #[derive(Debug, Clone)]
 pub enum JoinAndConnectError {
     Request(JsValue),
     Join(comn::JoinError),
     WebRTC(webrtc::ConnectError),
+    ConnectionClosed,
+}
+
+impl JoinAndConnectError {
+    /// Returns a message that can be shown to the user.
+    pub fn message(&self) -> String {
+        use webrtc::ConnectError;
+
+        match self {
+            JoinAndConnectError::Request(_) => "Could not reach the server".to_string(),
+            JoinAndConnectError::Join(comn::JoinError::FullGame) => "The game is full".to_string(),
+            JoinAndConnectError::Join(comn::JoinError::InvalidGameId) => {
+                "The game does not exist".to_string()
+            }
+            JoinAndConnectError::Join(comn::JoinError::InvalidPlayerName) => {
+                "Invalid player name".to_string()
+            }
+            JoinAndConnectError::Join(comn::JoinError::InvalidGameOptions) => {
+                "The server does not support these game options".to_string()
+            }
+            JoinAndConnectError::Join(comn::JoinError::IncompatibleVersion { server }) => format!(
+                "Your client is outdated (protocol version {} vs. server {}), please reload",
+                comn::PROTOCOL_VERSION,
+                server,
+            ),
+            JoinAndConnectError::WebRTC(ConnectError::ResponseStatus(status)) => {
+                format!("Server refused the connection (status {})", status)
+            }
+            JoinAndConnectError::WebRTC(ConnectError::NewRtcPeerConnection(_)) => {
+                "Your browser does not seem to support WebRTC".to_string()
+            }
+            JoinAndConnectError::WebRTC(ConnectError::NewRequest(_))
+            | JoinAndConnectError::WebRTC(ConnectError::Fetch(_))
+            | JoinAndConnectError::WebRTC(ConnectError::ResponseJson(_)) => {
+                "Could not reach the server".to_string()
+            }
+            JoinAndConnectError::WebRTC(ConnectError::CreateOffer(_))
+            | JoinAndConnectError::WebRTC(ConnectError::SetLocalDescription(_))
+            | JoinAndConnectError::WebRTC(ConnectError::SetRemoteDescription(_))
+            | JoinAndConnectError::WebRTC(ConnectError::AddIceCandidate(_)) => {
+                "Failed to establish a WebRTC connection".to_string()
+            }
+            JoinAndConnectError::ConnectionClosed => "The connection was closed".to_string(),
+        }
+    }
 }
 
 pub async fn join_and_connect(
@@ -23,13 +68,35 @@ pub async fn join_and_connect(
         .map_err(JoinAndConnectError::Request)?
         .map_err(JoinAndConnectError::Join)?;
 
-    let my_token = join_success.your_token;
+    let webrtc_client = connect(
+        join_success.your_token,
+        webrtc::Config::for_join(&join_success),
+        input,
+    )
+    .await?;
+
+    Ok(Runner::new(
+        join_success,
+        webrtc_client,
+        prediction::Config::default(),
+    ))
+}
+
+/// Establish a WebRTC connection for a player that has already joined. This
+/// can also be used to reconnect, since the server associates the connection
+/// with our player by the token in our messages.
+pub async fn connect(
+    my_token: comn::PlayerToken,
+    config: webrtc::Config,
+    input: &mut Input,
+) -> Result<webrtc::Client, JoinAndConnectError> {
+    let codec = config.codec;
     let on_message = Box::new(
         move |client_data: &webrtc::Data, message: &comn::ServerMessage| {
-            on_message(my_token, client_data, message)
+            on_message(my_token, codec, client_data, message)
         },
     );
-    let webrtc_client = webrtc::Client::connect(Default::default(), on_message)
+    let webrtc_client = webrtc::Client::connect(config, on_message)
         .await
         .map_err(JoinAndConnectError::WebRTC)?;
 
@@ -44,7 +111,11 @@ pub async fn join_and_connect(
         // TODO: Timeout
     }
 
-    Ok(Runner::new(join_success, webrtc_client))
+    if webrtc_client.status() != webrtc::Status::Open {
+        return Err(JoinAndConnectError::ConnectionClosed);
+    }
+
+    Ok(webrtc_client)
 }
 
 pub async fn join_request(request: comn::JoinRequest) -> Result<comn::JoinReply, JsValue> {
@@ -73,24 +144,34 @@ pub async fn join_request(request: comn::JoinRequest) -> Result<comn::JoinReply,
     assert!(resp_value.is_instance_of::<web_sys::Response>());
     let resp: web_sys::Response = resp_value.dyn_into().unwrap();
 
+    if !resp.ok() {
+        return Err(JsValue::from_str(&format!(
+            "Join request failed with status {}",
+            resp.status()
+        )));
+    }
+
     // Convert this other `Promise` into a rust `Future`.
     let reply = JsFuture::from(resp.json()?).await?;
 
     info!("Join reply: {:?}", reply);
 
     // Use serde to parse the JSON into a struct.
-    Ok(reply.into_serde().unwrap())
+    reply
+        .into_serde()
+        .map_err(|err| JsValue::from_str(&err.to_string()))
 }
 
 pub fn on_message(
     my_token: comn::PlayerToken,
+    codec: comn::CodecKind,
     client_data: &webrtc::Data,
     message: &comn::ServerMessage,
 ) {
     if let comn::ServerMessage::Ping(sequence_num) = message {
         let reply = comn::ClientMessage::Pong(*sequence_num);
         let signed_message = comn::SignedClientMessage(my_token, reply);
-        let data = signed_message.serialize();
+        let data = signed_message.serialize(&codec);
         if let Err(err) = client_data.send(&data) {
             warn!("Failed to send message: {:?}", err);
         }
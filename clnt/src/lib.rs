@@ -1,10 +1,19 @@
+mod bindings;
+mod event_log;
+mod gamepad;
 mod join;
 mod prediction;
+mod reconnect;
+mod replay;
 mod runner;
 mod view;
 mod webrtc;
 
-use std::{cell::RefCell, collections::HashSet, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashSet},
+    rc::Rc,
+};
 
 use wasm_bindgen::{
     prelude::{wasm_bindgen, Closure},
@@ -12,7 +21,7 @@ use wasm_bindgen::{
 };
 
 use instant::Instant;
-use log::info;
+use log::{info, warn};
 
 use quicksilver::{
     geom::{Rectangle, Transform, Vector},
@@ -23,7 +32,14 @@ use quicksilver::{
 
 use comn::util::stats;
 
-use crate::view::View;
+use crate::{
+    bindings::KeyBindings,
+    join::JoinAndConnectError,
+    reconnect::{BackoffConfig, Reconnect},
+    replay::ReplayPlayer,
+    runner::Runner,
+    view::View,
+};
 
 const SCREEN_SIZE: Vector = Vector {
     x: 1280.0,
@@ -47,18 +63,6 @@ pub fn main() {
     );
 }
 
-fn current_input(pressed_keys: &HashSet<Key>) -> comn::Input {
-    comn::Input {
-        move_left: pressed_keys.contains(&Key::A),
-        move_right: pressed_keys.contains(&Key::D),
-        move_up: pressed_keys.contains(&Key::W),
-        move_down: pressed_keys.contains(&Key::S),
-        dash: pressed_keys.contains(&Key::Space),
-        use_action: pressed_keys.contains(&Key::LShift),
-        shoot: pressed_keys.contains(&Key::Q),
-    }
-}
-
 // https://github.com/ryanisaacg/quicksilver/issues/628#issuecomment-670566767
 fn resize(gfx: &mut Graphics, window: &Window, prev_size: Vector) -> Vector {
     let size = window.size() * window.scale_factor();
@@ -71,6 +75,35 @@ fn resize(gfx: &mut Graphics, window: &Window, prev_size: Vector) -> Vector {
     size
 }
 
+/// Returns the value of the given parameter in the query string of the page.
+fn query_param(name: &str) -> Option<String> {
+    let search = web_sys::window()?.location().search().ok()?;
+    let prefix = format!("{}=", name);
+
+    search
+        .trim_start_matches('?')
+        .split('&')
+        .find(|param| param.starts_with(&prefix))
+        .map(|param| param[prefix.len()..].to_string())
+}
+
+/// Returns the URL of a replay to play back if the page was opened with a
+/// `?replay=<url>` query.
+fn replay_url() -> Option<String> {
+    query_param("replay")
+}
+
+/// Returns the key bindings, which can be overridden with a
+/// `?keys=move_left:Left,shoot:F` query.
+fn key_bindings() -> KeyBindings {
+    query_param("keys").map_or_else(KeyBindings::default, |spec| {
+        KeyBindings::parse(&spec).unwrap_or_else(|err| {
+            warn!("Invalid key bindings {:?}, using defaults: {:?}", spec, err);
+            KeyBindings::default()
+        })
+    })
+}
+
 /// Statistics for debugging.
 #[derive(Default)]
 struct Stats {
@@ -81,19 +114,15 @@ struct Stats {
 async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> quicksilver::Result<()> {
     info!("Starting up");
 
+    if let Some(url) = replay_url() {
+        return replay_app(window, gfx, input, url).await;
+    }
+
     let config = view::Config::default();
-    let resources = view::Resources::load(&mut gfx).await?;
+    let mut resources = view::Resources::load(&mut gfx).await?;
+    let key_bindings = key_bindings();
 
-    // TODO: Graceful error handling in client
-    let runner = join::join_and_connect(
-        comn::JoinRequest {
-            game_id: None,
-            player_name: "Pioneer".to_string(),
-        },
-        &mut input,
-    )
-    .await
-    .expect("Failed to connect");
+    let runner = connect(&window, &mut gfx, &mut input, &mut resources).await?;
 
     let mut view = View::new(
         config,
@@ -110,6 +139,7 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> quicksilver
 
     let mut pressed_keys: HashSet<Key> = HashSet::new();
     let mut last_time = Instant::now();
+    let mut reconnect: Option<Reconnect> = None;
 
     // Wrap the Runner in RefCell so that it can be used in Window callback
     let runner = Rc::new(RefCell::new(runner));
@@ -134,10 +164,22 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> quicksilver
             match event {
                 Event::KeyboardInput(event) => {
                     if !pressed_keys.contains(&event.key()) {
+                        // Debugging toggles, see `bindings::DEBUG_KEYS`.
                         match event.key() {
                             Key::K => {
                                 show_stats = !show_stats;
                             }
+                            Key::H => {
+                                view.toggle_shapes();
+                            }
+                            Key::M => {
+                                view.toggle_mute();
+                            }
+                            Key::N => {
+                                let mut runner = runner.borrow_mut();
+                                let netcode_mode = runner.netcode_mode().toggle();
+                                runner.set_netcode_mode(netcode_mode);
+                            }
                             Key::P => {
                                 let mut writer = std::io::Cursor::new(Vec::new());
                                 coarse_prof::write(&mut writer).unwrap();
@@ -171,6 +213,37 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> quicksilver
 
         window_size = resize(&mut gfx, &window, window_size);
 
+        // Try to get back into the game if we lost the connection. Note that
+        // we must not hold a borrow of the runner while waiting here.
+        if runner.borrow().can_reconnect() {
+            let now = Instant::now();
+            let state = reconnect.get_or_insert_with(|| {
+                info!("Lost connection, will try to reconnect");
+                Reconnect::new(BackoffConfig::default(), now)
+            });
+
+            if state.is_due(now) {
+                info!("Reconnecting, attempt {}", state.attempt() + 1);
+
+                let (my_token, webrtc_config) = {
+                    let runner = runner.borrow();
+                    (runner.my_token(), runner.webrtc_config().clone())
+                };
+                match join::connect(my_token, webrtc_config, &mut input).await {
+                    Ok(webrtc_client) => {
+                        runner.borrow_mut().reconnect(webrtc_client);
+                        reconnect = None;
+                    }
+                    Err(err) => {
+                        warn!("Failed to reconnect: {:?}", err);
+                        state.record_failure(Instant::now());
+                    }
+                }
+            }
+        } else {
+            reconnect = None;
+        }
+
         let mut runner = runner.borrow_mut();
 
         if lag_frames > 0 {
@@ -185,7 +258,12 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> quicksilver
         let game_events = if runner.is_good() {
             coarse_prof::profile!("update");
 
-            runner.update(start_time, last_dt, &current_input(&pressed_keys))
+            let mut game_input = key_bindings.current_input(&pressed_keys);
+            if let Some(gamepad_input) = gamepad::current_input() {
+                game_input = gamepad::combine_input(&game_input, &gamepad_input);
+            }
+
+            runner.update(start_time, last_dt, &game_input)
         } else {
             Vec::new()
         };
@@ -220,14 +298,21 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> quicksilver
                 &mut gfx,
                 state.as_ref(),
                 &runner.next_entities(),
+                runner.removed_entities(),
                 runner.interp_game_time(),
+                runner.bullet_extrapolation_time(),
             )?;
         }
 
         if !runner.is_good() {
+            let message = match reconnect.as_ref() {
+                Some(reconnect) if !reconnect.has_given_up() => "Reconnecting to server...",
+                _ => "Lost connection to server",
+            };
+
             view.resources_mut().font.draw(
                 &mut gfx,
-                "Lost connection to server",
+                message,
                 Color::RED,
                 Vector::new(250.0, 25.0),
             )?;
@@ -255,14 +340,46 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> quicksilver
                 "ping:               {:>7.3}",
                 runner.ping().estimate().as_secs_f32() * 1000.0
             ))?;
+            debug(&format!(
+                "ping jitter:        {:>7.3}",
+                runner.ping().jitter().as_secs_f32() * 1000.0
+            ))?;
             debug(&format!(
                 "recv stddev:        {:>7.3}",
                 1000.0 * runner.stats().recv_delay_std_dev,
             ))?;
+            debug(&format!(
+                "netcode mode:       {:>7}",
+                format!("{:?}", runner.netcode_mode()),
+            ))?;
+            debug(&format!(
+                "target lag (ms):    {:>7.3}",
+                runner.stats().target_time_lag_ms,
+            ))?;
+            debug(&format!(
+                "prediction error:   {:>7.3}",
+                runner.stats().prediction_error,
+            ))?;
             debug(&format!(
                 "loss (%):           {:>7.3}",
                 runner.stats().loss.estimate().map_or(100.0, |p| p * 100.0)
             ))?;
+            debug(&format!(
+                "recent loss (%):    {:>7.3}",
+                runner
+                    .stats()
+                    .loss
+                    .recent_estimate()
+                    .map_or(100.0, |p| p * 100.0)
+            ))?;
+            debug(&format!(
+                "lost ticks/s:       {:>7.3}",
+                runner
+                    .stats()
+                    .loss
+                    .lost_per_second(runner.settings().ticks_per_second as f32)
+                    .unwrap_or(0.0)
+            ))?;
             debug(&format!(
                 "skip loss (%):      {:>7.3}",
                 runner
@@ -303,6 +420,10 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> quicksilver
                 "input delay:       {}",
                 runner.stats().input_delay
             ))?;
+            debug(&format!(
+                "input latency (ms):{}",
+                runner.stats().input_latency_ms
+            ))?;
         }
 
         {
@@ -317,3 +438,232 @@ async fn app(window: Window, mut gfx: Graphics, mut input: Input) -> quicksilver
             .record(Instant::now().duration_since(start_time).as_secs_f32() * 1000.0);
     }
 }
+
+/// State of joining a game or loading a replay, shown to the user before the
+/// game starts.
+enum ConnectionState {
+    Connecting,
+    Failed(JoinAndConnectError),
+    LoadingReplay,
+    ReplayFailed(replay::LoadReplayError),
+}
+
+impl ConnectionState {
+    fn message(&self) -> String {
+        match self {
+            ConnectionState::Connecting => "Connecting...".to_string(),
+            ConnectionState::Failed(err) => format!("{}. Press Enter to retry.", err.message()),
+            ConnectionState::LoadingReplay => "Loading replay...".to_string(),
+            ConnectionState::ReplayFailed(err) => {
+                format!("{}. Press Enter to retry.", err.message())
+            }
+        }
+    }
+}
+
+/// Join a game, showing errors to the user and allowing them to retry.
+async fn connect(
+    window: &Window,
+    gfx: &mut Graphics,
+    input: &mut Input,
+    resources: &mut view::Resources,
+) -> quicksilver::Result<Runner> {
+    loop {
+        render_connection_state(window, gfx, resources, &ConnectionState::Connecting)?;
+
+        let result = join::join_and_connect(
+            comn::JoinRequest {
+                protocol_version: comn::PROTOCOL_VERSION,
+                game_id: None,
+                player_name: "Pioneer".to_string(),
+                join_code: None,
+                create_private: false,
+                game_options: Default::default(),
+            },
+            input,
+        )
+        .await;
+
+        let err = match result {
+            Ok(runner) => return Ok(runner),
+            Err(err) => err,
+        };
+
+        warn!("Failed to join: {:?}", err);
+
+        let state = ConnectionState::Failed(err);
+        wait_for_retry(window, gfx, input, resources, &state).await?;
+    }
+}
+
+/// Load a replay, showing errors to the user and allowing them to retry.
+async fn load_replay(
+    window: &Window,
+    gfx: &mut Graphics,
+    input: &mut Input,
+    resources: &mut view::Resources,
+    url: &str,
+) -> quicksilver::Result<ReplayPlayer> {
+    loop {
+        render_connection_state(window, gfx, resources, &ConnectionState::LoadingReplay)?;
+
+        let err = match replay::load_replay(url).await {
+            Ok(player) => return Ok(player),
+            Err(err) => err,
+        };
+
+        warn!("Failed to load replay {}: {:?}", url, err);
+
+        let state = ConnectionState::ReplayFailed(err);
+        wait_for_retry(window, gfx, input, resources, &state).await?;
+    }
+}
+
+/// Shows the failed state until the user requests a retry.
+async fn wait_for_retry(
+    window: &Window,
+    gfx: &mut Graphics,
+    input: &mut Input,
+    resources: &mut view::Resources,
+    state: &ConnectionState,
+) -> quicksilver::Result<()> {
+    render_connection_state(window, gfx, resources, state)?;
+
+    while let Some(event) = input.next_event().await {
+        match event {
+            Event::KeyboardInput(event) if event.is_down() && event.key() == Key::Return => {
+                break;
+            }
+            Event::Resized(_) => {
+                render_connection_state(window, gfx, resources, state)?;
+            }
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+fn render_connection_state(
+    window: &Window,
+    gfx: &mut Graphics,
+    resources: &mut view::Resources,
+    state: &ConnectionState,
+) -> quicksilver::Result<()> {
+    resize(gfx, window, Vector::ZERO);
+
+    gfx.clear(Color::from_hex("D4D6B9"));
+    resources.font.draw(
+        gfx,
+        &state.message(),
+        Color::BLACK,
+        Vector::new(20.0, window.size().y * window.scale_factor() / 2.0),
+    )?;
+    gfx.present(window)
+}
+
+async fn replay_app(
+    window: Window,
+    mut gfx: Graphics,
+    mut input: Input,
+    url: String,
+) -> quicksilver::Result<()> {
+    info!("Playing back replay {}", url);
+
+    let config = view::Config::default();
+    let mut resources = view::Resources::load(&mut gfx).await?;
+
+    let mut player = load_replay(&window, &mut gfx, &mut input, &mut resources, &url).await?;
+
+    let mut view = View::new(
+        config,
+        player.settings().clone(),
+        comn::PlayerId(1),
+        resources,
+        comn::Vector::new(window.size().x, window.size().y),
+        window.scale_factor(),
+    );
+
+    let seek_ticks = 5 * player.settings().ticks_per_second as u32;
+    let mut pressed_keys: HashSet<Key> = HashSet::new();
+    let mut last_time = Instant::now();
+    let mut window_size = resize(&mut gfx, &window, Vector::ZERO);
+
+    loop {
+        while let Some(event) = input.next_event().await {
+            match event {
+                Event::KeyboardInput(event) => {
+                    if event.is_down() && !pressed_keys.contains(&event.key()) {
+                        match event.key() {
+                            Key::Space => player.toggle_pause(),
+                            Key::Left => player.seek(comn::TickNum(
+                                player.tick_num().0.saturating_sub(seek_ticks),
+                            )),
+                            Key::Right => {
+                                player.seek(comn::TickNum(player.tick_num().0 + seek_ticks))
+                            }
+                            Key::Up => player.set_speed(player.speed() * 2.0),
+                            Key::Down => player.set_speed(player.speed() / 2.0),
+                            Key::H => view.toggle_shapes(),
+                            _ => (),
+                        }
+                    }
+
+                    if event.is_down() {
+                        pressed_keys.insert(event.key());
+                    } else {
+                        pressed_keys.remove(&event.key());
+                    }
+                }
+                Event::FocusChanged(event) if !event.is_focused() => {
+                    pressed_keys.clear();
+                }
+                _ => (),
+            }
+        }
+
+        window_size = resize(&mut gfx, &window, window_size);
+
+        let start_time = Instant::now();
+        let last_dt = start_time.duration_since(last_time);
+        last_time = start_time;
+
+        let game_events = player.update(last_dt);
+        let state = player.state();
+
+        view.set_window_size(
+            comn::Vector::new(window.size().x, window.size().y),
+            window.scale_factor(),
+        );
+        view.update(
+            start_time,
+            last_dt,
+            &pressed_keys,
+            state.as_ref(),
+            &game_events,
+            player.interp_game_time(),
+        );
+
+        gfx.clear(Color::from_hex("D4D6B9"));
+        view.render(
+            start_time,
+            &mut gfx,
+            state.as_ref(),
+            &player.next_entities(),
+            &BTreeMap::new(),
+            player.interp_game_time(),
+            0.0,
+        )?;
+        view.resources_mut().font_small.draw(
+            &mut gfx,
+            &format!(
+                "replay tick {} speed {}",
+                player.tick_num().0,
+                player.speed()
+            ),
+            Color::BLACK,
+            Vector::new(10.0, window.size().y * window.scale_factor() - 20.0),
+        )?;
+        gfx.present(&window)?;
+    }
+}
@@ -6,7 +6,49 @@ use comn::{game::RunContext, util::join};
 
 use crate::runner::ReceivedState;
 
-const MIN_PREDICTION_ERROR_FOR_REPLAY: f32 = 0.001;
+/// Weight of the newest prediction error in the moving average.
+const AVERAGE_ERROR_WEIGHT: f32 = 0.1;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Whether to predict our own entities at all. Without prediction, we only
+    /// render the authorative state.
+    pub enabled: bool,
+
+    /// Minimal accumulated prediction error at which we replay our inputs
+    /// following the corrected state.
+    pub min_error_for_replay: f32,
+
+    /// Fraction of the prediction error that we correct per tick.
+    pub smoothing_factor: f32,
+
+    /// If the moving average of the prediction error stays above this for
+    /// `max_divergence_ticks`, we consider our prediction to have diverged,
+    /// e.g. due to a mismatch in physics between client and server.
+    pub divergence_error: f32,
+    pub max_divergence_ticks: usize,
+
+    /// Whether to snap our prediction to the server state once it has
+    /// diverged, rather than smoothly correcting it forever.
+    pub snap_on_divergence: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_error_for_replay: 0.001,
+            smoothing_factor: 0.2,
+            divergence_error: 10.0,
+            max_divergence_ticks: 30,
+            snap_on_divergence: true,
+        }
+    }
+}
+
+/// Maximal distance between a predicted entity and an entity of the server
+/// with a matching spawn intent for us to consider them to be the same.
+const MAX_HAND_OFF_DISTANCE: f32 = 50.0;
 
 #[derive(Debug, Clone)]
 struct Record {
@@ -15,21 +57,38 @@ struct Record {
 }
 
 pub struct Prediction {
+    config: Config,
     my_player_id: comn::PlayerId,
     // TODO: Maximal size for prediction log
     log: BTreeMap<comn::TickNum, Record>,
     last_server_state_scratch: Option<comn::Game>,
+
+    average_error: f32,
+    divergence_ticks: usize,
 }
 
 impl Prediction {
-    pub fn new(my_player_id: comn::PlayerId) -> Self {
+    pub fn new(config: Config, my_player_id: comn::PlayerId) -> Self {
         Self {
+            config,
             my_player_id,
             log: BTreeMap::new(),
             last_server_state_scratch: None,
+            average_error: 0.0,
+            divergence_ticks: 0,
         }
     }
 
+    /// Moving average of the error in our prediction, as measured against the
+    /// authorative state.
+    pub fn average_error(&self) -> f32 {
+        self.average_error
+    }
+
+    pub fn set_smoothing_factor(&mut self, smoothing_factor: f32) {
+        self.config.smoothing_factor = smoothing_factor;
+    }
+
     pub fn record_tick_input(
         &mut self,
         tick_num: comn::TickNum,
@@ -61,37 +120,62 @@ impl Prediction {
         if let Some((server_state, my_last_input_num)) = server_state_and_my_last_input_num {
             let mut last_state = server_state.game.clone();
 
-            let prediction_error = if let Some(record) = self.log.get_mut(&my_last_input_num.next())
-            {
-                Self::correct_prediction(
-                    self.my_player_id,
-                    &mut record.entities,
-                    &server_state.game.entities,
-                )
-            } else {
-                0.0
-            };
+            self.hand_off_predicted_entities(&server_state.game.entities);
+
+            let mut prediction_error =
+                if let Some(record) = self.log.get_mut(&my_last_input_num.next()) {
+                    Self::correct_prediction(
+                        &self.config,
+                        self.my_player_id,
+                        &mut record.entities,
+                        &server_state.game.entities,
+                    )
+                } else {
+                    0.0
+                };
 
             if prediction_error > 0.0 {
                 info!("error: {}", prediction_error);
             }
 
+            if self.record_error(prediction_error) {
+                warn!(
+                    "Prediction diverged, average error {} for {} ticks",
+                    self.average_error, self.divergence_ticks,
+                );
+
+                if self.config.snap_on_divergence {
+                    // Stop rubber-banding by taking over the authorative
+                    // state for our entities, and then replay from there.
+                    if let Some(record) = self.log.get_mut(&my_last_input_num.next()) {
+                        record.entities =
+                            Self::extract_predicted_entities(&server_state.game, self.my_player_id);
+                    }
+
+                    self.average_error = 0.0;
+                    self.divergence_ticks = 0;
+                    prediction_error = prediction_error.max(self.config.min_error_for_replay);
+                }
+            }
+
             // We can now forget about any older predictions in the log.
             self.log = std::mem::replace(&mut self.log, BTreeMap::new())
                 .into_iter()
-                .filter(|&(tick_num, _)| tick_num > my_last_input_num)
+                .filter(|&(tick_num, _)| tick_num.is_after(my_last_input_num))
                 .collect();
 
             // Check if we need to replay our inputs following the corrected state.
-            if prediction_error >= MIN_PREDICTION_ERROR_FOR_REPLAY {
+            if prediction_error >= self.config.min_error_for_replay {
                 // Starting at the second-oldest state in our log (the oldest
                 // one just got corrected), re-apply the inputs that we had
                 // for those ticks.
-                let last_entities = self.log.values().next().unwrap().entities.clone();
-                Self::load_entities(&mut last_state, &last_entities);
+                let log_tick_nums = self.sorted_log_tick_nums();
+                let last_entities = self.log[&log_tick_nums[0]].entities.clone();
+                Self::load_entities(&mut last_state, self.my_player_id, &last_entities);
 
-                for (tick_num, record) in self.log.iter_mut().skip(1) {
-                    last_state.tick_num = *tick_num;
+                for tick_num in log_tick_nums.into_iter().skip(1) {
+                    let record = self.log.get_mut(&tick_num).unwrap();
+                    last_state.tick_num = tick_num;
                     Self::run_player_input(
                         &mut last_state,
                         self.my_player_id,
@@ -106,10 +190,15 @@ impl Prediction {
         }
 
         // Run prediction for the new given input.
+        let max_logged_tick_num = self.max_logged_tick_num();
         if let Some(last_state) = self.last_server_state_scratch.as_mut() {
             // Prepare state to run prediction in.
-            if let Some(last_record) = self.log.values().next_back() {
-                Self::load_entities(last_state, &last_record.entities);
+            let last_record = match max_logged_tick_num {
+                Some(tick_num) => self.log.get(&tick_num),
+                None => None,
+            };
+            if let Some(last_record) = last_record {
+                Self::load_entities(last_state, self.my_player_id, &last_record.entities);
             }
             last_state.tick_num = tick_num;
 
@@ -142,6 +231,21 @@ impl Prediction {
         }
     }
 
+    /// Updates the moving average of the prediction error, returning true if
+    /// our prediction has diverged for too long.
+    fn record_error(&mut self, error: f32) -> bool {
+        self.average_error =
+            AVERAGE_ERROR_WEIGHT * error + (1.0 - AVERAGE_ERROR_WEIGHT) * self.average_error;
+
+        if self.average_error > self.config.divergence_error {
+            self.divergence_ticks += 1;
+        } else {
+            self.divergence_ticks = 0;
+        }
+
+        self.divergence_ticks > self.config.max_divergence_ticks
+    }
+
     pub fn predicted_entities(&self, tick_num: comn::TickNum) -> Option<&comn::EntityMap> {
         self.log.get(&tick_num).map(|record| &record.entities)
     }
@@ -166,13 +270,84 @@ impl Prediction {
             .collect()
     }
 
+    /// Entities that we created in prediction live in a reserved id range. Once
+    /// the server's copy of such an entity arrives, we move our prediction
+    /// over to the server's id throughout the log, so that there is only a
+    /// single entity to render and correct.
+    fn hand_off_predicted_entities(&mut self, server: &comn::EntityMap) {
+        let mut handed_off: BTreeMap<comn::EntityId, comn::EntityId> = BTreeMap::new();
+
+        for record in self.log.values() {
+            for (predicted_id, predicted) in
+                record.entities.range(comn::EntityId::FIRST_PREDICTED..)
+            {
+                if handed_off.contains_key(predicted_id) {
+                    continue;
+                }
+
+                let server_id = server
+                    .iter()
+                    .filter(|(server_id, _)| !handed_off.values().any(|id| id == *server_id))
+                    .find(|(_, server)| Self::is_same_entity(predicted, server))
+                    .map(|(server_id, _)| *server_id);
+
+                if let Some(server_id) = server_id {
+                    handed_off.insert(*predicted_id, server_id);
+                }
+            }
+        }
+
+        for record in self.log.values_mut() {
+            for (predicted_id, server_id) in handed_off.iter() {
+                if let Some(entity) = record.entities.remove(predicted_id) {
+                    record.entities.insert(*server_id, entity);
+                }
+            }
+        }
+    }
+
+    fn is_same_entity(predicted: &comn::Entity, server: &comn::Entity) -> bool {
+        match (predicted.spawn_intent(), server.spawn_intent()) {
+            (Some(predicted_intent), Some(server_intent)) => {
+                let time = predicted_intent.start_time;
+
+                predicted_intent.matches(&server_intent)
+                    && (predicted.pos(time) - server.pos(time)).norm() < MAX_HAND_OFF_DISTANCE
+            }
+            _ => false,
+        }
+    }
+
     fn max_logged_tick_num(&self) -> Option<comn::TickNum> {
-        self.log.keys().next_back().copied()
+        comn::TickNum::newest(self.log.keys().copied())
+    }
+
+    /// Tick numbers of the log, oldest first.
+    fn sorted_log_tick_nums(&self) -> Vec<comn::TickNum> {
+        let mut tick_nums: Vec<comn::TickNum> = self.log.keys().copied().collect();
+        tick_nums.sort_by(|a, b| a.wrapping_cmp(*b));
+        tick_nums
     }
 
-    fn load_entities(state: &mut comn::Game, entities: &comn::EntityMap) {
-        // TODO: To be safe, we should remove all predicted entities from the
-        // state before adding the new entities.
+    fn load_entities(
+        state: &mut comn::Game,
+        my_player_id: comn::PlayerId,
+        entities: &comn::EntityMap,
+    ) {
+        // Remove all predicted entities from the state before adding the new
+        // entities, so that we do not keep around stale copies of entities
+        // whose ids have changed in the meantime.
+        let predicted_entity_ids: Vec<comn::EntityId> = state
+            .entities
+            .iter()
+            .filter(|(_, entity)| Self::is_predicted(my_player_id, entity))
+            .map(|(entity_id, _)| *entity_id)
+            .collect();
+
+        for entity_id in predicted_entity_ids {
+            state.entities.remove(&entity_id);
+        }
+
         state.entities.extend(
             entities
                 .iter()
@@ -200,6 +375,7 @@ impl Prediction {
     }
 
     fn correct_prediction(
+        config: &Config,
         my_player_id: comn::PlayerId,
         predicted: &mut comn::EntityMap,
         server: &comn::EntityMap,
@@ -207,13 +383,14 @@ impl Prediction {
         let mut error = 0.0;
 
         *predicted = join::full_join(predicted.iter(), server.iter())
-            .filter_map(|item| Self::correct_entity(my_player_id, item, &mut error))
+            .filter_map(|item| Self::correct_entity(config, my_player_id, item, &mut error))
             .collect();
 
         error
     }
 
     fn correct_entity(
+        config: &Config,
         my_player_id: comn::PlayerId,
         item: join::Item<&comn::EntityId, &comn::Entity, &comn::Entity>,
         error: &mut f32,
@@ -231,18 +408,18 @@ impl Prediction {
                             (d1.time_left - d2.time_left).abs() + (d1.dir - d2.dir).norm()
                         }
                         (None, None) => 0.0,
-                        _ => 1.0 * MIN_PREDICTION_ERROR_FOR_REPLAY,
+                        _ => 1.0 * config.min_error_for_replay,
                     };
 
                     Some((
                         *id,
                         Player(comn::PlayerEntity {
-                            pos: Self::correct_point(predicted.pos, server.pos, error),
+                            pos: Self::correct_point(config, predicted.pos, server.pos, error),
                             hook: match (&predicted.hook, &server.hook) {
-                                (Some(a), Some(b)) => Some(Self::correct_hook(a, b, error)),
+                                (Some(a), Some(b)) => Some(Self::correct_hook(config, a, b, error)),
                                 (None, None) => None,
                                 _ => {
-                                    *error += 2.0 * MIN_PREDICTION_ERROR_FOR_REPLAY;
+                                    *error += 2.0 * config.min_error_for_replay;
                                     server.hook.clone()
                                 }
                             },
@@ -257,7 +434,7 @@ impl Prediction {
                     // An entity that we predicted (most likely the
                     // PlayerEntity) no longer exists in the authorative
                     // state. Make sure to replay.
-                    *error += 3.0 * MIN_PREDICTION_ERROR_FOR_REPLAY;
+                    *error += 3.0 * config.min_error_for_replay;
                 }
                 None
             }
@@ -267,7 +444,7 @@ impl Prediction {
                     // prediction so that we include it. Might be that
                     // there is a better way to go about it, because
                     // this will replay prediction too often.
-                    *error += 4.0 * MIN_PREDICTION_ERROR_FOR_REPLAY;
+                    *error += 4.0 * config.min_error_for_replay;
                     Some((*id, server.clone()))
                 } else {
                     None
@@ -276,7 +453,12 @@ impl Prediction {
         }
     }
 
-    fn correct_point(predicted: comn::Point, server: comn::Point, error: &mut f32) -> comn::Point {
+    fn correct_point(
+        config: &Config,
+        predicted: comn::Point,
+        server: comn::Point,
+        error: &mut f32,
+    ) -> comn::Point {
         let delta = server - predicted;
 
         *error += delta.norm();
@@ -285,11 +467,12 @@ impl Prediction {
             server
         } else {
             // Smoothly correct prediction over time
-            predicted + delta * 0.2
+            predicted + delta * config.smoothing_factor
         }
     }
 
     fn correct_vector(
+        config: &Config,
         predicted: comn::Vector,
         server: comn::Vector,
         error: &mut f32,
@@ -302,11 +485,16 @@ impl Prediction {
             server
         } else {
             // Smoothly correct prediction over time
-            predicted + delta * 0.2
+            predicted + delta * config.smoothing_factor
         }
     }
 
-    fn correct_hook(predicted: &comn::Hook, server: &comn::Hook, error: &mut f32) -> comn::Hook {
+    fn correct_hook(
+        config: &Config,
+        predicted: &comn::Hook,
+        server: &comn::Hook,
+        error: &mut f32,
+    ) -> comn::Hook {
         use comn::Hook::*;
 
         match (predicted, server) {
@@ -318,7 +506,7 @@ impl Prediction {
                     time_left,
                 },
             ) => Shooting {
-                pos: Self::correct_point(*a, *b, error),
+                pos: Self::correct_point(config, *a, *b, error),
                 vel: *vel,
                 time_left: *time_left,
             },
@@ -333,32 +521,33 @@ impl Prediction {
                 },
             ) => {
                 if target_a != target_b {
-                    *error += MIN_PREDICTION_ERROR_FOR_REPLAY;
+                    *error += config.min_error_for_replay;
                 }
 
                 Attached {
                     target: *target_b,
-                    offset: Self::correct_vector(*offset_a, *offset_b, error),
+                    offset: Self::correct_vector(config, *offset_a, *offset_b, error),
                 }
             }
             (Contracting { pos: a }, Contracting { pos: b }) => Contracting {
-                pos: Self::correct_point(*a, *b, error),
+                pos: Self::correct_point(config, *a, *b, error),
             },
             _ => {
-                *error += MIN_PREDICTION_ERROR_FOR_REPLAY;
+                *error += config.min_error_for_replay;
                 server.clone()
             }
         }
     }
 
     fn add_predicted_entity(entities: &mut comn::EntityMap, entity: comn::Entity) {
-        // TODO: Some scheme for entity IDs of predicted entities
+        // Use the reserved id range, so that we never clash with ids that the
+        // server assigns.
         let entity_id = entities
-            .keys()
+            .range(comn::EntityId::FIRST_PREDICTED..)
             .next_back()
-            .copied()
-            .unwrap_or(comn::EntityId(0))
-            .next();
+            .map_or(comn::EntityId::FIRST_PREDICTED, |(entity_id, _)| {
+                entity_id.next()
+            });
 
         // Sanity check
         assert!(!entities.contains_key(&entity_id));
@@ -381,3 +570,89 @@ impl Prediction {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bullet(owner: comn::PlayerId, start_pos: comn::Point) -> comn::Entity {
+        comn::Entity::Bullet(comn::entities::Bullet {
+            owner: Some(owner),
+            start_time: 1.0,
+            motion_start_time: 1.0,
+            start_pos,
+            vel: comn::Vector::new(300.0, 0.0),
+        })
+    }
+
+    fn bullet_ids(entities: &comn::EntityMap) -> Vec<comn::EntityId> {
+        entities
+            .iter()
+            .filter(|(_, entity)| match entity {
+                comn::Entity::Bullet(_) => true,
+                _ => false,
+            })
+            .map(|(entity_id, _)| *entity_id)
+            .collect()
+    }
+
+    #[test]
+    fn predicted_bullet_is_handed_off_to_server_id() {
+        let my_player_id = comn::PlayerId(1);
+        let mut prediction = Prediction::new(Config::default(), my_player_id);
+
+        let predicted_id = comn::EntityId::FIRST_PREDICTED;
+        for tick_num in 10..13 {
+            let mut entities = comn::EntityMap::new();
+            entities.insert(
+                predicted_id,
+                bullet(my_player_id, comn::Point::new(100.0, 100.0)),
+            );
+
+            prediction.log.insert(
+                comn::TickNum(tick_num),
+                Record {
+                    entities,
+                    my_last_input: comn::Input::default(),
+                },
+            );
+        }
+
+        // The server spawned the bullet slightly elsewhere, and there is
+        // another bullet that is not ours.
+        let server_id = comn::EntityId(5);
+        let mut server = comn::EntityMap::new();
+        server.insert(
+            server_id,
+            bullet(my_player_id, comn::Point::new(110.0, 100.0)),
+        );
+        server.insert(
+            comn::EntityId(6),
+            bullet(comn::PlayerId(2), comn::Point::new(100.0, 100.0)),
+        );
+
+        prediction.hand_off_predicted_entities(&server);
+
+        for record in prediction.log.values() {
+            assert_eq!(bullet_ids(&record.entities), vec![server_id]);
+        }
+    }
+
+    #[test]
+    fn distant_bullets_are_not_the_same() {
+        let my_player_id = comn::PlayerId(1);
+        let predicted = bullet(my_player_id, comn::Point::new(100.0, 100.0));
+
+        assert!(Prediction::is_same_entity(
+            &predicted,
+            &bullet(my_player_id, comn::Point::new(120.0, 100.0)),
+        ));
+        assert!(!Prediction::is_same_entity(
+            &predicted,
+            &bullet(
+                my_player_id,
+                comn::Point::new(100.0 + 2.0 * MAX_HAND_OFF_DISTANCE, 100.0)
+            ),
+        ));
+    }
+}
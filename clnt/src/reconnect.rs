@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use instant::Instant;
+
+/// Exponential backoff for reconnecting after losing the connection.
+#[derive(Debug, Clone)]
+pub struct BackoffConfig {
+    pub initial_delay: Duration,
+    pub factor: f32,
+    pub max_delay: Duration,
+    pub max_attempts: usize,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            factor: 2.0,
+            max_delay: Duration::from_secs(8),
+            max_attempts: 5,
+        }
+    }
+}
+
+impl BackoffConfig {
+    /// Returns the delay before the given attempt, starting at zero, or `None`
+    /// if we should give up.
+    pub fn delay(&self, attempt: usize) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+
+        let delay = self.initial_delay.as_secs_f32() * self.factor.powi(attempt as i32);
+
+        Some(Duration::from_secs_f32(
+            delay.min(self.max_delay.as_secs_f32()),
+        ))
+    }
+}
+
+/// Keeps track of our reconnection attempts.
+#[derive(Debug, Clone)]
+pub struct Reconnect {
+    config: BackoffConfig,
+    attempt: usize,
+    next_attempt_time: Option<Instant>,
+}
+
+impl Reconnect {
+    pub fn new(config: BackoffConfig, now: Instant) -> Self {
+        let next_attempt_time = config.delay(0).map(|delay| now + delay);
+
+        Self {
+            config,
+            attempt: 0,
+            next_attempt_time,
+        }
+    }
+
+    pub fn attempt(&self) -> usize {
+        self.attempt
+    }
+
+    pub fn has_given_up(&self) -> bool {
+        self.next_attempt_time.is_none()
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.next_attempt_time.map_or(false, |time| now >= time)
+    }
+
+    /// Record that an attempt has failed, scheduling the next one.
+    pub fn record_failure(&mut self, now: Instant) {
+        self.attempt += 1;
+        self.next_attempt_time = self.config.delay(self.attempt).map(|delay| now + delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delay_secs(config: &BackoffConfig, attempt: usize) -> Option<f32> {
+        config.delay(attempt).map(|delay| delay.as_secs_f32())
+    }
+
+    fn assert_delay(config: &BackoffConfig, attempt: usize, secs: f32) {
+        let delay = delay_secs(config, attempt).unwrap();
+        assert!((delay - secs).abs() < 1e-3, "{} != {}", delay, secs);
+    }
+
+    #[test]
+    fn delay_grows_exponentially() {
+        let config = BackoffConfig::default();
+
+        for (attempt, secs) in [0.5, 1.0, 2.0, 4.0, 8.0].iter().enumerate() {
+            assert_delay(&config, attempt, *secs);
+        }
+        assert_eq!(delay_secs(&config, 5), None);
+    }
+
+    #[test]
+    fn delay_is_capped() {
+        let config = BackoffConfig {
+            max_delay: Duration::from_secs(3),
+            max_attempts: 10,
+            ..BackoffConfig::default()
+        };
+
+        assert_delay(&config, 2, 2.0);
+        assert_delay(&config, 3, 3.0);
+        assert_delay(&config, 9, 3.0);
+    }
+
+    #[test]
+    fn reconnect_gives_up_after_max_attempts() {
+        let config = BackoffConfig {
+            max_attempts: 2,
+            ..BackoffConfig::default()
+        };
+        let start = Instant::now();
+        let mut reconnect = Reconnect::new(config, start);
+
+        assert!(!reconnect.is_due(start));
+        assert!(reconnect.is_due(start + Duration::from_millis(500)));
+
+        let now = start + Duration::from_millis(500);
+        reconnect.record_failure(now);
+        assert_eq!(reconnect.attempt(), 1);
+        assert!(!reconnect.is_due(now + Duration::from_millis(999)));
+        assert!(reconnect.is_due(now + Duration::from_millis(1000)));
+
+        reconnect.record_failure(now + Duration::from_millis(1000));
+        assert!(reconnect.has_given_up());
+        assert!(!reconnect.is_due(now + Duration::from_secs(100)));
+    }
+}
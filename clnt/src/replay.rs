@@ -0,0 +1,178 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::JsFuture;
+
+use comn::replay::{Replay, ReplayError};
+
+#[derive(Debug)]
+pub enum LoadReplayError {
+    Request(JsValue),
+    ResponseStatus(u16),
+    Replay(ReplayError),
+}
+
+impl LoadReplayError {
+    /// Returns a message that can be shown to the user.
+    pub fn message(&self) -> String {
+        match self {
+            LoadReplayError::Request(_) => "Could not fetch the replay".to_string(),
+            LoadReplayError::ResponseStatus(status) => {
+                format!("Could not fetch the replay (status {})", status)
+            }
+            LoadReplayError::Replay(_) => "The replay is invalid".to_string(),
+        }
+    }
+}
+
+impl From<JsValue> for LoadReplayError {
+    fn from(err: JsValue) -> Self {
+        LoadReplayError::Request(err)
+    }
+}
+
+impl From<ReplayError> for LoadReplayError {
+    fn from(err: ReplayError) -> Self {
+        LoadReplayError::Replay(err)
+    }
+}
+
+/// Plays back a recorded replay, providing the same interface to the view as
+/// the live `Runner`.
+pub struct ReplayPlayer {
+    settings: Arc<comn::Settings>,
+
+    /// Recorded states, sorted by tick number ascending.
+    states: Vec<(comn::Game, Vec<comn::Event>)>,
+
+    interp_game_time: comn::GameTime,
+    speed: f32,
+    paused: bool,
+}
+
+impl ReplayPlayer {
+    pub fn new(replay: Replay) -> Result<Self, ReplayError> {
+        let states = replay.states()?;
+        let interp_game_time = states.first().map_or(0.0, |(state, _)| state.game_time());
+
+        Ok(Self {
+            settings: replay.settings,
+            states,
+            interp_game_time,
+            speed: 1.0,
+            paused: false,
+        })
+    }
+
+    pub fn settings(&self) -> &comn::Settings {
+        &self.settings
+    }
+
+    pub fn interp_game_time(&self) -> comn::GameTime {
+        self.interp_game_time
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed.max(0.0);
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    pub fn tick_num(&self) -> comn::TickNum {
+        comn::TickNum((self.interp_game_time / self.settings.tick_period()) as u32)
+    }
+
+    /// Jump directly to the given tick, clamped to the recorded range.
+    pub fn seek(&mut self, tick_num: comn::TickNum) {
+        if let (Some((first, _)), Some((last, _))) = (self.states.first(), self.states.last()) {
+            let tick_num = tick_num.max(first.tick_num).min(last.tick_num);
+            self.interp_game_time = self.settings.tick_game_time(tick_num);
+        }
+    }
+
+    pub fn update(&mut self, dt: Duration) -> Vec<comn::Event> {
+        if self.paused {
+            return Vec::new();
+        }
+
+        let max_game_time = self
+            .states
+            .last()
+            .map_or(0.0, |(state, _)| state.game_time());
+        let prev_game_time = self.interp_game_time;
+        self.interp_game_time =
+            (self.interp_game_time + self.speed * dt.as_secs_f32()).min(max_game_time);
+
+        // Start the events of all the ticks that we have crossed.
+        self.states
+            .iter()
+            .filter(|(state, _)| {
+                state.game_time() > prev_game_time && state.game_time() <= self.interp_game_time
+            })
+            .flat_map(|(_, events)| events.iter().cloned())
+            .collect()
+    }
+
+    fn current_index(&self) -> Option<usize> {
+        let tick_num = self.tick_num();
+
+        match self
+            .states
+            .binary_search_by_key(&tick_num, |(state, _)| state.tick_num)
+        {
+            Ok(index) => Some(index),
+            Err(index) => index.checked_sub(1),
+        }
+    }
+
+    pub fn state(&self) -> Option<comn::Game> {
+        self.current_index()
+            .map(|index| self.states[index].0.clone())
+    }
+
+    pub fn next_entities(&self) -> BTreeMap<comn::EntityId, (comn::GameTime, comn::Entity)> {
+        // Pair the current state with the next recorded one, so that
+        // interpolation works exactly like in live play.
+        self.current_index()
+            .and_then(|index| self.states.get(index + 1))
+            .map_or_else(BTreeMap::new, |(next_state, _)| {
+                let next_game_time = next_state.game_time();
+
+                next_state
+                    .entities
+                    .clone()
+                    .into_iter()
+                    .map(|(entity_id, entity)| (entity_id, (next_game_time, entity)))
+                    .collect()
+            })
+    }
+}
+
+/// Fetches and decodes the replay at the given URL.
+pub async fn load_replay(url: &str) -> Result<ReplayPlayer, LoadReplayError> {
+    let data = fetch_replay(url).await?;
+    let replay = Replay::decode(&data)?;
+
+    Ok(ReplayPlayer::new(replay)?)
+}
+
+async fn fetch_replay(url: &str) -> Result<Vec<u8>, LoadReplayError> {
+    let window = web_sys::window().unwrap();
+    let resp_value = JsFuture::from(window.fetch_with_str(url)).await?;
+    assert!(resp_value.is_instance_of::<web_sys::Response>());
+    let resp: web_sys::Response = resp_value.dyn_into().unwrap();
+
+    if !resp.ok() {
+        return Err(LoadReplayError::ResponseStatus(resp.status()));
+    }
+
+    let buffer = JsFuture::from(resp.array_buffer()?).await?;
+
+    Ok(js_sys::Uint8Array::new(&buffer).to_vec())
+}
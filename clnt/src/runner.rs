@@ -9,7 +9,11 @@ use log::{debug, info, warn};
 
 use comn::util::{diff::Diff, stats, GameTimeEstimation, LossEstimation, PingEstimation};
 
-use crate::{prediction::Prediction, webrtc};
+use crate::{
+    event_log::{self, EventLog},
+    prediction::{self, Prediction},
+    webrtc,
+};
 
 pub struct ReceivedState {
     pub game: comn::Game,
@@ -24,31 +28,144 @@ pub struct Stats {
     pub time_warp_factor: stats::Var,
     pub tick_interp: stats::Var,
     pub input_delay: stats::Var,
+
+    /// Time from sending an input until we display the server state in which
+    /// the input was executed.
+    pub input_latency_ms: stats::Var,
+
     pub received_ticks: stats::Var,
     pub recv_rate: f32,
     pub send_rate: f32,
     pub recv_delay_std_dev: f32,
+    pub target_time_lag_ms: f32,
+    pub prediction_error: f32,
     pub loss: LossEstimation,
     pub skip_loss: LossEstimation,
 }
 
 const MAX_TICKS_PER_UPDATE: usize = 5;
 const MAX_TIME_LAG_DEVIATION: f32 = 0.075;
+
+/// Bounds for how much slower or faster than real time we play back.
+const MIN_TIME_WARP_FACTOR: f32 = 0.5;
+const MAX_TIME_WARP_FACTOR: f32 = 2.0;
+
+/// Scale of the time lag deviation, in seconds, at which the time warp
+/// factor starts to saturate.
+const TIME_WARP_DEVIATION_SCALE: f32 = 0.005;
+
 const KEEP_STATES_BUFFER: u32 = 5;
 
+/// Upper bound on the number of received states and events that we keep
+/// around. Normally, pruning keeps the maps much smaller than this, but under
+/// heavy packet loss acknowledgments may not make it to the server, so that
+/// its diff base (and thereby our pruning) stalls.
+const MAX_RECEIVED_STATES: usize = 100;
+
+/// Determines how far we stay behind the stream of received ticks, so that we
+/// have ticks to interpolate between despite network jitter.
+#[derive(Debug, Clone)]
+pub struct JitterBufferConfig {
+    /// Minimal and maximal time lag, in multiples of the tick period.
+    pub min_ticks: f32,
+    pub max_ticks: f32,
+
+    /// Additional time lag per standard deviation of the tick receive delay.
+    pub std_dev_factor: f32,
+}
+
+impl Default for JitterBufferConfig {
+    fn default() -> Self {
+        Self {
+            min_ticks: 1.5,
+            max_ticks: 5.0,
+            std_dev_factor: 2.0,
+        }
+    }
+}
+
+/// Bundles our netcode parameters, trading off smoothness for delay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetcodeMode {
+    /// Stay further behind the server, so that we rarely run out of ticks to
+    /// interpolate between, and correct prediction errors gently.
+    Smooth,
+
+    /// Stay as close to the server as the connection allows, accepting that
+    /// entities snap more often.
+    Responsive,
+}
+
+impl Default for NetcodeMode {
+    fn default() -> Self {
+        NetcodeMode::Smooth
+    }
+}
+
+impl NetcodeMode {
+    pub fn jitter_buffer(self) -> JitterBufferConfig {
+        match self {
+            NetcodeMode::Smooth => JitterBufferConfig::default(),
+            NetcodeMode::Responsive => JitterBufferConfig {
+                min_ticks: 1.0,
+                max_ticks: 3.0,
+                std_dev_factor: 1.0,
+            },
+        }
+    }
+
+    /// Maximal number of ticks that we cross in one update before skipping.
+    pub fn max_ticks_per_update(self) -> usize {
+        match self {
+            NetcodeMode::Smooth => MAX_TICKS_PER_UPDATE,
+            NetcodeMode::Responsive => 3,
+        }
+    }
+
+    pub fn prediction_smoothing_factor(self) -> f32 {
+        match self {
+            NetcodeMode::Smooth => prediction::Config::default().smoothing_factor,
+            NetcodeMode::Responsive => 0.5,
+        }
+    }
+
+    pub fn toggle(self) -> Self {
+        match self {
+            NetcodeMode::Smooth => NetcodeMode::Responsive,
+            NetcodeMode::Responsive => NetcodeMode::Smooth,
+        }
+    }
+}
+
+/// Upper bound for how far we extrapolate the bullets of other players into
+/// the future when rendering.
+pub const MAX_BULLET_EXTRAPOLATION: comn::GameTime = 0.1;
+
+/// For how long entities are faded out after they have been removed from the
+/// game, so that e.g. bullets do not disappear abruptly.
+pub const DESPAWN_FADE_DURATION: comn::GameTime = 0.15;
+
 pub struct Runner {
     settings: Arc<comn::Settings>,
     my_token: comn::PlayerToken,
     my_player_id: comn::PlayerId,
+    codec: comn::CodecKind,
 
+    webrtc_config: webrtc::Config,
     webrtc_client: webrtc::Client,
     disconnected: bool,
 
     last_inputs: VecDeque<(comn::TickNum, comn::Input)>,
 
-    // TODO: Maximal size for received states
+    /// When we first sent our recent inputs, for measuring input latency.
+    input_send_times: BTreeMap<comn::TickNum, Instant>,
+    last_confirmed_input_num: Option<comn::TickNum>,
+
     received_states: BTreeMap<comn::TickNum, ReceivedState>,
     received_events: BTreeMap<comn::TickNum, Vec<comn::Event>>,
+    scoreboard: Option<(comn::TickNum, comn::Scoreboard)>,
+    removed_entities: BTreeMap<comn::EntityId, (comn::GameTime, comn::Entity)>,
+    event_log: EventLog,
     prediction: Option<Prediction>,
 
     interp_game_time: comn::GameTime,
@@ -60,23 +177,42 @@ pub struct Runner {
     next_time_warp_factor: f32,
 
     ping: PingEstimation,
+    netcode_mode: NetcodeMode,
+    jitter_buffer: JitterBufferConfig,
     stats: Stats,
 }
 
 impl Runner {
-    pub fn new(join: comn::JoinSuccess, webrtc_client: webrtc::Client) -> Self {
-        let prediction = Some(Prediction::new(join.your_player_id));
+    pub fn new(
+        join: comn::JoinSuccess,
+        webrtc_client: webrtc::Client,
+        prediction_config: prediction::Config,
+    ) -> Self {
+        let prediction = if prediction_config.enabled {
+            Some(Prediction::new(prediction_config, join.your_player_id))
+        } else {
+            None
+        };
         let recv_tick_time = GameTimeEstimation::new(join.game_settings.tick_period());
+        let webrtc_config = webrtc::Config::for_join(&join);
+        watch_status(&webrtc_client);
 
         Self {
             settings: Arc::new(join.game_settings),
             my_token: join.your_token,
             my_player_id: join.your_player_id,
+            codec: join.codec,
+            webrtc_config,
             webrtc_client,
             disconnected: false,
             last_inputs: VecDeque::new(),
+            input_send_times: BTreeMap::new(),
+            last_confirmed_input_num: None,
             received_states: BTreeMap::new(),
             received_events: BTreeMap::new(),
+            scoreboard: None,
+            removed_entities: BTreeMap::new(),
+            event_log: EventLog::new(event_log::Config::default()),
             prediction,
             interp_game_time: 0.0,
             next_tick_num: None,
@@ -84,6 +220,8 @@ impl Runner {
             recv_tick_time,
             next_time_warp_factor: 1.0,
             ping: PingEstimation::default(),
+            netcode_mode: NetcodeMode::default(),
+            jitter_buffer: NetcodeMode::default().jitter_buffer(),
             stats: Stats::default(),
         }
     }
@@ -92,6 +230,48 @@ impl Runner {
         self.my_player_id
     }
 
+    pub fn my_token(&self) -> comn::PlayerToken {
+        self.my_token
+    }
+
+    pub fn netcode_mode(&self) -> NetcodeMode {
+        self.netcode_mode
+    }
+
+    /// Switch our netcode parameters. This can be done at any time; the time
+    /// lag then smoothly moves towards its new target.
+    pub fn set_netcode_mode(&mut self, netcode_mode: NetcodeMode) {
+        info!("Switching to netcode mode {:?}", netcode_mode);
+
+        self.netcode_mode = netcode_mode;
+        self.jitter_buffer = netcode_mode.jitter_buffer();
+
+        if let Some(prediction) = self.prediction.as_mut() {
+            prediction.set_smoothing_factor(netcode_mode.prediction_smoothing_factor());
+        }
+    }
+
+    /// Returns the config that was used for connecting, so that we can
+    /// reconnect in the same way.
+    pub fn webrtc_config(&self) -> &webrtc::Config {
+        &self.webrtc_config
+    }
+
+    /// Returns true if we lost the connection without the server having told
+    /// us to disconnect.
+    pub fn can_reconnect(&self) -> bool {
+        !self.is_good() && !self.disconnected
+    }
+
+    /// Continue the game over a new connection.
+    pub fn reconnect(&mut self, webrtc_client: webrtc::Client) {
+        info!("Continuing with new connection");
+
+        watch_status(&webrtc_client);
+        self.webrtc_client = webrtc_client;
+        self.ping = PingEstimation::default();
+    }
+
     pub fn is_good(&self) -> bool {
         self.webrtc_client.status() == webrtc::Status::Open
             && !self.disconnected
@@ -114,8 +294,67 @@ impl Runner {
         self.interp_game_time
     }
 
+    /// Returns the events that we have started recently, together with the
+    /// time at which we started them.
+    pub fn recent_events(&self) -> impl Iterator<Item = &(Instant, comn::Event)> {
+        self.event_log.iter()
+    }
+
+    /// Returns entities that have been removed recently, together with the
+    /// game time of their removal. These can be faded out for
+    /// `DESPAWN_FADE_DURATION`.
+    pub fn removed_entities(&self) -> &BTreeMap<comn::EntityId, (comn::GameTime, comn::Entity)> {
+        &self.removed_entities
+    }
+
+    /// Returns how far we render the bullets of other players ahead of the
+    /// interpolated game time. Since bullets follow a fixed path, we can show
+    /// them closer to where they actually are on the server, hiding some of
+    /// our time lag.
+    pub fn bullet_extrapolation_time(&self) -> comn::GameTime {
+        self.target_time_lag().min(MAX_BULLET_EXTRAPOLATION)
+    }
+
+    /// Returns the most recent scoreboard that we have received.
+    pub fn scoreboard(&self) -> Option<&comn::Scoreboard> {
+        self.scoreboard.as_ref().map(|(_, scoreboard)| scoreboard)
+    }
+
     fn target_time_lag(&self) -> comn::GameTime {
-        self.settings.tick_period() * 1.5
+        // Connections with more jitter need a larger buffer.
+        let std_dev = self.recv_tick_time.recv_delay_std_dev().unwrap_or(0.0);
+        let tick_period = self.settings.tick_period();
+
+        (tick_period * self.jitter_buffer.min_ticks + self.jitter_buffer.std_dev_factor * std_dev)
+            .min(tick_period * self.jitter_buffer.max_ticks)
+    }
+
+    fn record_input_confirmed(&mut self, now: Instant, input_num: comn::TickNum) {
+        if self
+            .last_confirmed_input_num
+            .map_or(false, |last_num| !input_num.is_after(last_num))
+        {
+            return;
+        }
+        self.last_confirmed_input_num = Some(input_num);
+
+        if let Some(send_time) = self.input_send_times.get(&input_num) {
+            let latency = now.duration_since(*send_time);
+            self.stats
+                .input_latency_ms
+                .record(latency.as_secs_f32() * 1000.0);
+        }
+
+        // Older inputs can not be confirmed anymore.
+        let old_input_nums: Vec<comn::TickNum> = self
+            .input_send_times
+            .keys()
+            .copied()
+            .filter(|send_input_num| !send_input_num.is_after(input_num))
+            .collect();
+        for old_input_num in old_input_nums {
+            self.input_send_times.remove(&old_input_num);
+        }
     }
 
     fn tick_num(&self) -> comn::TickNum {
@@ -155,16 +394,7 @@ impl Runner {
                 .record(time_lag_deviation * 1000.0);
 
             if time_lag_deviation.abs() < MAX_TIME_LAG_DEVIATION {
-                /*let k = 0.5 + (2.0 - 0.5) / (1.0 + 2.0 * (time_lag_deviation / 0.05).exp());
-
-                if time_lag_deviation > 0.0 {
-                    1.0 / k
-                } else {
-                    k
-                }*/
-                //0.5 * ((-time_lag_deviation).tanh() + 2.0)
-                self.next_time_warp_factor =
-                    0.5 + (2.0 - 0.5) / (1.0 + 2.0 * (time_lag_deviation / 0.005).exp());
+                self.next_time_warp_factor = time_warp_factor(time_lag_deviation);
 
                 self.interp_game_time + self.next_time_warp_factor * dt.as_secs_f32()
             } else {
@@ -186,15 +416,14 @@ impl Runner {
         // Don't let time run further than the ticks that we have received.
         // This is here so that we stop local time if the server drops or
         // starts lagging heavily.
-        let max_tick_num = self
-            .received_states
-            .keys()
-            .rev()
-            .next()
-            .copied()
-            .unwrap_or(comn::TickNum(0))
-            .max(self.tick_num())
-            .max(self.next_tick_num.unwrap_or(comn::TickNum(0)));
+        let max_tick_num = comn::TickNum::newest(
+            self.received_states
+                .keys()
+                .copied()
+                .chain(std::iter::once(self.tick_num()))
+                .chain(self.next_tick_num),
+        )
+        .unwrap();
 
         // Advance our playback time.
         let prev_tick_num = self.tick_num();
@@ -211,11 +440,11 @@ impl Runner {
         //    8.
         // 3. We crossed more than one tick. This should happen only on lag
         //    spikes, be it local or in the network.
-        let mut crossed_tick_nums: Vec<comn::TickNum> = (prev_tick_num.0 + 1..=new_tick_num.0)
-            .map(comn::TickNum)
-            .collect();
+        let mut crossed_tick_nums: Vec<comn::TickNum> =
+            prev_tick_num.successors_until(new_tick_num).collect();
 
-        if crossed_tick_nums.len() > MAX_TICKS_PER_UPDATE {
+        let max_ticks_per_update = self.netcode_mode.max_ticks_per_update();
+        if crossed_tick_nums.len() > max_ticks_per_update {
             // It's possible that we have a large jump in ticks, e.g. due to a
             // lag spike, or because we are running in a background tab. In this
             // case, we don't want to overload ourselves by sending many input
@@ -226,8 +455,8 @@ impl Runner {
             // TODO: In order to nicely reinitialize prediction, we should take
             // those crossed ticks for which we actually received a server
             // state...
-            crossed_tick_nums.drain(0..crossed_tick_nums.len() - MAX_TICKS_PER_UPDATE);
-            assert!(crossed_tick_nums.len() == MAX_TICKS_PER_UPDATE);
+            crossed_tick_nums.drain(0..crossed_tick_nums.len() - max_ticks_per_update);
+            assert!(crossed_tick_nums.len() == max_ticks_per_update);
         }
 
         // Iterate over all the ticks that we have crossed, also including
@@ -243,6 +472,40 @@ impl Runner {
                 self.stats.skip_loss.record_received(tick_num.0 as usize);
             }
 
+            // Measure the latency of our inputs that this tick confirms.
+            if let Some(my_last_input_num) = self
+                .received_states
+                .get(tick_num)
+                .and_then(|received_state| received_state.my_last_input_num)
+            {
+                self.record_input_confirmed(now, my_last_input_num);
+            }
+
+            // Remember entities that were removed in this tick.
+            if let Some(received_state) = self.received_states.get(tick_num) {
+                let prev_tick_num = comn::TickNum::newest(
+                    self.received_states
+                        .keys()
+                        .copied()
+                        .filter(|prev_tick_num| prev_tick_num.is_before(*tick_num)),
+                );
+                let prev_state = match prev_tick_num {
+                    Some(prev_tick_num) => self.received_states.get(&prev_tick_num),
+                    None => None,
+                };
+
+                if let Some(prev_state) = prev_state {
+                    let removed_time = self.settings.tick_game_time(*tick_num);
+
+                    for (entity_id, entity) in prev_state.game.entities.iter() {
+                        if !received_state.game.entities.contains_key(entity_id) {
+                            self.removed_entities
+                                .insert(*entity_id, (removed_time, entity.clone()));
+                        }
+                    }
+                }
+            }
+
             // Start server events of crossed ticks.
             if let Some(tick_events) = self.received_events.get(tick_num) {
                 events.extend(tick_events.clone().into_iter());
@@ -250,14 +513,25 @@ impl Runner {
             }
 
             // Send inputs for server ticks we cross.
+            self.input_send_times.insert(*tick_num, now);
+            while self.input_send_times.len() > MAX_RECEIVED_STATES {
+                let oldest = comn::TickNum::oldest(self.input_send_times.keys().copied()).unwrap();
+                self.input_send_times.remove(&oldest);
+            }
+
             self.last_inputs.push_back((*tick_num, input.clone()));
             while self.last_inputs.len() > comn::MAX_INPUTS_PER_MESSAGE {
                 self.last_inputs.pop_front();
             }
 
-            self.send(comn::ClientMessage::Input(
-                self.last_inputs.iter().cloned().collect(),
-            ));
+            // Resending previous inputs is only redundancy against loss, so we
+            // skip it while the connection is congested.
+            let inputs = if self.webrtc_client.congested() {
+                self.last_inputs.back().cloned().into_iter().collect()
+            } else {
+                self.last_inputs.iter().cloned().collect()
+            };
+            self.send(comn::ClientMessage::Input(inputs));
 
             // Predict effects of our own input locally.
             if let Some(prediction) = self.prediction.as_mut() {
@@ -272,7 +546,32 @@ impl Runner {
 
         coarse_prof::profile!("cleanup");
 
-        if self.next_tick_num <= Some(self.tick_num()) {
+        // Forget about removed entities once they have faded out.
+        {
+            let interp_game_time = self.interp_game_time;
+            let faded_entity_ids: Vec<comn::EntityId> = self
+                .removed_entities
+                .iter()
+                .filter(|(_, (removed_time, _))| {
+                    interp_game_time >= *removed_time + DESPAWN_FADE_DURATION
+                })
+                .map(|(entity_id, _)| *entity_id)
+                .collect();
+
+            for entity_id in faded_entity_ids {
+                self.removed_entities.remove(&entity_id);
+            }
+        }
+
+        // Remember recent events, e.g. for a kill feed.
+        self.event_log.prune(now);
+        for event in events.iter() {
+            self.event_log.push(now, event.clone());
+        }
+
+        if self.next_tick_num.map_or(false, |next_tick_num| {
+            !next_tick_num.is_after(self.tick_num())
+        }) {
             // We have reached the tick that we were interpolating into, so
             // we'll need to look for the next interpolation target.
             self.next_tick_num = None;
@@ -280,12 +579,15 @@ impl Runner {
 
         // Do we have a tick to interpolate into ready?
         if self.next_tick_num.is_none() {
-            let min_ready_num = self.received_states.keys().find(|tick_num| {
-                **tick_num > self.tick_num() && tick_num.0 - self.tick_num().0 <= 3
-            });
+            let current_tick_num = self.tick_num();
+            let min_ready_num =
+                comn::TickNum::oldest(self.received_states.keys().copied().filter(|tick_num| {
+                    let distance = tick_num.distance(current_tick_num);
+                    distance > 0 && distance <= 3
+                }));
 
             if let Some(min_ready_num) = min_ready_num {
-                self.next_tick_num = Some(*min_ready_num);
+                self.next_tick_num = Some(min_ready_num);
             }
         }
 
@@ -298,7 +600,7 @@ impl Runner {
                 .received_events
                 .keys()
                 .copied()
-                .filter(|tick_num| *tick_num < self.tick_num())
+                .filter(|tick_num| tick_num.is_before(self.tick_num()))
                 .collect();
 
             for tick_num in remove_tick_nums {
@@ -320,7 +622,7 @@ impl Runner {
         self.stats
             .tick_interp
             .record(self.next_tick_num.map_or(0.0, |next_tick_num| {
-                (next_tick_num.0 - self.tick_num().0) as f32
+                next_tick_num.distance(self.tick_num()) as f32
             }));
         self.stats
             .time_warp_factor
@@ -329,6 +631,11 @@ impl Runner {
         self.stats.send_rate = self.webrtc_client.send_rate();
         self.stats.recv_rate = self.webrtc_client.recv_rate();
         self.stats.recv_delay_std_dev = self.recv_tick_time.recv_delay_std_dev().unwrap_or(-1.0);
+        self.stats.target_time_lag_ms = self.target_time_lag() * 1000.0;
+        self.stats.prediction_error = self
+            .prediction
+            .as_ref()
+            .map_or(0.0, Prediction::average_error);
 
         events
     }
@@ -338,12 +645,13 @@ impl Runner {
     pub fn state(&self) -> Option<comn::Game> {
         // Due to loss, we might not always have an authorative state for the
         // current tick num. Take the closest one then.
-        let mut state = self
-            .received_states
-            .iter()
-            .filter(|(tick_num, _)| **tick_num <= self.tick_num())
-            .next_back()
-            .map(|(_, state)| state.game.clone());
+        let mut state = comn::TickNum::newest(
+            self.received_states
+                .keys()
+                .copied()
+                .filter(|tick_num| !tick_num.is_after(self.tick_num())),
+        )
+        .map(|tick_num| self.received_states[&tick_num].game.clone());
 
         // When using prediction, overwrite the predicted entities in the
         // authorative state.
@@ -447,7 +755,7 @@ impl Runner {
 
         let signed_message = comn::SignedClientMessage(self.my_token, message);
 
-        let data = signed_message.serialize();
+        let data = signed_message.serialize(&self.codec);
 
         coarse_prof::profile!("webrtc");
         if let Err(err) = self.webrtc_client.send(&data) {
@@ -464,7 +772,7 @@ impl Runner {
         if let Some(my_last_input_num) = tick.your_last_input_num.as_ref() {
             self.stats
                 .input_delay
-                .record((recv_tick_num.0 - my_last_input_num.0) as f32 - 1.0);
+                .record(recv_tick_num.distance(*my_last_input_num) as f32 - 1.0);
         }
 
         if recv_game_time < self.interp_game_time {
@@ -497,7 +805,7 @@ impl Runner {
                     "Received state {:?} encoded w.r.t. tick num {:?}, which we do not have (our oldest is {:?})",
                     recv_tick_num,
                     diff_base_num,
-                    self.received_states.keys().next(),
+                    comn::TickNum::oldest(self.received_states.keys().copied()),
                 );
                 return;
             };
@@ -513,7 +821,8 @@ impl Runner {
                 .received_states
                 .keys()
                 .filter(|&&tick_num| {
-                    tick_num.0 + KEEP_STATES_BUFFER < diff_base_num.0 && tick_num < self.tick_num()
+                    diff_base_num.distance(tick_num) > KEEP_STATES_BUFFER as i32
+                        && tick_num.is_before(self.tick_num())
                 })
                 .copied()
                 .collect();
@@ -536,11 +845,21 @@ impl Runner {
             return;
         }
 
+        if let Some(scoreboard) = tick.scoreboard {
+            if self
+                .scoreboard
+                .as_ref()
+                .map_or(true, |(tick_num, _)| tick_num.is_before(recv_tick_num))
+            {
+                self.scoreboard = Some((recv_tick_num, scoreboard));
+            }
+        }
+
         let current_tick_num = self.tick_num();
         self.received_events.extend(
             tick.events
                 .into_iter()
-                .filter(|(tick_num, _)| *tick_num > current_tick_num),
+                .filter(|(tick_num, _)| tick_num.is_after(current_tick_num)),
         );
 
         // Statistics for debugging...
@@ -548,6 +867,8 @@ impl Runner {
             self.stats.received_ticks.record(1.0);
         }
 
+        let state_hash = new_state.state_hash();
+
         self.received_states.insert(
             recv_tick_num,
             ReceivedState {
@@ -556,9 +877,11 @@ impl Runner {
             },
         );
 
+        self.enforce_max_received_states(tick.diff_base);
+
         // Let the server know which ticks we actually received, so
         // that this can be used as the basis for delta encoding.
-        self.send(comn::ClientMessage::AckTick(recv_tick_num));
+        self.send(comn::ClientMessage::AckTick(recv_tick_num, state_hash));
 
         // Keep updating our estimate for when we expect to receive
         // ticks. This is an attempt to counter network jitter.
@@ -566,4 +889,85 @@ impl Runner {
         self.recv_tick_time
             .record_tick(time_since_start, recv_game_time);
     }
+
+    /// Drop the oldest received states and events if we have too many of them.
+    /// We never drop states that are still needed for interpolation, nor the
+    /// state that the server currently uses as a diff base.
+    fn enforce_max_received_states(&mut self, diff_base: Option<comn::TickNum>) {
+        let current_tick_num = self.tick_num();
+
+        while self.received_states.len() > MAX_RECEIVED_STATES {
+            let remove_tick_num =
+                comn::TickNum::oldest(self.received_states.keys().copied().filter(|&tick_num| {
+                    tick_num.is_before(current_tick_num) && Some(tick_num) != diff_base
+                }));
+
+            if let Some(tick_num) = remove_tick_num {
+                self.received_states.remove(&tick_num);
+            } else {
+                break;
+            }
+        }
+
+        while self.received_events.len() > MAX_RECEIVED_STATES {
+            let oldest_tick_num =
+                comn::TickNum::oldest(self.received_events.keys().copied()).unwrap();
+            self.received_events.remove(&oldest_tick_num);
+        }
+    }
+}
+
+/// Returns the factor by which to scale playback speed, given the difference
+/// between our target time lag and the current time lag. If we lag too little
+/// (positive deviation), we slow down, and if we lag too much, we speed up. At
+/// zero deviation, we play back in real time.
+fn time_warp_factor(time_lag_deviation: f32) -> f32 {
+    MIN_TIME_WARP_FACTOR
+        + (MAX_TIME_WARP_FACTOR - MIN_TIME_WARP_FACTOR)
+            / (1.0 + 2.0 * (time_lag_deviation / TIME_WARP_DEVIATION_SCALE).exp())
+}
+
+/// Logs transitions of the connection status as soon as they happen, so that
+/// the log shows exactly when we lose the connection.
+fn watch_status(webrtc_client: &webrtc::Client) {
+    webrtc_client.set_on_status_change(Box::new(|status| {
+        info!("Connection status changed to {:?}", status);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_warp_factor_is_real_time_at_zero_deviation() {
+        assert!((time_warp_factor(0.0) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn time_warp_factor_decreases_with_deviation() {
+        let num_steps = 100;
+        let factors: Vec<f32> = (0..=num_steps)
+            .map(|i| {
+                let tau = i as f32 / num_steps as f32;
+                time_warp_factor((2.0 * tau - 1.0) * MAX_TIME_LAG_DEVIATION)
+            })
+            .collect();
+
+        for (factor, next_factor) in factors.iter().zip(factors.iter().skip(1)) {
+            assert!(next_factor <= factor);
+        }
+        assert!(factors[0] > factors[num_steps]);
+    }
+
+    #[test]
+    fn time_warp_factor_reaches_bounds_at_max_deviation() {
+        let fastest = time_warp_factor(-MAX_TIME_LAG_DEVIATION);
+        let slowest = time_warp_factor(MAX_TIME_LAG_DEVIATION);
+
+        assert!(fastest <= MAX_TIME_WARP_FACTOR);
+        assert!(MAX_TIME_WARP_FACTOR - fastest < 1e-3);
+        assert!(slowest >= MIN_TIME_WARP_FACTOR);
+        assert!(slowest - MIN_TIME_WARP_FACTOR < 1e-3);
+    }
 }
@@ -30,27 +30,35 @@ impl ActiveEvent {
         gfx.set_transform(camera_transform);
 
         match self.event {
-            PlayerAteFood { player_id, amount } => {
-                if let Some((_, player)) = state.get_player_view_entity(player_id) {
-                    let dt = game_time - self.start_time;
+            PlayerAteFood {
+                player_id,
+                amount,
+                pos,
+            } => {
+                // Follow the player if possible, otherwise stay where the food
+                // was eaten.
+                let center = state
+                    .get_player_view_entity(player_id)
+                    .map_or(pos, |(_, player)| player.pos);
 
-                    for i in 0..NUM_CIRCLE_PARTICLES {
-                        let angle =
-                            (i as f32 / NUM_CIRCLE_PARTICLES as f32) * std::f32::consts::PI * 2.0;
-                        let dir = comn::Vector::new(angle.cos(), angle.sin());
-                        //let tau = (-(dt * 6.0 - 1.2).powi(2)).exp();
-                        let tau = (dt * std::f32::consts::PI / CIRCLE_DURATION).sin().powi(2);
-                        let pos = player.pos + dir * (tau * 40.0 + 50.0);
-                        let pos: mint::Vector2<f32> = pos.coords.into();
-                        let size = 10.0 * (amount as f32).sqrt().min(1.5);
-                        gfx.fill_circle(
-                            &Circle::new(pos.into(), size),
-                            Color {
-                                a: tau,
-                                ..crate::view::render::color_food()
-                            },
-                        );
-                    }
+                let dt = game_time - self.start_time;
+
+                for i in 0..NUM_CIRCLE_PARTICLES {
+                    let angle =
+                        (i as f32 / NUM_CIRCLE_PARTICLES as f32) * std::f32::consts::PI * 2.0;
+                    let dir = comn::Vector::new(angle.cos(), angle.sin());
+                    //let tau = (-(dt * 6.0 - 1.2).powi(2)).exp();
+                    let tau = (dt * std::f32::consts::PI / CIRCLE_DURATION).sin().powi(2);
+                    let pos = center + dir * (tau * 40.0 + 50.0);
+                    let pos: mint::Vector2<f32> = pos.coords.into();
+                    let size = 10.0 * (amount as f32).sqrt().min(1.5);
+                    gfx.fill_circle(
+                        &Circle::new(pos.into(), size),
+                        Color {
+                            a: tau,
+                            ..crate::view::render::color_food()
+                        },
+                    );
                 }
             }
             _ => unreachable!(),
@@ -0,0 +1,121 @@
+use std::collections::{BTreeSet, HashMap};
+
+use log::warn;
+use web_sys::HtmlAudioElement;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Sound {
+    Shot,
+    StunShot,
+    Spawn,
+    Death,
+    Food,
+    Catch,
+}
+
+impl Sound {
+    pub fn path(self) -> &'static str {
+        match self {
+            Sound::Shot => "shot.wav",
+            Sound::StunShot => "stun_shot.wav",
+            Sound::Spawn => "spawn.wav",
+            Sound::Death => "death.wav",
+            Sound::Food => "food.wav",
+            Sound::Catch => "catch.wav",
+        }
+    }
+
+    pub fn volume(self) -> f32 {
+        match self {
+            Sound::Shot => 0.3,
+            Sound::StunShot => 0.3,
+            Sound::Spawn => 0.5,
+            Sound::Death => 0.8,
+            Sound::Food => 0.2,
+            Sound::Catch => 1.0,
+        }
+    }
+}
+
+pub fn event_sound(event: &comn::Event) -> Option<Sound> {
+    use comn::Event::*;
+
+    match event {
+        PlayerShotGun { .. } => Some(Sound::Shot),
+        PlayerShotStunGun { .. } => Some(Sound::StunShot),
+        PlayerSpawned { .. } => Some(Sound::Spawn),
+        PlayerDied { .. } => Some(Sound::Death),
+        PlayerAteFood { .. } => Some(Sound::Food),
+        NewCatcher { .. } => Some(Sound::Catch),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub muted: bool,
+
+    /// Global volume, multiplied with the volume of each sound.
+    pub volume: f32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            muted: false,
+            volume: 0.5,
+        }
+    }
+}
+
+/// Plays sounds for game events via web audio.
+pub struct Audio {
+    config: Config,
+    elements: HashMap<Sound, HtmlAudioElement>,
+}
+
+impl Audio {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            elements: HashMap::new(),
+        }
+    }
+
+    pub fn toggle_mute(&mut self) {
+        self.config.muted = !self.config.muted;
+    }
+
+    pub fn play_events(&mut self, events: &[comn::Event]) {
+        if self.config.muted {
+            return;
+        }
+
+        // Play each sound at most once per frame, so that e.g. a burst of
+        // shots does not blow up the volume.
+        let sounds: BTreeSet<Sound> = events.iter().filter_map(event_sound).collect();
+
+        for sound in sounds {
+            self.play(sound);
+        }
+    }
+
+    fn play(&mut self, sound: Sound) {
+        let element = match self.elements.get(&sound) {
+            Some(element) => element,
+            None => match HtmlAudioElement::new_with_src(sound.path()) {
+                Ok(element) => self.elements.entry(sound).or_insert(element),
+                Err(e) => {
+                    warn!("Failed to create audio element for {:?}: {:?}", sound, e);
+                    return;
+                }
+            },
+        };
+
+        element.set_volume((self.config.volume * sound.volume()).into());
+        element.set_current_time(0.0);
+
+        if let Err(e) = element.play() {
+            warn!("Failed to play {:?}: {:?}", sound, e);
+        }
+    }
+}
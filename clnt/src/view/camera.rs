@@ -1,5 +1,7 @@
 use std::{collections::HashSet, time::Duration};
 
+use rand::Rng;
+
 use quicksilver::{
     geom::{Transform, Vector},
     input::Key,
@@ -11,6 +13,26 @@ use comn::geom;
 pub struct Config {
     pub smooth_pos_factor: f32,
     pub max_smooth_dist: f32,
+
+    /// For how many seconds of the followed player's velocity the camera
+    /// leads ahead of it, so that the action stays centered during dashes.
+    pub look_ahead: f32,
+
+    /// Rate at which the shake amplitude decays exponentially.
+    pub shake_decay: f32,
+    pub max_shake: f32,
+
+    /// Shake amplitudes triggered by events.
+    pub death_shake: f32,
+    pub my_death_shake: f32,
+    pub my_catcher_shake: f32,
+
+    /// Rate at which the zoom eases towards its target.
+    pub smooth_zoom_factor: f32,
+
+    /// Target zoom while we are dead or the catcher, respectively.
+    pub dead_zoom: f32,
+    pub catcher_zoom: f32,
 }
 
 impl Default for Config {
@@ -18,6 +40,15 @@ impl Default for Config {
         Self {
             smooth_pos_factor: 5.0,
             max_smooth_dist: 300.0,
+            look_ahead: 0.15,
+            shake_decay: 8.0,
+            max_shake: 40.0,
+            death_shake: 4.0,
+            my_death_shake: 25.0,
+            my_catcher_shake: 10.0,
+            smooth_zoom_factor: 3.0,
+            dead_zoom: 1.2,
+            catcher_zoom: 0.9,
         }
     }
 }
@@ -29,6 +60,10 @@ pub struct Camera {
     target: comn::Point,
     map_size: comn::Vector,
     scale: f32,
+    shake: f32,
+    shake_offset: comn::Vector,
+    zoom: f32,
+    target_zoom: f32,
 }
 
 impl Camera {
@@ -40,6 +75,44 @@ impl Camera {
             target: comn::Point::origin(),
             map_size,
             scale: 0.75,
+            shake: 0.0,
+            shake_offset: comn::Vector::zeros(),
+            zoom: 1.0,
+            target_zoom: 1.0,
+        }
+    }
+
+    /// Adds transient shake on top of following the player.
+    pub fn shake(&mut self, amplitude: f32) {
+        self.shake = (self.shake + amplitude).min(self.config.max_shake);
+    }
+
+    pub fn set_target_zoom(&mut self, zoom: f32) {
+        self.target_zoom = zoom;
+    }
+
+    pub fn on_event(&mut self, my_player_id: comn::PlayerId, event: &comn::Event) {
+        use comn::Event::*;
+
+        match event {
+            PlayerDied { player_id, .. } if *player_id == my_player_id => {
+                self.shake(self.config.my_death_shake);
+                self.set_target_zoom(self.config.dead_zoom);
+            }
+            PlayerDied { .. } => {
+                self.shake(self.config.death_shake);
+            }
+            PlayerSpawned { player_id, .. } if *player_id == my_player_id => {
+                self.set_target_zoom(1.0);
+            }
+            NewCatcher { player_id } if *player_id == my_player_id => {
+                self.shake(self.config.my_catcher_shake);
+                self.set_target_zoom(self.config.catcher_zoom);
+            }
+            NewCatcher { .. } => {
+                self.set_target_zoom(1.0);
+            }
+            _ => (),
         }
     }
 
@@ -52,9 +125,33 @@ impl Camera {
         window_size: comn::Vector,
         window_scale_factor: f32,
     ) {
-        let offset = window_size / (2.0 * self.scale / window_scale_factor);
+        let dt_secs = dt.as_secs_f32();
+
+        self.zoom = geom::smooth_to_target_f32(
+            self.config.smooth_zoom_factor,
+            self.zoom,
+            self.target_zoom,
+            dt_secs,
+        );
+        self.shake *= (-self.config.shake_decay * dt_secs).exp();
+        self.shake_offset = if self.shake > 0.01 {
+            let angle = rand::thread_rng().gen_range(0.0, 2.0 * std::f32::consts::PI);
+            comn::Vector::new(angle.cos(), angle.sin()) * self.shake
+        } else {
+            self.shake = 0.0;
+            comn::Vector::zeros()
+        };
+
+        let offset = window_size / (2.0 * self.scale() / window_scale_factor);
+
+        self.target = follow_entity.map_or(self.target, |entity| {
+            let lead = match &entity {
+                comn::Entity::Player(player) => player.vel * self.config.look_ahead,
+                _ => comn::Vector::zeros(),
+            };
 
-        self.target = follow_entity.map_or(self.target, |entity| entity.pos(game_time));
+            entity.pos(game_time) + lead
+        });
         self.target.x = self
             .target
             .x
@@ -71,7 +168,7 @@ impl Camera {
                 self.config.smooth_pos_factor,
                 self.pos,
                 self.target,
-                dt.as_secs_f32(),
+                dt_secs,
             )
         } else {
             // Camera is too far away, just snap to the target position.
@@ -81,8 +178,12 @@ impl Camera {
     }
 
     pub fn transform(&self) -> Transform {
-        let offset: mint::Vector2<f32> = (-self.centered_pos.coords).into();
-        Transform::translate(offset.into())
-            .then(Transform::scale(Vector::new(self.scale, self.scale)))
+        let offset: mint::Vector2<f32> = (-self.centered_pos.coords - self.shake_offset).into();
+        let scale = self.scale();
+        Transform::translate(offset.into()).then(Transform::scale(Vector::new(scale, scale)))
+    }
+
+    fn scale(&self) -> f32 {
+        self.scale * self.zoom
     }
 }
@@ -102,6 +102,9 @@ impl EventList {
                 DeathReason::CaughtBy(other_player_id) => {
                     format!("{} caught {}!!", other_player_id.0, player_id.0)
                 }
+                DeathReason::Stunned(other_player_id) => {
+                    format!("{} stunned {} to death", other_player_id.0, player_id.0)
+                }
             }),
             Event::NewCatcher { player_id } => Some(format!("{} is the new catcher", player_id.0)),
             _ => None,
@@ -0,0 +1,175 @@
+//! Limited vision mode: entities that are too far away from our player are
+//! culled. So that culling is not jarring, entities that have just left our
+//! vision are kept at the position where we last saw them, fading out.
+
+use std::collections::BTreeMap;
+
+/// For how long culled entities are faded out at their last-known position.
+pub const CULL_FADE_DURATION: comn::GameTime = 1.0;
+
+pub struct Fog {
+    radius: f32,
+
+    /// Position of our player in the last update.
+    center: Option<comn::Point>,
+
+    /// Entities that were visible in the last update.
+    visible: BTreeMap<comn::EntityId, comn::Entity>,
+
+    /// Entities that have left our vision, together with the game time at
+    /// which this happened and their last-known state.
+    culled: BTreeMap<comn::EntityId, (comn::GameTime, comn::Entity)>,
+}
+
+impl Fog {
+    pub fn new(radius: f32) -> Self {
+        Self {
+            radius,
+            center: None,
+            visible: BTreeMap::new(),
+            culled: BTreeMap::new(),
+        }
+    }
+
+    pub fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    pub fn culled_entities(&self) -> &BTreeMap<comn::EntityId, (comn::GameTime, comn::Entity)> {
+        &self.culled
+    }
+
+    /// Returns true if the entity should be rendered. The map's geometry is
+    /// always visible, so that players can find their way in the fog.
+    pub fn is_visible(&self, entity: &comn::Entity, time: comn::GameTime) -> bool {
+        match entity {
+            comn::Entity::Wall(_) | comn::Entity::FoodSpawn(_) | comn::Entity::Turret(_) => true,
+            _ => self.center.map_or(true, |center| {
+                (entity.pos(time) - center).norm() <= self.radius
+            }),
+        }
+    }
+
+    /// Updates which entities are visible from `center`, remembering those
+    /// that have just left our vision.
+    ///
+    /// Entities that disappear from the state while visible have been
+    /// removed from the game, so they are not kept.
+    pub fn update<'a>(
+        &mut self,
+        time: comn::GameTime,
+        center: comn::Point,
+        entities: impl Iterator<Item = (&'a comn::EntityId, &'a comn::Entity)>,
+    ) {
+        self.center = Some(center);
+
+        let mut visible = BTreeMap::new();
+
+        for (entity_id, entity) in entities {
+            if self.is_visible(entity, time) {
+                visible.insert(*entity_id, entity.clone());
+                self.culled.remove(entity_id);
+            } else if let Some(last_entity) = self.visible.remove(entity_id) {
+                self.culled.insert(*entity_id, (time, last_entity));
+            }
+        }
+
+        self.visible = visible;
+
+        // Forget about culled entities once they have faded out.
+        let expired: Vec<comn::EntityId> = self
+            .culled
+            .iter()
+            .filter(|(_, (culled_time, _))| time - culled_time >= CULL_FADE_DURATION)
+            .map(|(entity_id, _)| *entity_id)
+            .collect();
+
+        for entity_id in expired {
+            self.culled.remove(&entity_id);
+        }
+    }
+}
+
+/// Opacity of an entity that was culled at `culled_time`.
+pub fn culled_alpha(culled_time: comn::GameTime, time: comn::GameTime) -> f32 {
+    1.0 - ((time - culled_time) / CULL_FADE_DURATION)
+        .max(0.0)
+        .min(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn player(x: f32) -> comn::Entity {
+        comn::Entity::Player(comn::PlayerEntity::new(
+            comn::PlayerId(1),
+            comn::Point::new(x, 0.0),
+        ))
+    }
+
+    #[test]
+    fn culled_entity_is_kept_at_last_known_position_until_faded() {
+        let mut fog = Fog::new(100.0);
+        let center = comn::Point::origin();
+        let mut entities = BTreeMap::new();
+
+        entities.insert(comn::EntityId(1), player(90.0));
+        fog.update(0.0, center, entities.iter());
+        assert!(fog.culled_entities().is_empty());
+
+        entities.insert(comn::EntityId(1), player(110.0));
+        fog.update(0.1, center, entities.iter());
+        let (culled_time, entity) = &fog.culled_entities()[&comn::EntityId(1)];
+        assert!((culled_time - 0.1).abs() < 1e-6);
+        assert!((entity.pos(*culled_time).x - 90.0).abs() < 1e-3);
+
+        // The entity stays culled where we last saw it, even if it moves on.
+        entities.insert(comn::EntityId(1), player(200.0));
+        fog.update(0.1 + CULL_FADE_DURATION / 2.0, center, entities.iter());
+        let (_, entity) = &fog.culled_entities()[&comn::EntityId(1)];
+        assert!((entity.pos(0.0).x - 90.0).abs() < 1e-3);
+
+        fog.update(0.1 + CULL_FADE_DURATION + 0.01, center, entities.iter());
+        assert!(fog.culled_entities().is_empty());
+    }
+
+    #[test]
+    fn entity_returning_into_vision_is_no_longer_culled() {
+        let mut fog = Fog::new(100.0);
+        let center = comn::Point::origin();
+        let mut entities = BTreeMap::new();
+
+        entities.insert(comn::EntityId(1), player(90.0));
+        fog.update(0.0, center, entities.iter());
+        entities.insert(comn::EntityId(1), player(110.0));
+        fog.update(0.1, center, entities.iter());
+        assert!(fog.culled_entities().contains_key(&comn::EntityId(1)));
+
+        entities.insert(comn::EntityId(1), player(95.0));
+        fog.update(0.2, center, entities.iter());
+        assert!(fog.culled_entities().is_empty());
+        assert!(fog.is_visible(&entities[&comn::EntityId(1)], 0.2));
+    }
+
+    #[test]
+    fn removed_entity_is_not_culled() {
+        let mut fog = Fog::new(100.0);
+        let center = comn::Point::origin();
+        let mut entities = BTreeMap::new();
+
+        entities.insert(comn::EntityId(1), player(90.0));
+        fog.update(0.0, center, entities.iter());
+
+        entities.clear();
+        fog.update(0.1, center, entities.iter());
+        assert!(fog.culled_entities().is_empty());
+    }
+
+    #[test]
+    fn culled_alpha_fades_out() {
+        assert!((culled_alpha(1.0, 1.0) - 1.0).abs() < 1e-6);
+        assert!((culled_alpha(1.0, 1.0 + CULL_FADE_DURATION / 2.0) - 0.5).abs() < 1e-6);
+        assert!(culled_alpha(1.0, 1.0 + 2.0 * CULL_FADE_DURATION).abs() < 1e-6);
+    }
+}
@@ -1,6 +1,8 @@
 mod active_event;
+mod audio;
 mod camera;
 mod event_list;
+mod fog;
 mod overlay;
 mod particles;
 mod render;
@@ -20,8 +22,10 @@ use quicksilver::{
 };
 
 use active_event::ActiveEvent;
+use audio::Audio;
 use camera::Camera;
 use event_list::EventList;
+use fog::Fog;
 use particles::Particles;
 
 pub use resources::Resources;
@@ -30,6 +34,12 @@ pub use resources::Resources;
 pub struct Config {
     pub event_list: event_list::Config,
     pub camera: camera::Config,
+    pub audio: audio::Config,
+
+    /// If set, entities further away from our player than this are culled,
+    /// and everything outside of the radius is covered by fog, for a limited
+    /// vision mode.
+    pub vision_radius: Option<f32>,
 }
 
 pub struct View {
@@ -37,12 +47,15 @@ pub struct View {
     resources: Resources,
     event_list: EventList,
     camera: Camera,
+    audio: Audio,
     window_size: comn::Vector,
     window_scale_factor: f32,
     ground_particles: Particles,
     air_particles: Particles,
     last_game_time: Option<comn::GameTime>,
     active_events: Vec<ActiveEvent>,
+    show_shapes: bool,
+    fog: Option<Fog>,
 }
 
 impl View {
@@ -56,6 +69,7 @@ impl View {
     ) -> Self {
         let event_list = EventList::new(config.event_list);
         let camera = Camera::new(config.camera, settings.map.size);
+        let audio = Audio::new(config.audio);
         let ground_particles = Particles::new();
         let air_particles = Particles::new();
 
@@ -64,12 +78,15 @@ impl View {
             resources,
             event_list,
             camera,
+            audio,
             window_size,
             window_scale_factor,
             ground_particles,
             air_particles,
             last_game_time: None,
             active_events: Vec::new(),
+            show_shapes: false,
+            fog: config.vision_radius.map(Fog::new),
         }
     }
 
@@ -77,6 +94,16 @@ impl View {
         &mut self.resources
     }
 
+    pub fn toggle_mute(&mut self) {
+        self.audio.toggle_mute();
+    }
+
+    /// Toggles drawing the collision shapes of entities on top of the game,
+    /// for debugging mismatches between sprites and hitboxes.
+    pub fn toggle_shapes(&mut self) {
+        self.show_shapes = !self.show_shapes;
+    }
+
     pub fn set_window_size(&mut self, size: comn::Vector, scale_factor: f32) {
         self.window_size = size;
         self.window_scale_factor = scale_factor;
@@ -114,8 +141,11 @@ impl View {
         self.ground_particles.update(game_dt);
         self.air_particles.update(game_dt);
 
+        self.audio.play_events(game_events);
+
         for event in game_events {
             self.event_list.push(now, event.clone());
+            self.camera.on_event(self.my_player_id, event);
 
             use comn::Event::*;
             match event {
@@ -152,6 +182,12 @@ impl View {
             }
         }
 
+        if let (Some(fog), Some(state)) = (self.fog.as_mut(), state) {
+            if let Some((_, my_entity)) = state.get_player_entity(self.my_player_id) {
+                fog.update(game_time, my_entity.pos, state.entities.iter());
+            }
+        }
+
         self.active_events
             .retain(|active_event| active_event.is_active(game_time));
     }
@@ -189,7 +225,9 @@ impl View {
         gfx: &mut Graphics,
         state: Option<&comn::Game>,
         next_entities: &BTreeMap<comn::EntityId, (comn::GameTime, comn::Entity)>,
+        removed_entities: &BTreeMap<comn::EntityId, (comn::GameTime, comn::Entity)>,
         game_time: comn::GameTime,
+        bullet_extrapolation_time: comn::GameTime,
     ) -> quicksilver::Result<()> {
         if let Some(state) = state {
             {
@@ -204,8 +242,12 @@ impl View {
                     &mut self.resources,
                     state,
                     next_entities,
+                    removed_entities,
+                    self.fog.as_ref(),
                     game_time,
+                    bullet_extrapolation_time,
                     self.my_player_id,
+                    self.show_shapes,
                     self.camera.transform(),
                 )?;
             }
@@ -222,6 +264,14 @@ impl View {
                 }
             }
 
+            if let (Some(fog), Some((_, my_entity))) = (
+                self.fog.as_ref(),
+                state.get_player_entity(self.my_player_id),
+            ) {
+                coarse_prof::profile!("fog");
+                render::render_fog(gfx, my_entity.pos, fog.radius(), self.camera.transform());
+            }
+
             coarse_prof::profile!("overlay");
             overlay::render(
                 gfx,
@@ -12,17 +12,29 @@ use quicksilver::{
 };
 
 use comn::{
-    game::run::{BULLET_RADIUS, FOOD_MAX_LIFETIME, FOOD_SIZE, ROCKET_RADIUS, TURRET_RADIUS},
+    game::run::{BULLET_RADIUS, FOOD_SIZE, ROCKET_RADIUS, TURRET_RADIUS},
     geom,
     util::join,
 };
 
-use crate::view::Resources;
+use crate::{
+    runner::DESPAWN_FADE_DURATION,
+    view::{
+        fog::{self, Fog},
+        Resources,
+    },
+};
 
 pub fn color_enemy() -> Color {
     Color::from_hex("E13700")
 }
 
+pub fn color_team(team: comn::TeamId) -> Color {
+    const COLORS: &[&str] = &["072AC8", "7AE582", "E13700", "FFC100"];
+
+    Color::from_hex(COLORS[team.0 as usize % COLORS.len()])
+}
+
 pub fn color_food() -> Color {
     Color::from_hex("FFC100")
 }
@@ -42,12 +54,38 @@ pub fn interp_entities<'a>(
             join::Item::Right(_, _) => None,
             join::Item::Both(_, entity, (next_time, next_entity)) => {
                 let tau = (time - state.game_time()) / (next_time - state.game_time());
-                Some(entity.interp(next_entity, tau))
+                Some(interp_wrapped(state, entity, next_entity, tau))
             }
         },
     )
 }
 
+/// Interpolates between two states of an entity, unless the entity has
+/// wrapped around the map in between. In that case, interpolating would
+/// move it across the whole map, so we just snap.
+fn interp_wrapped(
+    state: &comn::Game,
+    entity: &comn::Entity,
+    next_entity: &comn::Entity,
+    tau: f32,
+) -> comn::Entity {
+    if state.settings.boundary_mode == comn::game::BoundaryMode::Wrap {
+        let time = state.game_time();
+        let delta = next_entity.pos(time) - entity.pos(time);
+        let half_size = state.settings.map.size / 2.0;
+
+        if delta.x.abs() > half_size.x || delta.y.abs() > half_size.y {
+            return if tau < 0.5 {
+                entity.clone()
+            } else {
+                next_entity.clone()
+            };
+        }
+    }
+
+    entity.interp(next_entity, tau)
+}
+
 pub fn interp_entity(
     state: &comn::Game,
     next_entities: &BTreeMap<comn::EntityId, (comn::GameTime, comn::Entity)>,
@@ -61,7 +99,7 @@ pub fn interp_entity(
         (Some(entity), None) => Some(entity.clone()),
         (Some(entity), Some((next_time, next_entity))) => {
             let tau = (time - state.game_time()) / (next_time - state.game_time());
-            Some(entity.interp(next_entity, tau))
+            Some(interp_wrapped(state, entity, next_entity, tau))
         }
         (None, _) => None,
     }
@@ -72,10 +110,16 @@ pub fn render_game(
     resources: &mut Resources,
     state: &comn::Game,
     next_entities: &BTreeMap<comn::EntityId, (comn::GameTime, comn::Entity)>,
+    removed_entities: &BTreeMap<comn::EntityId, (comn::GameTime, comn::Entity)>,
+    fog: Option<&Fog>,
     time: comn::GameTime,
+    bullet_extrapolation_time: comn::GameTime,
     my_player_id: comn::PlayerId,
+    show_shapes: bool,
     camera_transform: Transform,
 ) -> quicksilver::Result<()> {
+    let is_visible = |entity: &comn::Entity| fog.map_or(true, |fog| fog.is_visible(entity, time));
+
     {
         gfx.set_transform(camera_transform);
         let map_size: mint::Vector2<f32> = state.settings.map.size.into();
@@ -146,7 +190,7 @@ pub fn render_game(
     }
 
     // Lower layer
-    for entity in interp_entities(state, next_entities, time) {
+    for entity in interp_entities(state, next_entities, time).filter(is_visible) {
         match entity {
             comn::Entity::FoodSpawn(spawn) => {
                 let origin: mint::Vector2<f32> = spawn.pos.coords.into();
@@ -158,7 +202,7 @@ pub fn render_game(
         }
     }
 
-    for entity in interp_entities(state, next_entities, time) {
+    for entity in interp_entities(state, next_entities, time).filter(is_visible) {
         match entity {
             comn::Entity::FoodSpawn(spawn) => {
                 let transform = rect_to_transform(&spawn.rect(time));
@@ -176,15 +220,7 @@ pub fn render_game(
                 let rect = Rectangle::new(Vector::new(-0.5, -0.5), Vector::new(1.0, 1.0));
                 gfx.set_transform(transform.then(camera_transform));
 
-                let alpha = pareen::constant(1.0)
-                    .seq_ease_out(
-                        0.9,
-                        pareen::easer::functions::Sine,
-                        0.1,
-                        pareen::constant(0.0),
-                    )
-                    .squeeze(food.start_time..=food.start_time + FOOD_MAX_LIFETIME)
-                    .eval(time);
+                let alpha = food.alpha(time);
                 gfx.fill_rect(
                     &rect,
                     Color {
@@ -207,7 +243,7 @@ pub fn render_game(
     }
 
     // Main layer
-    for entity in interp_entities(state, next_entities, time) {
+    for entity in interp_entities(state, next_entities, time).filter(is_visible) {
         match entity {
             comn::Entity::Player(player) => {
                 render_player(
@@ -238,7 +274,8 @@ pub fn render_game(
                     (danger_guy.pos(time) - danger_guy.size / 2.0).coords.into();
                 let size: mint::Vector2<f32> = danger_guy.size.into();
                 let rect = Rectangle::new(Vector::new(-0.5, -0.5), Vector::new(1.0, 1.0));
-                let transform = if danger_guy.end_pos.y != danger_guy.start_pos.y {
+                let walk_dir = danger_guy.walk_dir(time);
+                let transform = if walk_dir.y.abs() > walk_dir.x.abs() {
                     Transform::rotate(90.0)
                 } else {
                     Transform::IDENTITY
@@ -250,14 +287,12 @@ pub fn render_game(
                 gfx.set_transform(transform);
 
                 // We need to play the frames backwards depending on the
-                // initial orientation of the danger guy.
-                let is_positive_first = (danger_guy.end_pos - danger_guy.start_pos)
-                    .dot(&comn::Vector::new(1.0, 1.0))
-                    > 0.0;
+                // direction in which the danger guy is walking along its path.
+                let is_positive = walk_dir.dot(&comn::Vector::new(1.0, 1.0)) > 0.0;
                 let walk_frames = |fps: f32| {
                     let anim = || pareen::cycle(7, fps);
 
-                    pareen::cond(is_positive_first, anim(), anim().backwards(0.0))
+                    pareen::cond(is_positive, anim(), anim().backwards(0.0))
                 };
 
                 let fps_0 = danger_guy.speed.0 / 12.0;
@@ -266,9 +301,7 @@ pub fn render_game(
                     pareen::constant(0).dur(danger_guy.wait_time.0),
                     walk_frames(fps_0).dur(danger_guy.walk_time().0),
                     pareen::constant(0).dur(danger_guy.wait_time.1),
-                    walk_frames(fps_1)
-                        .backwards(0.0)
-                        .dur(danger_guy.walk_time().1),
+                    walk_frames(fps_1).dur(danger_guy.walk_time().1),
                 )
                 .repeat()
                 .eval(time);
@@ -289,7 +322,12 @@ pub fn render_game(
                 //gfx.stroke_rect(&rect, Color::BLACK);
             }
             comn::Entity::Bullet(bullet) => {
-                let origin: mint::Vector2<f32> = bullet.pos(time).coords.into();
+                let pos = if bullet.owner == Some(my_player_id) {
+                    bullet.pos(time)
+                } else {
+                    extrapolate_bullet_pos(state, &bullet, time, bullet_extrapolation_time)
+                };
+                let origin: mint::Vector2<f32> = pos.coords.into();
                 let circle = Circle::new(origin.into(), BULLET_RADIUS);
                 let color = if bullet.owner == Some(my_player_id) {
                     Color::ORANGE
@@ -314,7 +352,7 @@ pub fn render_game(
             }
             comn::Entity::Turret(turret) => {
                 let origin: mint::Vector2<f32> = turret.pos.coords.into();
-                let color = if turret.target.is_some() {
+                let color = if turret.target.is_some() && !turret.is_reloading(time) {
                     color_enemy()
                 } else {
                     Color::from_rgba(150, 150, 150, 1.0)
@@ -336,24 +374,269 @@ pub fn render_game(
 
                 gfx.fill_rect(&rect, Color::BLACK);
             }
-            comn::Entity::Wall(wall) => {
-                let transform = rect_to_transform(&wall.rect.to_rect());
-                let rect = Rectangle::new(Vector::new(-0.5, -0.5), Vector::new(1.0, 1.0));
-                gfx.set_transform(transform.then(camera_transform));
-                gfx.fill_rect(&rect, Color::from_hex("373145"));
-                //gfx.fill_rect(&rect, color_wall());
-                gfx.stroke_rect(&rect, Color::BLACK);
-            }
+            comn::Entity::Wall(wall) => match &wall.shape {
+                geom::Shape::Polygon(polygon) => {
+                    let points: Vec<Vector> = polygon
+                        .points
+                        .iter()
+                        .map(|p| Vector::new(p.x, p.y))
+                        .collect();
+                    gfx.set_transform(camera_transform);
+                    gfx.fill_polygon(&points, Color::from_hex("373145"));
+                    gfx.stroke_polygon(&points, Color::BLACK);
+                }
+                geom::Shape::Circle(circle) => {
+                    let origin: mint::Vector2<f32> = circle.center.coords.into();
+                    let circle = Circle::new(origin.into(), circle.radius);
+                    gfx.set_transform(camera_transform);
+                    gfx.fill_circle(&circle, Color::from_hex("373145"));
+                    gfx.stroke_circle(&circle, Color::BLACK);
+                }
+                geom::Shape::AaRect(aa_rect) => {
+                    render_wall_rect(gfx, &aa_rect.to_rect(), camera_transform);
+                }
+                geom::Shape::Rect(rect) => {
+                    render_wall_rect(gfx, rect, camera_transform);
+                }
+            },
             comn::Entity::FoodSpawn(_) => (),
             comn::Entity::Food(_) => (),
         }
     }
 
+    for (removed_time, entity) in removed_entities.values() {
+        if is_visible(entity) {
+            let alpha = 1.0
+                - ((time - removed_time) / DESPAWN_FADE_DURATION)
+                    .max(0.0)
+                    .min(1.0);
+            render_faded_entity(
+                gfx,
+                entity,
+                *removed_time,
+                alpha,
+                my_player_id,
+                camera_transform,
+            );
+        }
+    }
+
+    if let Some(fog) = fog {
+        for (culled_time, entity) in fog.culled_entities().values() {
+            render_culled_entity(
+                gfx,
+                entity,
+                *culled_time,
+                time,
+                my_player_id,
+                camera_transform,
+            );
+        }
+    }
+
+    if show_shapes {
+        for entity in interp_entities(state, next_entities, time) {
+            render_shape(gfx, &entity.shape(time), camera_transform);
+        }
+    }
+
     gfx.set_transform(Transform::IDENTITY);
 
     Ok(())
 }
 
+/// Covers everything outside of the given circle by fog that gets denser with
+/// the distance, so that the edge of vision is soft.
+pub fn render_fog(
+    gfx: &mut Graphics,
+    center: comn::Point,
+    radius: f32,
+    camera_transform: Transform,
+) {
+    const NUM_SEGMENTS: usize = 64;
+    const NUM_RINGS: usize = 8;
+    const RING_WIDTH: f32 = 20.0;
+    const OUTER_RADIUS: f32 = 10000.0;
+
+    gfx.set_transform(camera_transform);
+
+    for ring in 0..=NUM_RINGS {
+        let inner = radius + ring as f32 * RING_WIDTH;
+        let outer = if ring == NUM_RINGS {
+            OUTER_RADIUS
+        } else {
+            inner + RING_WIDTH
+        };
+        let color = Color {
+            a: 0.9 * (ring + 1) as f32 / (NUM_RINGS + 1) as f32,
+            ..Color::from_hex("0A0903")
+        };
+
+        for segment in 0..NUM_SEGMENTS {
+            let angle = |i: usize| i as f32 / NUM_SEGMENTS as f32 * 2.0 * std::f32::consts::PI;
+            let point = |r: f32, i: usize| {
+                let p = center + comn::Vector::new(angle(i).cos(), angle(i).sin()) * r;
+                Vector::new(p.x, p.y)
+            };
+
+            gfx.fill_polygon(
+                &[
+                    point(inner, segment),
+                    point(outer, segment),
+                    point(outer, segment + 1),
+                    point(inner, segment + 1),
+                ],
+                color,
+            );
+        }
+    }
+}
+
+/// Outlines the given collision shape, for debugging.
+fn render_shape(gfx: &mut Graphics, shape: &geom::Shape, camera_transform: Transform) {
+    let color = Color::from_rgba(255, 0, 255, 1.0);
+
+    match shape {
+        geom::Shape::AaRect(aa_rect) => {
+            render_shape(gfx, &geom::Shape::Rect(aa_rect.to_rect()), camera_transform);
+        }
+        geom::Shape::Rect(rect) => {
+            let rect_transform = rect_to_transform(rect);
+            let unit_rect = Rectangle::new(Vector::new(-0.5, -0.5), Vector::new(1.0, 1.0));
+            gfx.set_transform(rect_transform.then(camera_transform));
+            gfx.stroke_rect(&unit_rect, color);
+        }
+        geom::Shape::Circle(circle) => {
+            let origin: mint::Vector2<f32> = circle.center.coords.into();
+            gfx.set_transform(camera_transform);
+            gfx.stroke_circle(&Circle::new(origin.into(), circle.radius), color);
+        }
+        geom::Shape::Polygon(polygon) => {
+            let points: Vec<Vector> = polygon
+                .points
+                .iter()
+                .map(|p| Vector::new(p.x, p.y))
+                .collect();
+            gfx.set_transform(camera_transform);
+            gfx.stroke_polygon(&points, color);
+        }
+    }
+}
+
+/// Draws an entity that has left our vision at its last-known position,
+/// fading out.
+fn render_culled_entity(
+    gfx: &mut Graphics,
+    entity: &comn::Entity,
+    culled_time: comn::GameTime,
+    time: comn::GameTime,
+    my_player_id: comn::PlayerId,
+    camera_transform: Transform,
+) {
+    let alpha = fog::culled_alpha(culled_time, time);
+    let player = match entity {
+        comn::Entity::Player(player) => player.to_view(),
+        comn::Entity::PlayerView(player) => player.clone(),
+        _ => {
+            render_faded_entity(
+                gfx,
+                entity,
+                culled_time,
+                alpha,
+                my_player_id,
+                camera_transform,
+            );
+            return;
+        }
+    };
+
+    // Player sprites cannot be faded, so we only hint at where the player was.
+    let origin: mint::Vector2<f32> = player.pos.coords.into();
+    let circle = Circle::new(origin.into(), player.size.norm() * 0.5);
+    gfx.set_transform(camera_transform);
+    gfx.fill_circle(
+        &circle,
+        Color {
+            a: 0.5 * alpha,
+            ..color_enemy()
+        },
+    );
+    gfx.stroke_circle(
+        &circle,
+        Color {
+            a: alpha,
+            ..Color::BLACK
+        },
+    );
+}
+
+/// Draws a bullet, rocket or food as it was at `entity_time` with the given
+/// opacity, e.g. for fading out entities that have been removed.
+fn render_faded_entity(
+    gfx: &mut Graphics,
+    entity: &comn::Entity,
+    entity_time: comn::GameTime,
+    alpha: f32,
+    my_player_id: comn::PlayerId,
+    camera_transform: Transform,
+) {
+    let black = Color {
+        a: alpha,
+        ..Color::BLACK
+    };
+
+    match entity {
+        comn::Entity::Bullet(bullet) => {
+            let origin: mint::Vector2<f32> = bullet.pos(entity_time).coords.into();
+            let circle = Circle::new(origin.into(), BULLET_RADIUS);
+            let color = if bullet.owner == Some(my_player_id) {
+                Color::ORANGE
+            } else {
+                color_enemy()
+            };
+            gfx.set_transform(camera_transform);
+            gfx.fill_circle(&circle, Color { a: alpha, ..color });
+            gfx.stroke_circle(&circle, black);
+        }
+        comn::Entity::Rocket(rocket) => {
+            let origin: mint::Vector2<f32> = rocket.pos(entity_time).coords.into();
+            let circle = Circle::new(origin.into(), ROCKET_RADIUS);
+            let color = if rocket.owner == Some(my_player_id) {
+                Color::ORANGE
+            } else {
+                color_enemy()
+            };
+            gfx.set_transform(camera_transform);
+            gfx.fill_circle(&circle, Color { a: alpha, ..color });
+            gfx.stroke_circle(&circle, black);
+        }
+        // Food that expired has already faded out on its own.
+        comn::Entity::Food(food) if !food.is_fading(entity_time) => {
+            let transform = rect_to_transform(&food.rect(entity_time));
+            let rect = Rectangle::new(Vector::new(-0.5, -0.5), Vector::new(1.0, 1.0));
+            gfx.set_transform(transform.then(camera_transform));
+            gfx.fill_rect(
+                &rect,
+                Color {
+                    a: alpha,
+                    ..color_food()
+                },
+            );
+            gfx.stroke_rect(&rect, black);
+        }
+        _ => (),
+    }
+}
+
+fn render_wall_rect(gfx: &mut Graphics, wall_rect: &geom::Rect, camera_transform: Transform) {
+    let transform = rect_to_transform(wall_rect);
+    let rect = Rectangle::new(Vector::new(-0.5, -0.5), Vector::new(1.0, 1.0));
+    gfx.set_transform(transform.then(camera_transform));
+    gfx.fill_rect(&rect, Color::from_hex("373145"));
+    //gfx.fill_rect(&rect, color_wall());
+    gfx.stroke_rect(&rect, Color::BLACK);
+}
+
 // 0a0903,ffc100,e13700,072ac8,7ae582
 
 fn render_player(
@@ -386,8 +669,25 @@ fn render_player(
     );
     gfx.draw_subimage(&resources.player, sub_rect, rect);
 
+    if let Some(team) = state
+        .players
+        .get(&player.owner)
+        .and_then(|player| player.team)
+    {
+        gfx.stroke_rect(&rect, color_team(team));
+    }
+
     gfx.set_transform(camera_transform);
 
+    if player.is_protected(time) {
+        let origin: mint::Vector2<f32> = player.pos.coords.into();
+        let radius = player.size.norm() * 0.6;
+        gfx.stroke_circle(
+            &Circle::new(origin.into(), radius),
+            Color::from_rgba(100, 200, 255, 0.8),
+        );
+    }
+
     if let Some(hook) = player.hook.as_ref() {
         render_hook(gfx, state, next_entities, time, player.pos, hook)?;
     }
@@ -415,7 +715,7 @@ fn render_hook(
         } => (*hook_pos, false),
         comn::Hook::Attached { target, offset } => {
             let hook_pos = interp_entity(state, next_entities, time, *target)
-                .map_or(pos, |interp_target| interp_target.pos(time) + offset);
+                .map_or(pos, |interp_target| interp_target.to_world(time, *offset));
             (hook_pos, false)
         }
         comn::Hook::Contracting { pos: hook_pos } => (*hook_pos, true),
@@ -440,6 +740,30 @@ fn render_hook(
     Ok(())
 }
 
+/// Position of the bullet slightly in the future, stopping at walls so that we
+/// do not show bullets flying through them.
+fn extrapolate_bullet_pos(
+    state: &comn::Game,
+    bullet: &comn::entities::Bullet,
+    time: comn::GameTime,
+    extrapolation_time: comn::GameTime,
+) -> comn::Point {
+    let pos = bullet.pos(time);
+    let ray = geom::Ray {
+        origin: pos,
+        dir: bullet.pos(time + extrapolation_time) - pos,
+    };
+    let walls = state
+        .entities
+        .iter()
+        .filter(|(_, entity)| entity.is_wall_like());
+    let t = comn::Game::trace_ray(&ray, time, walls)
+        .map_or(1.0, |hit| hit.t.max(0.0))
+        .min(1.0);
+
+    ray.origin + t * ray.dir
+}
+
 fn rect_to_transform(rect: &geom::Rect) -> Transform {
     let m_column: mint::ColumnMatrix3<f32> = na::Matrix3::new(
         rect.x_edge.x,
@@ -40,11 +40,13 @@ pub fn render(
 
     let x0 = pos.x;
     let x1 = pos.x + 50.0;
-    let x2 = pos.x + 200.0;
+    let x2 = pos.x + 150.0;
+    let x3 = pos.x + 200.0;
 
     font.draw(gfx, "id", Color::BLUE, Vector::new(x0, pos.y))?;
     font.draw(gfx, "name", Color::BLUE, Vector::new(x1, pos.y))?;
-    font.draw(gfx, "food", Color::BLUE, Vector::new(x2, pos.y))?;
+    font.draw(gfx, "score", Color::BLUE, Vector::new(x2, pos.y))?;
+    font.draw(gfx, "food", Color::BLUE, Vector::new(x3, pos.y))?;
 
     for (i, (player_id, player)) in players.into_iter().enumerate() {
         let y = pos.y + (i + 1) as f32 * 12.0;
@@ -55,7 +57,8 @@ pub fn render(
         };
         font.draw(gfx, &player_id.0.to_string(), color, Vector::new(x0, y))?;
         font.draw(gfx, &player.name, color, Vector::new(x1, y))?;
-        font.draw(gfx, &player.food.to_string(), color, Vector::new(x2, y))?;
+        font.draw(gfx, &player.score.to_string(), color, Vector::new(x2, y))?;
+        font.draw(gfx, &player.food.to_string(), color, Vector::new(x3, y))?;
     }
 
     Ok(())
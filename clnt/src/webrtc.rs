@@ -8,7 +8,7 @@ use std::{cell::RefCell, collections::VecDeque, rc::Rc, time::Duration};
 use instant::Instant;
 use log::{info, warn};
 
-use js_sys::{Reflect, JSON};
+use js_sys::Reflect;
 use wasm_bindgen::{prelude::*, JsCast};
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
@@ -31,6 +31,22 @@ pub enum ConnectError {
     AddIceCandidate(JsValue),
 }
 
+/// If the data channel buffers more than this number of bytes, we consider it
+/// congested and hold back further messages.
+const MAX_BUFFERED_AMOUNT: u32 = 16 * 1024;
+
+/// Maximal number of messages that we hold back while congested. Beyond that,
+/// the oldest messages are dropped, since they are likely outdated anyway.
+const MAX_SEND_QUEUE_LEN: usize = 32;
+
+#[derive(Debug, Clone)]
+pub enum SendError {
+    /// The connection has been closed, either by us or by the peer, so we
+    /// will not be able to send anything anymore.
+    Closed,
+    Channel(JsValue),
+}
+
 // TODO: webrtc::Status is redundant, can be replaced by ready_state()
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Status {
@@ -40,34 +56,89 @@ pub enum Status {
     Error,
 }
 
+/// Artificial lag and loss for testing bad connections locally, similar to
+/// the server's `FakeBadNet`. The settings apply in each direction.
+#[derive(Debug, Clone)]
+pub struct FakeBadNetConfig {
+    pub lag: Duration,
+    pub loss: f32,
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub address: String,
-    pub ice_server_urls: Vec<String>,
+    pub ice_servers: Vec<comn::IceServer>,
+    pub codec: comn::CodecKind,
+    pub fake_bad_net: Option<FakeBadNetConfig>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             address: "/connect_webrtc".to_string(),
-            ice_server_urls: vec![
-                "stun:stun.l.google.com:19302".to_string(),
-                /*"stun:stun1.l.google.com:19302".to_string(),
-                "stun:stun2.l.google.com:19302".to_string(),
-                "stun:stun3.l.google.com:19302".to_string(),
-                "stun:stun4.l.google.com:19302".to_string(),*/
-            ],
+            ice_servers: vec![comn::IceServer {
+                urls: vec![
+                    "stun:stun.l.google.com:19302".to_string(),
+                    /*"stun:stun1.l.google.com:19302".to_string(),
+                    "stun:stun2.l.google.com:19302".to_string(),
+                    "stun:stun3.l.google.com:19302".to_string(),
+                    "stun:stun4.l.google.com:19302".to_string(),*/
+                ],
+                username: None,
+                credential: None,
+            }],
+            codec: comn::CodecKind::default(),
+            fake_bad_net: None,
+        }
+    }
+}
+
+impl Config {
+    /// Returns the config for connecting after the server accepted our join
+    /// request. Defaults are used for anything the server did not specify.
+    pub fn for_join(join: &comn::JoinSuccess) -> Self {
+        let default = Self::default();
+
+        Self {
+            ice_servers: if join.ice_servers.is_empty() {
+                default.ice_servers
+            } else {
+                join.ice_servers.clone()
+            },
+            address: join.webrtc_signaling_url.clone().unwrap_or(default.address),
+            codec: join.codec,
+            ..default
         }
     }
 }
 
 pub struct Data {
     on_message: Box<dyn Fn(&Data, &comn::ServerMessage)>,
+    codec: comn::CodecKind,
     channel: RtcDataChannel,
     status: Status,
+
+    /// Called whenever `status` changes. Note that this is called while the
+    /// `Data` is borrowed, so it must not call back into the `Client`.
+    on_status_change: Option<Box<dyn Fn(Status)>>,
+
+    /// Set if we closed the connection ourselves, so that we can tell this
+    /// apart from the peer closing the connection.
+    closed_locally: bool,
+
     received: VecDeque<(Instant, comn::ServerMessage)>,
     now: (Instant, Instant),
 
+    fake_bad_net: Option<FakeBadNetConfig>,
+
+    /// Messages that are held back due to `fake_bad_net`, ordered by their
+    /// delivery time, since the lag is constant.
+    delayed_received: VecDeque<(Instant, comn::ServerMessage)>,
+    delayed_sends: RefCell<VecDeque<(Instant, Vec<u8>)>>,
+
+    /// Messages that are held back because the channel is congested.
+    send_queue: RefCell<VecDeque<Vec<u8>>>,
+
     recv_rate: stats::Var,
     send_rate: RefCell<stats::Var>,
 
@@ -96,10 +167,17 @@ impl Client {
 
         let data = Rc::new(RefCell::new(Data {
             on_message,
+            codec: config.codec,
             channel,
             status: Status::Connecting,
+            on_status_change: None,
+            closed_locally: false,
             received: VecDeque::new(),
             now: (Instant::now(), Instant::now()),
+            fake_bad_net: config.fake_bad_net.clone(),
+            delayed_received: VecDeque::new(),
+            delayed_sends: RefCell::new(VecDeque::new()),
+            send_queue: RefCell::new(VecDeque::new()),
             recv_rate: stats::Var::new(Duration::from_secs(10)),
             send_rate: RefCell::new(stats::Var::new(Duration::from_secs(10))),
             _peer: peer.clone(),
@@ -109,7 +187,6 @@ impl Client {
             let data = data.clone();
             move |_: &Event| data.borrow_mut().on_open()
         }) as Box<dyn FnMut(&Event)>);
-        // TODO: We'll also want to handle close events caused by the peer
         let on_close = Closure::wrap(Box::new({
             let data = data.clone();
             move |_: &Event| data.borrow_mut().on_close()
@@ -181,17 +258,41 @@ impl Client {
     }
 
     pub fn take_message(&mut self) -> Option<(Instant, comn::ServerMessage)> {
-        self.data.borrow_mut().received.pop_front()
+        let mut data = self.data.borrow_mut();
+        data.deliver_delayed(Instant::now());
+        data.flush_send_queue();
+        data.received.pop_front()
     }
 
-    pub fn send(&self, data: &[u8]) -> Result<(), JsValue> {
+    /// Returns true if the channel can not keep up with what we send, so that
+    /// low-priority messages should be skipped.
+    pub fn congested(&self) -> bool {
+        self.data.borrow().congested()
+    }
+
+    pub fn send(&self, data: &[u8]) -> Result<(), SendError> {
         self.data.borrow().send(data)
     }
 
+    /// Closes the connection from our side.
+    pub fn close(&self) {
+        let mut data = self.data.borrow_mut();
+        data.closed_locally = true;
+        data.delayed_sends.borrow_mut().clear();
+        data.send_queue.borrow_mut().clear();
+        data.channel.close();
+    }
+
     pub fn status(&self) -> Status {
         self.data.borrow().status
     }
 
+    /// Registers a callback that is invoked on each transition of `status`,
+    /// so that we do not have to rely on polling.
+    pub fn set_on_status_change(&self, on_status_change: Box<dyn Fn(Status)>) {
+        self.data.borrow_mut().on_status_change = Some(on_status_change);
+    }
+
     pub fn debug_ready_state(&self) {
         info!(
             "ready state: {:?}",
@@ -221,19 +322,40 @@ impl Data {
     pub fn on_open(&mut self) {
         info!("Connection has been established");
 
-        self.status = Status::Open;
+        self.set_status(Status::Open);
     }
 
     pub fn on_close(&mut self) {
-        info!("Connection has been closed");
+        if self.closed_locally {
+            info!("Connection has been closed by us");
+        } else {
+            info!("Connection has been closed by the peer");
+        }
 
-        self.status = Status::Closed;
+        self.set_status(Status::Closed);
     }
 
     pub fn on_error(&mut self, error: &ErrorEvent) {
         warn!("Connection error: {:?}", error);
 
-        self.status = Status::Error;
+        self.set_status(Status::Error);
+    }
+
+    fn set_status(&mut self, status: Status) {
+        if status != self.status {
+            self.status = status;
+
+            if !self.is_open() {
+                // There is no point in holding on to messages that we can not
+                // send anymore.
+                self.delayed_sends.borrow_mut().clear();
+                self.send_queue.borrow_mut().clear();
+            }
+
+            if let Some(on_status_change) = self.on_status_change.as_ref() {
+                on_status_change(status);
+            }
+        }
     }
 
     pub fn on_message(&mut self, event: &MessageEvent) {
@@ -248,7 +370,7 @@ impl Data {
 
             self.recv_rate.record(vec.len() as f32);
 
-            if let Some(message) = comn::ServerMessage::deserialize(&vec) {
+            if let Some(message) = comn::ServerMessage::deserialize(&self.codec, &vec) {
                 message
             } else {
                 warn!("Failed to deserialize message, ignoring");
@@ -259,30 +381,131 @@ impl Data {
             return;
         };
 
+        if let Some(fake_bad_net) = self.fake_bad_net.as_ref() {
+            if rand::random::<f32>() >= fake_bad_net.loss {
+                self.delayed_received
+                    .push_back((recv_time + fake_bad_net.lag, message));
+            }
+        } else {
+            self.deliver(recv_time, message);
+        }
+    }
+
+    fn deliver(&mut self, recv_time: Instant, message: comn::ServerMessage) {
         (self.on_message)(self, &message);
 
         self.received.push_back((recv_time, message));
     }
 
-    pub fn send(&self, data: &[u8]) -> Result<(), JsValue> {
+    /// Deliver the messages that have been held back by `fake_bad_net` and
+    /// whose time has come.
+    fn deliver_delayed(&mut self, now: Instant) {
+        while self
+            .delayed_received
+            .front()
+            .map_or(false, |(deliver_time, _)| *deliver_time <= now)
+        {
+            let (deliver_time, message) = self.delayed_received.pop_front().unwrap();
+            self.deliver(deliver_time, message);
+        }
+
+        while self
+            .delayed_sends
+            .borrow()
+            .front()
+            .map_or(false, |(send_time, _)| *send_time <= now)
+        {
+            let (_, data) = self.delayed_sends.borrow_mut().pop_front().unwrap();
+            if let Err(err) = self.send_now(&data) {
+                warn!("Failed to send delayed message: {:?}", err);
+            }
+        }
+    }
+
+    fn congested(&self) -> bool {
+        !self.send_queue.borrow().is_empty() || self.channel.buffered_amount() > MAX_BUFFERED_AMOUNT
+    }
+
+    /// Send messages that we held back due to congestion, as far as the
+    /// channel allows.
+    fn flush_send_queue(&self) {
+        while self.is_open() && self.channel.buffered_amount() <= MAX_BUFFERED_AMOUNT {
+            let data = if let Some(data) = self.send_queue.borrow_mut().pop_front() {
+                data
+            } else {
+                break;
+            };
+
+            if let Err(err) = self.channel.send_with_u8_array(&data) {
+                warn!("Failed to send queued message: {:?}", err);
+            }
+        }
+    }
+
+    fn send_now(&self, data: &[u8]) -> Result<(), SendError> {
+        self.flush_send_queue();
+
+        if self.congested() {
+            let mut send_queue = self.send_queue.borrow_mut();
+            if send_queue.len() >= MAX_SEND_QUEUE_LEN {
+                send_queue.pop_front();
+            }
+            send_queue.push_back(data.to_vec());
+
+            Ok(())
+        } else {
+            self.channel
+                .send_with_u8_array(data)
+                .map_err(SendError::Channel)
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        match self.status {
+            Status::Connecting | Status::Open => !self.closed_locally,
+            Status::Closed | Status::Error => false,
+        }
+    }
+
+    pub fn send(&self, data: &[u8]) -> Result<(), SendError> {
+        if !self.is_open() {
+            return Err(SendError::Closed);
+        }
+
         self.send_rate.borrow_mut().record(data.len() as f32);
 
-        self.channel.send_with_u8_array(data)
+        if let Some(fake_bad_net) = self.fake_bad_net.as_ref() {
+            if rand::random::<f32>() >= fake_bad_net.loss {
+                self.delayed_sends
+                    .borrow_mut()
+                    .push_back((Instant::now() + fake_bad_net.lag, data.to_vec()));
+            }
+
+            Ok(())
+        } else {
+            self.send_now(data)
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.close();
+
+        // Our callbacks are about to be freed, so JavaScript must not call
+        // them anymore.
+        let data = self.data.borrow();
+        data.channel.set_onopen(None);
+        data.channel.set_onclose(None);
+        data.channel.set_onerror(None);
+        data.channel.set_onmessage(None);
     }
 }
 
 fn new_rtc_peer_connection(config: &Config) -> Result<RtcPeerConnection, ConnectError> {
-    let ice_servers: JsValue = {
-        let json = "[{\"urls\":[".to_string()
-            + &config
-                .ice_server_urls
-                .iter()
-                .map(|url| "\"".to_string() + url + "\"")
-                .collect::<Vec<_>>()
-                .join(",")
-            + "]}]";
-        JSON::parse(&json).unwrap()
-    };
+    // Serializing gives us the layout of `RTCIceServer`, with TURN credentials
+    // only included where they are given.
+    let ice_servers = JsValue::from_serde(&config.ice_servers).unwrap();
 
     info!("WebRTC ICE servers: {:?}", ice_servers);
 
@@ -306,7 +529,8 @@ async fn request_session(
 ) -> Result<JsValue, ConnectError> {
     let mut opts = web_sys::RequestInit::new();
     opts.method("POST");
-    opts.mode(web_sys::RequestMode::SameOrigin);
+    // The server may tell us to connect through a different origin.
+    opts.mode(web_sys::RequestMode::Cors);
     opts.body(Some(
         &Reflect::get(&offer, &JsValue::from_str("sdp")).unwrap(),
     ));
@@ -0,0 +1,243 @@
+//! Wire formats for the messages exchanged between server and client.
+//!
+//! The server decides on a format and tells the client about it in
+//! `JoinSuccess`, so that we can compare the bandwidth of different formats
+//! without touching the call sites.
+
+use std::io::{Read, Write};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+pub trait Codec {
+    fn encode<T: Serialize>(&self, value: &T) -> Vec<u8>;
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Option<T>;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MsgpackCodec;
+
+impl Codec for MsgpackCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Vec<u8> {
+        rmp_serde::to_vec(value).unwrap()
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Option<T> {
+        rmp_serde::from_read_ref(data).ok()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Vec<u8> {
+        bincode::serialize(value).unwrap()
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Option<T> {
+        bincode::deserialize(data).ok()
+    }
+}
+
+/// Selects one of the codecs at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CodecKind {
+    Msgpack,
+    Bincode,
+}
+
+impl Default for CodecKind {
+    fn default() -> Self {
+        CodecKind::Msgpack
+    }
+}
+
+impl Codec for CodecKind {
+    fn encode<T: Serialize>(&self, value: &T) -> Vec<u8> {
+        match self {
+            CodecKind::Msgpack => MsgpackCodec.encode(value),
+            CodecKind::Bincode => BincodeCodec.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Option<T> {
+        match self {
+            CodecKind::Msgpack => MsgpackCodec.decode(data),
+            CodecKind::Bincode => BincodeCodec.decode(data),
+        }
+    }
+}
+
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+pub fn inflate(data: &[u8]) -> Option<Vec<u8>> {
+    let mut result = Vec::new();
+    DeflateDecoder::new(data).read_to_end(&mut result).ok()?;
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    use crate::{
+        game::{MapBuilder, SettingsBuilder},
+        util::diff::Diffable,
+        ClientMessage, Entity, EntityId, Event, Game, Input, PlayerEntity, PlayerId, PlayerToken,
+        Point, Scoreboard, SequenceNum, ServerMessage, SignedClientMessage, Tick, TickNum, Vector,
+        HEADER_DEFLATE, HEADER_PLAIN,
+    };
+
+    fn test_tick(num_entities: u32) -> Tick {
+        let map = MapBuilder::new(Vector::new(1000.0, 1000.0)).build();
+        let settings = Arc::new(SettingsBuilder::new(map).build());
+        let base_state = Game::new(settings.clone());
+        let mut state = Game::new(settings);
+
+        state.tick_num = TickNum(7);
+        for i in 0..num_entities {
+            state.entities.insert(
+                EntityId(i),
+                Entity::Player(PlayerEntity::new(
+                    PlayerId(i),
+                    Point::new(i as f32, 2.0 * i as f32),
+                )),
+            );
+        }
+
+        Tick {
+            diff_base: None,
+            diff: base_state.diff(&state),
+            events: vec![(
+                TickNum(7),
+                vec![Event::NewCatcher {
+                    player_id: PlayerId(1),
+                }],
+            )],
+            your_last_input_num: Some(TickNum(6)),
+            scoreboard: Some(Scoreboard::default()),
+        }
+    }
+
+    fn client_messages() -> Vec<SignedClientMessage> {
+        let token = PlayerToken(Uuid::nil());
+        let input = Input {
+            move_left: true,
+            shoot: true,
+            ..Input::default()
+        };
+
+        vec![
+            ClientMessage::Ping(SequenceNum(1)),
+            ClientMessage::Pong(SequenceNum(usize::MAX)),
+            ClientMessage::Input(vec![(TickNum(3), input.clone()), (TickNum(4), input)]),
+            ClientMessage::AckTick(TickNum(u32::MAX), u64::MAX),
+            ClientMessage::Disconnect,
+        ]
+        .into_iter()
+        .map(|message| SignedClientMessage(token, message))
+        .collect()
+    }
+
+    fn server_messages() -> Vec<ServerMessage> {
+        vec![
+            ServerMessage::Ping(SequenceNum(1)),
+            ServerMessage::Pong(SequenceNum(2)),
+            ServerMessage::Tick(test_tick(10)),
+            ServerMessage::Disconnect,
+        ]
+    }
+
+    /// Since the messages can not be compared directly, we check that decoding
+    /// and encoding again gives the same data.
+    fn assert_round_trip<T: Serialize + DeserializeOwned>(codec: &impl Codec, value: &T) {
+        let data = codec.encode(value);
+        let decoded: T = codec.decode(&data).unwrap();
+
+        assert_eq!(codec.encode(&decoded), data);
+    }
+
+    fn assert_messages_round_trip(codec: &impl Codec) {
+        for message in client_messages() {
+            assert_round_trip(codec, &message);
+        }
+        for message in server_messages() {
+            assert_round_trip(codec, &message);
+        }
+    }
+
+    #[test]
+    fn msgpack_round_trip() {
+        assert_messages_round_trip(&MsgpackCodec);
+        assert_messages_round_trip(&CodecKind::Msgpack);
+    }
+
+    #[test]
+    fn bincode_round_trip() {
+        assert_messages_round_trip(&BincodeCodec);
+        assert_messages_round_trip(&CodecKind::Bincode);
+    }
+
+    #[test]
+    fn invalid_data_is_rejected() {
+        let data = MsgpackCodec.encode(&client_messages()[2]);
+
+        assert!(MsgpackCodec
+            .decode::<SignedClientMessage>(&data[..data.len() - 1])
+            .is_none());
+        assert!(BincodeCodec
+            .decode::<SignedClientMessage>(&data[..data.len() - 1])
+            .is_none());
+    }
+
+    #[test]
+    fn small_ticks_are_not_compressed() {
+        let message = ServerMessage::Tick(test_tick(1));
+        let plain = MsgpackCodec.encode(&message);
+        let data = message.serialize(&MsgpackCodec, Some(plain.len()));
+
+        assert_eq!(data[0], HEADER_PLAIN);
+        assert_eq!(&data[1..], plain.as_slice());
+    }
+
+    #[test]
+    fn large_ticks_are_compressed() {
+        for codec in &[CodecKind::Msgpack, CodecKind::Bincode] {
+            let message = ServerMessage::Tick(test_tick(100));
+            let plain = codec.encode(&message);
+            let data = message.serialize(codec, Some(plain.len() - 1));
+
+            assert_eq!(data[0], HEADER_DEFLATE);
+            assert!(data.len() < plain.len());
+
+            let decoded = ServerMessage::deserialize(codec, &data).unwrap();
+            assert_eq!(codec.encode(&decoded), plain);
+        }
+    }
+
+    #[test]
+    fn only_ticks_are_compressed() {
+        let message = ServerMessage::Pong(SequenceNum(1));
+        let data = message.serialize(&MsgpackCodec, Some(0));
+
+        assert_eq!(data[0], HEADER_PLAIN);
+        assert!(ServerMessage::deserialize(&MsgpackCodec, &data).is_some());
+    }
+
+    #[test]
+    fn unknown_header_is_rejected() {
+        let mut data = ServerMessage::Disconnect.serialize(&MsgpackCodec, None);
+        data[0] = 42;
+
+        assert!(ServerMessage::deserialize(&MsgpackCodec, &data).is_none());
+    }
+}
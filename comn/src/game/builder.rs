@@ -0,0 +1,175 @@
+use crate::{
+    game::{
+        entities::{DangerGuy, FoodSpawn, Turret, Wall},
+        BoundaryMode, CatcherPolicy, Entity, Map, PlayerCollision, Point, Settings, Vector,
+    },
+    geom::Shape,
+    GameTime,
+};
+
+/// Builds a `Map` programmatically, e.g. for procedural maps, rather than
+/// loading it from a file.
+#[derive(Debug, Clone)]
+pub struct MapBuilder {
+    map: Map,
+}
+
+impl MapBuilder {
+    pub fn new(size: Vector) -> Self {
+        Self {
+            map: Map {
+                spawn_points: Vec::new(),
+                entities: Vec::new(),
+                size,
+            },
+        }
+    }
+
+    pub fn spawn_point(mut self, pos: Point) -> Self {
+        self.map.spawn_points.push(pos);
+        self
+    }
+
+    pub fn wall(self, shape: Shape) -> Self {
+        self.entity(Entity::Wall(Wall {
+            shape,
+            pass_normal: None,
+        }))
+    }
+
+    /// Adds a wall that players can pass when moving in the direction of
+    /// `pass_normal`.
+    pub fn one_way_wall(self, shape: Shape, pass_normal: Vector) -> Self {
+        self.entity(Entity::Wall(Wall {
+            shape,
+            pass_normal: Some(pass_normal.normalize()),
+        }))
+    }
+
+    pub fn danger_guy(self, danger_guy: DangerGuy) -> Self {
+        self.entity(Entity::DangerGuy(danger_guy))
+    }
+
+    pub fn turret(self, pos: Point) -> Self {
+        self.entity(Entity::Turret(Turret::new(pos)))
+    }
+
+    pub fn food_spawn(self, pos: Point) -> Self {
+        self.entity(Entity::FoodSpawn(FoodSpawn::new(pos)))
+    }
+
+    pub fn entity(mut self, entity: Entity) -> Self {
+        self.map.entities.push(entity);
+        self
+    }
+
+    pub fn build(self) -> Map {
+        self.map
+    }
+}
+
+/// Builds `Settings` with reasonable defaults for everything but the map.
+#[derive(Debug, Clone)]
+pub struct SettingsBuilder {
+    settings: Settings,
+}
+
+impl SettingsBuilder {
+    pub fn new(map: Map) -> Self {
+        Self {
+            settings: Settings {
+                max_num_players: 64,
+                ticks_per_second: 30,
+                map,
+                num_teams: None,
+                seed: 0,
+                spawn_protection_duration: 0.0,
+                catcher_policy: CatcherPolicy::Random,
+                boundary_mode: BoundaryMode::Clamp,
+                player_collision: PlayerCollision::Soft,
+                catch_food: 10,
+                food_per_pickup: 1,
+                share_catch_food: false,
+                bullet_max_lifetime: 5.0,
+                max_bullets_per_owner: 10,
+                turret_magazine_size: 3,
+                turret_reload_duration: 5.0,
+            },
+        }
+    }
+
+    pub fn max_num_players(mut self, max_num_players: usize) -> Self {
+        self.settings.max_num_players = max_num_players;
+        self
+    }
+
+    pub fn ticks_per_second(mut self, ticks_per_second: usize) -> Self {
+        self.settings.ticks_per_second = ticks_per_second;
+        self
+    }
+
+    pub fn num_teams(mut self, num_teams: Option<u32>) -> Self {
+        self.settings.num_teams = num_teams;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.settings.seed = seed;
+        self
+    }
+
+    pub fn spawn_protection_duration(mut self, duration: GameTime) -> Self {
+        self.settings.spawn_protection_duration = duration;
+        self
+    }
+
+    pub fn catcher_policy(mut self, catcher_policy: CatcherPolicy) -> Self {
+        self.settings.catcher_policy = catcher_policy;
+        self
+    }
+
+    pub fn boundary_mode(mut self, boundary_mode: BoundaryMode) -> Self {
+        self.settings.boundary_mode = boundary_mode;
+        self
+    }
+
+    pub fn player_collision(mut self, player_collision: PlayerCollision) -> Self {
+        self.settings.player_collision = player_collision;
+        self
+    }
+
+    pub fn catch_food(mut self, amount: u32) -> Self {
+        self.settings.catch_food = amount;
+        self
+    }
+
+    pub fn food_per_pickup(mut self, amount: u32) -> Self {
+        self.settings.food_per_pickup = amount;
+        self
+    }
+
+    pub fn share_catch_food(mut self, share: bool) -> Self {
+        self.settings.share_catch_food = share;
+        self
+    }
+
+    pub fn bullet_max_lifetime(mut self, lifetime: GameTime) -> Self {
+        self.settings.bullet_max_lifetime = lifetime;
+        self
+    }
+
+    pub fn max_bullets_per_owner(mut self, max_bullets: usize) -> Self {
+        self.settings.max_bullets_per_owner = max_bullets;
+        self
+    }
+
+    pub fn turret_magazine(mut self, size: u32, reload_duration: GameTime) -> Self {
+        self.settings.turret_magazine_size = size;
+        self.settings.turret_reload_duration = reload_duration;
+        self
+    }
+
+    pub fn build(self) -> Settings {
+        self.settings
+    }
+}
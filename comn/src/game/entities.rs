@@ -1,7 +1,11 @@
+use nalgebra as na;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    game::{run, EntityId, PlayerId, Point, Vector},
+    game::{
+        run::{self, SpawnIntent, SpawnKind},
+        EntityId, PlayerId, Point, Vector,
+    },
     geom::{self, AaRect, Circle, Rect, Shape},
     GameError, GameResult, GameTime,
 };
@@ -42,6 +46,29 @@ impl Entity {
         }
     }
 
+    /// Returns the intent with which the entity was spawned, for entities that
+    /// are spawned while running the game.
+    pub fn spawn_intent(&self) -> Option<SpawnIntent> {
+        match self {
+            Entity::Bullet(entity) => Some(SpawnIntent {
+                kind: SpawnKind::Bullet,
+                owner: entity.owner,
+                start_time: entity.start_time,
+            }),
+            Entity::Rocket(entity) => Some(SpawnIntent {
+                kind: SpawnKind::Rocket,
+                owner: entity.owner,
+                start_time: entity.start_time,
+            }),
+            Entity::Food(entity) => Some(SpawnIntent {
+                kind: SpawnKind::Food,
+                owner: None,
+                start_time: entity.start_time,
+            }),
+            _ => None,
+        }
+    }
+
     pub fn interp(&self, other: &Entity, alpha: f32) -> Entity {
         match (self, other) {
             (Entity::Player(this), Entity::Player(other)) => {
@@ -53,10 +80,41 @@ impl Entity {
             (Entity::Turret(this), Entity::Turret(other)) => {
                 Entity::Turret(this.interp(other, alpha))
             }
+            (Entity::Bullet(this), Entity::Bullet(other)) => {
+                Entity::Bullet(this.interp(other, alpha))
+            }
+            (Entity::DangerGuy(this), Entity::DangerGuy(other)) => {
+                Entity::DangerGuy(this.interp(other, alpha))
+            }
+            // Rockets and food describe their movement as a function of time,
+            // so they are smooth when sampled at the interpolation time, as
+            // long as their parameters stay the same. Food is re-based when
+            // it is attracted by a player, and then snaps to the new movement.
             _ => self.clone(),
         }
     }
 
+    /// Origin and rotation of the entity's local frame at the given time.
+    pub fn frame(&self, time: GameTime) -> (Point, f32) {
+        match self {
+            Entity::Player(entity) => (entity.pos, entity.angle),
+            Entity::PlayerView(entity) => (entity.pos, entity.angle),
+            _ => (self.pos(time), 0.0),
+        }
+    }
+
+    /// Transform a point from world coordinates into the entity's local frame.
+    pub fn to_local(&self, time: GameTime, point: Point) -> Vector {
+        let (origin, angle) = self.frame(time);
+        na::Rotation2::new(-angle) * (point - origin)
+    }
+
+    /// Transform a vector in the entity's local frame into world coordinates.
+    pub fn to_world(&self, time: GameTime, local: Vector) -> Point {
+        let (origin, angle) = self.frame(time);
+        origin + na::Rotation2::new(angle) * local
+    }
+
     pub fn can_hook_attach(&self) -> bool {
         match self {
             Entity::Bullet(_) => false,
@@ -97,6 +155,9 @@ pub enum Hook {
     },
     Attached {
         target: EntityId,
+
+        /// Position of the hook in the local frame of the target, so that the
+        /// hook sticks to the same point even if the target moves or turns.
         offset: Vector,
     },
     Contracting {
@@ -157,6 +218,7 @@ pub struct PlayerEntity {
     pub hook: Option<Hook>,
     pub hook_cooldown: GameTime,
     pub anim_frame: AnimState,
+    pub protected_until: GameTime,
 }
 
 impl PlayerEntity {
@@ -179,9 +241,14 @@ impl PlayerEntity {
             hook: None,
             hook_cooldown: 0.0,
             anim_frame: (0, 0.0),
+            protected_until: 0.0,
         }
     }
 
+    pub fn is_protected(&self, time: GameTime) -> bool {
+        time < self.protected_until
+    }
+
     pub fn to_view(&self) -> PlayerView {
         PlayerView {
             owner: self.owner,
@@ -191,6 +258,7 @@ impl PlayerEntity {
             hook: self.hook.clone(),
             is_dashing: self.dash.is_some(),
             anim_frame: self.anim_frame.0,
+            protected_until: self.protected_until,
         }
     }
 
@@ -244,9 +312,14 @@ pub struct PlayerView {
     pub hook: Option<Hook>,
     pub is_dashing: bool,
     pub anim_frame: Frame,
+    pub protected_until: GameTime,
 }
 
 impl PlayerView {
+    pub fn is_protected(&self, time: GameTime) -> bool {
+        time < self.protected_until
+    }
+
     pub fn rect(&self) -> Rect {
         AaRect::new_center(self.pos, self.size).rotate(self.angle)
     }
@@ -274,6 +347,15 @@ impl PlayerView {
 pub struct DangerGuy {
     pub start_pos: Point,
     pub end_pos: Point,
+
+    /// Points that the danger guy passes between `start_pos` and `end_pos`.
+    /// On the way back, they are visited in reverse order.
+    pub waypoints: Vec<Point>,
+
+    /// If set, the danger guy speeds up and slows down on each segment of
+    /// its path, rather than moving at constant speed.
+    pub easing: bool,
+
     pub size: Vector,
     pub speed: (f32, f32),
     pub wait_time: (GameTime, GameTime),
@@ -282,53 +364,188 @@ pub struct DangerGuy {
 }
 
 impl DangerGuy {
+    /// All the points of the path, starting at `start_pos` and ending at
+    /// `end_pos`.
+    pub fn path(&self) -> impl Iterator<Item = Point> + '_ {
+        std::iter::once(self.start_pos)
+            .chain(self.waypoints.iter().copied())
+            .chain(std::iter::once(self.end_pos))
+    }
+
+    pub fn path_len(&self) -> f32 {
+        self.path()
+            .zip(self.path().skip(1))
+            .map(|(a, b)| (b - a).norm())
+            .sum()
+    }
+
     pub fn walk_time(&self) -> (GameTime, GameTime) {
         (
-            (self.end_pos - self.start_pos).norm() / self.speed.0,
-            (self.end_pos - self.start_pos).norm() / self.speed.1,
+            self.path_len() / self.speed.0,
+            self.path_len() / self.speed.1,
         )
     }
 
     pub fn pos(&self, t: GameTime) -> Point {
-        pareen::seq_with_dur!(
-            pareen::constant(self.start_pos).dur(self.wait_time.0),
-            pareen::lerp(
-                pareen::constant(self.start_pos),
-                pareen::constant(self.end_pos)
-            )
-            .scale_to_dur(self.walk_time().0),
-            pareen::constant(self.end_pos).dur(self.wait_time.1),
-            pareen::lerp(
-                pareen::constant(self.end_pos),
-                pareen::constant(self.start_pos)
-            )
-            .scale_to_dur(self.walk_time().1),
-        )
-        .repeat()
-        .eval(t)
+        let (walk_time_go, walk_time_back) = self.walk_time();
+        let cycle_time = self.wait_time.0 + walk_time_go + self.wait_time.1 + walk_time_back;
+        if cycle_time <= 0.0 {
+            return self.start_pos;
+        }
+
+        let t = t.rem_euclid(cycle_time);
+
+        if t < self.wait_time.0 {
+            self.start_pos
+        } else if t < self.wait_time.0 + walk_time_go {
+            self.path_pos((t - self.wait_time.0) / walk_time_go)
+        } else if t < self.wait_time.0 + walk_time_go + self.wait_time.1 {
+            self.end_pos
+        } else {
+            let t = t - self.wait_time.0 - walk_time_go - self.wait_time.1;
+            self.path_pos(1.0 - t / walk_time_back)
+        }
+    }
+
+    /// Position on the path, where `tau` goes from 0 at `start_pos` to 1 at
+    /// `end_pos`.
+    fn path_pos(&self, tau: f32) -> Point {
+        let mut dist = tau.max(0.0).min(1.0) * self.path_len();
+
+        for (a, b) in self.path().zip(self.path().skip(1)) {
+            let segment_len = (b - a).norm();
+
+            if dist <= segment_len && segment_len > 0.0 {
+                let segment_tau = dist / segment_len;
+                let segment_tau = if self.easing {
+                    // Smoothstep
+                    segment_tau * segment_tau * (3.0 - 2.0 * segment_tau)
+                } else {
+                    segment_tau
+                };
+
+                return a + (b - a) * segment_tau;
+            }
+
+            dist -= segment_len;
+        }
+
+        self.end_pos
+    }
+
+    /// Direction in which the danger guy is moving at time `t`. While it is
+    /// waiting, this is the direction in which it will move next.
+    pub fn walk_dir(&self, t: GameTime) -> Vector {
+        const DT: GameTime = 0.01;
+
+        let delta = self.pos(t + DT) - self.pos(t);
+        if delta.norm() > 0.0 {
+            return delta.normalize();
+        }
+
+        let (walk_time_go, walk_time_back) = self.walk_time();
+        let cycle_time = self.wait_time.0 + walk_time_go + self.wait_time.1 + walk_time_back;
+        let (from, to) = if cycle_time <= 0.0 || t.rem_euclid(cycle_time) < self.wait_time.0 {
+            (self.start_pos, self.path().nth(1).unwrap_or(self.end_pos))
+        } else {
+            let last_waypoint = self.waypoints.last().copied().unwrap_or(self.start_pos);
+            (self.end_pos, last_waypoint)
+        };
+
+        let delta = to - from;
+        if delta.norm() > 0.0 {
+            delta.normalize()
+        } else {
+            Vector::x()
+        }
+    }
+
+    /// Movement over the `dt` seconds before `t`.
+    pub fn delta(&self, t: GameTime, dt: GameTime) -> Vector {
+        self.pos(t) - self.pos(t - dt)
     }
 
     pub fn aa_rect(&self, t: GameTime) -> AaRect {
         AaRect::new_center(self.pos(t), self.size)
     }
 
+    /// The rect covering all the space that the danger guy moves through.
+    pub fn path_aa_rect(&self) -> AaRect {
+        let (min, max) = self
+            .path()
+            .fold((self.start_pos, self.start_pos), |(min, max), p| {
+                (
+                    Point::new(min.x.min(p.x), min.y.min(p.y)),
+                    Point::new(max.x.max(p.x), max.y.max(p.y)),
+                )
+            });
+
+        AaRect::new_top_left(min - self.size / 2.0, max - min + self.size)
+    }
+
     pub fn shape(&self, t: GameTime) -> Shape {
         Shape::AaRect(self.aa_rect(t))
     }
+
+    /// Interpolates the path and timing of the danger guy. Since its position
+    /// depends linearly on the points of the path, sampling the result at
+    /// some time gives a position between the samples of `self` and `other`.
+    /// If the paths do not match, e.g. because the map has been reloaded, we
+    /// just snap.
+    pub fn interp(&self, other: &DangerGuy, alpha: f32) -> DangerGuy {
+        if self.waypoints.len() != other.waypoints.len() {
+            return if alpha < 0.5 {
+                self.clone()
+            } else {
+                other.clone()
+            };
+        }
+
+        let lerp_point = |a: Point, b: Point| a + alpha * (b - a);
+        let lerp = |a: f32, b: f32| a + alpha * (b - a);
+
+        DangerGuy {
+            start_pos: lerp_point(self.start_pos, other.start_pos),
+            end_pos: lerp_point(self.end_pos, other.end_pos),
+            waypoints: self
+                .waypoints
+                .iter()
+                .zip(other.waypoints.iter())
+                .map(|(a, b)| lerp_point(*a, *b))
+                .collect(),
+            size: self.size + alpha * (other.size - self.size),
+            speed: (
+                lerp(self.speed.0, other.speed.0),
+                lerp(self.speed.1, other.speed.1),
+            ),
+            wait_time: (
+                lerp(self.wait_time.0, other.wait_time.0),
+                lerp(self.wait_time.1, other.wait_time.1),
+            ),
+            ..self.clone()
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Bullet {
     pub owner: Option<PlayerId>,
+
+    /// Time at which the bullet was spawned, determining its lifetime.
     pub start_time: GameTime,
+
+    /// Time at which the bullet was at `start_pos`. This is reset when the
+    /// bullet wraps around the edge of the map.
+    pub motion_start_time: GameTime,
+
     pub start_pos: Point,
     pub vel: Vector,
 }
 
 impl Bullet {
     pub fn pos(&self, t: GameTime) -> Point {
-        if t >= self.start_time {
-            self.start_pos + self.vel * (t - self.start_time)
+        if t >= self.motion_start_time {
+            self.start_pos + self.vel * (t - self.motion_start_time)
         } else {
             self.start_pos
         }
@@ -340,6 +557,18 @@ impl Bullet {
             radius: 1.0,
         })
     }
+
+    /// Interpolates the movement of the bullet, which may have been re-based
+    /// between ticks. Wrapping around the map is handled by the caller.
+    pub fn interp(&self, other: &Bullet, alpha: f32) -> Bullet {
+        Bullet {
+            motion_start_time: self.motion_start_time
+                + alpha * (other.motion_start_time - self.motion_start_time),
+            start_pos: self.start_pos + alpha * (other.start_pos - self.start_pos),
+            vel: self.vel + alpha * (other.vel - self.vel),
+            ..self.clone()
+        }
+    }
 }
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Rocket {
@@ -376,21 +605,32 @@ impl Rocket {
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Turret {
     pub pos: Point,
+    pub range: f32,
     pub target: Option<EntityId>,
     pub angle: f32,
     pub next_shot_time: GameTime,
+
+    /// Once the magazine is empty, `next_shot_time` is the time at which the
+    /// turret is done reloading.
+    pub shots_left: u32,
 }
 
 impl Turret {
     pub fn new(pos: Point) -> Self {
         Self {
             pos,
+            range: run::TURRET_RANGE,
             target: None,
             angle: 0.0,
             next_shot_time: 0.0,
+            shots_left: 0,
         }
     }
 
+    pub fn is_reloading(&self, time: GameTime) -> bool {
+        self.shots_left == 0 && time < self.next_shot_time
+    }
+
     pub fn angle_to_pos(&self, pos: Point) -> f32 {
         let d = pos - self.pos;
         d.y.atan2(d.x)
@@ -413,16 +653,25 @@ impl Turret {
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Wall {
-    pub rect: AaRect,
+    pub shape: Shape,
+
+    /// If set, the wall is one-way: players can pass through it when moving
+    /// in the direction of this normal, but not when moving against it.
+    pub pass_normal: Option<Vector>,
 }
 
 impl Wall {
+    pub fn blocks_movement(&self, offset: Vector) -> bool {
+        self.pass_normal
+            .map_or(true, |pass_normal| offset.dot(&pass_normal) < 0.0)
+    }
+
     pub fn pos(&self) -> Point {
-        self.rect.center()
+        self.shape.center()
     }
 
     pub fn shape(&self) -> Shape {
-        Shape::AaRect(self.rect)
+        self.shape.clone()
     }
 }
 
@@ -447,17 +696,26 @@ impl FoodSpawn {
             .rotate(time * run::FOOD_ROTATION_SPEED)
     }
 
+    /// The circle enclosing the rotating food rect, so that the shape does
+    /// not depend on time.
     pub fn shape(&self, _: GameTime) -> Shape {
         Shape::Circle(Circle {
             center: self.pos,
-            radius: run::FOOD_SIZE * 2.0f32.sqrt(),
+            radius: run::FOOD_SIZE / 2.0f32.sqrt(),
         })
     }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Food {
+    /// Time at which the food was spawned, determining its lifetime.
     pub start_time: GameTime,
+
+    /// Time at which the food had `start_pos` and `start_vel`. This is reset
+    /// whenever the food starts a new movement, e.g. when it is attracted by
+    /// a player.
+    pub motion_start_time: GameTime,
+
     pub start_pos: Point,
     pub start_vel: Vector,
     pub factor: f32,
@@ -468,11 +726,24 @@ impl Food {
     pub fn pos(&self, time: GameTime) -> Point {
         // v(t) = vel * exp(-factor*t)
 
-        let dt = time - self.start_time;
+        let dt = time - self.motion_start_time;
 
         self.start_pos + self.start_vel * (1.0 - (-self.factor * dt).exp()) / self.factor
     }
 
+    pub fn vel(&self, time: GameTime) -> Vector {
+        let dt = time - self.motion_start_time;
+
+        self.start_vel * (-self.factor * dt).exp()
+    }
+
+    /// Starts a new movement at the given time, leaving the lifetime as is.
+    pub fn start_motion(&mut self, time: GameTime, pos: Point, vel: Vector) {
+        self.motion_start_time = time;
+        self.start_pos = pos;
+        self.start_vel = vel;
+    }
+
     pub fn rect(&self, time: GameTime) -> Rect {
         AaRect::new_center(self.pos(time), Vector::new(run::FOOD_SIZE, run::FOOD_SIZE)).to_rect()
     }
@@ -480,6 +751,25 @@ impl Food {
     pub fn shape(&self, time: GameTime) -> Shape {
         Shape::Rect(self.rect(time))
     }
+
+    pub fn is_fading(&self, time: GameTime) -> bool {
+        time >= self.start_time + run::FOOD_MAX_LIFETIME * run::FOOD_FADE_START
+    }
+
+    /// Opacity for rendering, reaching zero at the end of the food's lifetime.
+    pub fn alpha(&self, time: GameTime) -> f32 {
+        pareen::constant(1.0)
+            .seq_ease_out(
+                run::FOOD_FADE_START,
+                pareen::easer::functions::Sine,
+                1.0 - run::FOOD_FADE_START,
+                pareen::constant(0.0),
+            )
+            .squeeze(self.start_time..=self.start_time + run::FOOD_MAX_LIFETIME)
+            .eval(time)
+            .max(0.0)
+            .min(1.0)
+    }
 }
 
 impl_opaque_diff!(Entity);
@@ -490,3 +780,89 @@ impl_opaque_diff!(Turret);
 impl_opaque_diff!(Wall);
 impl_opaque_diff!(FoodSpawn);
 impl_opaque_diff!(Food);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Asserts that `p` lies on the segment between `a` and `b`.
+    fn assert_between(p: Point, a: Point, b: Point) {
+        let detour = (p - a).norm() + (b - p).norm() - (b - a).norm();
+        assert!(detour < 1e-2, "{:?} is not between {:?} and {:?}", p, a, b);
+    }
+
+    #[test]
+    fn rebased_bullet_interp_lies_between_samples() {
+        let bullet = Bullet {
+            owner: None,
+            start_time: 0.0,
+            motion_start_time: 0.0,
+            start_pos: Point::new(100.0, 100.0),
+            vel: Vector::new(300.0, 0.0),
+        };
+        let next_bullet = Bullet {
+            motion_start_time: 0.1,
+            start_pos: Point::new(130.0, 120.0),
+            ..bullet.clone()
+        };
+
+        let time = 0.15;
+        for i in 0..=10 {
+            let alpha = i as f32 / 10.0;
+            assert_between(
+                bullet.interp(&next_bullet, alpha).pos(time),
+                bullet.pos(time),
+                next_bullet.pos(time),
+            );
+        }
+    }
+
+    #[test]
+    fn moved_danger_guy_interp_lies_between_samples() {
+        let danger_guy = DangerGuy {
+            start_pos: Point::new(100.0, 100.0),
+            end_pos: Point::new(300.0, 100.0),
+            waypoints: vec![Point::new(200.0, 200.0)],
+            easing: true,
+            size: Vector::new(20.0, 20.0),
+            speed: (100.0, 100.0),
+            wait_time: (1.0, 1.0),
+            phase: 0.0,
+            is_hot: true,
+        };
+        let next_danger_guy = DangerGuy {
+            start_pos: Point::new(100.0, 150.0),
+            end_pos: Point::new(300.0, 150.0),
+            waypoints: vec![Point::new(200.0, 250.0)],
+            ..danger_guy.clone()
+        };
+
+        for &time in &[0.5, 1.5, 2.5, 4.0] {
+            for i in 0..=10 {
+                let alpha = i as f32 / 10.0;
+                assert_between(
+                    danger_guy.interp(&next_danger_guy, alpha).pos(time),
+                    danger_guy.pos(time),
+                    next_danger_guy.pos(time),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unchanged_danger_guy_interp_is_identity() {
+        let danger_guy = DangerGuy {
+            start_pos: Point::new(100.0, 100.0),
+            end_pos: Point::new(300.0, 100.0),
+            waypoints: Vec::new(),
+            easing: false,
+            size: Vector::new(20.0, 20.0),
+            speed: (100.0, 50.0),
+            wait_time: (0.5, 1.0),
+            phase: 0.0,
+            is_hot: true,
+        };
+
+        assert_eq!(danger_guy.interp(&danger_guy, 0.3), danger_guy);
+    }
+}
@@ -1,5 +1,7 @@
+pub mod builder;
 pub mod entities;
 pub mod run;
+pub mod sim;
 
 use std::collections::BTreeMap;
 use std::sync::Arc;
@@ -10,12 +12,16 @@ use nalgebra as na;
 
 use crate::{
     geom,
-    util::diff::{ApplyError, BTreeMapDiff, Diff, Diffable},
+    util::{
+        diff::{ApplyError, BTreeMapDiff, Diff, Diffable},
+        hash::StableHasher,
+    },
     GameTime,
 };
 
+pub use builder::{MapBuilder, SettingsBuilder};
 pub use entities::Entity;
-pub use run::RunContext;
+pub use run::{RayHit, RunContext, SpawnIntent, SpawnKind};
 
 pub type Time = f32;
 pub type Vector = na::Vector2<f32>;
@@ -37,14 +43,146 @@ pub struct Map {
     pub size: Vector,
 }
 
+/// Problems found by `Map::validate`. Indices refer to `Map::spawn_points`
+/// and `Map::entities`, respectively.
+#[derive(Debug, Clone)]
+pub enum MapError {
+    NoSpawnPoints,
+    SpawnPointOutOfBounds { spawn_point: usize, pos: Point },
+    SpawnPointInEntity { spawn_point: usize, entity: usize },
+    EntityOutOfBounds { entity: usize, pos: Point },
+}
+
+impl Map {
+    /// Checks that the map is playable: spawn points must be within the map
+    /// and not inside of walls or the path of danger guys, and entities must
+    /// be within the map.
+    pub fn validate(&self) -> std::result::Result<(), MapError> {
+        let bounds = geom::AaRect::new_top_left(Point::origin(), self.size);
+
+        if self.spawn_points.is_empty() {
+            return Err(MapError::NoSpawnPoints);
+        }
+
+        for (spawn_point, pos) in self.spawn_points.iter().enumerate() {
+            if !bounds.contains_point(*pos) {
+                return Err(MapError::SpawnPointOutOfBounds {
+                    spawn_point,
+                    pos: *pos,
+                });
+            }
+
+            let blocking_entity = self.entities.iter().position(|entity| match entity {
+                Entity::Wall(wall) => wall.shape.contains_point(*pos),
+                Entity::DangerGuy(danger_guy) => danger_guy.path_aa_rect().contains_point(*pos),
+                _ => false,
+            });
+
+            if let Some(entity) = blocking_entity {
+                return Err(MapError::SpawnPointInEntity {
+                    spawn_point,
+                    entity,
+                });
+            }
+        }
+
+        for (entity, entity_value) in self.entities.iter().enumerate() {
+            let pos = entity_value.pos(0.0);
+
+            if !bounds.contains_point(pos) {
+                return Err(MapError::EntityOutOfBounds { entity, pos });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Determines how a new catcher is chosen when there is none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CatcherPolicy {
+    /// Choose uniformly among the living players.
+    Random,
+
+    /// Prefer the players that have been catcher least recently. Ties are
+    /// broken randomly.
+    LeastRecent,
+}
+
+/// Determines what happens to entities that reach the edge of the map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BoundaryMode {
+    /// Players are kept inside of the map, while bullets are removed.
+    Clamp,
+
+    /// Entities leaving the map at one edge appear at the opposite edge.
+    Wrap,
+}
+
+/// Determines whether players of different teams push each other apart.
+/// Catching works the same way in all modes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PlayerCollision {
+    /// Players pass through each other.
+    None,
+
+    /// Players collide, except for the catcher while and shortly after
+    /// dashing, so that it can dash through the players it catches.
+    Soft,
+
+    /// Players always collide.
+    Solid,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Settings {
     pub max_num_players: usize,
     pub ticks_per_second: usize,
     pub map: Map,
+
+    /// If set, players are split into this many teams. Players of the same
+    /// team do not collide with or catch each other.
+    pub num_teams: Option<u32>,
+
+    /// Seed for all randomness in running the game on the server, so that
+    /// games can be reproduced.
+    pub seed: u64,
+
+    /// For how long players can not be caught or shot after spawning.
+    pub spawn_protection_duration: GameTime,
+
+    pub catcher_policy: CatcherPolicy,
+
+    pub boundary_mode: BoundaryMode,
+    pub player_collision: PlayerCollision,
+
+    /// Amount of food that a player gets for catching another player, and
+    /// for taking the food of a food spawn.
+    pub catch_food: u32,
+    pub food_per_pickup: u32,
+
+    /// If set, all the members of the catcher's team get the food for a
+    /// catch, rather than just the catcher.
+    pub share_catch_food: bool,
+
+    /// Bullets and rockets are removed after this time, even if they have
+    /// not hit anything.
+    pub bullet_max_lifetime: GameTime,
+
+    /// Maximal number of bullets and rockets that a player can have in the
+    /// game at once. When shooting more, the oldest one is removed.
+    pub max_bullets_per_owner: usize,
+
+    /// Turrets shoot this many times before they need to reload.
+    pub turret_magazine_size: u32,
+    pub turret_reload_duration: GameTime,
 }
 
 impl Settings {
+    pub fn builder(map: Map) -> SettingsBuilder {
+        SettingsBuilder::new(map)
+    }
+
     pub fn tick_period(&self) -> GameTime {
         1.0 / (self.ticks_per_second as f32)
     }
@@ -56,6 +194,14 @@ impl Settings {
     pub fn aa_rect(&self) -> geom::AaRect {
         geom::AaRect::new_top_left(Point::new(0.0, 0.0), self.map.size)
     }
+
+    /// Moves the point to the opposite edge if it is outside of the map.
+    pub fn wrap_point(&self, p: Point) -> Point {
+        Point::new(
+            p.x.rem_euclid(self.map.size.x),
+            p.y.rem_euclid(self.map.size.y),
+        )
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
@@ -67,21 +213,72 @@ impl PlayerId {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct TeamId(pub u32);
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct EntityId(pub u32);
 
 impl EntityId {
+    /// Entity ids starting at this value are reserved for entities that are
+    /// predicted by clients. The server never assigns ids in this range.
+    pub const FIRST_PREDICTED: EntityId = EntityId(1 << 31);
+
     pub fn next(&self) -> EntityId {
         EntityId(self.0 + 1)
     }
+
+    pub fn is_predicted(&self) -> bool {
+        *self >= Self::FIRST_PREDICTED
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct TickNum(pub u32);
 
+/// Tick numbers increase forever, so they may eventually wrap around. The
+/// derived `Ord` is fine for keying maps within a session, but whenever we
+/// compare tick numbers that come from the network, we use the wrap-aware
+/// methods below, which follow sequence number arithmetic as in TCP.
 impl TickNum {
     pub fn next(&self) -> TickNum {
-        TickNum(self.0 + 1)
+        TickNum(self.0.wrapping_add(1))
+    }
+
+    /// Signed distance from `other` to `self`, assuming that the two tick
+    /// numbers are less than `2^31` ticks apart.
+    pub fn distance(&self, other: TickNum) -> i32 {
+        self.0.wrapping_sub(other.0) as i32
+    }
+
+    pub fn is_after(&self, other: TickNum) -> bool {
+        self.distance(other) > 0
+    }
+
+    pub fn is_before(&self, other: TickNum) -> bool {
+        self.distance(other) < 0
+    }
+
+    /// Wrap-aware ordering, e.g. for sorting tick numbers.
+    pub fn wrapping_cmp(&self, other: TickNum) -> std::cmp::Ordering {
+        self.distance(other).cmp(&0)
+    }
+
+    /// The tick numbers after `self`, up to and including `end`. Empty if
+    /// `end` is not after `self`.
+    pub fn successors_until(&self, end: TickNum) -> impl Iterator<Item = TickNum> {
+        let start = *self;
+        let num = end.distance(start).max(0) as u32;
+
+        (1..=num).map(move |i| TickNum(start.0.wrapping_add(i)))
+    }
+
+    pub fn oldest(tick_nums: impl Iterator<Item = TickNum>) -> Option<TickNum> {
+        tick_nums.min_by(|a, b| a.wrapping_cmp(*b))
+    }
+
+    pub fn newest(tick_nums: impl Iterator<Item = TickNum>) -> Option<TickNum> {
+        tick_nums.max_by(|a, b| a.wrapping_cmp(*b))
     }
 }
 
@@ -107,6 +304,22 @@ pub enum DeathReason {
     ShotBy(Option<PlayerId>),
     TouchedTheDanger,
     CaughtBy(PlayerId),
+
+    /// Died while being stunned by the given player. Note that nothing stuns
+    /// players yet, so this is not produced for now.
+    Stunned(PlayerId),
+}
+
+impl DeathReason {
+    /// Returns the player that gets the credit for the death, if any.
+    pub fn credited_player(&self) -> Option<PlayerId> {
+        match self {
+            DeathReason::ShotBy(player_id) => *player_id,
+            DeathReason::TouchedTheDanger => None,
+            DeathReason::CaughtBy(player_id) => Some(*player_id),
+            DeathReason::Stunned(player_id) => Some(*player_id),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -131,6 +344,7 @@ pub enum Event {
     PlayerAteFood {
         player_id: PlayerId,
         amount: u32,
+        pos: Point,
     },
     NewCatcher {
         player_id: PlayerId,
@@ -149,6 +363,14 @@ pub struct Player {
     pub name: String,
     pub state: PlayerState,
     pub food: u32,
+    pub team: Option<TeamId>,
+
+    /// Points for killing other players, minus penalties for dying. Unlike
+    /// food, this can not be lost to others.
+    pub score: i32,
+
+    /// The last tick in which the player became catcher.
+    pub last_catcher_tick: Option<TickNum>,
 }
 
 impl_opaque_diff!(Player);
@@ -192,6 +414,40 @@ impl Game {
     pub fn game_time(&self) -> GameTime {
         self.tick_game_time(self.tick_num)
     }
+
+    pub fn is_same_team(&self, a: PlayerId, b: PlayerId) -> bool {
+        let team_a = self.players.get(&a).and_then(|player| player.team);
+        let team_b = self.players.get(&b).and_then(|player| player.team);
+
+        team_a.is_some() && team_a == team_b
+    }
+
+    /// A cheap hash of the state, which the client sends back to the server
+    /// so that we can detect if their states diverge. Only the players and
+    /// the positions of entities are considered.
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = StableHasher::default();
+        let time = self.game_time();
+
+        hasher.write_u32(self.tick_num.0);
+        hasher.write_u32(self.catcher.map_or(u32::MAX, |player_id| player_id.0));
+
+        for (player_id, player) in self.players.iter() {
+            hasher.write_u32(player_id.0);
+            hasher.write_u32(player.food);
+            hasher.write_u32(player.score as u32);
+        }
+
+        for (entity_id, entity) in self.entities.iter() {
+            let pos = entity.pos(time);
+
+            hasher.write_u32(entity_id.0);
+            hasher.write_f32(pos.x);
+            hasher.write_f32(pos.y);
+        }
+
+        hasher.finish()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -227,10 +483,218 @@ impl Diff for GameDiff {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScoreboardEntry {
+    pub player_id: PlayerId,
+    pub name: String,
+    pub food: u32,
+    pub score: i32,
+    pub is_catcher: bool,
+}
+
+/// Standings of the players in a game, sorted by food descending.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Scoreboard {
+    pub entries: Vec<ScoreboardEntry>,
+}
+
+impl Scoreboard {
+    pub fn from_game(game: &Game) -> Self {
+        let mut entries: Vec<ScoreboardEntry> = game
+            .players
+            .iter()
+            .map(|(player_id, player)| ScoreboardEntry {
+                player_id: *player_id,
+                name: player.name.clone(),
+                food: player.food,
+                score: player.score,
+                is_catcher: game.catcher == Some(*player_id),
+            })
+            .collect();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.food));
+
+        Self { entries }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Tick {
     pub diff_base: Option<TickNum>,
     pub diff: GameDiff,
     pub events: Vec<(TickNum, Vec<Event>)>,
     pub your_last_input_num: Option<TickNum>,
+
+    /// Only set if the scoreboard has changed w.r.t. `diff_base`.
+    pub scoreboard: Option<Scoreboard>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::codec::{Codec, MsgpackCodec};
+
+    #[test]
+    fn tick_num_next_wraps() {
+        assert_eq!(TickNum(u32::MAX).next(), TickNum(0));
+    }
+
+    #[test]
+    fn tick_num_compares_across_wrap() {
+        let before = TickNum(u32::MAX - 1);
+        let after = TickNum(2);
+
+        assert_eq!(after.distance(before), 4);
+        assert_eq!(before.distance(after), -4);
+        assert!(after.is_after(before));
+        assert!(before.is_before(after));
+        assert!(!after.is_before(before));
+        assert!(!after.is_after(after));
+        assert_eq!(before.wrapping_cmp(after), std::cmp::Ordering::Less);
+    }
+
+    #[test]
+    fn tick_num_oldest_and_newest_across_wrap() {
+        let tick_nums = [
+            TickNum(1),
+            TickNum(u32::MAX),
+            TickNum(0),
+            TickNum(u32::MAX - 2),
+        ];
+
+        assert_eq!(
+            TickNum::oldest(tick_nums.iter().copied()),
+            Some(TickNum(u32::MAX - 2))
+        );
+        assert_eq!(TickNum::newest(tick_nums.iter().copied()), Some(TickNum(1)));
+    }
+
+    #[test]
+    fn tick_num_successors_until_across_wrap() {
+        let successors: Vec<TickNum> = TickNum(u32::MAX - 1).successors_until(TickNum(1)).collect();
+
+        assert_eq!(successors, vec![TickNum(u32::MAX), TickNum(0), TickNum(1)]);
+        assert_eq!(TickNum(5).successors_until(TickNum(5)).count(), 0);
+        assert_eq!(TickNum(5).successors_until(TickNum(3)).count(), 0);
+    }
+
+    #[test]
+    fn scoreboard_is_sorted_by_food() {
+        let map = builder::MapBuilder::new(Vector::new(1000.0, 1000.0)).build();
+        let mut sim = sim::Simulation::new(Arc::new(builder::SettingsBuilder::new(map).build()));
+
+        let a = sim.add_player("a", Point::new(100.0, 100.0));
+        let b = sim.add_player("b", Point::new(200.0, 100.0));
+        let c = sim.add_player("c", Point::new(300.0, 100.0));
+        sim.state.players.get_mut(&a).unwrap().food = 5;
+        sim.state.players.get_mut(&b).unwrap().food = 20;
+        sim.state.players.get_mut(&c).unwrap().score = 3;
+        sim.state.catcher = Some(c);
+
+        let scoreboard = Scoreboard::from_game(&sim.state);
+        let player_ids: Vec<PlayerId> = scoreboard
+            .entries
+            .iter()
+            .map(|entry| entry.player_id)
+            .collect();
+
+        assert_eq!(player_ids, vec![b, a, c]);
+        assert_eq!(scoreboard.entries[0].name, "b");
+        assert_eq!(scoreboard.entries[2].score, 3);
+        assert_eq!(
+            scoreboard
+                .entries
+                .iter()
+                .map(|entry| entry.is_catcher)
+                .collect::<Vec<_>>(),
+            vec![false, false, true]
+        );
+    }
+
+    fn test_sim() -> sim::Simulation {
+        let map = builder::MapBuilder::new(Vector::new(1000.0, 1000.0)).build();
+        let mut sim = sim::Simulation::new(Arc::new(builder::SettingsBuilder::new(map).build()));
+
+        sim.add_player("a", Point::new(100.0, 100.0));
+        sim.add_player("b", Point::new(800.0, 800.0));
+        sim
+    }
+
+    fn move_right_inputs(sim: &sim::Simulation) -> BTreeMap<PlayerId, Input> {
+        sim.state
+            .players
+            .keys()
+            .map(|player_id| {
+                let input = Input {
+                    move_right: true,
+                    ..Input::default()
+                };
+                (*player_id, input)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn only_players_with_equal_teams_are_in_the_same_team() {
+        let mut sim = test_sim();
+        let a = PlayerId(1);
+        let b = PlayerId(2);
+
+        assert!(!sim.state.is_same_team(a, b));
+        assert!(!sim.state.is_same_team(a, PlayerId(42)));
+
+        sim.state.players.get_mut(&a).unwrap().team = Some(TeamId(0));
+        assert!(!sim.state.is_same_team(a, b));
+
+        sim.state.players.get_mut(&b).unwrap().team = Some(TeamId(1));
+        assert!(!sim.state.is_same_team(a, b));
+
+        sim.state.players.get_mut(&b).unwrap().team = Some(TeamId(0));
+        assert!(sim.state.is_same_team(a, b));
+        assert!(sim.state.is_same_team(b, a));
+    }
+
+    #[test]
+    fn equal_states_hash_equally() {
+        let mut sim = test_sim();
+        let inputs = move_right_inputs(&sim);
+        for _ in 0..10 {
+            sim.step(&inputs).unwrap();
+        }
+
+        let state = sim.state.clone();
+        assert_eq!(state.state_hash(), sim.state.state_hash());
+
+        let mut moved_state = state.clone();
+        let (entity_id, _) = moved_state.get_player_entity(PlayerId(1)).unwrap();
+        if let Some(Entity::Player(ent)) = moved_state.entities.get_mut(&entity_id) {
+            ent.pos.x += 1.0;
+        }
+        assert_ne!(moved_state.state_hash(), state.state_hash());
+
+        let mut fed_state = state.clone();
+        fed_state.players.get_mut(&PlayerId(2)).unwrap().food += 1;
+        assert_ne!(fed_state.state_hash(), state.state_hash());
+    }
+
+    #[test]
+    fn applied_diff_reproduces_state_hash() {
+        let mut sim = test_sim();
+        let base = sim.state.clone();
+
+        let inputs = move_right_inputs(&sim);
+        for _ in 0..10 {
+            sim.step(&inputs).unwrap();
+        }
+
+        // Send the diff through the wire format, as the server does.
+        let data = MsgpackCodec.encode(&base.diff(&sim.state));
+        let diff: GameDiff = MsgpackCodec.decode(&data).unwrap();
+
+        let mut client_state = base;
+        diff.apply(&mut client_state).unwrap();
+
+        assert_eq!(client_state.tick_num, sim.state.tick_num);
+        assert_eq!(client_state.state_hash(), sim.state.state_hash());
+    }
 }
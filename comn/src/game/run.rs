@@ -1,21 +1,31 @@
 use std::collections::{BTreeMap, BTreeSet};
 
+use rand::{seq::IteratorRandom, Rng};
+
 use crate::{
-    entities::{AnimState, Dash, Frame},
-    geom::{self, Ray},
+    entities::{AnimState, Bullet, Dash, Food, Frame},
+    game::{BoundaryMode, CatcherPolicy, PlayerCollision},
+    geom::{self, Ray, Shape},
     DeathReason, Entity, EntityId, Event, Game, GameError, GameResult, GameTime, Hook, Input,
-    PlayerEntity, PlayerId, PlayerMap, PlayerView, Point, Rocket, Vector,
+    PlayerEntity, PlayerId, PlayerMap, PlayerState, PlayerView, Point, Rocket, TickNum, Turret,
+    Vector,
 };
 
 pub const PLAYER_ACCEL_FACTOR: f32 = 30.0;
+pub const PLAYER_MAX_COLLISION_ITERATIONS: usize = 4;
 pub const PLAYER_CATCHER_SIZE_SCALE: f32 = 1.5;
-pub const PLAYER_CATCH_FOOD: u32 = 10;
+pub const SCORE_PER_SHOT: i32 = 1;
+pub const SCORE_PER_CATCH: i32 = 3;
+pub const SCORE_PER_STUN: i32 = 2;
+pub const SCORE_DEATH_PENALTY: i32 = 1;
 pub const PLAYER_DASH_ACCEL_FACTOR: f32 = 40.0;
 pub const PLAYER_DASH_COOLDOWN: f32 = 2.5;
 pub const PLAYER_DASH_DURATION: GameTime = 0.6;
 pub const PLAYER_DASH_SPEED: f32 = 850.0;
 pub const PLAYER_DASH_TURN_FACTOR: f32 = 0.8;
+pub const PLAYER_MAX_LOSE_FOOD: u32 = 5;
 pub const PLAYER_MAX_SIZE_BUMP: f32 = 50.0;
+pub const PLAYER_MIN_LOSE_FOOD: u32 = 1;
 pub const PLAYER_MOVE_L: f32 = 28.2;
 pub const PLAYER_MOVE_SPEED: f32 = 300.0;
 pub const PLAYER_MOVE_W: f32 = 56.6;
@@ -52,22 +62,502 @@ pub const ROCKET_SPEED: f32 = 500.0;
 
 pub const TURRET_RADIUS: f32 = 30.0;
 pub const TURRET_RANGE: f32 = 400.0;
+pub const TURRET_SHOOT_ANGLE: f32 = 0.3;
+pub const TURRET_SHOOT_PERIOD: GameTime = 2.5;
+pub const TURRET_SPAWN_OFFSET: f32 = 12.0;
+pub const TURRET_TURN_FACTOR: f32 = 0.1;
+pub const TURRET_MAX_TURN_SPEED: f32 = 3.0;
 
 pub const FOOD_SIZE: f32 = 20.0;
 pub const FOOD_ROTATION_SPEED: f32 = 3.0;
 pub const FOOD_RESPAWN_DURATION: f32 = 5.0;
 pub const FOOD_MAX_LIFETIME: f32 = 10.0;
+/// Fraction of `FOOD_MAX_LIFETIME` after which food starts to fade out.
+pub const FOOD_FADE_START: f32 = 0.9;
+pub const FOOD_MIN_SPEED: f32 = 300.0;
+pub const FOOD_MAX_SPEED: f32 = 700.0;
+pub const FOOD_SPEED_MIN_FACTOR: f32 = 5.0;
+pub const FOOD_SPEED_MAX_FACTOR: f32 = 10.0;
+pub const FOOD_MAGNET_RADIUS: f32 = 120.0;
+pub const FOOD_MAGNET_ACCEL: f32 = 1500.0;
+
+/// Result of `Game::trace_ray`.
+#[derive(Clone, Debug)]
+pub struct RayHit<'a> {
+    /// Ray parameter of the hit, i.e. the hit is at `origin + t * dir`.
+    pub t: f32,
+    pub entity_id: EntityId,
+    pub entity: &'a Entity,
+
+    /// Outward normal of the entity's shape at the hit.
+    pub normal: Vector,
+}
 
+/// Collects the effects of running the simulation. Entities are not added or
+/// removed directly, since only the caller knows how to assign entity ids;
+/// it is up to the caller to apply `new_entities`, `removed_entities` and
+/// `killed_players` to the game afterwards.
 #[derive(Clone, Debug, Default)]
 pub struct RunContext {
+    /// Set by clients when predicting their own input. Some effects, such as
+    /// hitting other players, are then left to the server.
     pub is_predicting: bool,
+
+    /// Events that happened, to be forwarded to the clients.
     pub events: Vec<Event>,
+
+    /// Entities to be added to the game.
     pub new_entities: Vec<Entity>,
+
+    /// Entities to be removed from the game.
     pub removed_entities: BTreeSet<EntityId>,
+
+    /// Players that died, together with the reason.
     pub killed_players: BTreeMap<PlayerId, DeathReason>,
 }
 
+/// Kinds of entities that are spawned while running the game.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpawnKind {
+    Bullet,
+    Rocket,
+    Food,
+}
+
+/// Describes why an entity was spawned. Entity ids are assigned separately by
+/// the server and by client prediction, so this is what we can use to match
+/// a predicted spawn with the authorative one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpawnIntent {
+    pub kind: SpawnKind,
+    pub owner: Option<PlayerId>,
+    pub start_time: GameTime,
+}
+
+impl SpawnIntent {
+    pub fn matches(&self, other: &SpawnIntent) -> bool {
+        self.kind == other.kind
+            && self.owner == other.owner
+            && (self.start_time - other.start_time).abs() < 0.001
+    }
+}
+
+impl RunContext {
+    pub fn spawn_bullet(&mut self, bullet: Bullet) -> SpawnIntent {
+        self.spawn(Entity::Bullet(bullet))
+    }
+
+    pub fn spawn_rocket(&mut self, rocket: Rocket) -> SpawnIntent {
+        self.spawn(Entity::Rocket(rocket))
+    }
+
+    pub fn spawn_food(&mut self, food: Food) -> SpawnIntent {
+        self.spawn(Entity::Food(food))
+    }
+
+    fn spawn(&mut self, entity: Entity) -> SpawnIntent {
+        let intent = entity
+            .spawn_intent()
+            .expect("spawned entity must have a spawn intent");
+        self.new_entities.push(entity);
+
+        intent
+    }
+}
+
 impl Game {
+    /// Update the entities that are not controlled by players. This is the
+    /// authorative logic that only the server runs; clients only predict
+    /// the effects of their own input.
+    pub fn run_tick(&mut self, context: &mut RunContext, rng: &mut impl Rng) -> GameResult<()> {
+        assert!(!context.is_predicting);
+
+        if let Some(catcher) = self.catcher {
+            let catcher_alive = self
+                .players
+                .get(&catcher)
+                .map_or(false, |player| player.state == PlayerState::Alive);
+            if !catcher_alive {
+                self.catcher = None;
+            }
+        }
+
+        if self.catcher.is_none() {
+            if let Some(catcher) = self.choose_catcher(rng) {
+                self.set_catcher(catcher, context);
+            }
+        }
+
+        let mut updates = Vec::new();
+
+        for (entity_id, entity) in self.entities.iter() {
+            let mut entity = entity.clone();
+            let update = self.update_entity(*entity_id, &mut entity, context);
+
+            if update {
+                updates.push((*entity_id, entity));
+            }
+        }
+
+        self.entities.extend(updates);
+
+        Ok(())
+    }
+
+    fn update_entity(
+        &self,
+        entity_id: EntityId,
+        entity: &mut Entity,
+        context: &mut RunContext,
+    ) -> bool {
+        let dt = self.settings.tick_period();
+
+        match entity {
+            Entity::Bullet(bullet) => {
+                if self.game_time() - bullet.start_time > self.settings.bullet_max_lifetime {
+                    context.removed_entities.insert(entity_id);
+                    return false;
+                }
+
+                let pos = bullet.pos(self.game_time());
+                if self.settings.boundary_mode == BoundaryMode::Wrap
+                    && !self.settings.aa_rect().contains_point(pos)
+                {
+                    // Continue the bullet's movement from the opposite edge.
+                    bullet.start_pos = self.settings.wrap_point(pos);
+                    bullet.motion_start_time = self.game_time();
+                    return true;
+                }
+
+                if self.any_solid_neutral_contains_circle(
+                    entity_id,
+                    bullet.owner,
+                    bullet.pos(self.game_time()),
+                    BULLET_RADIUS,
+                ) {
+                    context.removed_entities.insert(entity_id);
+                }
+                false
+            }
+            Entity::Rocket(rocket) => {
+                if self.game_time() - rocket.start_time > self.settings.bullet_max_lifetime {
+                    context.removed_entities.insert(entity_id);
+                    return false;
+                }
+
+                if self.any_solid_neutral_contains_circle(
+                    entity_id,
+                    rocket.owner,
+                    rocket.pos(self.game_time()),
+                    ROCKET_RADIUS,
+                ) {
+                    //context.removed_entities.insert(entity_id);
+                }
+                false
+            }
+            Entity::Turret(turret) => {
+                self.update_turret(entity_id, turret, context);
+                true
+            }
+            Entity::FoodSpawn(spawn) if !spawn.has_food => {
+                if let Some(respawn_time) = spawn.respawn_time {
+                    if self.game_time() >= respawn_time {
+                        spawn.has_food = true;
+                        spawn.respawn_time = None;
+                        return true;
+                    }
+                }
+                false
+            }
+            Entity::Food(food) => {
+                if self.game_time() - food.start_time > FOOD_MAX_LIFETIME {
+                    context.removed_entities.insert(entity_id);
+                    false
+                } else {
+                    for entity_b in self.entities.values() {
+                        if entity_b.is_wall_like()
+                            && entity_b
+                                .shape(self.game_time())
+                                .contains_point(food.pos(self.game_time()))
+                        {
+                            // Replace the Food by a non-moving one
+                            context.removed_entities.insert(entity_id);
+                            context.spawn_food(Food {
+                                start_pos: food.pos(self.game_time() - dt / 2.0),
+                                start_vel: Vector::zeros(),
+                                ..food.clone()
+                            });
+                            return false;
+                        }
+                    }
+
+                    let pos = food.pos(self.game_time());
+                    if self.settings.boundary_mode == BoundaryMode::Wrap
+                        && !self.settings.aa_rect().contains_point(pos)
+                    {
+                        food.start_motion(
+                            self.game_time(),
+                            self.settings.wrap_point(pos),
+                            food.vel(self.game_time()),
+                        );
+                        return true;
+                    }
+
+                    self.apply_food_magnet(food)
+                }
+            }
+            _ => false,
+        }
+    }
+
+    /// Accelerate food towards the closest nearby player. Since the movement
+    /// of food is given in closed form, we need to start a new movement from
+    /// the current position whenever the velocity changes.
+    fn apply_food_magnet(&self, food: &mut Food) -> bool {
+        let dt = self.settings.tick_period();
+        let pos = food.pos(self.game_time());
+
+        let closest_player_pos = self
+            .entities
+            .values()
+            .filter_map(|entity| match entity {
+                Entity::Player(player) => Some(player.pos),
+                _ => None,
+            })
+            .filter(|player_pos| (player_pos - pos).norm() < FOOD_MAGNET_RADIUS)
+            .min_by(|a, b| {
+                (a - pos)
+                    .norm()
+                    .partial_cmp(&(b - pos).norm())
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+
+        if let Some(player_pos) = closest_player_pos {
+            let delta = player_pos - pos;
+
+            if delta.norm() > 0.0 {
+                let vel = food.vel(self.game_time()) + delta.normalize() * FOOD_MAGNET_ACCEL * dt;
+                food.start_motion(self.game_time(), pos, vel);
+
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Makes room for a new bullet of the given player, so that there are at
+    /// most `max_bullets_per_owner` bullets and rockets of the player.
+    fn remove_oldest_bullets(&self, owner: PlayerId, context: &mut RunContext) {
+        let mut bullets: Vec<(GameTime, EntityId)> = self
+            .entities
+            .iter()
+            .filter(|(entity_id, _)| !context.removed_entities.contains(entity_id))
+            .filter_map(|(entity_id, entity)| match entity {
+                Entity::Bullet(bullet) if bullet.owner == Some(owner) => {
+                    Some((bullet.start_time, *entity_id))
+                }
+                Entity::Rocket(rocket) if rocket.owner == Some(owner) => {
+                    Some((rocket.start_time, *entity_id))
+                }
+                _ => None,
+            })
+            .collect();
+
+        let max_bullets = self.settings.max_bullets_per_owner.saturating_sub(1);
+        if bullets.len() > max_bullets {
+            bullets.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+
+            let num_remove = bullets.len() - max_bullets;
+            for (_, entity_id) in bullets.into_iter().take(num_remove) {
+                context.removed_entities.insert(entity_id);
+            }
+        }
+    }
+
+    fn update_turret(&self, entity_id: EntityId, turret: &mut Turret, context: &mut RunContext) {
+        turret.target = self
+            .entities
+            .iter()
+            .filter(|(other_id, _)| **other_id != entity_id)
+            .filter_map(|(other_id, other_entity)| {
+                other_entity.player().ok().map(|player| {
+                    (
+                        other_id,
+                        other_entity,
+                        (turret.pos - player.pos).norm_squared(),
+                    )
+                })
+            })
+            .filter(|(other_id, other_entity, dist)| {
+                let ray = Ray {
+                    origin: turret.pos,
+                    dir: other_entity.pos(self.game_time()) - turret.pos,
+                };
+
+                *dist <= turret.range * turret.range
+                    && Self::trace_ray(
+                        &ray,
+                        self.game_time(),
+                        self.entities.iter().filter(|(between_id, _)| {
+                            **between_id != entity_id && **between_id != **other_id
+                        }),
+                    )
+                    .map_or(true, |hit| hit.t > 1.0)
+            })
+            .min_by(|(_, _, dist1), (_, _, dist2)| dist1.partial_cmp(dist2).unwrap())
+            .map(|(other_id, _, _)| *other_id);
+
+        if let Some(target) = turret.target {
+            let target_pos = self.entities[&target].pos(self.game_time());
+            let target_angle = turret.angle_to_pos(target_pos);
+            let angle_dist = geom::angle_dist(target_angle, turret.angle);
+            // Sweep towards the target, rather than snapping to it.
+            let max_turn = TURRET_MAX_TURN_SPEED * self.settings.tick_period();
+            turret.angle += (angle_dist * TURRET_TURN_FACTOR)
+                .max(-max_turn)
+                .min(max_turn);
+
+            if self.game_time() >= turret.next_shot_time && turret.shots_left == 0 {
+                turret.shots_left = self.settings.turret_magazine_size;
+            }
+
+            if self.game_time() >= turret.next_shot_time
+                && turret.shots_left > 0
+                && angle_dist.abs() < TURRET_SHOOT_ANGLE
+            {
+                turret.shots_left -= 1;
+                turret.next_shot_time = if turret.shots_left == 0 {
+                    self.game_time() + self.settings.turret_reload_duration
+                } else {
+                    self.game_time() + TURRET_SHOOT_PERIOD
+                };
+
+                let delta = Vector::new(turret.angle.cos(), turret.angle.sin());
+
+                context.spawn_bullet(Bullet {
+                    owner: None,
+                    start_time: self.game_time(),
+                    motion_start_time: self.game_time(),
+                    start_pos: turret.pos + TURRET_SPAWN_OFFSET * delta,
+                    vel: delta * BULLET_MOVE_SPEED,
+                });
+            }
+        }
+    }
+
+    /// Let the killed player drop some of its food, update the scores, and
+    /// choose a new catcher if necessary.
+    pub fn on_kill_player(
+        &mut self,
+        ent: &PlayerEntity,
+        reason: DeathReason,
+        context: &mut RunContext,
+        rng: &mut impl Rng,
+    ) -> GameResult<()> {
+        let credit = match reason {
+            DeathReason::ShotBy(_) => SCORE_PER_SHOT,
+            DeathReason::TouchedTheDanger => 0,
+            DeathReason::CaughtBy(_) => SCORE_PER_CATCH,
+            DeathReason::Stunned(_) => SCORE_PER_STUN,
+        };
+        if let Some(credited_player) = reason
+            .credited_player()
+            .filter(|player_id| *player_id != ent.owner)
+            .and_then(|player_id| self.players.get_mut(&player_id))
+        {
+            credited_player.score += credit;
+        }
+
+        let player = self.players.get_mut(&ent.owner).unwrap();
+        let spawn_food = player
+            .food
+            .min(PLAYER_MAX_LOSE_FOOD)
+            .max(PLAYER_MIN_LOSE_FOOD);
+        player.food -= spawn_food.min(player.food);
+        player.score -= SCORE_DEATH_PENALTY;
+
+        for _ in 0..spawn_food {
+            let angle = rng.gen::<f32>() * std::f32::consts::PI * 2.0;
+            let speed = rng.gen_range(FOOD_MIN_SPEED, FOOD_MAX_SPEED);
+            let start_vel = Vector::new(speed * angle.cos(), speed * angle.sin());
+            let factor = rng.gen_range(FOOD_SPEED_MIN_FACTOR, FOOD_SPEED_MAX_FACTOR);
+
+            let food = Food {
+                start_time: self.game_time(),
+                motion_start_time: self.game_time(),
+                start_pos: ent.pos,
+                start_vel,
+                factor,
+                amount: 1,
+            };
+            context.spawn_food(food);
+        }
+
+        if self.catcher == Some(ent.owner) {
+            // Choose a new catcher
+            let new_catcher = self
+                .entities
+                .iter()
+                .filter_map(|(_, other_entity)| {
+                    other_entity.player().ok().map(|other_player| {
+                        (other_player.owner, (ent.pos - other_player.pos).norm())
+                    })
+                })
+                .filter(|(other_owner, _)| *other_owner != ent.owner)
+                .min_by(|(_, dist1), (_, dist2)| dist1.partial_cmp(dist2).unwrap())
+                .map(|(other_owner, _)| other_owner);
+
+            self.catcher = None;
+            if let Some(catcher) = new_catcher {
+                self.set_catcher(catcher, context);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn choose_catcher(&self, rng: &mut impl Rng) -> Option<PlayerId> {
+        let candidates: Vec<(PlayerId, Option<TickNum>)> = self
+            .players
+            .iter()
+            .filter(|(_, player)| !player.name.contains("bot")) // TODO: remove bot discrimination
+            .filter(|(_, player)| player.state == PlayerState::Alive)
+            .map(|(player_id, player)| (*player_id, player.last_catcher_tick))
+            .collect();
+
+        match self.settings.catcher_policy {
+            CatcherPolicy::Random => candidates
+                .iter()
+                .map(|(player_id, _)| *player_id)
+                .choose(rng),
+            CatcherPolicy::LeastRecent => {
+                // Players that have never been catcher come first, since
+                // `None` is smaller than any `Some`.
+                let least_recent = candidates.iter().map(|(_, tick)| *tick).min()?;
+
+                candidates
+                    .iter()
+                    .filter(|(_, tick)| *tick == least_recent)
+                    .map(|(player_id, _)| *player_id)
+                    .choose(rng)
+            }
+        }
+    }
+
+    fn set_catcher(&mut self, player_id: PlayerId, context: &mut RunContext) {
+        self.catcher = Some(player_id);
+
+        if let Some(player) = self.players.get_mut(&player_id) {
+            player.last_catcher_tick = Some(self.tick_num);
+        }
+
+        context.events.push(Event::NewCatcher { player_id });
+    }
+
+    /// Apply the input of one player to their player entity. `input_state` is
+    /// the state that the player saw when giving the input, which is used for
+    /// lag compensation when hitting other entities.
     pub fn run_player_input(
         &mut self,
         player_id: PlayerId,
@@ -259,13 +749,14 @@ impl Game {
                     * PLAYER_MOVE_SPEED
                     * (any_move_key as usize as f32)
             };
+            // Dashing uses its own factor, so that we reach `PLAYER_DASH_SPEED`
+            // quickly. Smoothing must happen exactly once per tick.
             let factor = if ent.dash.is_some() {
                 PLAYER_DASH_ACCEL_FACTOR
             } else {
                 PLAYER_ACCEL_FACTOR
             };
             ent.vel = geom::smooth_to_target_vector(factor, ent.vel, target_vel, dt);
-            ent.vel = geom::smooth_to_target_vector(PLAYER_ACCEL_FACTOR, ent.vel, target_vel, dt);
             if (ent.vel - target_vel).norm() < 0.01 {
                 ent.vel = target_vel;
             }
@@ -291,32 +782,42 @@ impl Game {
                             dir: pos + pos_delta - ent.pos,
                         };
 
-                        let hook = Self::trace_ray(
+                        // Walls and attachable entities are traced together,
+                        // so that we can only attach to the first thing that
+                        // the hook hits.
+                        let hit = Self::trace_ray(
                             &ray,
                             input_time,
                             input_state.entities.iter().filter(|(other_id, other_ent)| {
-                                **other_id != entity_id && other_ent.can_hook_attach()
+                                **other_id != entity_id
+                                    && (other_ent.can_hook_attach() || other_ent.is_wall_like())
                             }),
                         )
-                        .filter(|(t, _, _)| *t <= 1.0)
-                        .map_or(
-                            Hook::Shooting {
+                        .filter(|hit| hit.t <= 1.0);
+
+                        let hook = match hit {
+                            Some(hit) if hit.entity.can_hook_attach() => Hook::Attached {
+                                target: hit.entity_id,
+                                offset: hit
+                                    .entity
+                                    .to_local(input_time, ray.origin + hit.t * ray.dir),
+                            },
+                            Some(hit) => Hook::Contracting {
+                                pos: ray.origin + hit.t * ray.dir,
+                            },
+                            None => Hook::Shooting {
                                 pos: pos + pos_delta,
                                 vel,
                                 time_left: next_time_left,
                             },
-                            |(t, other_id, other_ent)| Hook::Attached {
-                                target: *other_id,
-                                offset: ray.origin + t * ray.dir - other_ent.pos(input_time),
-                            },
-                        );
+                        };
 
                         Some(hook)
                     }
                 }
                 Hook::Attached { target, offset } => {
                     input_state.entities.get(&target).and_then(|target_ent| {
-                        let hook_pos = target_ent.pos(input_time) + offset;
+                        let hook_pos = target_ent.to_world(input_time, offset);
                         let distance = (hook_pos - ent.pos).norm();
 
                         if !input.use_action
@@ -360,16 +861,28 @@ impl Game {
 
         let mut caught_players = BTreeSet::new();
 
+        // Non-hot danger guys act as moving platforms, carrying along the
+        // players that are in contact with them.
+        let mut platform_delta = None;
+
         // TODO: Should probably use auth state for player-player collisions?
         for (other_entity_id, other_entity) in input_state.entities.iter() {
             let (other_shape, flip) = match other_entity {
-                Entity::Player(other_ent) if other_ent.owner != ent.owner => {
+                Entity::Player(other_ent)
+                    if other_ent.owner != ent.owner
+                        && !input_state.is_same_team(ent.owner, other_ent.owner) =>
+                {
                     (Some(other_ent.shape()), false)
                 }
-                Entity::PlayerView(other_ent) if other_ent.owner != ent.owner => {
+                Entity::PlayerView(other_ent)
+                    if other_ent.owner != ent.owner
+                        && !input_state.is_same_team(ent.owner, other_ent.owner) =>
+                {
                     (Some(other_ent.shape()), false)
                 }
-                Entity::Wall(other_ent) => (Some(other_ent.shape()), true),
+                Entity::Wall(other_ent) if other_ent.blocks_movement(offset) => {
+                    (Some(other_ent.shape()), true)
+                }
                 Entity::DangerGuy(other_ent) if !other_ent.is_hot => {
                     //Some(other_ent.aa_rect(input_time + self.settings.tick_period()).to_rect())
                     (Some(other_ent.shape(self.game_time())), true)
@@ -387,20 +900,31 @@ impl Game {
                 if let Entity::Player(_) | Entity::PlayerView(_) = other_entity {
                     // TODO: Decide whom to favor regarding catching... or if
                     // we should even make it happen over a longer duration.
-                    if self.catcher == Some(ent.owner) {
-                        if ent.dash.is_some() {
-                            caught_players.insert(*other_entity_id);
-                        }
+                    let is_catcher = self.catcher == Some(ent.owner);
+                    if is_catcher && ent.dash.is_some() {
+                        caught_players.insert(*other_entity_id);
+                    }
 
-                        // To prevent prediction errors, we disable collision
-                        // even some time _after_ dashing as the catcher.
-                        // (The prediction error happens because we cannot
-                        // predict locally that we caught the other player, so
-                        // we collide if the dash stops while we are still on
-                        // top.)
-                        if ent.dash.is_some() || PLAYER_DASH_COOLDOWN - ent.dash_cooldown < 0.5 {
-                            collide = false;
+                    collide = match self.settings.player_collision {
+                        PlayerCollision::None => false,
+                        PlayerCollision::Soft => {
+                            // To prevent prediction errors, we disable
+                            // collision even some time _after_ dashing as the
+                            // catcher. (The prediction error happens because
+                            // we cannot predict locally that we caught the
+                            // other player, so we collide if the dash stops
+                            // while we are still on top.)
+                            !is_catcher
+                                || (ent.dash.is_none()
+                                    && PLAYER_DASH_COOLDOWN - ent.dash_cooldown >= 0.5)
                         }
+                        PlayerCollision::Solid => true,
+                    };
+                }
+
+                if let Entity::DangerGuy(other_ent) = other_entity {
+                    if platform_delta.is_none() {
+                        platform_delta = Some(other_ent.delta(self.game_time() + dt, dt));
                     }
                 }
 
@@ -414,6 +938,59 @@ impl Game {
             }
         }
 
+        // Move along with the platform that we are standing on. This happens
+        // before resolving collisions, so that the platform cannot carry us
+        // into a wall.
+        if let Some(platform_delta) = platform_delta {
+            offset += platform_delta;
+        }
+
+        // Resolving one collision may push us into another solid entity, e.g.
+        // in tight corners. Re-test the accumulated offset until it is free of
+        // collisions. If this does not converge, we stay where we are. Moving
+        // entities are tested at the end of the tick, since that is where
+        // they will be when we have moved.
+        {
+            let solid_shapes: Vec<Shape> = input_state
+                .entities
+                .values()
+                .filter_map(|other_entity| match other_entity {
+                    Entity::Wall(other_ent) if other_ent.blocks_movement(offset) => {
+                        Some(other_ent.shape())
+                    }
+                    Entity::DangerGuy(other_ent) if !other_ent.is_hot => {
+                        Some(other_ent.shape(self.game_time() + dt))
+                    }
+                    Entity::Turret(other_ent) => Some(other_ent.shape()),
+                    _ => None,
+                })
+                .collect();
+
+            let mut converged = false;
+            for _ in 0..PLAYER_MAX_COLLISION_ITERATIONS {
+                let mut any_collision = false;
+
+                for other_shape in solid_shapes.iter() {
+                    if let Some(collision) = ent.rect().collision(other_shape, offset) {
+                        // Ignore the numerical noise of just touching.
+                        if collision.resolution_vector.norm() > 1e-3 {
+                            offset += collision.resolution_vector;
+                            any_collision = true;
+                        }
+                    }
+                }
+
+                if !any_collision {
+                    converged = true;
+                    break;
+                }
+            }
+
+            if !converged {
+                offset = Vector::zeros();
+            }
+        }
+
         // Allow reflecting off walls when dashing
         if let (Some(dash), Some(flip_axis)) = (ent.dash.as_mut(), flip_axis) {
             assert!(flip_axis.x.is_finite());
@@ -430,17 +1007,24 @@ impl Game {
 
         ent.pos += offset;
 
-        // Clip to map boundary
-        ent.pos.x = ent
-            .pos
-            .x
-            .min(self.settings.map.size.x - PLAYER_SIT_W / 2.0)
-            .max(PLAYER_SIT_W / 2.0);
-        ent.pos.y = ent
-            .pos
-            .y
-            .min(self.settings.map.size.y - PLAYER_SIT_W / 2.0)
-            .max(PLAYER_SIT_W / 2.0);
+        match self.settings.boundary_mode {
+            BoundaryMode::Clamp => {
+                // Clip to map boundary
+                ent.pos.x = ent
+                    .pos
+                    .x
+                    .min(self.settings.map.size.x - PLAYER_SIT_W / 2.0)
+                    .max(PLAYER_SIT_W / 2.0);
+                ent.pos.y = ent
+                    .pos
+                    .y
+                    .min(self.settings.map.size.y - PLAYER_SIT_W / 2.0)
+                    .max(PLAYER_SIT_W / 2.0);
+            }
+            BoundaryMode::Wrap => {
+                ent.pos = self.settings.wrap_point(ent.pos);
+            }
+        }
 
         // Start or dashing
         ent.dash_cooldown = (ent.dash_cooldown - dt).max(0.0);
@@ -474,12 +1058,13 @@ impl Game {
             if input.shoot {
                 let start_pos = ent.rect().transform_point(Point::new(0.5, 0.0));
 
-                context.new_entities.push(Entity::Rocket(Rocket {
+                self.remove_oldest_bullets(ent.owner, context);
+                context.spawn_rocket(Rocket {
                     owner: Some(ent.owner),
                     start_time: input_time,
                     start_pos,
                     angle: ent.angle,
-                }));
+                });
 
                 ent.shots_left -= 1;
 
@@ -507,7 +1092,9 @@ impl Game {
                         killed = Some(DeathReason::TouchedTheDanger);
                     }
                 }
-                Entity::Bullet(bullet) if bullet.owner != Some(ent.owner) => {
+                Entity::Bullet(bullet)
+                    if bullet.owner != Some(ent.owner) && !ent.is_protected(input_time) =>
+                {
                     if ent.rect().contains_point(bullet.pos(input_time)) {
                         context.removed_entities.insert(*entity_id);
                         killed = Some(DeathReason::ShotBy(bullet.owner));
@@ -540,9 +1127,21 @@ impl Game {
         if !context.is_predicting {
             for caught_entity_id in caught_players {
                 // If we are doing reconciliation, the entity might no longer exist in auth state.
-                if self.entities.contains_key(&caught_entity_id) {
+                let is_protected = match self.entities.get(&caught_entity_id) {
+                    Some(Entity::Player(caught_ent)) => caught_ent.is_protected(self.game_time()),
+                    Some(_) => false,
+                    None => continue,
+                };
+
+                if !is_protected {
                     self.kill_player(caught_entity_id, DeathReason::CaughtBy(ent.owner), context)?;
-                    Self::take_food(&mut self.players, ent, PLAYER_CATCH_FOOD, context);
+                    let pos = ent.pos;
+                    let amount = self.settings.catch_food;
+                    Self::take_food(&mut self.players, ent, amount, pos, context);
+
+                    if self.settings.share_catch_food {
+                        Self::share_food(&mut self.players, ent.owner, amount);
+                    }
                 }
             }
         }
@@ -553,16 +1152,20 @@ impl Game {
             for (entity_id, entity) in self.entities.iter_mut() {
                 match entity {
                     Entity::FoodSpawn(spawn) if spawn.has_food => {
-                        if geom::rect_collision(
-                            &spawn.rect(input_time),
-                            &ent.rect(),
-                            Vector::zeros(),
-                        )
-                        .is_some()
+                        if ent
+                            .rect()
+                            .collision(&spawn.shape(input_time), Vector::zeros())
+                            .is_some()
                         {
                             spawn.has_food = false;
                             spawn.respawn_time = Some(time + FOOD_RESPAWN_DURATION);
-                            Self::take_food(&mut self.players, ent, 1, context);
+                            Self::take_food(
+                                &mut self.players,
+                                ent,
+                                self.settings.food_per_pickup,
+                                spawn.pos,
+                                context,
+                            );
                         }
                     }
                     Entity::Food(food) => {
@@ -571,14 +1174,18 @@ impl Game {
                             continue;
                         }
 
-                        if geom::rect_collision(
-                            &food.rect(input_time),
-                            &ent.rect(),
-                            Vector::zeros(),
-                        )
-                        .is_some()
+                        if ent
+                            .rect()
+                            .collision(&food.shape(input_time), Vector::zeros())
+                            .is_some()
                         {
-                            Self::take_food(&mut self.players, ent, food.amount, context);
+                            Self::take_food(
+                                &mut self.players,
+                                ent,
+                                food.amount,
+                                food.pos(input_time),
+                                context,
+                            );
                             context.removed_entities.insert(*entity_id);
                         }
                     }
@@ -594,6 +1201,7 @@ impl Game {
         players: &mut PlayerMap,
         ent: &mut PlayerEntity,
         amount: u32,
+        pos: Point,
         context: &mut RunContext,
     ) {
         players.get_mut(&ent.owner).unwrap().food += amount;
@@ -603,9 +1211,25 @@ impl Game {
         context.events.push(Event::PlayerAteFood {
             player_id: ent.owner,
             amount,
+            pos,
         });
     }
 
+    /// Gives food to the other members of the player's team, if any.
+    fn share_food(players: &mut PlayerMap, player_id: PlayerId, amount: u32) {
+        let team = players.get(&player_id).and_then(|player| player.team);
+
+        if team.is_none() {
+            return;
+        }
+
+        for (other_id, other) in players.iter_mut() {
+            if *other_id != player_id && other.team == team {
+                other.food += amount;
+            }
+        }
+    }
+
     fn kill_player(
         &mut self,
         entity_id: EntityId,
@@ -658,6 +1282,10 @@ impl Game {
             .next()
     }
 
+    /// Returns true if the circle is outside of the map, or if it overlaps
+    /// any solid entity other than `entity_id`. Turrets are only considered
+    /// solid for circles that are owned by a player, so that turrets do not
+    /// block their own bullets.
     pub fn any_solid_neutral_contains_circle(
         &self,
         entity_id: EntityId,
@@ -669,6 +1297,11 @@ impl Game {
             return true;
         }
 
+        let circle = geom::Circle {
+            center: pos,
+            radius,
+        };
+
         for (entity_id_b, entity_b) in self.entities.iter() {
             if entity_id == *entity_id_b {
                 continue;
@@ -676,7 +1309,7 @@ impl Game {
 
             match entity_b {
                 Entity::DangerGuy(danger_guy) => {
-                    if danger_guy.aa_rect(self.game_time()).contains_point(pos) {
+                    if danger_guy.shape(self.game_time()).overlaps_circle(&circle) {
                         return true;
                     }
                 }
@@ -686,7 +1319,7 @@ impl Game {
                     }
                 }
                 Entity::Wall(wall) => {
-                    if wall.rect.contains_point(pos) {
+                    if wall.shape.overlaps_circle(&circle) {
                         return true;
                     }
                 }
@@ -697,19 +1330,28 @@ impl Game {
         false
     }
 
+    /// Find the first of the given entities that the ray hits. This is used
+    /// for everything that needs line of sight, e.g. turrets and hooks.
     pub fn trace_ray<'a>(
         ray: &Ray,
         time: f32,
         entities: impl Iterator<Item = (&'a EntityId, &'a Entity)>,
-    ) -> Option<(f32, &'a EntityId, &'a Entity)> {
-        // FIXME: The unwrap here can actually fail!
+    ) -> Option<RayHit<'a>> {
         entities
             .filter_map(|(entity_id, entity)| {
-                ray.intersections(&entity.shape(time))
+                let shape = entity.shape(time);
+                ray.intersections(&shape)
                     .first()
-                    .map(|t| (t, entity_id, entity))
+                    .filter(|t| !t.is_nan())
+                    .map(|t| (t, entity_id, entity, shape))
+            })
+            .min_by(|(t1, _, _, _), (t2, _, _, _)| t1.partial_cmp(t2).unwrap())
+            .map(|(t, entity_id, entity, shape)| RayHit {
+                t,
+                entity_id: *entity_id,
+                entity,
+                normal: shape.normal_at(ray.origin + t * ray.dir),
             })
-            .min_by(|(t1, _, _), (t2, _, _)| t1.partial_cmp(t2).unwrap())
     }
 
     fn cycle_anim(
@@ -735,3 +1377,147 @@ impl Game {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    use super::*;
+
+    use crate::{
+        game::{
+            builder::{MapBuilder, SettingsBuilder},
+            sim::Simulation,
+        },
+        DangerGuy,
+    };
+
+    #[test]
+    fn wrapping_bullet_expires() {
+        let map = MapBuilder::new(Vector::new(200.0, 200.0)).build();
+        let settings = SettingsBuilder::new(map)
+            .boundary_mode(BoundaryMode::Wrap)
+            .bullet_max_lifetime(2.0)
+            .build();
+        let num_ticks = 3 * settings.ticks_per_second;
+
+        let mut sim = Simulation::new(Arc::new(settings));
+        let bullet_id = EntityId(100);
+        sim.state.entities.insert(
+            bullet_id,
+            Entity::Bullet(Bullet {
+                owner: None,
+                start_time: 0.0,
+                motion_start_time: 0.0,
+                start_pos: Point::new(100.0, 100.0),
+                vel: Vector::new(BULLET_MOVE_SPEED, 0.0),
+            }),
+        );
+
+        let mut wrapped = false;
+        for _ in 0..num_ticks {
+            sim.step(&BTreeMap::new()).unwrap();
+
+            if let Some(Entity::Bullet(bullet)) = sim.state.entities.get(&bullet_id) {
+                wrapped |= bullet.motion_start_time > bullet.start_time;
+            }
+        }
+
+        assert!(wrapped);
+        assert!(!sim.state.entities.contains_key(&bullet_id));
+    }
+
+    #[test]
+    fn turret_shoots_at_target_periodically() {
+        let map = MapBuilder::new(Vector::new(1000.0, 1000.0))
+            .turret(Point::new(500.0, 500.0))
+            .build();
+        let settings = SettingsBuilder::new(map).turret_magazine(100, 5.0).build();
+        let dt = settings.tick_period();
+        let num_ticks = (4.0 * TURRET_SHOOT_PERIOD / dt) as usize;
+
+        let mut sim = Simulation::new(Arc::new(settings));
+
+        // Without inputs, the player stays in the turret's line of sight, and
+        // is not hit by the bullets.
+        sim.add_player("a", Point::new(700.0, 500.0));
+
+        let turret = |sim: &Simulation| {
+            sim.state
+                .entities
+                .values()
+                .find_map(|entity| match entity {
+                    Entity::Turret(turret) => Some(turret.clone()),
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        let mut shot_times = Vec::new();
+        for _ in 0..num_ticks {
+            let time = sim.state.game_time();
+            let context = sim.step(&BTreeMap::new()).unwrap();
+
+            let has_shot = context.new_entities.iter().any(|entity| match entity {
+                Entity::Bullet(bullet) => bullet.owner.is_none(),
+                _ => false,
+            });
+            if has_shot {
+                assert!(turret(&sim).target.is_some());
+                assert!((turret(&sim).next_shot_time - (time + TURRET_SHOOT_PERIOD)).abs() < 1e-3);
+                shot_times.push(time);
+            }
+        }
+
+        assert!(shot_times.len() >= 3);
+        for (time, next_time) in shot_times.iter().zip(shot_times.iter().skip(1)) {
+            let period = next_time - time;
+            assert!(period > TURRET_SHOOT_PERIOD - 1e-3);
+            assert!(period < TURRET_SHOOT_PERIOD + dt + 1e-3);
+        }
+    }
+
+    #[test]
+    fn player_moves_with_platform() {
+        let platform = DangerGuy {
+            start_pos: Point::new(300.0, 400.0),
+            end_pos: Point::new(700.0, 400.0),
+            waypoints: Vec::new(),
+            easing: false,
+            size: Vector::new(200.0, 200.0),
+            speed: (60.0, 60.0),
+            wait_time: (0.0, 0.0),
+            phase: 0.0,
+            is_hot: false,
+        };
+        let map = MapBuilder::new(Vector::new(1000.0, 1000.0))
+            .danger_guy(platform.clone())
+            .build();
+        let settings = SettingsBuilder::new(map).build();
+        let dt = settings.tick_period();
+
+        let mut sim = Simulation::new(Arc::new(settings));
+
+        // Overlap the platform's upper edge, so that we are in contact.
+        let player_id = sim.add_player("a", Point::new(300.0, 310.0));
+        let mut inputs = BTreeMap::new();
+        inputs.insert(player_id, Input::default());
+
+        let start_pos = sim.state.get_player_entity(player_id).unwrap().1.pos;
+        sim.step(&inputs).unwrap();
+        let end_pos = sim.state.get_player_entity(player_id).unwrap().1.pos;
+
+        let platform_delta = platform.delta(dt, dt);
+        assert!(platform_delta.x > 0.0);
+        assert!((end_pos.x - start_pos.x - platform_delta.x).abs() < 1e-3);
+
+        // We have been pushed out of the platform where it will be at the end
+        // of the tick.
+        let player = sim.state.get_player_entity(player_id).unwrap().1;
+        assert!(end_pos.y < start_pos.y);
+        assert!(player
+            .rect()
+            .collision(&platform.shape(dt), Vector::zeros())
+            .map_or(true, |collision| collision.resolution_vector.norm() < 1e-3));
+    }
+}
@@ -0,0 +1,224 @@
+//! Headless driver for running a `Game` without the network stack, e.g. for
+//! trying out gameplay scenarios.
+//!
+//! Each step runs the same game logic as the server: entity updates, player
+//! input and the consequences of players dying. Respawning is left to the
+//! server, so killed players stay dead.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use rand::{rngs::StdRng, SeedableRng};
+
+use crate::{
+    game::RunContext, DeathReason, Entity, EntityId, Game, GameResult, Input, Player, PlayerEntity,
+    PlayerId, PlayerState, Point, Settings,
+};
+
+pub struct Simulation {
+    pub state: Game,
+    next_entity_id: EntityId,
+    rng: StdRng,
+}
+
+impl Simulation {
+    pub fn new(settings: Arc<Settings>) -> Self {
+        let rng = StdRng::seed_from_u64(settings.seed);
+        let state = Game::new(settings);
+        let next_entity_id = state
+            .entities
+            .keys()
+            .next_back()
+            .map_or(EntityId(0), EntityId::next);
+
+        Self {
+            state,
+            next_entity_id,
+            rng,
+        }
+    }
+
+    /// Add a player that is alive at the given position.
+    pub fn add_player(&mut self, name: &str, pos: Point) -> PlayerId {
+        let player_id = self
+            .state
+            .players
+            .keys()
+            .next_back()
+            .map_or(PlayerId(1), PlayerId::next);
+
+        self.state.players.insert(
+            player_id,
+            Player {
+                name: name.to_string(),
+                state: PlayerState::Alive,
+                food: 0,
+                team: None,
+                score: 0,
+                last_catcher_tick: None,
+            },
+        );
+        self.add_entity(Entity::Player(PlayerEntity::new(player_id, pos)));
+
+        player_id
+    }
+
+    /// Run one tick with the given inputs. Returns the context, which
+    /// contains the events that happened in the tick.
+    pub fn step(&mut self, inputs: &BTreeMap<PlayerId, Input>) -> GameResult<RunContext> {
+        let mut context = RunContext::default();
+
+        self.state.run_tick(&mut context, &mut self.rng)?;
+
+        for (player_id, input) in inputs.iter() {
+            self.state
+                .run_player_input(*player_id, input, None, &mut context)?;
+        }
+
+        for (player_id, reason) in context.killed_players.clone() {
+            self.kill_player(player_id, reason, &mut context)?;
+        }
+
+        for entity in context.new_entities.iter().cloned() {
+            self.add_entity(entity);
+        }
+
+        for entity_id in context.removed_entities.iter() {
+            self.state.entities.remove(entity_id);
+        }
+
+        self.state.tick_num = self.state.tick_num.next();
+
+        Ok(context)
+    }
+
+    fn kill_player(
+        &mut self,
+        player_id: PlayerId,
+        reason: DeathReason,
+        context: &mut RunContext,
+    ) -> GameResult<()> {
+        if let Some(player) = self.state.players.get_mut(&player_id) {
+            player.state = PlayerState::Dead;
+        }
+
+        if let Some((entity_id, ent)) = self.state.get_player_entity(player_id) {
+            let ent = ent.clone();
+            self.state
+                .on_kill_player(&ent, reason, context, &mut self.rng)?;
+            self.state.entities.remove(&entity_id);
+        }
+
+        Ok(())
+    }
+
+    fn add_entity(&mut self, entity: Entity) -> EntityId {
+        let entity_id = self.next_entity_id;
+        self.next_entity_id = entity_id.next();
+        self.state.entities.insert(entity_id, entity);
+
+        entity_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        game::{
+            builder::{MapBuilder, SettingsBuilder},
+            PlayerCollision,
+        },
+        TeamId, Vector,
+    };
+
+    #[test]
+    fn catcher_catches_target_and_takes_food() {
+        let map = MapBuilder::new(Vector::new(1000.0, 1000.0))
+            .spawn_point(Point::new(500.0, 500.0))
+            .build();
+        let settings = SettingsBuilder::new(map)
+            .player_collision(PlayerCollision::None)
+            .build();
+        let catch_food = settings.catch_food;
+
+        let mut sim = Simulation::new(Arc::new(settings));
+        // Let the players overlap, so that a dash catches no matter which
+        // direction it goes.
+        let a = sim.add_player("a", Point::new(400.0, 400.0));
+        let b = sim.add_player("b", Point::new(405.0, 400.0));
+        sim.state.players.get_mut(&a).unwrap().food = 5;
+        sim.state.players.get_mut(&b).unwrap().food = 5;
+
+        // The first tick chooses a catcher.
+        sim.step(&BTreeMap::new()).unwrap();
+        let catcher = sim.state.catcher.unwrap();
+        let target = if catcher == a { b } else { a };
+
+        let mut inputs = BTreeMap::new();
+        inputs.insert(
+            catcher,
+            Input {
+                dash: true,
+                ..Input::default()
+            },
+        );
+
+        for _ in 0..10 {
+            sim.step(&inputs).unwrap();
+
+            if sim.state.players[&target].state == PlayerState::Dead {
+                break;
+            }
+        }
+
+        assert_eq!(sim.state.players[&target].state, PlayerState::Dead);
+        assert!(sim.state.get_player_entity(target).is_none());
+        assert_eq!(sim.state.players[&catcher].food, 5 + catch_food);
+
+        // The target drops its food into the game.
+        assert_eq!(sim.state.players[&target].food, 0);
+        let num_food = sim
+            .state
+            .entities
+            .values()
+            .filter(|entity| matches!(entity, Entity::Food(_)))
+            .count();
+        assert_eq!(num_food, 5);
+    }
+
+    #[test]
+    fn teammates_do_not_catch_each_other() {
+        let map = MapBuilder::new(Vector::new(1000.0, 1000.0))
+            .spawn_point(Point::new(500.0, 500.0))
+            .build();
+        let settings = SettingsBuilder::new(map).num_teams(Some(2)).build();
+
+        let mut sim = Simulation::new(Arc::new(settings));
+        let a = sim.add_player("a", Point::new(400.0, 400.0));
+        let b = sim.add_player("b", Point::new(405.0, 400.0));
+        for player_id in &[a, b] {
+            sim.state.players.get_mut(player_id).unwrap().team = Some(TeamId(1));
+        }
+
+        sim.step(&BTreeMap::new()).unwrap();
+        let catcher = sim.state.catcher.unwrap();
+        let teammate = if catcher == a { b } else { a };
+
+        let mut inputs = BTreeMap::new();
+        inputs.insert(
+            catcher,
+            Input {
+                dash: true,
+                ..Input::default()
+            },
+        );
+
+        for _ in 0..10 {
+            sim.step(&inputs).unwrap();
+        }
+
+        assert_eq!(sim.state.players[&teammate].state, PlayerState::Alive);
+        assert!(sim.state.get_player_entity(teammate).is_some());
+    }
+}
@@ -5,19 +5,59 @@ use serde::{Deserialize, Serialize};
 
 use crate::{Point, Vector};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Shape {
     AaRect(AaRect),
     Rect(Rect),
     Circle(Circle),
+    Polygon(Polygon),
 }
 
 impl Shape {
+    pub fn center(&self) -> Point {
+        match self {
+            Shape::AaRect(shape) => shape.center(),
+            Shape::Rect(shape) => shape.center,
+            Shape::Circle(shape) => shape.center,
+            Shape::Polygon(shape) => shape.center(),
+        }
+    }
+
     pub fn contains_point(&self, point: Point) -> bool {
         match self {
             Shape::AaRect(shape) => shape.contains_point(point),
             Shape::Rect(shape) => shape.contains_point(point),
             Shape::Circle(shape) => shape.contains_point(point),
+            Shape::Polygon(shape) => shape.contains_point(point),
+        }
+    }
+
+    /// Returns the outward normal of the boundary part that is closest to
+    /// the given point.
+    pub fn normal_at(&self, point: Point) -> Vector {
+        match self {
+            Shape::AaRect(shape) => shape.to_rect().to_polygon().normal_at(point),
+            Shape::Rect(shape) => shape.to_polygon().normal_at(point),
+            Shape::Circle(shape) => {
+                let delta = point - shape.center;
+                if delta.norm() > 0.0 {
+                    delta.normalize()
+                } else {
+                    Vector::x()
+                }
+            }
+            Shape::Polygon(shape) => shape.normal_at(point),
+        }
+    }
+
+    pub fn overlaps_circle(&self, circle: &Circle) -> bool {
+        match self {
+            Shape::AaRect(shape) => shape.to_rect().to_polygon().overlaps_circle(circle),
+            Shape::Rect(shape) => shape.to_polygon().overlaps_circle(circle),
+            Shape::Circle(shape) => {
+                (shape.center - circle.center).norm() <= shape.radius + circle.radius
+            }
+            Shape::Polygon(shape) => shape.overlaps_circle(circle),
         }
     }
 }
@@ -90,6 +130,20 @@ pub fn interp_angle(alpha: f32, beta: f32, t: f32) -> f32 {
     alpha + t * angle_dist(beta, alpha)
 }
 
+/// Distance of a point to the line segment from `p` to `q`.
+pub fn segment_point_distance(p: Point, q: Point, point: Point) -> f32 {
+    let edge = q - p;
+    let len_squared = edge.norm_squared();
+
+    let tau = if len_squared > 0.0 {
+        (edge.dot(&(point - p)) / len_squared).max(0.0).min(1.0)
+    } else {
+        0.0
+    };
+
+    (p + tau * edge - point).norm()
+}
+
 // Awesome resource:
 // https://www.codeproject.com/Articles/15573/2D-Polygon-Collision-Detection
 
@@ -114,7 +168,7 @@ impl AxisProjection {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Rect {
     pub center: Point,
     pub x_edge: Vector,
@@ -133,6 +187,17 @@ impl Rect {
             .chain(once(self.center + self.x_edge / 2.0 + self.y_edge / 2.0))
     }
 
+    pub fn to_polygon(&self) -> Polygon {
+        Polygon {
+            points: vec![
+                self.center - self.x_edge / 2.0 - self.y_edge / 2.0,
+                self.center + self.x_edge / 2.0 - self.y_edge / 2.0,
+                self.center + self.x_edge / 2.0 + self.y_edge / 2.0,
+                self.center - self.x_edge / 2.0 + self.y_edge / 2.0,
+            ],
+        }
+    }
+
     pub fn project_to_edge(&self, edge: Vector) -> AxisProjection {
         use std::cmp::Ordering::Equal;
 
@@ -164,6 +229,7 @@ impl Rect {
         match other {
             Shape::Rect(other) => rect_collision(self, other, delta),
             Shape::AaRect(other) => rect_collision(self, &other.to_rect(), delta),
+            Shape::Polygon(other) => polygon_collision(&self.to_polygon(), other, delta),
             Shape::Circle(other) => {
                 assert!(self.x_edge.x.is_finite());
                 assert!(self.x_edge.y.is_finite());
@@ -261,6 +327,71 @@ pub fn rect_collision(a: &Rect, b: &Rect, delta: Vector) -> Option<Collision> {
     }
 }
 
+pub fn polygon_collision(a: &Polygon, b: &Polygon, delta: Vector) -> Option<Collision> {
+    // Same as `rect_collision`, but testing the normals of all the polygon
+    // edges.
+
+    let mut intersecting = true;
+    let mut will_intersect = true;
+
+    let mut min_interval_distance = std::f32::INFINITY;
+    let mut translation_axis = Vector::zeros();
+
+    let (a_center, b_center) = (a.center(), b.center());
+
+    for (p, q) in a.iter_edges().chain(b.iter_edges()) {
+        let edge = q - p;
+        let axis = Vector::new(-edge.y, edge.x).normalize();
+        if !axis.x.is_finite() || !axis.y.is_finite() {
+            // Degenerate edge, skip.
+            continue;
+        }
+
+        let mut a_projection = a.project_to_edge(axis);
+        let b_projection = b.project_to_edge(axis);
+
+        if a_projection.interval_distance(&b_projection) > 0.0 {
+            intersecting = false;
+        }
+
+        let delta_projection = axis.dot(&delta);
+
+        if delta_projection < 0.0 {
+            a_projection.min += delta_projection;
+        } else {
+            a_projection.max += delta_projection;
+        }
+
+        let interval_distance = a_projection.interval_distance(&b_projection);
+        if interval_distance > 0.0 {
+            will_intersect = false;
+        }
+
+        if !intersecting && !will_intersect {
+            return None;
+        }
+
+        let interval_distance = interval_distance.abs();
+        if interval_distance < min_interval_distance && interval_distance > 0.0 {
+            min_interval_distance = interval_distance;
+
+            translation_axis = if (a_center - b_center).dot(&axis) < 0.0 {
+                -axis
+            } else {
+                axis
+            };
+        }
+    }
+
+    if will_intersect && min_interval_distance.is_finite() {
+        Some(Collision {
+            resolution_vector: translation_axis * min_interval_distance,
+        })
+    } else {
+        None
+    }
+}
+
 pub fn aa_rect_circle_collision(
     rect: &AaRect,
     circle: &Circle,
@@ -310,7 +441,7 @@ pub fn aa_rect_circle_collision(
     // https://stackoverflow.com/questions/18704999/how-to-fix-circle-and-rectangle-overlap-in-collision-response/18790389#18790389
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Circle {
     pub center: Point,
     pub radius: f32,
@@ -322,6 +453,121 @@ impl Circle {
     }
 }
 
+/// A convex polygon. The points can be given in either winding order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Polygon {
+    pub points: Vec<Point>,
+}
+
+impl Polygon {
+    /// Returns `None` if the points do not form a convex polygon.
+    pub fn new_convex(points: Vec<Point>) -> Option<Self> {
+        if Self::is_convex(&points) {
+            Some(Self { points })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_convex(points: &[Point]) -> bool {
+        if points.len() < 3 {
+            return false;
+        }
+
+        let mut sign = 0.0;
+
+        for i in 0..points.len() {
+            let a = points[i];
+            let b = points[(i + 1) % points.len()];
+            let c = points[(i + 2) % points.len()];
+
+            let (ab, bc) = (b - a, c - b);
+            let cross = ab.x * bc.y - ab.y * bc.x;
+
+            if cross != 0.0 {
+                if sign != 0.0 && cross.signum() != sign {
+                    return false;
+                }
+                sign = cross.signum();
+            }
+        }
+
+        // All points on one line do not make for a polygon.
+        sign != 0.0
+    }
+
+    pub fn center(&self) -> Point {
+        let sum = self
+            .points
+            .iter()
+            .fold(Vector::zeros(), |sum, point| sum + point.coords);
+
+        Point::from(sum / self.points.len() as f32)
+    }
+
+    pub fn iter_edges(&self) -> impl Iterator<Item = (Point, Point)> + '_ {
+        self.points
+            .iter()
+            .zip(self.points.iter().cycle().skip(1))
+            .map(|(p, q)| (*p, *q))
+    }
+
+    /// Returns the outward normal of the edge that is closest to the point.
+    pub fn normal_at(&self, point: Point) -> Vector {
+        self.iter_edge_normals()
+            .map(|(p, normal)| {
+                let normal = normal.normalize();
+                (normal.dot(&(point - p)).abs(), normal)
+            })
+            .min_by(|(d1, _), (d2, _)| d1.partial_cmp(d2).unwrap())
+            .map_or(Vector::x(), |(_, normal)| normal)
+    }
+
+    /// Iterate the edges together with their normals, which point to the
+    /// outside of the polygon.
+    pub fn iter_edge_normals(&self) -> impl Iterator<Item = (Point, Vector)> + '_ {
+        let center = self.center();
+
+        self.iter_edges().map(move |(p, q)| {
+            let edge = q - p;
+            let normal = Vector::new(edge.y, -edge.x);
+
+            if normal.dot(&(p - center)) < 0.0 {
+                (p, -normal)
+            } else {
+                (p, normal)
+            }
+        })
+    }
+
+    pub fn project_to_edge(&self, edge: Vector) -> AxisProjection {
+        AxisProjection {
+            min: self
+                .points
+                .iter()
+                .map(|p| edge.dot(&p.coords))
+                .fold(std::f32::INFINITY, f32::min),
+            max: self
+                .points
+                .iter()
+                .map(|p| edge.dot(&p.coords))
+                .fold(std::f32::NEG_INFINITY, f32::max),
+        }
+    }
+
+    pub fn contains_point(&self, point: Point) -> bool {
+        self.iter_edge_normals()
+            .all(|(p, normal)| normal.dot(&(point - p)) <= 0.0)
+    }
+
+    pub fn overlaps_circle(&self, circle: &Circle) -> bool {
+        self.contains_point(circle.center)
+            || self
+                .iter_edges()
+                .any(|(p, q)| segment_point_distance(p, q, circle.center) <= circle.radius)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Ray {
     pub origin: Point,
@@ -374,6 +620,35 @@ impl Ray {
 
                 ray_rotated.intersections(&Shape::AaRect(aa_rect_origin))
             }
+            Shape::Polygon(polygon) => {
+                // Cyrus-Beck clipping: each edge bounds the ray parameter from
+                // one side.
+
+                let mut t_min = std::f32::NEG_INFINITY;
+                let mut t_max = std::f32::INFINITY;
+
+                for (p, normal) in polygon.iter_edge_normals() {
+                    let num = normal.dot(&(p - self.origin));
+                    let denom = normal.dot(&self.dir);
+
+                    if denom == 0.0 {
+                        if num < 0.0 {
+                            // Parallel to the edge and outside of it.
+                            return RayIntersections(None);
+                        }
+                    } else if denom < 0.0 {
+                        t_min = t_min.max(num / denom);
+                    } else {
+                        t_max = t_max.min(num / denom);
+                    }
+                }
+
+                if t_min > t_max {
+                    RayIntersections(None)
+                } else {
+                    Self::collect_times(t_min, t_max)
+                }
+            }
             Shape::Circle(circle) => {
                 // https://stackoverflow.com/questions/1073336/circle-line-segment-collision-detection-algorithm
 
@@ -448,3 +723,105 @@ impl Iterator for RayIntersectionsIter {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A triangle whose left edge is at x = 20 and that points to the right.
+    fn triangle() -> Polygon {
+        Polygon::new_convex(vec![
+            Point::new(20.0, -50.0),
+            Point::new(60.0, 0.0),
+            Point::new(20.0, 50.0),
+        ])
+        .unwrap()
+    }
+
+    fn player_rect() -> Rect {
+        AaRect::new_center(Point::origin(), Vector::new(20.0, 20.0)).to_rect()
+    }
+
+    fn assert_close(a: Vector, b: Vector) {
+        assert!((a - b).norm() < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn concave_polygons_are_rejected() {
+        let points = vec![
+            Point::new(0.0, 0.0),
+            Point::new(10.0, 0.0),
+            Point::new(5.0, 2.0),
+            Point::new(10.0, 10.0),
+            Point::new(0.0, 10.0),
+        ];
+
+        assert!(Polygon::new_convex(points).is_none());
+        assert!(Polygon::new_convex(vec![Point::origin(), Point::new(1.0, 1.0)]).is_none());
+    }
+
+    #[test]
+    fn triangle_wall_blocks_player() {
+        let wall = Shape::Polygon(triangle());
+
+        let collision = player_rect()
+            .collision(&wall, Vector::new(15.0, 0.0))
+            .unwrap();
+        assert_close(collision.resolution_vector, Vector::new(-5.0, 0.0));
+
+        assert!(player_rect()
+            .collision(&wall, Vector::new(5.0, 0.0))
+            .is_none());
+        assert!(player_rect()
+            .collision(&wall, Vector::new(-15.0, 0.0))
+            .is_none());
+    }
+
+    #[test]
+    fn ray_through_polygon_is_clipped() {
+        let wall = Shape::Polygon(triangle());
+        let ray = Ray {
+            origin: Point::origin(),
+            dir: Vector::new(100.0, 0.0),
+        };
+
+        let (t_min, t_max) = ray.intersections(&wall).0.unwrap();
+        assert!((t_min - 0.2).abs() < 1e-4);
+        assert!((t_max - 0.6).abs() < 1e-4);
+    }
+
+    #[test]
+    fn ray_starting_inside_polygon_hits_the_exit() {
+        let wall = Shape::Polygon(triangle());
+        let ray = Ray {
+            origin: Point::new(30.0, 0.0),
+            dir: Vector::new(100.0, 0.0),
+        };
+
+        let t = ray.intersections(&wall).first().unwrap();
+        assert!((t - 0.3).abs() < 1e-4);
+    }
+
+    #[test]
+    fn ray_missing_polygon_has_no_intersections() {
+        let wall = Shape::Polygon(triangle());
+
+        let passing_ray = Ray {
+            origin: Point::new(0.0, 100.0),
+            dir: Vector::new(100.0, 0.0),
+        };
+        assert_eq!(passing_ray.intersections(&wall), RayIntersections(None));
+
+        let parallel_ray = Ray {
+            origin: Point::new(0.0, 0.0),
+            dir: Vector::new(0.0, 100.0),
+        };
+        assert_eq!(parallel_ray.intersections(&wall), RayIntersections(None));
+
+        let opposite_ray = Ray {
+            origin: Point::new(0.0, 0.0),
+            dir: Vector::new(-100.0, 0.0),
+        };
+        assert_eq!(opposite_ray.intersections(&wall), RayIntersections(None));
+    }
+}
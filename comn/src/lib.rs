@@ -3,17 +3,22 @@
 
 #[macro_use]
 pub mod util;
+pub mod codec;
 pub mod game;
 pub mod geom;
+pub mod prelude;
+pub mod replay;
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 pub use crate::{
+    codec::{Codec, CodecKind},
     game::{
         entities::{DangerGuy, Hook, PlayerEntity, PlayerView, Rocket, Turret},
         DeathReason, Entity, EntityId, EntityMap, Event, Game, Input, Item, Map, Matrix, Player,
-        PlayerId, PlayerMap, PlayerState, Point, Settings, Tick, TickNum, Time, Vector,
+        PlayerId, PlayerMap, PlayerState, Point, Scoreboard, ScoreboardEntry, Settings, TeamId,
+        Tick, TickNum, Time, Vector,
     },
     util::ping::SequenceNum,
 };
@@ -29,10 +34,53 @@ pub struct GameId(pub Uuid);
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct PlayerToken(pub Uuid);
 
+/// Version of the network protocol. This needs to be increased whenever the
+/// format of the messages between client and server changes.
+pub const PROTOCOL_VERSION: u32 = 1;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct JoinRequest {
+    /// Defaults to zero for old clients that do not send a version, so
+    /// that they are rejected with a proper error.
+    #[serde(default)]
+    pub protocol_version: u32,
     pub game_id: Option<GameId>,
     pub player_name: String,
+
+    /// Join the private game with the given code.
+    #[serde(default)]
+    pub join_code: Option<String>,
+
+    /// Create a new private game that others can join with the code given
+    /// in `JoinSuccess`.
+    #[serde(default)]
+    pub create_private: bool,
+
+    /// Options for creating a private game. The server may reject values that
+    /// are out of its limits.
+    #[serde(default)]
+    pub game_options: GameOptions,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GameOptions {
+    pub ticks_per_second: Option<usize>,
+    pub max_num_players: Option<usize>,
+}
+
+/// A STUN or TURN server that clients use for establishing the WebRTC
+/// connection. The fields mirror the `RTCIceServer` dictionary of browsers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+
+    /// Only needed for TURN servers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    /// Only needed for TURN servers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -41,6 +89,20 @@ pub struct JoinSuccess {
     pub game_settings: Settings,
     pub your_token: PlayerToken,
     pub your_player_id: PlayerId,
+    pub codec: CodecKind,
+
+    /// Code for inviting others, if this is a private game.
+    pub join_code: Option<String>,
+
+    /// ICE servers for the WebRTC connection. If empty, the client falls back
+    /// to its default servers.
+    #[serde(default)]
+    pub ice_servers: Vec<IceServer>,
+
+    /// URL for establishing the WebRTC connection. If not given, the client
+    /// uses `/connect_webrtc` on the origin it joined through.
+    #[serde(default)]
+    pub webrtc_signaling_url: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -48,10 +110,29 @@ pub enum JoinError {
     InvalidGameId,
     InvalidPlayerName,
     FullGame,
+    IncompatibleVersion { server: u32 },
+    InvalidGameOptions,
 }
 
 pub type JoinReply = Result<JoinSuccess, JoinError>;
 
+pub const MAX_PLAYER_NAME_LEN: usize = 16;
+
+/// Returns the player name with surrounding whitespace removed, or an error
+/// if the name is empty, too long, or contains control characters.
+pub fn validate_player_name(name: &str) -> Result<String, JoinError> {
+    let name = name.trim();
+
+    if name.is_empty()
+        || name.chars().count() > MAX_PLAYER_NAME_LEN
+        || name.chars().any(char::is_control)
+    {
+        Err(JoinError::InvalidPlayerName)
+    } else {
+        Ok(name.to_string())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerMessage {
     Ping(SequenceNum),
@@ -65,8 +146,9 @@ pub enum ClientMessage {
     Ping(SequenceNum),
     Pong(SequenceNum),
     Input(Vec<(TickNum, Input)>),
-    // TODO: Send some kind of hash with the AckTick
-    AckTick(TickNum),
+    /// Acknowledges receiving a tick, together with the `Game::state_hash`
+    /// of the state that the client reconstructed for the tick.
+    AckTick(TickNum, u64),
     Disconnect,
 }
 
@@ -75,26 +157,74 @@ pub const MAX_INPUTS_PER_MESSAGE: usize = 5;
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SignedClientMessage(pub PlayerToken, pub ClientMessage);
 
+const HEADER_PLAIN: u8 = 0;
+const HEADER_DEFLATE: u8 = 1;
+
 impl ServerMessage {
-    pub fn serialize(&self) -> Vec<u8> {
-        //bincode::serialize(self).unwrap()
-        rmp_serde::to_vec(self).unwrap()
+    /// Serialize the message, prefixed by a one-byte header that tells if the
+    /// payload is compressed. If `compress_threshold` is given, ticks that
+    /// are larger than this number of bytes are compressed.
+    pub fn serialize(&self, codec: &impl Codec, compress_threshold: Option<usize>) -> Vec<u8> {
+        let data = codec.encode(self);
+
+        let compress = match self {
+            ServerMessage::Tick(_) => compress_threshold.map_or(false, |max| data.len() > max),
+            _ => false,
+        };
+
+        if compress {
+            let mut result = vec![HEADER_DEFLATE];
+            result.extend(codec::deflate(&data));
+            result
+        } else {
+            let mut result = vec![HEADER_PLAIN];
+            result.extend(data);
+            result
+        }
     }
 
-    pub fn deserialize(data: &[u8]) -> Option<Self> {
-        //bincode::deserialize(data).ok()
-        rmp_serde::from_read_ref(data).ok()
+    pub fn deserialize(codec: &impl Codec, data: &[u8]) -> Option<Self> {
+        let (&header, data) = data.split_first()?;
+
+        match header {
+            HEADER_PLAIN => codec.decode(data),
+            HEADER_DEFLATE => codec.decode(&codec::inflate(data)?),
+            _ => None,
+        }
     }
 }
 
 impl SignedClientMessage {
-    pub fn serialize(&self) -> Vec<u8> {
-        //bincode::serialize(self).unwrap()
-        rmp_serde::to_vec(self).unwrap()
+    pub fn serialize(&self, codec: &impl Codec) -> Vec<u8> {
+        codec.encode(self)
+    }
+
+    pub fn deserialize(codec: &impl Codec, data: &[u8]) -> Option<Self> {
+        codec.decode(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn player_names_are_trimmed() {
+        assert_eq!(validate_player_name("  leo ").unwrap(), "leo");
+    }
+
+    #[test]
+    fn invalid_player_names_are_rejected() {
+        let too_long = "x".repeat(MAX_PLAYER_NAME_LEN + 1);
+
+        for name in &["", "   ", "le\no", too_long.as_str()] {
+            assert!(validate_player_name(name).is_err(), "{:?}", name);
+        }
     }
 
-    pub fn deserialize(data: &[u8]) -> Option<Self> {
-        //bincode::deserialize(data).ok()
-        rmp_serde::from_read_ref(data).ok()
+    #[test]
+    fn player_name_length_counts_chars() {
+        let name = "ü".repeat(MAX_PLAYER_NAME_LEN);
+        assert_eq!(validate_player_name(&name).unwrap(), name);
     }
 }
@@ -0,0 +1,68 @@
+//! The types needed for running the game simulation outside of the client and
+//! server, e.g. in bots or analysis tools.
+//!
+//! A `Game` is created from `Settings`. Each tick, the inputs of the players
+//! are applied with `Game::run_player_input`, and then the rest of the world
+//! is updated with `Game::run_tick`. Both report their effects through a
+//! `RunContext`, which the caller is responsible for applying to the game,
+//! as the server does in `serv::game`.
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use comn::prelude::*;
+//! use rand::SeedableRng;
+//!
+//! # fn main() -> GameResult<()> {
+//! let map = MapBuilder::new(Vector::new(1000.0, 1000.0))
+//!     .spawn_point(Point::new(500.0, 500.0))
+//!     .build();
+//! let mut game = Game::new(Arc::new(SettingsBuilder::new(map).build()));
+//! let mut rng = rand::rngs::StdRng::seed_from_u64(game.settings.seed);
+//!
+//! let player_id = PlayerId(1);
+//! game.players.insert(
+//!     player_id,
+//!     Player {
+//!         name: "bot".to_string(),
+//!         state: PlayerState::Alive,
+//!         food: 0,
+//!         team: None,
+//!         score: 0,
+//!         last_catcher_tick: None,
+//!     },
+//! );
+//! game.entities.insert(
+//!     EntityId(0),
+//!     Entity::Player(PlayerEntity::new(player_id, Point::new(500.0, 500.0))),
+//! );
+//!
+//! // Step one tick.
+//! let mut context = RunContext::default();
+//! let input = Input {
+//!     move_right: true,
+//!     ..Input::default()
+//! };
+//! game.run_player_input(player_id, &input, None, &mut context)?;
+//! game.run_tick(&mut context, &mut rng)?;
+//! for entity_id in context.removed_entities.iter() {
+//!     game.entities.remove(entity_id);
+//! }
+//! game.tick_num = game.tick_num.next();
+//!
+//! assert_eq!(game.tick_num, TickNum(1));
+//! assert!(game.get_player_entity(player_id).is_some());
+//! # Ok(())
+//! # }
+//! ```
+
+pub use crate::{
+    game::{
+        entities::{Bullet, DangerGuy, Food, FoodSpawn, Hook, PlayerEntity, Rocket, Turret, Wall},
+        DeathReason, Entity, EntityId, EntityMap, Event, Game, Input, Map, MapBuilder, Player,
+        PlayerId, PlayerMap, PlayerState, Point, RunContext, Settings, SettingsBuilder, TeamId,
+        TickNum, Vector,
+    },
+    geom::Shape,
+    GameError, GameResult, GameTime,
+};
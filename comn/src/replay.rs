@@ -0,0 +1,89 @@
+//! Format of recorded game replays.
+//!
+//! A replay is a sequence of length-prefixed msgpack records. The first record
+//! is the `Settings` of the game, so that the replay can be played back
+//! without the original map. Each following record is a `ReplayTick`, which
+//! is delta encoded w.r.t. the previous tick.
+
+use std::{convert::TryInto, sync::Arc};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    game::GameDiff,
+    util::diff::{ApplyError, Diff},
+    Event, Game, Settings, TickNum,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayTick {
+    pub tick_num: TickNum,
+    pub events: Vec<Event>,
+    pub diff: GameDiff,
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Truncated,
+    Decode(rmp_serde::decode::Error),
+    Apply(TickNum, ApplyError),
+}
+
+pub fn encode_record<T: Serialize>(value: &T) -> Vec<u8> {
+    let data = rmp_serde::to_vec(value).unwrap();
+    let mut record = (data.len() as u32).to_le_bytes().to_vec();
+    record.extend(data);
+    record
+}
+
+fn decode_record<T: DeserializeOwned>(data: &mut &[u8]) -> Result<T, ReplayError> {
+    if data.len() < 4 {
+        return Err(ReplayError::Truncated);
+    }
+
+    let len = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+    if data.len() < 4 + len {
+        return Err(ReplayError::Truncated);
+    }
+
+    let value = rmp_serde::from_read_ref(&data[4..4 + len]).map_err(ReplayError::Decode)?;
+    *data = &data[4 + len..];
+
+    Ok(value)
+}
+
+#[derive(Debug, Clone)]
+pub struct Replay {
+    pub settings: Arc<Settings>,
+    pub ticks: Vec<ReplayTick>,
+}
+
+impl Replay {
+    pub fn decode(mut data: &[u8]) -> Result<Self, ReplayError> {
+        let settings = Arc::new(decode_record(&mut data)?);
+        let mut ticks = Vec::new();
+
+        while !data.is_empty() {
+            ticks.push(decode_record(&mut data)?);
+        }
+
+        Ok(Self { settings, ticks })
+    }
+
+    /// Reconstruct the full game state of every recorded tick.
+    pub fn states(&self) -> Result<Vec<(Game, Vec<Event>)>, ReplayError> {
+        let mut state = Game::new(self.settings.clone());
+        let mut states = Vec::new();
+
+        for tick in self.ticks.iter() {
+            tick.diff
+                .clone()
+                .apply(&mut state)
+                .map_err(|e| ReplayError::Apply(tick.tick_num, e))?;
+
+            states.push((state.clone(), tick.events.clone()));
+        }
+
+        Ok(states)
+    }
+}
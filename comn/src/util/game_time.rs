@@ -4,10 +4,22 @@ use crate::{util::stats, GameTime};
 
 const SAMPLE_DURATION: f32 = 2.0;
 
+/// Samples whose residual w.r.t. the current fit exceeds this many standard
+/// deviations are considered outliers.
+const OUTLIER_STD_DEV_FACTOR: f32 = 4.0;
+
+/// Minimal number of samples before we start rejecting outliers.
+const MIN_OUTLIER_SAMPLES: usize = 10;
+
+/// If this many samples in a row are outliers, we assume that the timing has
+/// actually changed and accept them again.
+const MAX_CONSECUTIVE_OUTLIERS: usize = 5;
+
 #[derive(Debug, Clone)]
 pub struct GameTimeEstimation {
     recv_period: GameTime,
     recv_times: VecDeque<(f32, GameTime)>,
+    num_consecutive_outliers: usize,
 }
 
 impl GameTimeEstimation {
@@ -15,6 +27,7 @@ impl GameTimeEstimation {
         Self {
             recv_period,
             recv_times: VecDeque::new(),
+            num_consecutive_outliers: 0,
         }
     }
 
@@ -26,6 +39,16 @@ impl GameTimeEstimation {
             }
         }
 
+        if self.is_outlier(recv_time, game_time) {
+            self.num_consecutive_outliers += 1;
+
+            if self.num_consecutive_outliers <= MAX_CONSECUTIVE_OUTLIERS {
+                return;
+            }
+        } else {
+            self.num_consecutive_outliers = 0;
+        }
+
         self.recv_times.push_back((recv_time, game_time));
 
         while let Some((first_recv_time, _)) = self.recv_times.front() {
@@ -50,6 +73,39 @@ impl GameTimeEstimation {
         }
     }
 
+    /// Standard deviation of the samples around the fitted line. Since we fit
+    /// with a fixed slope of one, the residuals are just the offsets between
+    /// game time and receive time, shifted by their mean.
+    pub fn residual_std_dev(&self) -> Option<f32> {
+        if !self.recv_times.is_empty() {
+            Some(stats::std_dev(self.offsets()))
+        } else {
+            None
+        }
+    }
+
+    fn offsets<'a>(&'a self) -> impl Iterator<Item = f32> + 'a {
+        self.recv_times
+            .iter()
+            .map(|(recv_time, game_time)| game_time - recv_time)
+    }
+
+    fn is_outlier(&self, recv_time: f32, game_time: GameTime) -> bool {
+        if self.recv_times.len() < MIN_OUTLIER_SAMPLES {
+            return false;
+        }
+
+        let mean = stats::mean(self.offsets());
+
+        // Do not reject anything if the samples happen to be very regular.
+        let std_dev = self
+            .residual_std_dev()
+            .unwrap_or(0.0)
+            .max(self.recv_period * 0.5);
+
+        ((game_time - recv_time) - mean).abs() > OUTLIER_STD_DEV_FACTOR * std_dev
+    }
+
     pub fn has_started(&self) -> bool {
         !self.recv_times.is_empty()
     }
@@ -74,3 +74,96 @@ where
         }
     }
 }
+
+pub fn full_join3<First, Second, Third, K, T, U, V>(
+    first: First,
+    second: Second,
+    third: Third,
+) -> FullJoin3Iter<First, Second, Third>
+where
+    First: Iterator<Item = (K, T)>,
+    Second: Iterator<Item = (K, U)>,
+    Third: Iterator<Item = (K, V)>,
+    K: Ord,
+{
+    FullJoin3Iter {
+        first: first.peekable(),
+        second: second.peekable(),
+        third: third.peekable(),
+    }
+}
+
+/// Element of a full join of three sequences, tagged by which of the
+/// sequences contain the key `K`.
+pub enum Item3<K, T, U, V> {
+    First(K, T),
+    Second(K, U),
+    Third(K, V),
+    FirstSecond(K, T, U),
+    FirstThird(K, T, V),
+    SecondThird(K, U, V),
+    All(K, T, U, V),
+}
+
+/// Iterator over the full join of three sequences of key value pairs. The
+/// sequences are assumed to be sorted by the key in ascending order.
+pub struct FullJoin3Iter<First, Second, Third>
+where
+    First: Iterator,
+    Second: Iterator,
+    Third: Iterator,
+{
+    first: Peekable<First>,
+    second: Peekable<Second>,
+    third: Peekable<Third>,
+}
+
+fn next_if_key<I, K, T>(iter: &mut Peekable<I>, key: &K) -> Option<(K, T)>
+where
+    I: Iterator<Item = (K, T)>,
+    K: Ord,
+{
+    if iter.peek().map_or(false, |(k, _)| k == key) {
+        iter.next()
+    } else {
+        None
+    }
+}
+
+impl<First, Second, Third, K, T, U, V> Iterator for FullJoin3Iter<First, Second, Third>
+where
+    First: Iterator<Item = (K, T)>,
+    Second: Iterator<Item = (K, U)>,
+    Third: Iterator<Item = (K, V)>,
+    K: Ord + Clone,
+{
+    type Item = Item3<K, T, U, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Find the smallest key and advance all the iterators that have it.
+        let min_key = [
+            self.first.peek().map(|(k, _)| k),
+            self.second.peek().map(|(k, _)| k),
+            self.third.peek().map(|(k, _)| k),
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .map(|k| (*k).clone())?;
+
+        let first = next_if_key(&mut self.first, &min_key).map(|(_, v)| v);
+        let second = next_if_key(&mut self.second, &min_key).map(|(_, v)| v);
+        let third = next_if_key(&mut self.third, &min_key).map(|(_, v)| v);
+
+        Some(match (first, second, third) {
+            (Some(t), None, None) => Item3::First(min_key, t),
+            (None, Some(u), None) => Item3::Second(min_key, u),
+            (None, None, Some(v)) => Item3::Third(min_key, v),
+            (Some(t), Some(u), None) => Item3::FirstSecond(min_key, t, u),
+            (Some(t), None, Some(v)) => Item3::FirstThird(min_key, t, v),
+            (None, Some(u), Some(v)) => Item3::SecondThird(min_key, u, v),
+            (Some(t), Some(u), Some(v)) => Item3::All(min_key, t, u, v),
+            (None, None, None) => unreachable!(),
+        })
+    }
+}
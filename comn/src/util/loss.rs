@@ -1,43 +1,91 @@
 use std::collections::BTreeSet;
 
-const NUM_KEEP_DURATION: usize = 1000;
+const DEFAULT_WINDOW: usize = 1000;
+const DEFAULT_RECENT_WINDOW: usize = 100;
 
-#[derive(Default, Debug, Clone)]
+/// Margin of sequence numbers that we ignore at both ends of the window, since
+/// packets near the end may still be in transit.
+const EDGE_MARGIN: usize = 10;
+
+#[derive(Debug, Clone)]
 pub struct LossEstimation {
+    /// Number of sequence numbers that we keep track of.
+    window: usize,
+
+    /// Number of sequence numbers used for `recent_estimate`.
+    recent_window: usize,
+
     received: BTreeSet<usize>,
 }
 
+impl Default for LossEstimation {
+    fn default() -> Self {
+        Self::new(DEFAULT_WINDOW, DEFAULT_RECENT_WINDOW)
+    }
+}
+
 impl LossEstimation {
+    pub fn new(window: usize, recent_window: usize) -> Self {
+        assert!(recent_window <= window);
+
+        Self {
+            window,
+            recent_window,
+            received: BTreeSet::new(),
+        }
+    }
+
     pub fn record_received(&mut self, sequence_num: usize) {
+        // Forget about sequence numbers that have left the window.
         self.received = self
             .received
-            .clone()
-            .into_iter()
-            .filter(|other_num| other_num + NUM_KEEP_DURATION >= sequence_num)
-            .collect();
+            .split_off(&sequence_num.saturating_sub(self.window));
 
         self.received.insert(sequence_num);
     }
 
+    /// Estimated probability of loss over the whole window.
     pub fn estimate(&self) -> Option<f32> {
-        let first = self.received.iter().next();
-        let last = self.received.iter().next_back();
-
-        if let (Some(&first), Some(&last)) = (first, last) {
-            assert!(last >= first);
-            if last - first > 20 {
-                // If we include first/last in the range, we will underestimate loss.
-                let num_received = self
-                    .received
-                    .iter()
-                    .filter(|&&num| num >= first + 10 && num + 10 <= last)
-                    .count();
-                let duration = last - first - 19;
-
-                Some(1.0 - num_received as f32 / duration as f32)
-            } else {
-                None
-            }
+        self.received
+            .iter()
+            .next()
+            .and_then(|&first| self.estimate_since(first))
+    }
+
+    /// Estimated probability of loss over the most recent sequence numbers,
+    /// so that bursts of loss show up quickly and decay out again.
+    pub fn recent_estimate(&self) -> Option<f32> {
+        self.received.iter().next_back().and_then(|&last| {
+            let first = self
+                .received
+                .range(last.saturating_sub(self.recent_window)..)
+                .next()
+                .copied()?;
+
+            self.estimate_since(first)
+        })
+    }
+
+    /// Estimated number of lost packets per second, given the rate at which
+    /// sequence numbers are sent.
+    pub fn lost_per_second(&self, sequence_nums_per_second: f32) -> Option<f32> {
+        self.recent_estimate()
+            .map(|loss| loss * sequence_nums_per_second)
+    }
+
+    fn estimate_since(&self, first: usize) -> Option<f32> {
+        let last = *self.received.iter().next_back()?;
+        assert!(last >= first);
+
+        if last - first > 2 * EDGE_MARGIN {
+            // If we include first/last in the range, we will underestimate loss.
+            let num_received = self
+                .received
+                .range(first + EDGE_MARGIN..=last - EDGE_MARGIN)
+                .count();
+            let duration = last - first - (2 * EDGE_MARGIN - 1);
+
+            Some(1.0 - num_received as f32 / duration as f32)
         } else {
             None
         }
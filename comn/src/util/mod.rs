@@ -1,4 +1,5 @@
 pub mod game_time;
+pub mod hash;
 pub mod join;
 pub mod loss;
 pub mod ping;
@@ -9,5 +10,5 @@ pub mod diff;
 
 pub use game_time::GameTimeEstimation;
 pub use loss::LossEstimation;
-pub use ping::PingEstimation;
+pub use ping::{PingConfig, PingEstimation};
 pub use timer::Timer;
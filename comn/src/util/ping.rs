@@ -8,10 +8,32 @@ use serde::{Deserialize, Serialize};
 pub struct SequenceNum(pub usize);
 
 const INITIAL_ESTIMATE_MS: u64 = 100;
-const PING_PERIOD_MS: u64 = 500;
-const TIMEOUT_MS: u64 = 5_000;
 const NUM_KEEP_DURATIONS: usize = 100;
 
+#[derive(Debug, Clone)]
+pub struct PingConfig {
+    /// How often we send pings.
+    pub interval: Duration,
+
+    /// The connection is considered timed out if we do not receive any pong
+    /// for this long.
+    pub timeout: Duration,
+
+    /// Maximal number of pings that we wait for. If exceeded, we forget about
+    /// the oldest ones.
+    pub max_outstanding: usize,
+}
+
+impl Default for PingConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(500),
+            timeout: Duration::from_millis(5_000),
+            max_outstanding: 20,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum ReceivedPongError {
     InvalidSequenceNum,
@@ -19,40 +41,68 @@ pub enum ReceivedPongError {
 
 #[derive(Debug, Clone)]
 pub struct PingEstimation {
+    config: PingConfig,
     next_sequence_num: SequenceNum,
     waiting_pings: Vec<(SequenceNum, Instant)>,
     last_send_time: Option<Instant>,
     last_received_pong_time: Instant,
     last_rtts: VecDeque<Duration>,
     estimate: Duration,
+    jitter: Duration,
 }
 
 impl Default for PingEstimation {
     fn default() -> Self {
+        Self::new(PingConfig::default())
+    }
+}
+
+impl PingEstimation {
+    pub fn new(config: PingConfig) -> Self {
+        Self::new_at(config, Instant::now())
+    }
+
+    /// Creates a ping estimation whose timeout starts counting at the given
+    /// time.
+    pub fn new_at(config: PingConfig, now: Instant) -> Self {
         Self {
+            config,
             next_sequence_num: SequenceNum(0),
             waiting_pings: Vec::new(),
             last_send_time: None,
-            last_received_pong_time: Instant::now(),
+            last_received_pong_time: now,
             last_rtts: VecDeque::new(),
             estimate: Duration::from_millis(INITIAL_ESTIMATE_MS),
+            jitter: Duration::from_millis(0),
         }
     }
-}
 
-impl PingEstimation {
+    pub fn config(&self) -> &PingConfig {
+        &self.config
+    }
+
     pub fn estimate(&self) -> Duration {
         self.estimate
     }
 
+    /// Mean absolute deviation of the recent round-trip times.
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
     pub fn next_ping_sequence_num(&mut self, now: Instant) -> Option<SequenceNum> {
-        if self.last_send_time.map_or(true, |last_time| {
-            now - last_time > Duration::from_millis(PING_PERIOD_MS)
-        }) {
+        if self
+            .last_send_time
+            .map_or(true, |last_time| now - last_time > self.config.interval)
+        {
             let sequence_num = self.next_sequence_num;
             self.last_send_time = Some(now);
             self.waiting_pings.push((sequence_num, now));
 
+            if self.waiting_pings.len() > self.config.max_outstanding {
+                self.waiting_pings.remove(0);
+            }
+
             self.next_sequence_num = SequenceNum(sequence_num.0 + 1);
             Some(sequence_num)
         } else {
@@ -79,6 +129,7 @@ impl PingEstimation {
                 self.last_rtts.pop_front();
             }
             self.estimate = self.calculate_estimate();
+            self.jitter = self.calculate_jitter();
 
             // Due to the unreliable connection, it is possible that earlier
             // waiting pings have not been answered.
@@ -91,7 +142,7 @@ impl PingEstimation {
     }
 
     pub fn is_timeout(&self, now: Instant) -> bool {
-        now - self.last_received_pong_time >= Duration::from_millis(TIMEOUT_MS)
+        now - self.last_received_pong_time >= self.config.timeout
     }
 
     fn calculate_estimate(&self) -> Duration {
@@ -102,4 +153,18 @@ impl PingEstimation {
             Duration::from_secs_f32(sum / self.last_rtts.len() as f32)
         }
     }
+
+    fn calculate_jitter(&self) -> Duration {
+        if self.last_rtts.is_empty() {
+            Duration::from_millis(0)
+        } else {
+            let mean = self.estimate.as_secs_f32();
+            let sum: f32 = self
+                .last_rtts
+                .iter()
+                .map(|rtt| (rtt.as_secs_f32() - mean).abs())
+                .sum();
+            Duration::from_secs_f32(sum / self.last_rtts.len() as f32)
+        }
+    }
 }
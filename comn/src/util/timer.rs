@@ -17,18 +17,30 @@ impl Timer {
         Timer::with_duration(Duration::from_secs_f32(1.0 / times))
     }
 
+    pub fn time_per_second_at(times: f32, now: Instant) -> Timer {
+        Timer::with_duration_at(Duration::from_secs_f32(1.0 / times), now)
+    }
+
     pub fn with_duration(period: Duration) -> Timer {
-        Timer {
-            period,
-            init: Instant::now(),
-        }
+        Timer::with_duration_at(period, Instant::now())
+    }
+
+    /// Creates a timer that starts counting at the given time, for using the
+    /// timer with a time source other than `Instant::now`.
+    pub fn with_duration_at(period: Duration, now: Instant) -> Timer {
+        Timer { period, init: now }
     }
 
     /// Look if the time has elapsed and if so, starts the countdown for the next tick.
     ///
     /// You can use a while loop instead of an if to catch up in the event that you where late
     pub fn tick(&mut self) -> bool {
-        if self.init.elapsed() >= self.period {
+        self.tick_at(Instant::now())
+    }
+
+    /// Same as `Self::tick()`, but for the given current time.
+    pub fn tick_at(&mut self, now: Instant) -> bool {
+        if now.duration_since(self.init) >= self.period {
             self.init += self.period;
             true
         } else {
@@ -39,8 +51,13 @@ impl Timer {
     /// Similar to Self::tick() but tells you how many ticks have passed, rather than just if a tick has passed.
     /// This is usefull in situations where catching up isn't needed or possible
     pub fn exhaust(&mut self) -> Option<NonZeroUsize> {
+        self.exhaust_at(Instant::now())
+    }
+
+    /// Same as `Self::exhaust()`, but for the given current time.
+    pub fn exhaust_at(&mut self, now: Instant) -> Option<NonZeroUsize> {
         let mut count = 0;
-        while self.tick() {
+        while self.tick_at(now) {
             count += 1;
         }
         NonZeroUsize::new(count)
@@ -56,4 +73,43 @@ impl Timer {
     pub fn remaining(&self) -> Option<Duration> {
         self.init.elapsed().checked_sub(self.period)
     }
+
+    /// How far we have progressed towards the next tick, in the range `[0, 1]`.
+    /// This can be used for interpolating between ticks when rendering.
+    pub fn alpha(&self) -> f32 {
+        self.alpha_at(Instant::now())
+    }
+
+    /// Same as `Self::alpha()`, but for the given current time.
+    pub fn alpha_at(&self, now: Instant) -> f32 {
+        (now.duration_since(self.init).as_secs_f32() / self.period.as_secs_f32()).min(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alpha_progresses_towards_the_next_tick() {
+        let start = Instant::now();
+        let timer = Timer::with_duration_at(Duration::from_millis(100), start);
+
+        assert!(timer.alpha_at(start).abs() < 1e-6);
+        assert!((timer.alpha_at(start + Duration::from_millis(25)) - 0.25).abs() < 1e-3);
+        assert!((timer.alpha_at(start + Duration::from_millis(300)) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn alpha_resets_after_tick() {
+        let start = Instant::now();
+        let mut timer = Timer::with_duration_at(Duration::from_millis(100), start);
+
+        let now = start + Duration::from_millis(130);
+        assert!(timer.tick_at(now));
+
+        let alpha = timer.alpha_at(now);
+        assert!(alpha >= 0.0 && alpha <= 1.0);
+        assert!((alpha - 0.3).abs() < 1e-3);
+    }
 }
@@ -1,7 +1,81 @@
+use std::collections::VecDeque;
+
 use rand::Rng;
 
+use comn::{game::run, geom::Ray, Entity, Point, Vector};
+
+/// Distance to the catcher at which hunter bots start to flee.
+pub const HUNTER_FLEE_DISTANCE: f32 = 300.0;
+
+/// Distance that hunter bots look ahead for walls when steering.
+pub const HUNTER_LOOKAHEAD: f32 = 100.0;
+
+/// Minimal cosine of the angle between the bot's facing direction and its
+/// target for it to dash or shoot the hook.
+pub const HUNTER_FACING_COS: f32 = 0.9;
+
+#[derive(Debug, Clone)]
+pub struct BotConfig {
+    /// Number of ticks by which the bot delays its reaction to the game state.
+    pub reaction_ticks: usize,
+
+    /// Maximal random angle, in radians, that is added to the direction in
+    /// which the bot wants to move.
+    pub aim_jitter: f32,
+
+    /// Between 0 and 1. Aggressive bots keep going for food even if the
+    /// catcher is close.
+    pub aggression: f32,
+
+    /// If set, the bot dashes when it is the catcher and its target is at most
+    /// this far away.
+    pub dash_range: Option<f32>,
+
+    /// Whether the bot uses the hook to get closer to its target when it is
+    /// the catcher.
+    pub use_hook: bool,
+}
+
+impl Default for BotConfig {
+    fn default() -> Self {
+        Self::medium()
+    }
+}
+
+impl BotConfig {
+    pub fn easy() -> Self {
+        Self {
+            reaction_ticks: 10,
+            aim_jitter: 0.8,
+            aggression: 0.0,
+            dash_range: None,
+            use_hook: false,
+        }
+    }
+
+    pub fn medium() -> Self {
+        Self {
+            reaction_ticks: 5,
+            aim_jitter: 0.3,
+            aggression: 0.3,
+            dash_range: Some(150.0),
+            use_hook: false,
+        }
+    }
+
+    pub fn hard() -> Self {
+        Self {
+            reaction_ticks: 1,
+            aim_jitter: 0.05,
+            aggression: 0.6,
+            dash_range: Some(250.0),
+            use_hook: true,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
-pub enum Bot {
+pub enum Behavior {
     Random {
         last_input: comn::Input,
     },
@@ -10,30 +84,80 @@ pub enum Bot {
         time_left: comn::GameTime,
         right: bool,
     },
+    Hunter,
 }
 
-impl Bot {
+impl Behavior {
     pub fn random() -> Self {
-        Bot::Random {
+        Behavior::Random {
             last_input: comn::Input::default(),
         }
     }
 
     pub fn left_right(duration: comn::GameTime) -> Self {
-        Bot::LeftRight {
+        Behavior::LeftRight {
             duration,
             time_left: duration,
             right: true,
         }
     }
 
-    pub fn get_next_input(&mut self, state: &comn::Game) -> comn::Input {
-        use Bot::*;
-
+    /// Short name of the behavior, used for naming bots.
+    pub fn name(&self) -> &'static str {
         match self {
-            Random { last_input } => {
-                let mut rng = rand::thread_rng();
+            Behavior::Random { .. } => "random",
+            Behavior::LeftRight { .. } => "left_right",
+            Behavior::Hunter => "hunter",
+        }
+    }
+}
 
+#[derive(Debug, Clone)]
+pub struct Bot {
+    config: BotConfig,
+    behavior: Behavior,
+
+    /// Inputs that the bot has decided on, but not yet executed, due to its
+    /// reaction delay. Sorted by time ascending.
+    pending_inputs: VecDeque<comn::Input>,
+}
+
+impl Bot {
+    pub fn new(behavior: Behavior, config: BotConfig) -> Self {
+        Self {
+            config,
+            behavior,
+            pending_inputs: VecDeque::new(),
+        }
+    }
+
+    pub fn get_next_input(
+        &mut self,
+        player_id: comn::PlayerId,
+        state: &comn::Game,
+        rng: &mut impl Rng,
+    ) -> comn::Input {
+        let input = self.decide_input(player_id, state, rng);
+        self.pending_inputs.push_back(input);
+
+        if self.pending_inputs.len() > self.config.reaction_ticks {
+            self.pending_inputs.pop_front().unwrap()
+        } else {
+            // We have not reacted to anything yet.
+            comn::Input::default()
+        }
+    }
+
+    fn decide_input(
+        &mut self,
+        player_id: comn::PlayerId,
+        state: &comn::Game,
+        rng: &mut impl Rng,
+    ) -> comn::Input {
+        use Behavior::*;
+
+        match &mut self.behavior {
+            Random { last_input } => {
                 for (p, b) in &mut [
                     (0.02, &mut last_input.move_left),
                     (0.02, &mut last_input.move_right),
@@ -74,6 +198,330 @@ impl Bot {
 
                 result
             }
+            Hunter => {
+                let mut input = dir_to_input(hunter_dir(player_id, state, &self.config, rng));
+
+                if state.catcher == Some(player_id) {
+                    add_catcher_actions(player_id, state, &self.config, &mut input);
+                }
+
+                input
+            }
+        }
+    }
+}
+
+fn hunter_dir(
+    player_id: comn::PlayerId,
+    state: &comn::Game,
+    config: &BotConfig,
+    rng: &mut impl Rng,
+) -> Vector {
+    let time = state.game_time();
+    let pos = if let Some((_, entity)) = state.get_player_entity(player_id) {
+        entity.pos
+    } else {
+        return Vector::zeros();
+    };
+
+    let player_positions = state.entities.values().filter_map(|entity| match entity {
+        Entity::Player(player) if player.owner != player_id => Some((player.owner, player.pos)),
+        _ => None,
+    });
+
+    let target_dir = if state.catcher == Some(player_id) {
+        // We are the catcher, so hunt the closest player.
+        closest(pos, player_positions.map(|(_, other_pos)| other_pos)).map(|target| target - pos)
+    } else {
+        let catcher_pos = player_positions
+            .filter(|(owner, _)| state.catcher == Some(*owner))
+            .map(|(_, other_pos)| other_pos)
+            .next()
+            .filter(|catcher_pos| {
+                (*catcher_pos - pos).norm() < HUNTER_FLEE_DISTANCE * (1.0 - config.aggression)
+            });
+
+        if let Some(catcher_pos) = catcher_pos {
+            Some(pos - catcher_pos)
+        } else {
+            let food_positions = state.entities.values().filter_map(|entity| match entity {
+                Entity::Food(food) => Some(food.pos(time)),
+                Entity::FoodSpawn(spawn) if spawn.has_food => Some(spawn.pos),
+                _ => None,
+            });
+
+            closest(pos, food_positions).map(|target| target - pos)
+        }
+    };
+
+    let target_dir = match target_dir.filter(|dir| dir.norm() > 0.1) {
+        Some(dir) => dir.normalize(),
+        None => return Vector::zeros(),
+    };
+
+    // Inaccurate bots randomly miss the right direction a bit.
+    let noise = if config.aim_jitter > 0.0 {
+        rng.gen_range(-config.aim_jitter, config.aim_jitter)
+    } else {
+        0.0
+    };
+    let target_dir = rotate(target_dir, noise);
+
+    // Simple obstacle avoidance: try turning away until we find a direction
+    // that is not blocked by a wall.
+    [0.0f32, 45.0, -45.0, 90.0, -90.0]
+        .iter()
+        .map(|angle| rotate(target_dir, angle.to_radians()))
+        .find(|dir| !is_blocked_by_wall(state, pos, *dir))
+        .unwrap_or(target_dir)
+}
+
+fn add_catcher_actions(
+    player_id: comn::PlayerId,
+    state: &comn::Game,
+    config: &BotConfig,
+    input: &mut comn::Input,
+) {
+    let entity = if let Some((_, entity)) = state.get_player_entity(player_id) {
+        entity
+    } else {
+        return;
+    };
+
+    let target_pos = closest(
+        entity.pos,
+        state.entities.values().filter_map(|other| match other {
+            Entity::Player(other) if other.owner != player_id => Some(other.pos),
+            _ => None,
+        }),
+    );
+    let target_pos = if let Some(target_pos) = target_pos {
+        target_pos
+    } else {
+        return;
+    };
+
+    let delta = target_pos - entity.pos;
+    let distance = delta.norm();
+    let is_facing = distance > 0.0
+        && Vector::new(entity.angle.cos(), entity.angle.sin()).dot(&(delta / distance))
+            >= HUNTER_FACING_COS;
+
+    if let Some(dash_range) = config.dash_range {
+        input.dash = is_facing
+            && distance <= dash_range
+            && entity.dash.is_none()
+            && entity.dash_cooldown == 0.0;
+    }
+
+    if config.use_hook {
+        // Keep holding the hook once it is out, so that it pulls us in.
+        input.use_action = if entity.hook.is_some() {
+            distance > run::HOOK_MIN_DISTANCE
+        } else {
+            is_facing
+                && distance > 2.0 * run::HOOK_MIN_DISTANCE
+                && distance <= run::HOOK_MAX_DISTANCE
+                && entity.hook_cooldown == 0.0
+        };
+    }
+}
+
+fn closest(pos: Point, targets: impl Iterator<Item = Point>) -> Option<Point> {
+    targets.min_by(|a, b| {
+        (*a - pos)
+            .norm_squared()
+            .partial_cmp(&(*b - pos).norm_squared())
+            .unwrap_or(std::cmp::Ordering::Equal)
+    })
+}
+
+fn is_blocked_by_wall(state: &comn::Game, pos: Point, dir: Vector) -> bool {
+    let ray = Ray {
+        origin: pos,
+        dir: dir * HUNTER_LOOKAHEAD,
+    };
+
+    let walls = state
+        .entities
+        .iter()
+        .filter(|(_, entity)| matches!(entity, Entity::Wall(_)));
+
+    comn::Game::trace_ray(&ray, state.game_time(), walls).map_or(false, |hit| hit.t <= 1.0)
+}
+
+fn rotate(dir: Vector, angle: f32) -> Vector {
+    Vector::new(
+        angle.cos() * dir.x - angle.sin() * dir.y,
+        angle.sin() * dir.x + angle.cos() * dir.y,
+    )
+}
+
+fn dir_to_input(dir: Vector) -> comn::Input {
+    // Only press keys for significant direction components, so that the bot
+    // can move diagonally as well as straight.
+    let threshold = 0.38;
+
+    comn::Input {
+        move_left: dir.x < -threshold,
+        move_right: dir.x > threshold,
+        move_up: dir.y < -threshold,
+        move_down: dir.y > threshold,
+        ..comn::Input::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use comn::{
+        game::{sim::Simulation, MapBuilder, SettingsBuilder},
+        PlayerEntity,
+    };
+
+    use super::*;
+
+    fn test_sim(map: MapBuilder) -> Simulation {
+        Simulation::new(Arc::new(SettingsBuilder::new(map.build()).build()))
+    }
+
+    fn test_map() -> MapBuilder {
+        MapBuilder::new(Vector::new(1000.0, 1000.0))
+    }
+
+    /// A bot that reacts immediately and always moves exactly where it wants.
+    fn precise_config() -> BotConfig {
+        BotConfig {
+            reaction_ticks: 0,
+            aim_jitter: 0.0,
+            aggression: 0.0,
+            dash_range: None,
+            use_hook: false,
+        }
+    }
+
+    fn keys(input: &comn::Input) -> [bool; 7] {
+        [
+            input.move_left,
+            input.move_right,
+            input.move_up,
+            input.move_down,
+            input.dash,
+            input.use_action,
+            input.shoot,
+        ]
+    }
+
+    fn is_moving_left(input: &comn::Input) -> bool {
+        keys(input) == [true, false, false, false, false, false, false]
+    }
+
+    fn player_entity_mut(sim: &mut Simulation, player_id: comn::PlayerId) -> &mut PlayerEntity {
+        let (entity_id, _) = sim.state.get_player_entity(player_id).unwrap();
+
+        match sim.state.entities.get_mut(&entity_id) {
+            Some(Entity::Player(entity)) => entity,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn hunter_seeks_food() {
+        let mut sim = test_sim(test_map().food_spawn(Point::new(200.0, 500.0)));
+        let bot_id = sim.add_player("bot", Point::new(500.0, 500.0));
+
+        let mut bot = Bot::new(Behavior::Hunter, precise_config());
+        let mut rng = StdRng::seed_from_u64(0);
+        let input = bot.get_next_input(bot_id, &sim.state, &mut rng);
+
+        assert!(is_moving_left(&input), "{:?}", input);
+    }
+
+    #[test]
+    fn hunter_flees_from_catcher() {
+        let mut sim = test_sim(test_map().food_spawn(Point::new(800.0, 500.0)));
+        let bot_id = sim.add_player("bot", Point::new(500.0, 500.0));
+        let catcher_id = sim.add_player("catcher", Point::new(600.0, 500.0));
+        sim.state.catcher = Some(catcher_id);
+
+        let mut bot = Bot::new(Behavior::Hunter, precise_config());
+        let mut rng = StdRng::seed_from_u64(0);
+        let input = bot.get_next_input(bot_id, &sim.state, &mut rng);
+
+        assert!(is_moving_left(&input), "{:?}", input);
+    }
+
+    #[test]
+    fn reaction_is_delayed() {
+        let mut sim = test_sim(test_map().food_spawn(Point::new(200.0, 500.0)));
+        let bot_id = sim.add_player("bot", Point::new(500.0, 500.0));
+
+        let config = BotConfig {
+            reaction_ticks: 3,
+            ..precise_config()
+        };
+        let mut bot = Bot::new(Behavior::Hunter, config);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        for _ in 0..3 {
+            let input = bot.get_next_input(bot_id, &sim.state, &mut rng);
+            assert_eq!(keys(&input), [false; 7]);
+        }
+
+        let input = bot.get_next_input(bot_id, &sim.state, &mut rng);
+        assert!(is_moving_left(&input), "{:?}", input);
+    }
+
+    #[test]
+    fn seeded_bots_are_deterministic() {
+        let mut sim = test_sim(test_map().food_spawn(Point::new(200.0, 500.0)));
+        let bot_id = sim.add_player("bot", Point::new(500.0, 500.0));
+
+        for behavior in &[Behavior::Hunter, Behavior::random()] {
+            let mut bot_a = Bot::new(behavior.clone(), BotConfig::easy());
+            let mut bot_b = Bot::new(behavior.clone(), BotConfig::easy());
+            let mut rng_a = StdRng::seed_from_u64(42);
+            let mut rng_b = StdRng::seed_from_u64(42);
+
+            for _ in 0..500 {
+                let input_a = bot_a.get_next_input(bot_id, &sim.state, &mut rng_a);
+                let input_b = bot_b.get_next_input(bot_id, &sim.state, &mut rng_b);
+
+                assert_eq!(keys(&input_a), keys(&input_b), "{}", behavior.name());
+            }
         }
     }
+
+    #[test]
+    fn catcher_dashes_when_off_cooldown() {
+        let mut sim = test_sim(test_map());
+        let bot_id = sim.add_player("bot", Point::new(500.0, 500.0));
+        let target_id = sim.add_player("target", Point::new(600.0, 500.0));
+        sim.state.catcher = Some(bot_id);
+
+        let config = BotConfig {
+            dash_range: Some(150.0),
+            ..precise_config()
+        };
+        let mut bot = Bot::new(Behavior::Hunter, config);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        // We are facing the target, which is in range.
+        player_entity_mut(&mut sim, bot_id).angle = 0.0;
+        let input = bot.get_next_input(bot_id, &sim.state, &mut rng);
+        assert!(input.dash);
+        assert!(input.move_right);
+
+        player_entity_mut(&mut sim, bot_id).dash_cooldown = 0.5;
+        let input = bot.get_next_input(bot_id, &sim.state, &mut rng);
+        assert!(!input.dash);
+
+        player_entity_mut(&mut sim, bot_id).dash_cooldown = 0.0;
+        player_entity_mut(&mut sim, target_id).pos = Point::new(700.0, 500.0);
+        let input = bot.get_next_input(bot_id, &sim.state, &mut rng);
+        assert!(!input.dash);
+    }
 }
@@ -0,0 +1,61 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Source of time for the runner, so that we can run games faster than real
+/// time, e.g. for testing bots.
+///
+/// The clock is shared with everything that timestamps messages for the
+/// runner, so that the runner never compares times from different clocks.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Waits until the given duration has passed.
+    fn sleep(&self, duration: Duration);
+}
+
+/// The actual time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// A clock that only moves forward when told to. Sleeping advances the clock
+/// immediately, so that whoever sleeps runs as fast as possible.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+}
+
+impl ManualClock {
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
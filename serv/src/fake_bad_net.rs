@@ -28,6 +28,14 @@ pub struct Config {
     pub lag_mean: Duration,
     pub lag_std_dev: f32,
     pub loss: f32,
+
+    /// Probability that a message is held back for an additional random delay
+    /// of up to `max_reorder_delay`, so that later messages overtake it.
+    pub reorder_prob: f32,
+    pub max_reorder_delay: Duration,
+
+    /// Probability that a message is delivered twice.
+    pub duplicate_prob: f32,
 }
 
 pub struct FakeBadNet<S: Stream> {
@@ -40,7 +48,7 @@ pub struct FakeBadNet<S: Stream> {
 
 impl<S: Stream> FakeBadNet<S>
 where
-    S::Item: AddFakeLag,
+    S::Item: AddFakeLag + Clone,
 {
     pub fn new(config: Config, orig_rx: S, new_tx: mpsc::UnboundedSender<S::Item>) -> Self {
         let lag_distribution =
@@ -66,18 +74,30 @@ where
                     let mut rng = rand::thread_rng();
 
                     match message {
-                        Some(mut message) => {
+                        Some(message) => {
                             if rng.gen::<f32>() > self.config.loss {
-                                let lag = Duration::from_secs_f32(
-                                    self.lag_distribution.sample(&mut rng).max(0.0) / 1000.0,
-                                );
-                                message.add_fake_lag(lag);
-                                self.delay_queue.insert(message, lag);
+                                let num_copies =
+                                    if rng.gen::<f32>() < self.config.duplicate_prob { 2 } else { 1 };
+
+                                for _ in 0..num_copies {
+                                    let mut lag = Duration::from_secs_f32(
+                                        self.lag_distribution.sample(&mut rng).max(0.0) / 1000.0,
+                                    );
+                                    if rng.gen::<f32>() < self.config.reorder_prob {
+                                        lag += self.config.max_reorder_delay.mul_f32(rng.gen());
+                                    }
+
+                                    let mut message = message.clone();
+                                    message.add_fake_lag(lag);
+                                    self.delay_queue.insert(message, lag);
+                                }
                             }
                         }
                         None => {
-                            // TODO: Can this happen in a case other than
-                            // closed channel?
+                            // The sender has been dropped. Terminate, so that
+                            // `new_tx` is dropped as well and the receiving
+                            // end can shut down.
+                            return;
                         }
                     }
                 }
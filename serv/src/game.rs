@@ -1,14 +1,15 @@
 use std::{
     collections::{BTreeMap, VecDeque},
     sync::Arc,
+    time::Instant,
 };
 
-use log::{debug, info};
-use rand::seq::SliceRandom;
+use log::{debug, info, warn};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
 
-use comn::{game::RunContext, Entity, PlayerState};
+use comn::{game::RunContext, util::Timer, Entity, PlayerState};
 
-use crate::{bot::Bot, run};
+use crate::bot::Bot;
 
 pub const FIRST_SPAWN_DURATION: comn::GameTime = 0.5;
 pub const RESPAWN_DURATION: comn::GameTime = 2.0;
@@ -33,10 +34,24 @@ pub struct Game {
 
     /// Previous states, used for reconciliation. Sorted by tick number.
     prev_states: VecDeque<comn::Game>,
+
+    /// Source of all randomness in the game, seeded from the settings.
+    rng: StdRng,
+
+    /// Games may have different tick rates, so each game has its own timer.
+    pub tick_timer: Timer,
 }
 
 impl Game {
-    pub fn new(settings: Arc<comn::Settings>) -> Self {
+    pub fn new(settings: Arc<comn::Settings>, now: Instant) -> Self {
+        // Maps are validated when loading, but settings may come from
+        // elsewhere. We can still run the game, just not as intended.
+        if let Err(err) = settings.map.validate() {
+            warn!("Creating game with invalid map: {:?}", err);
+        }
+
+        let rng = StdRng::seed_from_u64(settings.seed);
+        let tick_timer = Timer::time_per_second_at(settings.ticks_per_second as f32, now);
         let state = comn::Game::new(settings);
         let next_entity_id = state
             .entities
@@ -52,6 +67,8 @@ impl Game {
             players_meta: BTreeMap::new(),
             prev_states: VecDeque::new(),
             last_events: Vec::new(),
+            rng,
+            tick_timer,
         }
     }
 
@@ -79,11 +96,14 @@ impl Game {
 
         let spawn_time = self.state.game_time() + FIRST_SPAWN_DURATION;
         let player = comn::Player {
-            name: player_name,
+            name: self.unique_player_name(player_name),
             state: PlayerState::Respawning {
                 respawn_time: spawn_time,
             },
             food: 0,
+            team: self.smallest_team(),
+            score: 0,
+            last_catcher_tick: None,
         };
         let player_meta = PlayerMeta {
             last_input_num: None,
@@ -100,12 +120,54 @@ impl Game {
         player_id
     }
 
+    /// Appends a number to the name if another player already uses it. The
+    /// name is shortened if necessary, so that it stays within
+    /// `comn::MAX_PLAYER_NAME_LEN`.
+    fn unique_player_name(&self, player_name: String) -> String {
+        let is_taken = |name: &str| {
+            self.state
+                .players
+                .values()
+                .any(|player| player.name == name)
+        };
+
+        if !is_taken(&player_name) {
+            return player_name;
+        }
+
+        (2..)
+            .map(|n| {
+                let suffix = format!(" ({})", n);
+                let base: String = player_name
+                    .chars()
+                    .take(comn::MAX_PLAYER_NAME_LEN.saturating_sub(suffix.len()))
+                    .collect();
+
+                format!("{}{}", base.trim_end(), suffix)
+            })
+            .find(|name| !is_taken(name))
+            .unwrap()
+    }
+
+    /// Returns the team with the least players, so that teams stay balanced.
+    fn smallest_team(&self) -> Option<comn::TeamId> {
+        let num_teams = self.settings().num_teams?;
+
+        (0..num_teams).map(comn::TeamId).min_by_key(|team| {
+            self.state
+                .players
+                .values()
+                .filter(|player| player.team == Some(*team))
+                .count()
+        })
+    }
+
     pub fn run_tick(&mut self, inputs: &[(comn::PlayerId, comn::TickNum, comn::Input)]) {
         //debug!("tick with {} inputs", inputs.len());
         let current_time = self.state.game_time();
         let mut context = RunContext::default();
 
-        run::run_tick(&mut self.state, &mut context).unwrap();
+        self.state.run_tick(&mut context, &mut self.rng).unwrap();
 
         // TODO: Sort player input by tick num
         for (player_id, input_tick_num, input) in inputs {
@@ -118,9 +180,7 @@ impl Game {
                 .filter(|prev_state| {
                     self.state.game_time() - prev_state.game_time() <= MAX_RECONCILIATION_DURATION
                 })
-                .min_by_key(|prev_state| {
-                    (prev_state.tick_num.0 as isize - input_tick_num.0 as isize).abs()
-                });
+                .min_by_key(|prev_state| prev_state.tick_num.distance(*input_tick_num).abs());
 
             // Debugging
             if let Some(input_state) = input_state.as_ref() {
@@ -149,7 +209,7 @@ impl Game {
 
         for (player_id, player_meta) in self.players_meta.iter_mut() {
             if let Some(bot) = player_meta.bot.as_mut() {
-                let input = bot.get_next_input(&self.state);
+                let input = bot.get_next_input(*player_id, &self.state, &mut self.rng);
 
                 self.state
                     .run_player_input(*player_id, &input, None, &mut context)
@@ -168,20 +228,23 @@ impl Game {
                 PlayerState::Respawning { respawn_time } if current_time >= respawn_time => {
                     debug!("Respawning player {:?}", player_id);
 
-                    // TODO: Random
-                    let spawn_pos = self
-                        .state
-                        .settings
-                        .map
-                        .spawn_points
-                        .choose(&mut rand::thread_rng())
-                        .unwrap();
-
-                    context
-                        .new_entities
-                        .push(Entity::Player(comn::PlayerEntity::new(
-                            *player_id, *spawn_pos,
-                        )));
+                    let spawn_pos = match self.state.settings.map.spawn_points.choose(&mut self.rng)
+                    {
+                        Some(spawn_pos) => *spawn_pos,
+                        None => {
+                            warn!(
+                                "Map has no spawn points, spawning {:?} at the center",
+                                player_id,
+                            );
+                            comn::Point::from(self.state.settings.map.size / 2.0)
+                        }
+                    };
+
+                    let mut ent = comn::PlayerEntity::new(*player_id, spawn_pos);
+                    ent.protected_until =
+                        current_time + self.state.settings.spawn_protection_duration;
+
+                    context.new_entities.push(Entity::Player(ent));
 
                     player.state = PlayerState::Alive;
                 }
@@ -258,6 +321,7 @@ impl Game {
 
         // Sanity checks
         assert!(!self.state.entities.contains_key(&entity_id));
+        assert!(!entity_id.is_predicted());
         if let comn::Entity::Player(entity) = &entity {
             assert!(self.state.get_player_entity(entity.owner).is_none());
         }
@@ -286,8 +350,144 @@ impl Game {
 
         if let Some((player_entity_id, player_entity)) = self.state.get_player_entity(player_id) {
             let player_entity = player_entity.clone();
-            run::on_kill_player(&mut self.state, &player_entity, reason, context).unwrap();
+            self.state
+                .on_kill_player(&player_entity, reason, context, &mut self.rng)
+                .unwrap();
             self.remove_entity(player_entity_id);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bot::{Behavior, BotConfig};
+
+    fn test_game(settings: comn::game::SettingsBuilder) -> Game {
+        Game::new(Arc::new(settings.build()), Instant::now())
+    }
+
+    fn test_settings() -> comn::game::SettingsBuilder {
+        let map = comn::game::MapBuilder::new(comn::Vector::new(1000.0, 1000.0))
+            .spawn_point(comn::Point::new(500.0, 500.0))
+            .build();
+
+        comn::game::SettingsBuilder::new(map)
+    }
+
+    fn player_name(game: &Game, player_id: comn::PlayerId) -> &str {
+        &game.state.players[&player_id].name
+    }
+
+    #[test]
+    fn duplicate_names_are_numbered() {
+        let mut game = test_game(test_settings());
+
+        let first = game.join("leo".to_string(), None);
+        let second = game.join("leo".to_string(), None);
+        let third = game.join("leo".to_string(), None);
+
+        assert_eq!(player_name(&game, first), "leo");
+        assert_eq!(player_name(&game, second), "leo (2)");
+        assert_eq!(player_name(&game, third), "leo (3)");
+    }
+
+    #[test]
+    fn numbered_names_fit_the_length_limit() {
+        let mut game = test_game(test_settings());
+        let long_name = "x".repeat(comn::MAX_PLAYER_NAME_LEN);
+
+        let player_ids: Vec<_> = (0..12)
+            .map(|_| game.join(long_name.clone(), None))
+            .collect();
+
+        let names: Vec<&str> = player_ids
+            .iter()
+            .map(|player_id| player_name(&game, *player_id))
+            .collect();
+
+        assert_eq!(names[1], "xxxxxxxxxxxx (2)");
+        assert_eq!(names[11], "xxxxxxxxxxx (12)");
+
+        for name in &names {
+            assert!(comn::validate_player_name(name).is_ok());
+        }
+        for (i, name) in names.iter().enumerate() {
+            assert!(!names[..i].contains(name));
+        }
+    }
+
+    fn player_team(game: &Game, player_id: comn::PlayerId) -> Option<comn::TeamId> {
+        game.state.players[&player_id].team
+    }
+
+    #[test]
+    fn players_join_the_smallest_team() {
+        let mut game = test_game(test_settings().num_teams(Some(2)));
+
+        let player_ids: Vec<_> = (0..4).map(|i| game.join(format!("p{}", i), None)).collect();
+        let teams: Vec<_> = player_ids
+            .iter()
+            .map(|player_id| player_team(&game, *player_id))
+            .collect();
+
+        assert_eq!(
+            teams,
+            vec![
+                Some(comn::TeamId(0)),
+                Some(comn::TeamId(1)),
+                Some(comn::TeamId(0)),
+                Some(comn::TeamId(1)),
+            ]
+        );
+
+        // Refill the team that a player has left.
+        game.remove_player(player_ids[3]);
+        let new_player_id = game.join("new".to_string(), None);
+        assert_eq!(player_team(&game, new_player_id), Some(comn::TeamId(1)));
+    }
+
+    #[test]
+    fn players_have_no_team_without_team_mode() {
+        let mut game = test_game(test_settings());
+        let player_id = game.join("leo".to_string(), None);
+
+        assert_eq!(player_team(&game, player_id), None);
+    }
+
+    fn run_seeded_game(seed: u64, num_ticks: usize) -> Vec<u64> {
+        let map = comn::game::MapBuilder::new(comn::Vector::new(1000.0, 1000.0))
+            .spawn_point(comn::Point::new(200.0, 200.0))
+            .spawn_point(comn::Point::new(800.0, 200.0))
+            .spawn_point(comn::Point::new(500.0, 800.0))
+            .food_spawn(comn::Point::new(500.0, 500.0))
+            .build();
+        let mut game = test_game(comn::game::SettingsBuilder::new(map).seed(seed));
+
+        let player_id = game.join("leo".to_string(), None);
+        for behavior in &[Behavior::Hunter, Behavior::random()] {
+            let bot = Bot::new(behavior.clone(), BotConfig::easy());
+            game.join(behavior.name().to_string(), Some(bot));
+        }
+
+        (0..num_ticks)
+            .map(|i| {
+                let input = comn::Input {
+                    move_left: i % 40 < 20,
+                    move_right: i % 40 >= 20,
+                    dash: i % 50 == 0,
+                    ..comn::Input::default()
+                };
+                game.run_tick(&[(player_id, game.state.tick_num, input)]);
+
+                game.state.state_hash()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_and_inputs_give_same_states() {
+        assert_eq!(run_seeded_game(7, 300), run_seeded_game(7, 300));
+    }
+}
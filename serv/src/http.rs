@@ -1,25 +1,49 @@
-use std::{future::Future, net::SocketAddr, path::PathBuf, sync::Arc};
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
 use log::{debug, info, warn};
 
-use futures::TryStreamExt;
 use tokio::{fs::File, io::AsyncReadExt, stream::StreamExt, sync::oneshot};
 
 use hyper::{
-    header::HeaderValue, server::conn::AddrStream, Body, Method, Request, Response, StatusCode,
+    body::HttpBody, header::HeaderValue, server::conn::AddrStream, Body, Method, Request, Response,
+    StatusCode,
 };
 use webrtc_unreliable::SessionEndpoint;
 
-use crate::runner::{JoinMessage, JoinTx};
+use crate::{
+    metrics,
+    rate_limit::RateLimiter,
+    runner::{JoinMessage, JoinTx, SharedStats},
+};
 
 static INTERNAL_SERVER_ERROR: &[u8] = b"Internal Server Error";
 static NOT_FOUND: &[u8] = b"Not Found";
 static BAD_REQUEST: &[u8] = b"Bad Request";
+static TOO_MANY_REQUESTS: &[u8] = b"Too Many Requests";
+static PAYLOAD_TOO_LARGE: &[u8] = b"Payload Too Large";
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Config {
     pub listen_addr: SocketAddr,
     pub clnt_dir: PathBuf,
+
+    /// Number of join and WebRTC connection requests per second that we
+    /// allow from a single IP address.
+    pub join_rate: f32,
+
+    /// Number of join and WebRTC connection requests that a single IP address
+    /// may send in quick succession.
+    pub join_burst: f32,
+
+    /// Maximal size of the request body for joining and for establishing
+    /// WebRTC connections. Larger requests are rejected.
+    pub max_body_bytes: usize,
 }
 
 #[derive(Clone)]
@@ -27,6 +51,8 @@ pub struct Server {
     config: Arc<Config>,
     join_tx: JoinTx,
     session_endpoint: SessionEndpoint,
+    shared_stats: SharedStats,
+    join_limiter: Arc<Mutex<RateLimiter>>,
 }
 
 pub const STATIC_FILES: &[(&str, &str, &str)] = &[
@@ -45,11 +71,23 @@ pub const STATIC_FILES: &[(&str, &str, &str)] = &[
 ];
 
 impl Server {
-    pub fn new(config: Config, join_tx: JoinTx, session_endpoint: SessionEndpoint) -> Self {
+    pub fn new(
+        config: Config,
+        join_tx: JoinTx,
+        session_endpoint: SessionEndpoint,
+        shared_stats: SharedStats,
+    ) -> Self {
+        let join_limiter = Arc::new(Mutex::new(RateLimiter::new(
+            config.join_rate,
+            config.join_burst,
+        )));
+
         Self {
             config: Arc::new(config),
             join_tx,
             session_endpoint,
+            shared_stats,
+            join_limiter,
         }
     }
 
@@ -64,6 +102,8 @@ impl Server {
             let config = self.config.clone();
             let join_tx = self.join_tx.clone();
             let session_endpoint = self.session_endpoint.clone();
+            let shared_stats = self.shared_stats.clone();
+            let join_limiter = self.join_limiter.clone();
             let remote_addr = addr_stream.remote_addr();
 
             async move {
@@ -72,6 +112,8 @@ impl Server {
                         config.clone(),
                         join_tx.clone(),
                         session_endpoint.clone(),
+                        shared_stats.clone(),
+                        join_limiter.clone(),
                         remote_addr,
                         req,
                     )
@@ -91,12 +133,43 @@ async fn service(
     config: Arc<Config>,
     join_tx: JoinTx,
     mut session_endpoint: SessionEndpoint,
+    shared_stats: SharedStats,
+    join_limiter: Arc<Mutex<RateLimiter>>,
     remote_addr: SocketAddr,
     req: Request<Body>,
 ) -> Result<Response<Body>, hyper::Error> {
     debug!("{}: {} {}", remote_addr, req.method(), req.uri().path());
 
+    if is_rate_limited(&join_limiter, &req, remote_addr.ip(), Instant::now()) {
+        warn!("Rate limiting {} for {}", req.uri().path(), remote_addr);
+        return Ok(too_many_requests());
+    }
+
     match (req.method(), req.uri().path()) {
+        // Export statistics for monitoring
+        (&Method::GET, "/metrics") => {
+            let body = metrics::render(&shared_stats.lock().unwrap());
+
+            Ok(Response::builder()
+                .header("Content-Type", metrics::CONTENT_TYPE)
+                .body(body.into())
+                .unwrap())
+        }
+
+        // Export the runner's profiling report, starting from scratch afterwards
+        (&Method::GET, "/profile") => {
+            let body = {
+                let mut snapshot = shared_stats.lock().unwrap();
+                snapshot.reset_profile = true;
+                snapshot.profile.clone()
+            };
+
+            Ok(Response::builder()
+                .header("Content-Type", "text/plain")
+                .body(body.into())
+                .unwrap())
+        }
+
         // Serve static files
         (&Method::GET, file) => {
             let item = STATIC_FILES.iter().find(|(key, _, _)| *key == file);
@@ -112,7 +185,13 @@ async fn service(
         (&Method::POST, "/connect_webrtc") => {
             debug!("WebRTC session request from {}", remote_addr);
 
-            match session_endpoint.http_session_request(req.into_body()).await {
+            let body = match read_body(req.into_body(), config.max_body_bytes).await? {
+                Some(body) => body,
+                None => return Ok(payload_too_large()),
+            };
+            let body_stream = futures::stream::iter(vec![Ok::<_, hyper::Error>(body)]);
+
+            match session_endpoint.http_session_request(body_stream).await {
                 Ok(mut resp) => {
                     resp.headers_mut().insert(
                         hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN,
@@ -125,44 +204,88 @@ async fn service(
         }
 
         // Join a game
-        (&Method::POST, "/join") => {
-            // FIXME: Does this allow attackers to OOM the server by sending an infinite request?
-            let body = req
-                .into_body()
-                .map(|chunk| chunk.map(|chunk| chunk.as_ref().to_vec()))
-                .try_concat()
-                .await?;
-
-            let join_request = match serde_json::from_slice(body.as_slice()) {
-                Ok(x) => x,
-                Err(_) => return Ok(bad_request()),
-            };
+        (&Method::POST, "/join") => join(join_tx, req.into_body(), config.max_body_bytes).await,
 
-            let (reply_tx, reply_rx) = oneshot::channel();
-            let join_message = JoinMessage {
-                request: join_request,
-                reply_tx,
-            };
+        // Return 404 Not Found for other routes
+        _ => Ok(not_found()),
+    }
+}
 
-            if join_tx.send(join_message).is_err() {
-                warn!("join_tx closed, ignoring join request");
-                return Ok(internal_server_error());
-            }
+/// Returns true if the request may create games or WebRTC sessions, and its
+/// address has exceeded the allowed rate of such requests.
+fn is_rate_limited(
+    join_limiter: &Mutex<RateLimiter>,
+    req: &Request<Body>,
+    addr: IpAddr,
+    now: Instant,
+) -> bool {
+    req.method() == Method::POST
+        && (req.uri().path() == "/join" || req.uri().path() == "/connect_webrtc")
+        && !join_limiter.lock().unwrap().check(addr, now)
+}
 
-            if let Ok(join_reply) = reply_rx.await {
-                Ok(Response::builder()
-                    .header("Content-Type", "application/json")
-                    .body(serde_json::to_string(&join_reply).unwrap().into())
-                    .unwrap())
-            } else {
-                warn!("reply_rx closed, ignoring join request");
-                Ok(internal_server_error())
-            }
+/// Pass a join request on to the runner and wait for its reply.
+async fn join(
+    join_tx: JoinTx,
+    body: Body,
+    max_body_bytes: usize,
+) -> Result<Response<Body>, hyper::Error> {
+    let body = match read_body(body, max_body_bytes).await? {
+        Some(body) => body,
+        None => return Ok(payload_too_large()),
+    };
+
+    let join_request = match serde_json::from_slice(body.as_slice()) {
+        Ok(x) => x,
+        Err(_) => return Ok(bad_request()),
+    };
+
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let join_message = JoinMessage {
+        request: join_request,
+        reply_tx,
+    };
+
+    if join_tx.send(join_message).is_err() {
+        warn!("join_tx closed, ignoring join request");
+        return Ok(internal_server_error());
+    }
+
+    if let Ok(join_reply) = reply_rx.await {
+        Ok(Response::builder()
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_string(&join_reply).unwrap().into())
+            .unwrap())
+    } else {
+        warn!("reply_rx closed, ignoring join request");
+        Ok(internal_server_error())
+    }
+}
+
+/// Read the whole body of a request, unless it is larger than `max_bytes`.
+/// We read incrementally, so that we can stop early for large requests.
+async fn read_body(mut body: Body, max_bytes: usize) -> Result<Option<Vec<u8>>, hyper::Error> {
+    if body
+        .size_hint()
+        .upper()
+        .map_or(false, |upper| upper > max_bytes as u64)
+    {
+        return Ok(None);
+    }
+
+    let mut result = Vec::new();
+
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+
+        if result.len() + chunk.len() > max_bytes {
+            return Ok(None);
         }
 
-        // Return 404 Not Found for other routes
-        _ => Ok(not_found()),
+        result.extend_from_slice(chunk.as_ref());
     }
+
+    Ok(Some(result))
 }
 
 /// Serve a file.
@@ -211,6 +334,20 @@ fn bad_request() -> Response<Body> {
         .unwrap()
 }
 
+fn payload_too_large() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .body(PAYLOAD_TOO_LARGE.into())
+        .unwrap()
+}
+
+fn too_many_requests() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .body(TOO_MANY_REQUESTS.into())
+        .unwrap()
+}
+
 fn not_found() -> Response<Body> {
     Response::builder()
         .status(StatusCode::NOT_FOUND)
@@ -224,3 +361,124 @@ fn internal_server_error() -> Response<Body> {
         .body(INTERNAL_SERVER_ERROR.into())
         .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+
+    fn post(path: &str) -> Request<Body> {
+        Request::builder()
+            .method(Method::POST)
+            .uri(path)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    /// A body whose size is not known in advance, so that it has to be
+    /// measured while reading.
+    fn streamed_body(num_chunks: usize, chunk_len: usize) -> Body {
+        let (mut sender, body) = Body::channel();
+
+        tokio::spawn(async move {
+            for _ in 0..num_chunks {
+                if sender
+                    .send_data(vec![b' '; chunk_len].into())
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        body
+    }
+
+    #[test]
+    fn rapid_joins_are_rate_limited() {
+        let limiter = Mutex::new(RateLimiter::new(0.5, 5.0));
+        let addr: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+
+        let limited: Vec<bool> = (0..10)
+            .map(|_| is_rate_limited(&limiter, &post("/join"), addr, now))
+            .collect();
+
+        assert!(limited[..5].iter().all(|limited| !limited));
+        assert!(limited[5..].iter().all(|limited| *limited));
+
+        // Another address has its own budget.
+        let other_addr: IpAddr = "10.0.0.2".parse().unwrap();
+        assert!(!is_rate_limited(&limiter, &post("/join"), other_addr, now));
+    }
+
+    #[test]
+    fn slow_joins_are_not_rate_limited() {
+        let limiter = Mutex::new(RateLimiter::new(0.5, 5.0));
+        let addr: IpAddr = "10.0.0.1".parse().unwrap();
+        let start_time = Instant::now();
+
+        for i in 0..20 {
+            let now = start_time + Duration::from_secs(2 * i);
+            assert!(!is_rate_limited(&limiter, &post("/join"), addr, now));
+        }
+    }
+
+    #[test]
+    fn join_and_connect_webrtc_share_the_limit() {
+        let limiter = Mutex::new(RateLimiter::new(0.5, 5.0));
+        let addr: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+
+        for _ in 0..5 {
+            assert!(!is_rate_limited(&limiter, &post("/join"), addr, now));
+        }
+        assert!(is_rate_limited(
+            &limiter,
+            &post("/connect_webrtc"),
+            addr,
+            now
+        ));
+
+        // Static files are never limited.
+        let get = Request::builder().uri("/").body(Body::empty()).unwrap();
+        assert!(!is_rate_limited(&limiter, &get, addr, now));
+    }
+
+    #[tokio::test]
+    async fn read_body_accepts_bodies_up_to_the_limit() {
+        let body = read_body(Body::from(vec![b' '; 64]), 64).await.unwrap();
+        assert_eq!(body, Some(vec![b' '; 64]));
+
+        let body = read_body(streamed_body(4, 16), 64).await.unwrap();
+        assert_eq!(body, Some(vec![b' '; 64]));
+    }
+
+    #[tokio::test]
+    async fn read_body_rejects_large_bodies() {
+        assert_eq!(
+            read_body(Body::from(vec![b' '; 65]), 64).await.unwrap(),
+            None
+        );
+        assert_eq!(read_body(streamed_body(5, 16), 64).await.unwrap(), None);
+    }
+
+    async fn assert_join_rejected_as_too_large(body: Body) {
+        let (join_tx, mut join_rx) = mpsc::unbounded_channel();
+
+        let response = join(join_tx, body, 64).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(join_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn large_join_is_rejected_without_reaching_the_runner() {
+        assert_join_rejected_as_too_large(Body::from(vec![b' '; 65])).await;
+        assert_join_rejected_as_too_large(streamed_body(5, 16)).await;
+    }
+}
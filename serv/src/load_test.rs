@@ -0,0 +1,256 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use log::{info, warn};
+use rand::Rng;
+use tokio::sync::oneshot;
+
+use comn::util::diff::Diff;
+
+use crate::{
+    clock::Clock,
+    runner::{JoinMessage, JoinTx},
+    webrtc::{MessageIn, MessageOut, RecvMessageTx, SendMessageRx, SendMessageTx},
+};
+
+/// Number of received states that synthetic clients keep before their
+/// acknowledged diff base, in case ticks arrive out of order.
+const KEEP_STATES_BUFFER: i32 = 5;
+
+/// Probability per tick that a synthetic client changes its input.
+const CHANGE_INPUT_PROB: f32 = 0.05;
+
+/// Interval in which the harness logs how many ticks it has received.
+const LOG_INTERVAL_TICKS: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub num_clients: usize,
+    pub codec: comn::CodecKind,
+}
+
+struct Client {
+    token: comn::PlayerToken,
+    settings: Arc<comn::Settings>,
+    received_states: BTreeMap<comn::TickNum, comn::Game>,
+    input: comn::Input,
+}
+
+/// Synthetic clients that play in-process, for measuring the runner under
+/// load without real browsers.
+///
+/// The harness sits between the runner and the WebRTC server. Messages for
+/// synthetic clients are handled here, while all other messages are forwarded
+/// to the WebRTC server, so real players can still join. Synthetic clients
+/// reply to each tick with an acknowledgement and a random input for that
+/// tick, so they send input at the tick rate.
+pub struct LoadTest {
+    config: Config,
+    clock: Arc<dyn Clock>,
+    join_tx: JoinTx,
+    recv_message_tx: RecvMessageTx,
+    send_message_rx: SendMessageRx,
+    forward_tx: SendMessageTx,
+    clients: HashMap<SocketAddr, Client>,
+    num_received_ticks: usize,
+}
+
+impl LoadTest {
+    pub fn new(
+        config: Config,
+        clock: Arc<dyn Clock>,
+        join_tx: JoinTx,
+        recv_message_tx: RecvMessageTx,
+        send_message_rx: SendMessageRx,
+        forward_tx: SendMessageTx,
+    ) -> Self {
+        Self {
+            config,
+            clock,
+            join_tx,
+            recv_message_tx,
+            send_message_rx,
+            forward_tx,
+            clients: HashMap::new(),
+            num_received_ticks: 0,
+        }
+    }
+
+    pub async fn run(mut self) {
+        for i in 0..self.config.num_clients {
+            if let Err(err) = self.join(i).await {
+                warn!("Synthetic client {} failed to join: {:?}", i, err);
+            }
+        }
+
+        info!("Joined {} synthetic clients", self.clients.len());
+
+        while let Some(message_out) = self.send_message_rx.recv().await {
+            if self.clients.contains_key(&message_out.peer) {
+                self.handle_message(message_out);
+            } else if self.forward_tx.send(message_out).is_err() {
+                warn!("forward_tx closed, terminating");
+                return;
+            }
+        }
+
+        // The runner has terminated. By returning here, `forward_tx` is
+        // dropped, so that the WebRTC server can shut down as well.
+    }
+
+    async fn join(&mut self, i: usize) -> Result<(), comn::JoinError> {
+        let request = comn::JoinRequest {
+            protocol_version: comn::PROTOCOL_VERSION,
+            game_id: None,
+            player_name: format!("synth{}", i),
+            join_code: None,
+            create_private: false,
+            game_options: comn::GameOptions::default(),
+        };
+
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.join_tx
+            .send(JoinMessage { request, reply_tx })
+            .expect("Runner has terminated");
+        let success = reply_rx.await.expect("Runner has terminated")?;
+
+        // Synthetic clients get addresses that no real peer can have.
+        let peer = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), i as u16 + 1);
+
+        self.clients.insert(
+            peer,
+            Client {
+                token: success.your_token,
+                settings: Arc::new(success.game_settings),
+                received_states: BTreeMap::new(),
+                input: comn::Input::default(),
+            },
+        );
+
+        // The runner only learns the client's address once it receives a
+        // message, so we start with a ping.
+        self.send(peer, comn::ClientMessage::Ping(comn::SequenceNum(0)));
+
+        Ok(())
+    }
+
+    fn handle_message(&mut self, message_out: MessageOut) {
+        let message = match comn::ServerMessage::deserialize(&self.config.codec, &message_out.data)
+        {
+            Some(message) => message,
+            None => {
+                warn!(
+                    "Failed to deserialize message for synthetic client {:?}",
+                    message_out.peer
+                );
+                return;
+            }
+        };
+
+        match message {
+            comn::ServerMessage::Ping(sequence_num) => {
+                self.send(message_out.peer, comn::ClientMessage::Pong(sequence_num));
+            }
+            comn::ServerMessage::Pong(_) => (),
+            comn::ServerMessage::Tick(tick) => {
+                let client = self.clients.get_mut(&message_out.peer).unwrap();
+
+                if let Some((tick_num, state_hash)) = client.receive_tick(tick) {
+                    client.update_input();
+                    let input = client.input.clone();
+
+                    self.send(
+                        message_out.peer,
+                        comn::ClientMessage::AckTick(tick_num, state_hash),
+                    );
+                    self.send(
+                        message_out.peer,
+                        comn::ClientMessage::Input(vec![(tick_num, input)]),
+                    );
+
+                    self.num_received_ticks += 1;
+                    if self.num_received_ticks % LOG_INTERVAL_TICKS == 0 {
+                        info!(
+                            "Synthetic clients have received {} ticks",
+                            self.num_received_ticks
+                        );
+                    }
+                }
+            }
+            comn::ServerMessage::Disconnect => {
+                warn!("Synthetic client {:?} was disconnected", message_out.peer);
+                self.clients.remove(&message_out.peer);
+            }
+        }
+    }
+
+    fn send(&self, peer: SocketAddr, message: comn::ClientMessage) {
+        let token = self.clients[&peer].token;
+        let data = comn::SignedClientMessage(token, message).serialize(&self.config.codec);
+
+        // We do not care if the runner has terminated, since we will notice
+        // when `send_message_rx` is closed.
+        let _ = self.recv_message_tx.send(MessageIn {
+            peer,
+            data,
+            recv_time: self.clock.now(),
+        });
+    }
+}
+
+impl Client {
+    /// Delta decodes the tick, returning its number and the hash of the
+    /// resulting state on success.
+    fn receive_tick(&mut self, tick: comn::Tick) -> Option<(comn::TickNum, u64)> {
+        let mut state = if let Some(diff_base_num) = tick.diff_base {
+            let state = self.received_states.get(&diff_base_num)?.clone();
+
+            // The server will never again encode w.r.t. older ticks.
+            let old_tick_nums: Vec<comn::TickNum> = self
+                .received_states
+                .keys()
+                .copied()
+                .filter(|&tick_num| diff_base_num.distance(tick_num) > KEEP_STATES_BUFFER)
+                .collect();
+            for tick_num in old_tick_nums {
+                self.received_states.remove(&tick_num);
+            }
+
+            state
+        } else {
+            comn::Game::new(self.settings.clone())
+        };
+
+        if let Err(err) = tick.diff.apply(&mut state) {
+            warn!("Synthetic client failed to delta decode tick: {:?}", err);
+            return None;
+        }
+
+        let tick_num = state.tick_num;
+        let state_hash = state.state_hash();
+        self.received_states.insert(tick_num, state);
+
+        Some((tick_num, state_hash))
+    }
+
+    /// Randomly changes the input every now and then, so that players keep
+    /// moving in one direction for a while.
+    fn update_input(&mut self) {
+        let mut rng = rand::thread_rng();
+
+        if rng.gen::<f32>() < CHANGE_INPUT_PROB {
+            self.input = comn::Input {
+                move_left: rng.gen(),
+                move_right: rng.gen(),
+                move_up: rng.gen(),
+                move_down: rng.gen(),
+                dash: rng.gen_bool(0.1),
+                use_action: rng.gen_bool(0.1),
+                shoot: rng.gen_bool(0.2),
+            };
+        }
+    }
+}
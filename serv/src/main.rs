@@ -4,23 +4,32 @@
 #![type_length_limit = "600000000"]
 
 mod bot;
+mod clock;
 mod fake_bad_net;
 mod game;
 mod http;
-mod run;
+mod load_test;
+mod mapgen;
+mod metrics;
+mod rate_limit;
+mod replay;
 mod runner;
+mod sent_states;
 mod tiled;
 mod webrtc;
 
-use std::{path::PathBuf, time::Duration};
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
 use clap::Arg;
 use log::{info, warn};
 
 use tokio::sync::oneshot;
 
+use clock::{Clock, ManualClock, RealClock};
 use fake_bad_net::FakeBadNet;
 
+const WEBRTC_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Clone, Debug)]
 pub struct Config {
     pub http_server: http::Config,
@@ -32,44 +41,294 @@ pub struct Config {
 async fn main() {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("debug"));
 
-    let matches = clap::App::new("serv")
-        .arg(
-            Arg::with_name("http_address")
-                .long("http_address")
-                .takes_value(true)
-                .required(true)
-                .help("listen on the specified address/port for HTTP"),
-        )
-        .arg(
-            Arg::with_name("webrtc_address")
-                .long("webrtc_address")
-                .takes_value(true)
-                .required(true)
-                .help("listen on the specified address/port for WebRTC"),
-        )
-        .arg(
-            Arg::with_name("clnt_dir")
-                .long("clnt_dir")
-                .takes_value(true)
-                .default_value("clnt/static")
-                .help("Directory containing static files to be served over HTTP"),
-        )
-        .arg(
-            Arg::with_name("map")
-                .long("map")
-                .takes_value(true)
-                .default_value("maps/test.tmx")
-                .help("Path to TMX map file"),
-        )
-        .get_matches();
+    let matches =
+        clap::App::new("serv")
+            .arg(
+                Arg::with_name("http_address")
+                    .long("http_address")
+                    .takes_value(true)
+                    .required(true)
+                    .help("listen on the specified address/port for HTTP"),
+            )
+            .arg(
+                Arg::with_name("webrtc_address")
+                    .long("webrtc_address")
+                    .takes_value(true)
+                    .required(true)
+                    .help("listen on the specified address/port for WebRTC"),
+            )
+            .arg(
+                Arg::with_name("webrtc_public_address")
+                    .long("webrtc_public_address")
+                    .takes_value(true)
+                    .help("address/port that clients reach the WebRTC server at, if it differs"),
+            )
+            .arg(
+                Arg::with_name("webrtc_signaling_url")
+                    .long("webrtc_signaling_url")
+                    .takes_value(true)
+                    .help("URL that clients use for establishing WebRTC connections"),
+            )
+            .arg(
+                Arg::with_name("clnt_dir")
+                    .long("clnt_dir")
+                    .takes_value(true)
+                    .default_value("clnt/static")
+                    .help("Directory containing static files to be served over HTTP"),
+            )
+            .arg(
+                Arg::with_name("wrap_map")
+                    .long("wrap_map")
+                    .help("Let entities leaving the map appear at the opposite edge"),
+            )
+            .arg(
+                Arg::with_name("player_collision")
+                    .long("player_collision")
+                    .takes_value(true)
+                    .possible_values(&["none", "soft", "solid"])
+                    .default_value("soft")
+                    .help("Whether players push each other apart"),
+            )
+            .arg(
+                Arg::with_name("map")
+                    .long("map")
+                    .takes_value(true)
+                    .default_value("maps/test.tmx")
+                    .help("Path to TMX map file, or generate:SEED for a generated map"),
+            )
+            .arg(
+                Arg::with_name("replay_dir")
+                    .long("replay_dir")
+                    .takes_value(true)
+                    .help("Record replays of all games into the specified directory"),
+            )
+            .arg(
+                Arg::with_name("num_bots")
+                    .long("num_bots")
+                    .takes_value(true)
+                    .default_value("2")
+                    .help("Number of bots to add to each new game"),
+            )
+            .arg(
+                Arg::with_name("bot_behavior")
+                    .long("bot_behavior")
+                    .takes_value(true)
+                    .possible_values(&["hunter", "random", "left_right"])
+                    .default_value("hunter")
+                    .help("How the bots play. The simpler behaviors are useful for testing"),
+            )
+            .arg(
+                Arg::with_name("bot_difficulty")
+                    .long("bot_difficulty")
+                    .takes_value(true)
+                    .possible_values(&["easy", "medium", "hard"])
+                    .default_value("medium")
+                    .help("Skill level of the bots"),
+            )
+            .arg(
+                Arg::with_name("codec")
+                    .long("codec")
+                    .takes_value(true)
+                    .possible_values(&["msgpack", "bincode"])
+                    .default_value("msgpack")
+                    .help("Format of the messages exchanged with clients"),
+            )
+            .arg(
+                Arg::with_name("seed")
+                    .long("seed")
+                    .takes_value(true)
+                    .help("Seed for the randomness in games (random if not given)"),
+            )
+            .arg(
+                Arg::with_name("ping_timeout_ms")
+                    .long("ping_timeout_ms")
+                    .takes_value(true)
+                    .help("Disconnect players that do not answer pings for this long"),
+            )
+            .arg(
+                Arg::with_name("num_teams")
+                    .long("num_teams")
+                    .takes_value(true)
+                    .help("Split the players into the specified number of teams"),
+            )
+            .arg(
+                Arg::with_name("share_catch_food")
+                    .long("share_catch_food")
+                    .help("Give the food for catching a player to the catcher's whole team"),
+            )
+            .arg(
+                Arg::with_name("watch_map")
+                    .long("watch_map")
+                    .help("Reload the map file when it changes, using it for new games"),
+            )
+            .arg(
+                Arg::with_name("allow_peer_migration")
+                    .long("allow_peer_migration")
+                    .help("Allow players to switch to a new peer at any time"),
+            )
+            .arg(
+                Arg::with_name("compress_ticks")
+                    .long("compress_ticks")
+                    .help("Compress ticks that are larger than a threshold"),
+            )
+            .arg(
+                Arg::with_name("keyframe_interval")
+                    .long("keyframe_interval")
+                    .takes_value(true)
+                    .help("Send a tick from scratch to each player at least every this many ticks"),
+            )
+            .arg(
+                Arg::with_name("stun_server")
+                    .long("stun_server")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("URL of a STUN server for clients to use (may be given multiple times)"),
+            )
+            .arg(
+                Arg::with_name("turn_server")
+                    .long("turn_server")
+                    .takes_value(true)
+                    .requires_all(&["turn_username", "turn_credential"])
+                    .help("URL of a TURN server for clients to use"),
+            )
+            .arg(
+                Arg::with_name("turn_username")
+                    .long("turn_username")
+                    .takes_value(true)
+                    .help("Username for the TURN server"),
+            )
+            .arg(
+                Arg::with_name("turn_credential")
+                    .long("turn_credential")
+                    .takes_value(true)
+                    .help("Credential for the TURN server"),
+            )
+            .arg(Arg::with_name("fast_forward").long("fast_forward").help(
+                "Run games as fast as possible instead of in real time, e.g. for watching bots",
+            ))
+            .arg(
+                Arg::with_name("synthetic_clients")
+                    .long("synthetic_clients")
+                    .takes_value(true)
+                    .help("Spawn this many in-process clients with random inputs for load tests"),
+            )
+            .arg(
+                Arg::with_name("net_lag_ms")
+                    .long("net_lag_ms")
+                    .takes_value(true)
+                    .help("Simulate bad network: mean lag in milliseconds, in each direction"),
+            )
+            .arg(
+                Arg::with_name("net_lag_jitter")
+                    .long("net_lag_jitter")
+                    .takes_value(true)
+                    .help("Simulate bad network: standard deviation of the lag in milliseconds"),
+            )
+            .arg(
+                Arg::with_name("net_loss")
+                    .long("net_loss")
+                    .takes_value(true)
+                    .help("Simulate bad network: probability of losing a message"),
+            )
+            .arg(
+                Arg::with_name("net_reorder")
+                    .long("net_reorder")
+                    .takes_value(true)
+                    .help("Simulate bad network: probability of delaying a message out of order"),
+            )
+            .get_matches();
 
-    let game_map = tiled::load_map(matches.value_of("map").unwrap()).unwrap();
+    let map_arg = matches.value_of("map").unwrap();
+    let generate_seed = if map_arg.starts_with(mapgen::GENERATE_PREFIX) {
+        Some(
+            map_arg[mapgen::GENERATE_PREFIX.len()..]
+                .parse()
+                .expect("could not parse map generation seed"),
+        )
+    } else {
+        None
+    };
+    let game_map = match generate_seed {
+        Some(seed) => mapgen::generate_map(seed),
+        None => tiled::load_map(map_arg).unwrap(),
+    };
     let runner_config = runner::Config {
         max_num_games: 32,
         game_settings: comn::Settings {
             max_num_players: 64,
             ticks_per_second: 30,
             map: game_map,
+            num_teams: matches
+                .value_of("num_teams")
+                .map(|num_teams| num_teams.parse().expect("could not parse number of teams")),
+            seed: matches.value_of("seed").map_or_else(rand::random, |seed| {
+                seed.parse().expect("could not parse seed")
+            }),
+            spawn_protection_duration: 2.0,
+            catcher_policy: comn::game::CatcherPolicy::LeastRecent,
+            boundary_mode: if matches.is_present("wrap_map") {
+                comn::game::BoundaryMode::Wrap
+            } else {
+                comn::game::BoundaryMode::Clamp
+            },
+            player_collision: match matches.value_of("player_collision").unwrap() {
+                "none" => comn::game::PlayerCollision::None,
+                "solid" => comn::game::PlayerCollision::Solid,
+                _ => comn::game::PlayerCollision::Soft,
+            },
+            catch_food: 10,
+            food_per_pickup: 1,
+            share_catch_food: matches.is_present("share_catch_food"),
+            bullet_max_lifetime: 5.0,
+            max_bullets_per_owner: 10,
+            turret_magazine_size: 3,
+            turret_reload_duration: 5.0,
+        },
+        replay_dir: matches.value_of("replay_dir").map(PathBuf::from),
+        watch_map: if matches.is_present("watch_map") && generate_seed.is_none() {
+            Some(PathBuf::from(map_arg))
+        } else {
+            None
+        },
+        num_bots: matches
+            .value_of("num_bots")
+            .unwrap()
+            .parse()
+            .expect("could not parse number of bots"),
+        bot_behavior: match matches.value_of("bot_behavior").unwrap() {
+            "random" => bot::Behavior::random(),
+            "left_right" => bot::Behavior::left_right(1.0),
+            _ => bot::Behavior::Hunter,
+        },
+        bot_config: match matches.value_of("bot_difficulty").unwrap() {
+            "easy" => bot::BotConfig::easy(),
+            "hard" => bot::BotConfig::hard(),
+            _ => bot::BotConfig::medium(),
+        },
+        codec: match matches.value_of("codec").unwrap() {
+            "bincode" => comn::CodecKind::Bincode,
+            _ => comn::CodecKind::Msgpack,
+        },
+        compress_threshold: if matches.is_present("compress_ticks") {
+            Some(runner::DEFAULT_COMPRESS_THRESHOLD)
+        } else {
+            None
+        },
+        keyframe_interval: matches
+            .value_of("keyframe_interval")
+            .map(|interval| interval.parse().expect("could not parse keyframe interval")),
+        allow_peer_migration: matches.is_present("allow_peer_migration"),
+        ice_servers: ice_servers(&matches),
+        webrtc_signaling_url: matches.value_of("webrtc_signaling_url").map(str::to_string),
+        input_buffer: runner::InputBufferConfig::default(),
+        ping: comn::util::PingConfig {
+            timeout: Duration::from_millis(
+                matches.value_of("ping_timeout_ms").map_or(5_000, |ms| {
+                    ms.parse().expect("could not parse ping timeout")
+                }),
+            ),
+            ..comn::util::PingConfig::default()
         },
     };
     let http_server_config = http::Config {
@@ -79,6 +338,9 @@ async fn main() {
             .parse()
             .expect("could not parse HTTP address/port"),
         clnt_dir: PathBuf::from(matches.value_of("clnt_dir").unwrap()),
+        join_rate: 0.5,
+        join_burst: 5.0,
+        max_body_bytes: 16 * 1024,
     };
     let webrtc_server_config = webrtc::Config {
         listen_addr: matches
@@ -86,6 +348,11 @@ async fn main() {
             .unwrap()
             .parse()
             .expect("could not parse WebRTC address/port"),
+        public_addr: matches.value_of("webrtc_public_address").map(|address| {
+            address
+                .parse()
+                .expect("could not parse WebRTC public address/port")
+        }),
     };
     let config = Config {
         http_server: http_server_config,
@@ -96,19 +363,7 @@ async fn main() {
     let (recv_message_tx, recv_message_rx) = webrtc::recv_message_channel();
     let (send_message_tx, send_message_rx) = webrtc::send_message_channel();
 
-    let fake_bad_net_config = Some((
-        fake_bad_net::Config {
-            lag_mean: Duration::from_millis(125),
-            lag_std_dev: 0.0,
-            loss: 0.00,
-        },
-        fake_bad_net::Config {
-            lag_mean: Duration::from_millis(125),
-            lag_std_dev: 0.0,
-            loss: 0.00,
-        },
-    ));
-    let fake_bad_net_config = None;
+    let fake_bad_net_config = fake_bad_net_config(&matches).map(|config| (config.clone(), config));
 
     let (recv_message_rx, send_message_rx) = if let Some((config_in, config_out)) =
         fake_bad_net_config
@@ -125,24 +380,69 @@ async fn main() {
         (recv_message_rx, send_message_rx)
     };
 
+    // Synthetic clients intercept the runner's messages to them, forwarding
+    // all other messages to the WebRTC server.
+    let load_test_recv_message_tx = recv_message_tx.clone();
+    let num_synthetic_clients: Option<usize> = matches.value_of("synthetic_clients").map(|n| {
+        n.parse()
+            .expect("could not parse number of synthetic clients")
+    });
+    let (send_message_tx, load_test_channels) = if let Some(num_clients) = num_synthetic_clients {
+        let (runner_send_message_tx, runner_send_message_rx) = webrtc::send_message_channel();
+
+        (
+            runner_send_message_tx,
+            Some((num_clients, runner_send_message_rx, send_message_tx)),
+        )
+    } else {
+        (send_message_tx, None)
+    };
+
     let (shutdown_http_tx, shutdown_http_rx) = oneshot::channel();
     let (shutdown_runner_tx, shutdown_runner_rx) = oneshot::channel();
     let (shutdown_webrtc_tx, shutdown_webrtc_rx) = oneshot::channel();
 
-    let webrtc_server = webrtc::Server::new(config.webrtc_server, recv_message_tx, send_message_rx)
-        .await
-        .expect("Error starting WebRTC server");
+    let clock: Arc<dyn Clock> = if matches.is_present("fast_forward") {
+        Arc::new(ManualClock::default())
+    } else {
+        Arc::new(RealClock)
+    };
+
+    let webrtc_server = webrtc::Server::new(
+        config.webrtc_server,
+        clock.clone(),
+        recv_message_tx,
+        send_message_rx,
+    )
+    .await
+    .expect("Error starting WebRTC server");
     let session_endpoint = webrtc_server.session_endpoint();
 
+    let codec = config.runner.codec;
     let runner = runner::Runner::new(
         config.runner,
+        clock.clone(),
         recv_message_rx,
         send_message_tx,
         shutdown_runner_rx,
     );
     let join_tx = runner.join_tx();
+    let shared_stats = runner.shared_stats();
+
+    if let Some((num_clients, runner_send_message_rx, send_message_tx)) = load_test_channels {
+        let load_test = load_test::LoadTest::new(
+            load_test::Config { num_clients, codec },
+            clock,
+            join_tx.clone(),
+            load_test_recv_message_tx,
+            runner_send_message_rx,
+            send_message_tx,
+        );
+        tokio::spawn(load_test.run());
+    }
 
-    let http_server = http::Server::new(config.http_server, join_tx, session_endpoint);
+    let http_server =
+        http::Server::new(config.http_server, join_tx, session_endpoint, shared_stats);
 
     let runner_thread = tokio::task::spawn_blocking(move || runner.run());
     let http_server_task =
@@ -177,12 +477,75 @@ async fn main() {
 
     runner_thread.await.expect("Failed to join runner thread");
 
-    info!("Runner thread terminated, shutting down WebRTC server");
-    if shutdown_webrtc_tx.send(()).is_err() {
-        info!("WebRTC server has already shut down");
+    // The runner has dropped its sending channel, so the WebRTC server will
+    // terminate on its own once it has sent the remaining messages (such as
+    // the disconnect messages). Only force it to stop if this takes too long.
+    info!("Runner thread terminated, waiting for WebRTC server to flush messages");
+    let mut webrtc_server_task = webrtc_server_task;
+    match tokio::time::timeout(WEBRTC_FLUSH_TIMEOUT, &mut webrtc_server_task).await {
+        Ok(result) => result.expect("Failed to join WebRTC server"),
+        Err(_) => {
+            warn!("WebRTC server did not flush in time, shutting it down");
+            if shutdown_webrtc_tx.send(()).is_err() {
+                info!("WebRTC server has already shut down");
+            }
+
+            webrtc_server_task
+                .await
+                .expect("Failed to join WebRTC server");
+        }
     }
+}
+
+/// Returns the STUN and TURN servers that clients should use for WebRTC.
+fn ice_servers(matches: &clap::ArgMatches) -> Vec<comn::IceServer> {
+    let mut ice_servers = Vec::new();
 
-    webrtc_server_task
-        .await
-        .expect("Failed to join WebRTC server");
+    if let Some(urls) = matches.values_of("stun_server") {
+        ice_servers.push(comn::IceServer {
+            urls: urls.map(str::to_string).collect(),
+            username: None,
+            credential: None,
+        });
+    }
+
+    if let Some(url) = matches.value_of("turn_server") {
+        ice_servers.push(comn::IceServer {
+            urls: vec![url.to_string()],
+            username: matches.value_of("turn_username").map(str::to_string),
+            credential: matches.value_of("turn_credential").map(str::to_string),
+        });
+    }
+
+    ice_servers
+}
+
+/// Returns the configuration for simulating a bad network if any of the
+/// corresponding arguments are given.
+fn fake_bad_net_config(matches: &clap::ArgMatches) -> Option<fake_bad_net::Config> {
+    let parse_f32 = |name: &str| {
+        matches.value_of(name).map(|value| {
+            value
+                .parse::<f32>()
+                .unwrap_or_else(|_| panic!("could not parse {}", name))
+        })
+    };
+
+    let lag_ms = parse_f32("net_lag_ms");
+    let lag_jitter = parse_f32("net_lag_jitter");
+    let loss = parse_f32("net_loss");
+    let reorder = parse_f32("net_reorder");
+
+    if lag_ms.is_none() && lag_jitter.is_none() && loss.is_none() && reorder.is_none() {
+        return None;
+    }
+
+    Some(fake_bad_net::Config {
+        lag_mean: Duration::from_secs_f32(lag_ms.unwrap_or(0.0) / 1000.0),
+        lag_std_dev: lag_jitter.unwrap_or(0.0),
+        loss: loss.unwrap_or(0.0),
+        reorder_prob: reorder.unwrap_or(0.0),
+        max_reorder_delay: Duration::from_millis(100),
+        duplicate_prob: 0.0,
+    })
 }
@@ -0,0 +1,85 @@
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use comn::{
+    game::MapBuilder,
+    geom::{AaRect, Shape},
+};
+
+/// Prefix of the map argument for generating a map from a seed.
+pub const GENERATE_PREFIX: &str = "generate:";
+
+/// The map is split into a grid of cells, each of which holds at most one
+/// object.
+const NUM_CELLS_X: usize = 8;
+const NUM_CELLS_Y: usize = 6;
+const CELL_SIZE: f32 = 300.0;
+
+/// Minimal free space between a wall and the border of its cell. Since walls
+/// never leave their cell, there are always gaps of at least twice this size
+/// between walls, so all of the free space stays connected and passable.
+const WALL_MARGIN: f32 = 70.0;
+const MIN_WALL_SIZE: f32 = 40.0;
+
+const NUM_SPAWN_POINTS: usize = 8;
+const WALL_PROB: f64 = 0.4;
+const TURRET_PROB: f64 = 0.08;
+const FOOD_SPAWN_PROB: f64 = 0.15;
+
+/// Generates an arena of random walls, turrets and food spawns. The same seed
+/// always gives the same map.
+pub fn generate_map(seed: u64) -> comn::Map {
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let mut cells: Vec<(usize, usize)> = (0..NUM_CELLS_X)
+        .flat_map(|x| (0..NUM_CELLS_Y).map(move |y| (x, y)))
+        .collect();
+    cells.shuffle(&mut rng);
+
+    let mut builder = MapBuilder::new(comn::Vector::new(
+        NUM_CELLS_X as f32 * CELL_SIZE,
+        NUM_CELLS_Y as f32 * CELL_SIZE,
+    ));
+
+    // Spawn points get cells of their own, so that they are never inside of
+    // a wall.
+    let (spawn_cells, other_cells) = cells.split_at(NUM_SPAWN_POINTS);
+
+    for &cell in spawn_cells {
+        builder = builder.spawn_point(cell_center(cell));
+    }
+
+    for &cell in other_cells {
+        let roll: f64 = rng.gen();
+
+        builder = if roll < WALL_PROB {
+            let max_size = CELL_SIZE - 2.0 * WALL_MARGIN;
+            let size = comn::Vector::new(
+                rng.gen_range(MIN_WALL_SIZE, max_size),
+                rng.gen_range(MIN_WALL_SIZE, max_size),
+            );
+            let max_shift = (comn::Vector::new(max_size, max_size) - size) / 2.0;
+            let center = cell_center(cell)
+                + comn::Vector::new(
+                    (rng.gen::<f32>() * 2.0 - 1.0) * max_shift.x,
+                    (rng.gen::<f32>() * 2.0 - 1.0) * max_shift.y,
+                );
+
+            builder.wall(Shape::AaRect(AaRect::new_center(center, size)))
+        } else if roll < WALL_PROB + TURRET_PROB {
+            builder.turret(cell_center(cell))
+        } else if roll < WALL_PROB + TURRET_PROB + FOOD_SPAWN_PROB {
+            builder.food_spawn(cell_center(cell))
+        } else {
+            builder
+        };
+    }
+
+    let map = builder.build();
+    debug_assert!(map.validate().is_ok());
+
+    map
+}
+
+fn cell_center((x, y): (usize, usize)) -> comn::Point {
+    comn::Point::new((x as f32 + 0.5) * CELL_SIZE, (y as f32 + 0.5) * CELL_SIZE)
+}
@@ -0,0 +1,161 @@
+//! Rendering of runner statistics in the Prometheus text exposition format.
+
+use std::fmt::Write;
+
+use comn::util::stats;
+
+use crate::runner::{StatsSnapshot, Traffic};
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+pub fn render(snapshot: &StatsSnapshot) -> String {
+    let mut out = String::new();
+    let stats = &snapshot.stats;
+
+    write_var(
+        &mut out,
+        "catcheb_num_players",
+        "Number of players",
+        &stats.num_players,
+    );
+    write_var(
+        &mut out,
+        "catcheb_num_games",
+        "Number of games",
+        &stats.num_games,
+    );
+    write_var(
+        &mut out,
+        "catcheb_inputs_per_player_tick",
+        "Number of inputs per player and tick",
+        &stats.num_inputs_per_player_tick,
+    );
+    write_var(
+        &mut out,
+        "catcheb_input_delay_ticks",
+        "Delay of player inputs in ticks",
+        &stats.input_delay,
+    );
+    write_var(
+        &mut out,
+        "catcheb_input_buffer_ticks",
+        "Buffer of player inputs in ticks",
+        &stats.input_buffer,
+    );
+    write_var(
+        &mut out,
+        "catcheb_last_sent_len",
+        "Number of states remembered for delta encoding",
+        &stats.last_sent_len,
+    );
+    write_var(
+        &mut out,
+        "catcheb_tick_message_size_bytes",
+        "Size of tick messages in bytes",
+        &stats.tick_message_size,
+    );
+    write_var(
+        &mut out,
+        "catcheb_state_divergence_ratio",
+        "Ratio of acknowledged ticks whose state hash differed from ours",
+        &stats.state_divergence,
+    );
+
+    write_header(
+        &mut out,
+        "catcheb_webrtc_peers",
+        "Number of players with an active WebRTC peer",
+    );
+    writeln!(out, "catcheb_webrtc_peers {}", snapshot.num_peers).unwrap();
+
+    write_header(
+        &mut out,
+        "catcheb_game_players",
+        "Number of players per game, including bots",
+    );
+    for (game_id, num_players) in snapshot.game_num_players.iter() {
+        writeln!(
+            out,
+            "catcheb_game_players{{game_id=\"{}\"}} {}",
+            game_id.0, num_players
+        )
+        .unwrap();
+    }
+
+    write_var(
+        &mut out,
+        "catcheb_scratch_tick_ratio",
+        "Ratio of ticks that could not be delta encoded",
+        &stats.scratch_ticks,
+    );
+
+    let traffic_counters: [(&str, &str, fn(&Traffic) -> u64); 5] = [
+        (
+            "catcheb_player_recv_bytes_total",
+            "Number of bytes received from a player",
+            |traffic| traffic.recv_bytes,
+        ),
+        (
+            "catcheb_player_recv_packets_total",
+            "Number of packets received from a player",
+            |traffic| traffic.recv_packets,
+        ),
+        (
+            "catcheb_player_send_bytes_total",
+            "Number of bytes sent to a player",
+            |traffic| traffic.send_bytes,
+        ),
+        (
+            "catcheb_player_send_packets_total",
+            "Number of packets sent to a player",
+            |traffic| traffic.send_packets,
+        ),
+        (
+            "catcheb_player_scratch_ticks_total",
+            "Number of ticks sent to a player that could not be delta encoded",
+            |traffic| traffic.scratch_ticks,
+        ),
+    ];
+    for (name, help, value) in traffic_counters.iter() {
+        write_typed_header(&mut out, name, help, "counter");
+        for (game_id, player_id, traffic) in snapshot.player_traffic.iter() {
+            writeln!(
+                out,
+                "{}{{game_id=\"{}\",player_id=\"{}\"}} {}",
+                name,
+                game_id.0,
+                player_id.0,
+                value(traffic),
+            )
+            .unwrap();
+        }
+    }
+
+    out
+}
+
+fn write_header(out: &mut String, name: &str, help: &str) {
+    write_typed_header(out, name, help, "gauge");
+}
+
+fn write_typed_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn write_var(out: &mut String, name: &str, help: &str, var: &stats::Var) {
+    // We export the mean over the sample duration of the variable, since the
+    // last value can be very noisy.
+    write_header(out, name, help);
+    writeln!(out, "{} {}", name, format_value(var.mean())).unwrap();
+}
+
+fn format_value(value: Option<f32>) -> String {
+    match value {
+        Some(value) if value.is_finite() => value.to_string(),
+        Some(value) if value.is_nan() => "NaN".to_string(),
+        Some(value) if value > 0.0 => "+Inf".to_string(),
+        Some(_) => "-Inf".to_string(),
+        None => "NaN".to_string(),
+    }
+}
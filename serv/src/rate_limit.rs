@@ -0,0 +1,123 @@
+use std::{collections::HashMap, net::IpAddr, time::Instant};
+
+/// Token bucket rate limiting keyed by the source IP address.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    /// Number of tokens that are added to each bucket per second.
+    rate: f32,
+
+    /// Maximal number of tokens in a bucket.
+    burst: f32,
+
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    tokens: f32,
+    last_update: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f32, burst: f32) -> Self {
+        assert!(rate > 0.0);
+        assert!(burst >= 1.0);
+
+        Self {
+            rate,
+            burst,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Try to take a token for the given address. Returns false if the
+    /// address has exceeded its rate.
+    pub fn check(&mut self, addr: IpAddr, now: Instant) -> bool {
+        let (rate, burst) = (self.rate, self.burst);
+
+        // Forget about addresses whose bucket has been refilled completely,
+        // so that the map does not grow indefinitely.
+        self.buckets.retain(|_, bucket| {
+            bucket.tokens + rate * now.duration_since(bucket.last_update).as_secs_f32() < burst
+        });
+
+        let bucket = self.buckets.entry(addr).or_insert(Bucket {
+            tokens: burst,
+            last_update: now,
+        });
+
+        bucket.tokens = (bucket.tokens
+            + rate * now.duration_since(bucket.last_update).as_secs_f32())
+        .min(burst);
+        bucket.last_update = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn burst_is_allowed_and_then_refilled_at_rate() {
+        let mut limiter = RateLimiter::new(2.0, 3.0);
+        let addr: IpAddr = "10.0.0.1".parse().unwrap();
+        let start_time = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.check(addr, start_time));
+        }
+        assert!(!limiter.check(addr, start_time));
+
+        // One token is added every half second.
+        assert!(!limiter.check(addr, start_time + Duration::from_millis(400)));
+        assert!(limiter.check(addr, start_time + Duration::from_millis(600)));
+        assert!(!limiter.check(addr, start_time + Duration::from_millis(600)));
+    }
+
+    #[test]
+    fn steady_cadence_at_rate_is_allowed() {
+        let mut limiter = RateLimiter::new(2.0, 1.0);
+        let addr: IpAddr = "10.0.0.1".parse().unwrap();
+        let start_time = Instant::now();
+
+        for i in 0..100 {
+            assert!(limiter.check(addr, start_time + Duration::from_millis(500 * i)));
+        }
+    }
+
+    #[test]
+    fn addresses_are_limited_separately() {
+        let mut limiter = RateLimiter::new(1.0, 1.0);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.check(a, now));
+        assert!(!limiter.check(a, now));
+        assert!(limiter.check(b, now));
+    }
+
+    #[test]
+    fn refilled_buckets_are_forgotten() {
+        let mut limiter = RateLimiter::new(1.0, 2.0);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let start_time = Instant::now();
+
+        assert!(limiter.check(a, start_time));
+        assert_eq!(limiter.buckets.len(), 1);
+
+        assert!(limiter.check(b, start_time + Duration::from_secs(1)));
+        assert_eq!(limiter.buckets.len(), 1);
+        assert!(limiter.buckets.contains_key(&b));
+    }
+}
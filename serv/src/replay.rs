@@ -0,0 +1,105 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use comn::{
+    replay::{self, ReplayTick},
+    util::diff::Diffable,
+};
+
+/// Records the ticks of a game to a file, so that they can be played back
+/// later for debugging. See `comn::replay` for the format.
+pub struct ReplayWriter {
+    writer: BufWriter<File>,
+    last_state: comn::Game,
+}
+
+impl ReplayWriter {
+    pub fn create(dir: &Path, game_id: comn::GameId, state: &comn::Game) -> io::Result<Self> {
+        let path = dir.join(format!("{}.replay", game_id.0));
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        writer.write_all(&replay::encode_record(&*state.settings))?;
+
+        Ok(Self {
+            writer,
+            last_state: comn::Game::new(state.settings.clone()),
+        })
+    }
+
+    pub fn record_tick(&mut self, state: &comn::Game, events: &[comn::Event]) -> io::Result<()> {
+        let tick = ReplayTick {
+            tick_num: state.tick_num,
+            events: events.to_vec(),
+            diff: self.last_state.diff(state),
+        };
+
+        self.writer.write_all(&replay::encode_record(&tick))?;
+        self.last_state = state.clone();
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc};
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn recorded_replay_can_be_read_back() {
+        let map = comn::game::MapBuilder::new(comn::Vector::new(1000.0, 1000.0))
+            .spawn_point(comn::Point::new(500.0, 500.0))
+            .build();
+        let settings = Arc::new(comn::game::SettingsBuilder::new(map).build());
+        let game_id = comn::GameId(Uuid::new_v4());
+        let dir = std::env::temp_dir();
+
+        let mut state = comn::Game::new(settings);
+        let mut recorded = Vec::new();
+        let mut writer = ReplayWriter::create(&dir, game_id, &state).unwrap();
+
+        for i in 1..=10 {
+            state.tick_num = comn::TickNum(i);
+            state.entities.insert(
+                comn::EntityId(i % 3),
+                comn::Entity::Player(comn::PlayerEntity::new(
+                    comn::PlayerId(i % 3),
+                    comn::Point::new(10.0 * i as f32, 0.0),
+                )),
+            );
+            let events = vec![comn::Event::NewCatcher {
+                player_id: comn::PlayerId(i),
+            }];
+
+            writer.record_tick(&state, &events).unwrap();
+            recorded.push(state.clone());
+        }
+
+        writer.flush().unwrap();
+
+        let path = dir.join(format!("{}.replay", game_id.0));
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let replay = replay::Replay::decode(&data).unwrap();
+        let states = replay.states().unwrap();
+
+        assert_eq!(states.len(), recorded.len());
+        for ((state, events), recorded_state) in states.iter().zip(recorded.iter()) {
+            assert_eq!(state.tick_num, recorded_state.tick_num);
+            assert_eq!(state.entities, recorded_state.entities);
+            assert_eq!(state.state_hash(), recorded_state.state_hash());
+            assert_eq!(events.len(), 1);
+        }
+    }
+}
@@ -1,12 +1,13 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet},
     net::SocketAddr,
-    sync::Arc,
-    time::{Duration, Instant},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
 };
 
 use log::{debug, info, warn};
-use rand::seq::IteratorRandom;
+use rand::{seq::IteratorRandom, Rng};
 use tokio::sync::{
     mpsc::{self, error::TryRecvError},
     oneshot,
@@ -14,20 +15,58 @@ use tokio::sync::{
 use uuid::Uuid;
 
 use comn::{
-    util::{diff::Diffable, stats, GameTimeEstimation, PingEstimation, Timer},
+    util::{diff::Diffable, stats, GameTimeEstimation, PingConfig, PingEstimation, Timer},
     GameTime,
 };
 
 use crate::{
-    bot::Bot,
+    bot::{Behavior, Bot, BotConfig},
+    clock::Clock,
     game::Game,
+    replay::ReplayWriter,
+    sent_states::SentStates,
+    tiled,
     webrtc::{self, RecvMessageRx, SendMessageTx},
 };
 
-const PLAYER_INPUT_BUFFER: f32 = 1.5;
 const MAX_PLAYER_INPUT_AGE: f32 = 1.0;
+/// Number of ticks for which a state that we sent may serve as the basis for
+/// delta encoding. This also bounds the number of states that we remember
+/// for a player (see `SentStates`), since acks of older states are useless.
 const MAX_DIFF_TICKS: u32 = 50;
 
+/// If we had to send this many ticks in a row from scratch to a player, we
+/// assume that their acks arrive too late, and widen their diff window.
+const MAX_CONSECUTIVE_SCRATCH_TICKS: usize = 30;
+
+/// Upper bound for widening the diff window of a player.
+const MAX_WIDENED_DIFF_TICKS: u32 = 4 * MAX_DIFF_TICKS;
+const JOIN_CODE_LEN: usize = 6;
+const MIN_TICKS_PER_SECOND: usize = 10;
+const MAX_TICKS_PER_SECOND: usize = 60;
+
+/// Inputs for ticks that are further ahead of our time are considered to be
+/// malicious.
+const MAX_INPUT_TICKS_AHEAD: u32 = 3;
+
+/// Players may not send more new inputs per second than this factor times the
+/// number of ticks per second.
+const MAX_INPUT_RATE_FACTOR: f32 = 1.5;
+
+/// Players may have this many inputs rejected per second without
+/// consequences, since bursts can happen e.g. after packet loss.
+const MAX_REJECTED_INPUTS_PER_SECOND: usize = 50;
+
+/// Players who exceed `MAX_REJECTED_INPUTS_PER_SECOND` for this many seconds
+/// in a row are disconnected.
+const MAX_ABUSIVE_INPUT_SECONDS: usize = 5;
+const WATCH_MAP_PERIOD: Duration = Duration::from_secs(1);
+const PUBLISH_STATS_PERIOD: Duration = Duration::from_secs(1);
+
+/// If we have not heard from a player's peer for this long, we assume that
+/// the client lost its connection, and allow it to continue from a new peer.
+const RECONNECT_SILENCE: Duration = Duration::from_millis(1000);
+
 #[derive(Debug, Clone)]
 struct Player {
     /// Each player is in exactly one running game.
@@ -36,9 +75,13 @@ struct Player {
     /// The player id is unique only in the game.
     player_id: comn::PlayerId,
 
-    /// WebRTC peer address.
+    /// WebRTC peer address. The player's token is bound to this peer, see
+    /// `Config::allow_peer_migration`.
     peer: Option<SocketAddr>,
 
+    /// Last time that we received a message from `peer`.
+    last_peer_recv_time: Instant,
+
     /// Ping estimation.
     ping: PingEstimation,
 
@@ -50,7 +93,7 @@ struct Player {
     /// Inputs that we received from this player recently. The TickNum key is
     /// the tick that the player *saw* while it executed the input. In our
     /// server time, this tick will be somewhere in the past. Note that we try
-    /// to buffer the inputs slightly (see `PLAYER_INPUT_BUFFER`), so that we
+    /// to buffer the inputs slightly (see `InputBufferConfig`), so that we
     /// can try to hide network jitter. Inputs are sorted by TickNum
     /// descending.
     inputs: Vec<(comn::TickNum, comn::Input)>,
@@ -59,35 +102,173 @@ struct Player {
     /// stream `GameTime`. This is used for buffering `inputs`.
     recv_input_time: GameTimeEstimation,
 
+    /// Number of new inputs that we received since `input_count_start_tick`.
+    /// Used for limiting the rate of inputs.
+    num_counted_inputs: usize,
+    input_count_start_tick: comn::TickNum,
+
+    /// Number of inputs that we rejected as implausible since
+    /// `input_count_start_tick`.
+    num_rejected_inputs: usize,
+
+    /// Number of consecutive seconds in which we rejected too many inputs.
+    num_abusive_input_seconds: usize,
+
     /// Last tick that the player has acknowledged receiving from us. Used as
     /// the basis for delta encoding.
     last_ack_tick: Option<comn::TickNum>,
 
-    /// Last states that we have sent to the player, ordered by the tick number
-    /// ascending.
-    last_sent: VecDeque<(Vec<comn::Event>, comn::Game)>,
+    /// Last states that we have sent to the player.
+    last_sent: SentStates,
+
+    /// Number of ticks for which a sent state may serve as the basis for
+    /// delta encoding. Starts at `MAX_DIFF_TICKS`.
+    diff_ticks: u32,
+
+    /// Number of ticks that we had to send from scratch since the last one
+    /// that we could delta encode.
+    num_consecutive_scratch_ticks: usize,
+
+    /// Number of ticks that we sent since the last one from scratch.
+    ticks_since_keyframe: u32,
+
+    /// Amount of data that we exchanged with the player.
+    traffic: Traffic,
 }
 
 impl Player {
-    fn new(input_period: GameTime, game_id: comn::GameId, player_id: comn::PlayerId) -> Self {
+    fn new(
+        input_period: GameTime,
+        ping_config: PingConfig,
+        game_id: comn::GameId,
+        player_id: comn::PlayerId,
+        tick_num: comn::TickNum,
+        now: Instant,
+    ) -> Self {
         Self {
             game_id,
             player_id,
             peer: None,
-            ping: PingEstimation::default(),
+            last_peer_recv_time: now,
+            ping: PingEstimation::new_at(ping_config, now),
             last_input: None,
             inputs: Vec::new(),
             recv_input_time: GameTimeEstimation::new(input_period),
+            num_counted_inputs: 0,
+            input_count_start_tick: tick_num,
+            num_rejected_inputs: 0,
+            num_abusive_input_seconds: 0,
             last_ack_tick: None,
-            last_sent: VecDeque::new(),
+            last_sent: SentStates::default(),
+            diff_ticks: MAX_DIFF_TICKS,
+            num_consecutive_scratch_ticks: 0,
+            ticks_since_keyframe: 0,
+            traffic: Traffic::default(),
         }
     }
 }
 
+/// Counters of the messages exchanged with a player, in serialized form.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Traffic {
+    pub recv_bytes: u64,
+    pub recv_packets: u64,
+    pub send_bytes: u64,
+    pub send_packets: u64,
+
+    /// Number of ticks that we could not delta encode.
+    pub scratch_ticks: u64,
+}
+
+impl Traffic {
+    pub fn record_recv(&mut self, len: usize) {
+        self.recv_bytes += len as u64;
+        self.recv_packets += 1;
+    }
+
+    pub fn record_send(&mut self, len: usize) {
+        self.send_bytes += len as u64;
+        self.send_packets += 1;
+    }
+}
+
+/// Determines for how long we buffer player inputs before using them, so that
+/// we can deal with jitter. The buffer is chosen per player, so that players
+/// with stable connections get less input delay.
+#[derive(Debug, Clone)]
+pub struct InputBufferConfig {
+    /// Minimal and maximal buffer, in multiples of the tick period.
+    pub min_ticks: f32,
+    pub max_ticks: f32,
+
+    /// Additional buffer per standard deviation of the input receive delay.
+    pub std_dev_factor: f32,
+}
+
+impl Default for InputBufferConfig {
+    fn default() -> Self {
+        Self {
+            min_ticks: 1.0,
+            max_ticks: 4.0,
+            std_dev_factor: 2.0,
+        }
+    }
+}
+
+impl InputBufferConfig {
+    pub fn buffer(&self, tick_period: GameTime, recv_delay_std_dev: f32) -> GameTime {
+        (tick_period * self.min_ticks + self.std_dev_factor * recv_delay_std_dev)
+            .min(tick_period * self.max_ticks)
+    }
+}
+
+/// Ticks below this size in bytes are unlikely to benefit from compression.
+pub const DEFAULT_COMPRESS_THRESHOLD: usize = 512;
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub max_num_games: usize,
     pub game_settings: comn::Settings,
+
+    /// If set, each game is recorded to a replay file in this directory.
+    pub replay_dir: Option<PathBuf>,
+
+    /// If set, the map is reloaded from this TMX file whenever it changes.
+    /// The new map is only used for games that are created afterwards.
+    pub watch_map: Option<PathBuf>,
+
+    /// Number of bots that are added to each new game.
+    pub num_bots: usize,
+    pub bot_behavior: Behavior,
+    pub bot_config: BotConfig,
+
+    /// Format of the messages exchanged with clients.
+    pub codec: comn::CodecKind,
+
+    /// If set, ticks that are larger than this number of bytes are
+    /// compressed before sending.
+    pub compress_threshold: Option<usize>,
+
+    /// If set, we send a tick from scratch (a keyframe) to each player at
+    /// least every this many ticks, even if we could delta encode. This bounds
+    /// for how long errors in delta encoding can persist on clients.
+    pub keyframe_interval: Option<u32>,
+
+    /// If false, a player's token is bound to the first peer that used it.
+    /// Messages from other peers are then ignored, unless the player seems
+    /// to be reconnecting after having lost its connection.
+    pub allow_peer_migration: bool,
+
+    /// ICE servers that are passed to clients in `JoinSuccess`. If empty,
+    /// clients use their default servers.
+    pub ice_servers: Vec<comn::IceServer>,
+
+    /// URL that clients use for establishing the WebRTC connection, in case it
+    /// is not served through our own HTTP server.
+    pub webrtc_signaling_url: Option<String>,
+
+    pub input_buffer: InputBufferConfig,
+    pub ping: PingConfig,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -96,10 +277,32 @@ pub struct Stats {
     pub num_games: stats::Var,
     pub num_inputs_per_player_tick: stats::Var,
     pub input_delay: stats::Var,
+    pub input_buffer: stats::Var,
     pub last_sent_len: stats::Var,
     pub tick_message_size: stats::Var,
+    pub state_divergence: stats::Var,
+    pub scratch_ticks: stats::Var,
+}
+
+/// Snapshot of the runner's statistics, shared with the HTTP server for
+/// monitoring.
+#[derive(Debug, Clone, Default)]
+pub struct StatsSnapshot {
+    pub stats: Stats,
+    pub num_peers: usize,
+    pub game_num_players: Vec<(comn::GameId, usize)>,
+    pub player_traffic: Vec<(comn::GameId, comn::PlayerId, Traffic)>,
+
+    /// Profiling report of the runner thread.
+    pub profile: String,
+
+    /// Set by the HTTP server to let the runner know that it should start
+    /// profiling from scratch.
+    pub reset_profile: bool,
 }
 
+pub type SharedStats = Arc<Mutex<StatsSnapshot>>;
+
 pub struct JoinMessage {
     pub request: comn::JoinRequest,
     pub reply_tx: oneshot::Sender<comn::JoinReply>,
@@ -111,9 +314,15 @@ pub type JoinRx = mpsc::UnboundedReceiver<JoinMessage>;
 
 pub struct Runner {
     config: Config,
+    clock: Arc<dyn Clock>,
 
     games: HashMap<comn::GameId, Game>,
     players: HashMap<comn::PlayerToken, Player>,
+    replays: HashMap<comn::GameId, ReplayWriter>,
+
+    /// Codes of private games. Private games are not considered when a
+    /// player wants to join just any game.
+    join_codes: HashMap<String, comn::GameId>,
 
     join_tx: JoinTx,
     join_rx: JoinRx,
@@ -124,35 +333,48 @@ pub struct Runner {
     shutdown_rx: oneshot::Receiver<()>,
     shutdown: bool,
 
-    tick_timer: Timer,
-
     stats: Stats,
     print_stats_timer: Timer,
+    shared_stats: SharedStats,
+    publish_stats_timer: Timer,
+
+    watch_map_timer: Timer,
+    map_modified: Option<SystemTime>,
 }
 
 impl Runner {
     pub fn new(
         config: Config,
+        clock: Arc<dyn Clock>,
         recv_message_rx: RecvMessageRx,
         send_message_tx: SendMessageTx,
         shutdown_rx: oneshot::Receiver<()>,
     ) -> Self {
         let (join_tx, join_rx) = mpsc::unbounded_channel();
-        let tick_timer =
-            comn::util::Timer::time_per_second(config.game_settings.ticks_per_second as f32);
+        let now = clock.now();
+        let map_modified = config
+            .watch_map
+            .as_ref()
+            .and_then(|path| map_modified_time(path));
         Runner {
             config,
+            clock,
             games: HashMap::new(),
             players: HashMap::new(),
+            replays: HashMap::new(),
+            join_codes: HashMap::new(),
             join_tx,
             join_rx,
             recv_message_rx,
             send_message_tx,
             shutdown_rx,
             shutdown: false,
-            tick_timer,
             stats: Stats::default(),
-            print_stats_timer: Timer::with_duration(Duration::from_secs(5)),
+            print_stats_timer: Timer::with_duration_at(Duration::from_secs(5), now),
+            shared_stats: Arc::new(Mutex::new(StatsSnapshot::default())),
+            publish_stats_timer: Timer::with_duration_at(PUBLISH_STATS_PERIOD, now),
+            watch_map_timer: Timer::with_duration_at(WATCH_MAP_PERIOD, now),
+            map_modified,
         }
     }
 
@@ -160,11 +382,19 @@ impl Runner {
         self.join_tx.clone()
     }
 
+    pub fn shared_stats(&self) -> SharedStats {
+        self.shared_stats.clone()
+    }
+
     pub fn run(mut self) {
         while !self.shutdown {
             self.run_update();
 
-            if self.print_stats_timer.exhaust().is_some() {
+            if self
+                .print_stats_timer
+                .exhaust_at(self.clock.now())
+                .is_some()
+            {
                 debug!("num players:          {}", self.stats.num_players);
                 debug!("num games:            {}", self.stats.num_games);
                 debug!(
@@ -172,11 +402,48 @@ impl Runner {
                     self.stats.num_inputs_per_player_tick
                 );
                 debug!("input delay:          {}", self.stats.input_delay);
+                debug!("input buffer:         {}", self.stats.input_buffer);
                 debug!("last sent len:        {}", self.stats.last_sent_len);
                 debug!("tick message size:    {}", self.stats.tick_message_size);
+                debug!("state divergence:     {}", self.stats.state_divergence);
+                debug!("scratch ticks:        {}", self.stats.scratch_ticks);
+
+                for player in self.players.values() {
+                    debug!(
+                        "traffic of {:?} in {:?}: recv {} bytes / {} packets, send {} bytes / {} packets ({} from scratch)",
+                        player.player_id,
+                        player.game_id,
+                        player.traffic.recv_bytes,
+                        player.traffic.recv_packets,
+                        player.traffic.send_bytes,
+                        player.traffic.send_packets,
+                        player.traffic.scratch_ticks,
+                    );
+                }
+            }
+
+            if self
+                .publish_stats_timer
+                .exhaust_at(self.clock.now())
+                .is_some()
+            {
+                self.publish_stats();
+
+                // Flush replays every now and then, so that they can be
+                // inspected while the game is still running, and so that we
+                // lose little if the server crashes.
+                self.flush_replays();
             }
 
-            std::thread::sleep(std::time::Duration::from_millis(1));
+            self.clock.sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn flush_replays(&mut self) {
+        for (game_id, replay) in self.replays.iter_mut() {
+            if let Err(err) = replay.flush() {
+                warn!("Failed to flush replay of game {:?}: {}", game_id, err);
+            }
         }
     }
 
@@ -199,9 +466,12 @@ impl Runner {
                 }
             }
 
-            // Wait a little bit to allow WebRTC to send packages.
-            std::thread::sleep(Duration::from_secs(1));
+            self.flush_replays();
 
+            // Note that we do not need to wait for the messages to be sent
+            // here. Once the runner terminates, `send_message_tx` is dropped,
+            // and the WebRTC server terminates after it has sent all of the
+            // remaining messages.
             info!("Finished shutting down");
 
             self.shutdown = true;
@@ -236,11 +506,22 @@ impl Runner {
                 return;
             }
         } {
-            let signed_message = comn::SignedClientMessage::deserialize(&message_in.data);
+            let signed_message =
+                comn::SignedClientMessage::deserialize(&self.config.codec, &message_in.data);
+
+            // Fake lag is added to the receive time, while the message is
+            // delayed in real time, which need not match our clock. Make sure
+            // that messages never arrive in our future.
+            let recv_time = message_in.recv_time.min(self.clock.now());
 
             match signed_message {
                 Some(signed_message) => {
-                    self.handle_message(message_in.peer, message_in.recv_time, signed_message);
+                    self.handle_message(
+                        message_in.peer,
+                        recv_time,
+                        message_in.data.len(),
+                        signed_message,
+                    );
                 }
                 None => {
                     warn!(
@@ -251,12 +532,14 @@ impl Runner {
             }
         }
 
+        let now = self.clock.now();
+
         // Disconnect players.
         let remove_player_tokens: Vec<comn::PlayerToken> = self
             .players
             .iter()
             .filter_map(|(player_token, player)| {
-                if player.ping.is_timeout(Instant::now()) {
+                if player.ping.is_timeout(now) {
                     Some(*player_token)
                 } else {
                     None
@@ -276,21 +559,37 @@ impl Runner {
         // Ping players.
         let mut messages = Vec::new();
 
-        for player in self.players.values_mut() {
-            if let Some(sequence_num) = player.ping.next_ping_sequence_num(Instant::now()) {
-                if let Some(peer) = player.peer {
-                    messages.push((peer, comn::ServerMessage::Ping(sequence_num)));
+        for (player_token, player) in self.players.iter_mut() {
+            if let Some(sequence_num) = player.ping.next_ping_sequence_num(now) {
+                if player.peer.is_some() {
+                    messages.push((*player_token, comn::ServerMessage::Ping(sequence_num)));
                 }
             }
         }
 
-        for (peer, message) in messages {
-            self.send(peer, message);
+        for (player_token, message) in messages {
+            self.send_to_player(player_token, message);
         }
 
-        // Run the game.
-        while self.tick_timer.tick() {
-            self.run_tick();
+        // Check if the map file has been changed.
+        if self.watch_map_timer.exhaust_at(now).is_some() {
+            self.reload_map_if_modified();
+        }
+
+        // Run the games, each at its own tick rate.
+        loop {
+            let game_ids: HashSet<comn::GameId> = self
+                .games
+                .iter_mut()
+                .filter(|(_, game)| game.tick_timer.tick_at(now))
+                .map(|(game_id, _)| *game_id)
+                .collect();
+
+            if game_ids.is_empty() {
+                break;
+            }
+
+            self.run_tick(&game_ids);
         }
     }
 
@@ -298,6 +597,7 @@ impl Runner {
         &mut self,
         peer: SocketAddr,
         recv_time: Instant,
+        len: usize,
         message: comn::SignedClientMessage,
     ) {
         let player = if let Some(player) = self.players.get_mut(&message.0) {
@@ -308,13 +608,27 @@ impl Runner {
         };
 
         if Some(peer) != player.peer {
+            let is_reconnect = recv_time.saturating_duration_since(player.last_peer_recv_time)
+                >= RECONNECT_SILENCE;
+
+            if player.peer.is_some() && !self.config.allow_peer_migration && !is_reconnect {
+                warn!(
+                    "Received message from {:?} with token bound to {:?}, ignoring",
+                    peer, player.peer,
+                );
+                return;
+            }
+
             debug!("Changing peer from {:?} to {:?}", player.peer, peer);
             player.peer = Some(peer);
         }
 
+        player.last_peer_recv_time = recv_time;
+        player.traffic.record_recv(len);
+
         match message.1 {
             comn::ClientMessage::Ping(sequence_num) => {
-                self.send(peer, comn::ServerMessage::Pong(sequence_num));
+                self.send_to_player(message.0, comn::ServerMessage::Pong(sequence_num));
             }
             comn::ClientMessage::Pong(sequence_num) => {
                 if player.ping.record_pong(recv_time, sequence_num).is_err() {
@@ -323,9 +637,28 @@ impl Runner {
             }
             comn::ClientMessage::Input(inputs) => {
                 self.record_player_input(message.0, &inputs);
+
+                let player = &self.players[&message.0];
+                if player.num_abusive_input_seconds >= MAX_ABUSIVE_INPUT_SECONDS {
+                    warn!(
+                        "Disconnecting {:?} for sending implausible inputs",
+                        message.0
+                    );
+
+                    let (game_id, player_id) = (player.game_id, player.player_id);
+
+                    // Let the client know, so that it does not keep sending.
+                    for _ in 0..3 {
+                        self.send_to_player(message.0, comn::ServerMessage::Disconnect);
+                    }
+
+                    let game = self.games.get_mut(&game_id).unwrap();
+                    game.remove_player(player_id);
+                    self.players.remove(&message.0);
+                }
             }
-            comn::ClientMessage::AckTick(ack_num) => {
-                self.record_player_ack_tick(message.0, ack_num);
+            comn::ClientMessage::AckTick(ack_num, state_hash) => {
+                self.record_player_ack_tick(message.0, ack_num, state_hash);
             }
             comn::ClientMessage::Disconnect => {
                 debug!("Player {:?} disconnected", message.0);
@@ -337,8 +670,13 @@ impl Runner {
         }
     }
 
-    fn run_tick(&mut self) {
-        let tick_inputs = self.collect_player_inputs_for_tick();
+    fn run_tick(&mut self, game_ids: &HashSet<comn::GameId>) {
+        coarse_prof::profile!("run_tick");
+
+        let tick_inputs = {
+            coarse_prof::profile!("collect_player_inputs_for_tick");
+            self.collect_player_inputs_for_tick(game_ids)
+        };
 
         // Record some statistics for monitoring.
         self.stats.num_players.record(self.players.len() as f32);
@@ -348,21 +686,56 @@ impl Runner {
                 .values()
                 .map(|inputs| inputs.len() as f32)
                 .sum::<f32>()
-                / (self.players.len() as f32 * self.games.len() as f32),
+                / (self.players.len() as f32 * game_ids.len() as f32),
         );
 
         // Update the games given the player inputs.
         for (game_id, game) in self.games.iter_mut() {
+            if !game_ids.contains(game_id) {
+                continue;
+            }
+
+            coarse_prof::profile!("game_run_tick");
             game.run_tick(tick_inputs[game_id].as_slice());
+
+            if let Some(replay) = self.replays.get_mut(game_id) {
+                if let Err(err) = replay.record_tick(&game.state, &game.last_events) {
+                    warn!("Failed to record replay of game {:?}: {}", game_id, err);
+                    self.replays.remove(game_id);
+                }
+            }
         }
 
+        // The scoreboard is the same for all the players in a game.
+        let scoreboards: HashMap<comn::GameId, comn::Scoreboard> = self
+            .games
+            .iter()
+            .filter(|(game_id, _)| game_ids.contains(game_id))
+            .map(|(game_id, game)| (*game_id, comn::Scoreboard::from_game(&game.state)))
+            .collect();
+
         // Send out tick messages.
         let mut messages = Vec::new();
-        for player in self.players.values_mut() {
-            if let Some(peer) = player.peer {
+        for (player_token, player) in self.players.iter_mut() {
+            if !game_ids.contains(&player.game_id) {
+                continue;
+            }
+
+            if player.peer.is_some() {
                 let game = &self.games[&player.game_id];
-                let tick = Self::prepare_tick_for_player(player, game);
-                messages.push((peer, comn::ServerMessage::Tick(tick)));
+                let tick = {
+                    coarse_prof::profile!("prepare_tick_for_player");
+                    Self::prepare_tick_for_player(
+                        player,
+                        game,
+                        &scoreboards[&player.game_id],
+                        self.config.keyframe_interval,
+                    )
+                };
+                self.stats
+                    .scratch_ticks
+                    .record(if tick.diff_base.is_none() { 1.0 } else { 0.0 });
+                messages.push((*player_token, comn::ServerMessage::Tick(tick)));
 
                 self.stats
                     .last_sent_len
@@ -370,7 +743,7 @@ impl Runner {
             }
         }
 
-        for (peer, message) in messages {
+        for (player_token, message) in messages {
             // FIXME: Here, we will run into a problem as soon as a state
             // update is larger than the MTU of WebRTC (1200 Bytes, AFAIK).
             // We'll need to do one of two things:
@@ -378,22 +751,46 @@ impl Runner {
             //    updates. For example, remove the oldest events, or the
             //    entities that are the farthest away.
             // 2. Implement sending fragmented packets.
-            self.send(peer, message);
+            self.send_to_player(player_token, message);
         }
     }
 
-    fn send(&mut self, peer: SocketAddr, message: comn::ServerMessage) {
-        let data = message.serialize();
+    /// Sends a message to the player's current peer, if any, keeping track of
+    /// the player's traffic.
+    fn send_to_player(&mut self, player_token: comn::PlayerToken, message: comn::ServerMessage) {
+        let peer = match self
+            .players
+            .get(&player_token)
+            .and_then(|player| player.peer)
+        {
+            Some(peer) => peer,
+            None => return,
+        };
+
+        let len = self.send(peer, message);
+        self.players
+            .get_mut(&player_token)
+            .unwrap()
+            .traffic
+            .record_send(len);
+    }
+
+    /// Returns the number of bytes that were sent.
+    fn send(&mut self, peer: SocketAddr, message: comn::ServerMessage) -> usize {
+        let data = message.serialize(&self.config.codec, self.config.compress_threshold);
 
         if let comn::ServerMessage::Tick(_) = message {
             self.stats.tick_message_size.record(data.len() as f32);
         }
 
+        let len = data.len();
         let message_out = webrtc::MessageOut { peer, data };
 
         if self.send_message_tx.send(message_out).is_err() {
             info!("send_message_tx closed, will terminate thread");
         }
+
+        len
     }
 
     fn record_player_input(
@@ -413,11 +810,42 @@ impl Runner {
             return;
         }
 
+        // Start counting inputs anew every second. A second with too many
+        // rejected inputs only counts as abuse if it directly follows the
+        // previous one.
+        let window_ticks = game.settings.ticks_per_second as i32;
+        let elapsed_ticks = game.tick_num.distance(player.input_count_start_tick);
+        if elapsed_ticks >= window_ticks {
+            if player.num_rejected_inputs > MAX_REJECTED_INPUTS_PER_SECOND
+                && elapsed_ticks < 2 * window_ticks
+            {
+                player.num_abusive_input_seconds += 1;
+            } else {
+                player.num_abusive_input_seconds = 0;
+            }
+
+            player.num_counted_inputs = 0;
+            player.num_rejected_inputs = 0;
+            player.input_count_start_tick = game.tick_num;
+        }
+        let max_inputs_per_second =
+            (game.settings.ticks_per_second as f32 * MAX_INPUT_RATE_FACTOR) as usize;
+
+        let max_input_num =
+            comn::TickNum::newest(inputs.iter().map(|(tick_num, _)| *tick_num)).unwrap();
+        if max_input_num.distance(game.tick_num) > MAX_INPUT_TICKS_AHEAD as i32 {
+            warn!(
+                "Received input {:?} from {:?} which is ahead of us ({:?}), ignoring",
+                max_input_num, player_token, game.tick_num,
+            );
+            player.num_rejected_inputs += inputs.len();
+            return;
+        }
+
         // Keep track of when we receive player input, so that we can predict
         // when we'll receive the next player input. This results in a mapping
         // from our game time to the player's input game time as we receive it.
         {
-            let max_input_num = inputs.iter().map(|(tick_num, _)| *tick_num).max().unwrap();
             player
                 .recv_input_time
                 .record_tick(game.game_time(), game.tick_game_time(max_input_num));
@@ -445,7 +873,9 @@ impl Runner {
             if player
                 .last_input
                 .as_ref()
-                .map_or(false, |(last_input_num, _)| *input_num <= *last_input_num)
+                .map_or(false, |(last_input_num, _)| {
+                    !input_num.is_after(*last_input_num)
+                })
             {
                 continue;
             }
@@ -454,56 +884,106 @@ impl Runner {
             // number descending.
             match player
                 .inputs
-                .binary_search_by(|(other_input_num, _)| input_num.cmp(other_input_num))
+                .binary_search_by(|(other_input_num, _)| input_num.wrapping_cmp(*other_input_num))
             {
                 Ok(_) => {
                     // We have received input for the same tick
                     // more than once, just ignore.
                 }
                 Err(pos) => {
+                    if player.num_counted_inputs >= max_inputs_per_second {
+                        player.num_rejected_inputs += 1;
+                        continue;
+                    }
+
+                    player.num_counted_inputs += 1;
                     player.inputs.insert(pos, (*input_num, input.clone()));
                 }
             }
         }
     }
 
-    fn record_player_ack_tick(&mut self, player_token: comn::PlayerToken, ack_num: comn::TickNum) {
+    fn record_player_ack_tick(
+        &mut self,
+        player_token: comn::PlayerToken,
+        ack_num: comn::TickNum,
+        state_hash: u64,
+    ) {
         let player = self.players.get_mut(&player_token).unwrap();
         let game = &self.games[&player.game_id].state;
 
-        if ack_num > game.tick_num {
+        if ack_num.is_after(game.tick_num) {
             warn!(
                 "Received AckTick from {:?} which is ahead of us ({:?} vs {:?}), ignoring",
                 player_token, game.tick_num, ack_num,
             );
-        } else if player
+            return;
+        }
+
+        // Compare the client's state with the one we sent. A mismatch
+        // indicates a bug in delta encoding.
+        if let Some(sent_state) = player.last_sent.get(ack_num) {
+            let diverged = sent_state.state_hash != state_hash;
+            if diverged {
+                warn!(
+                    "State of {:?} diverged from ours in tick {:?}",
+                    player_token, ack_num,
+                );
+            }
+
+            self.stats
+                .state_divergence
+                .record(if diverged { 1.0 } else { 0.0 });
+        }
+
+        if player
             .last_ack_tick
-            .map_or(true, |last_ack_num| ack_num > last_ack_num)
+            .map_or(true, |last_ack_num| ack_num.is_after(last_ack_num))
         {
             player.last_ack_tick = Some(ack_num);
 
             // We can now forget all the states that are older than the one
             // whose acknowledgment we just received.
-            while player
-                .last_sent
-                .front()
-                .map_or(false, |(_events, state)| state.tick_num < ack_num)
-            {
-                player.last_sent.pop_front();
-            }
+            player.last_sent.prune_before(ack_num);
         }
     }
 
     fn try_join_game(&mut self, request: comn::JoinRequest) -> comn::JoinReply {
-        let game_id = self.get_non_full_game_to_join(request.game_id)?;
+        if request.protocol_version != comn::PROTOCOL_VERSION {
+            return Err(comn::JoinError::IncompatibleVersion {
+                server: comn::PROTOCOL_VERSION,
+            });
+        }
+
+        let player_name = comn::validate_player_name(&request.player_name)?;
+        let game_id = if request.create_private {
+            self.create_private_game(&request.game_options)?
+        } else if let Some(join_code) = request.join_code.as_ref() {
+            let game_id = self
+                .join_codes
+                .get(&join_code.trim().to_uppercase())
+                .copied()
+                .ok_or(comn::JoinError::InvalidGameId)?;
+            self.get_non_full_game_to_join(Some(game_id))?
+        } else {
+            self.get_non_full_game_to_join(request.game_id)?
+        };
+        let join_code = self.game_join_code(game_id);
         let game = self.games.get_mut(&game_id).unwrap();
         assert!(!game.is_full());
 
         let player_token = comn::PlayerToken(Uuid::new_v4());
         assert!(!self.players.contains_key(&player_token));
 
-        let player_id = game.join(request.player_name, None);
-        let player = Player::new(game.settings().tick_period(), game_id, player_id);
+        let player_id = game.join(player_name, None);
+        let player = Player::new(
+            game.settings().tick_period(),
+            self.config.ping.clone(),
+            game_id,
+            player_id,
+            game.state.tick_num,
+            self.clock.now(),
+        );
         self.players.insert(player_token, player);
 
         Ok(comn::JoinSuccess {
@@ -511,9 +991,49 @@ impl Runner {
             game_settings: game.settings().clone(),
             your_token: player_token,
             your_player_id: player_id,
+            codec: self.config.codec,
+            join_code,
+            ice_servers: self.config.ice_servers.clone(),
+            webrtc_signaling_url: self.config.webrtc_signaling_url.clone(),
         })
     }
 
+    fn create_private_game(
+        &mut self,
+        options: &comn::GameOptions,
+    ) -> Result<comn::GameId, comn::JoinError> {
+        let settings = self.game_settings_with_options(options)?;
+
+        if self.games.len() >= self.config.max_num_games {
+            warn!(
+                "Cannot create private game, reached the game limit of {}",
+                self.config.max_num_games
+            );
+            return Err(comn::JoinError::FullGame);
+        }
+
+        let game_id = self.add_game_with_settings(settings);
+
+        let join_code = loop {
+            let join_code = random_join_code();
+            if !self.join_codes.contains_key(&join_code) {
+                break join_code;
+            }
+        };
+
+        info!("Created private game {:?} with code {}", game_id, join_code);
+        self.join_codes.insert(join_code, game_id);
+
+        Ok(game_id)
+    }
+
+    fn game_join_code(&self, game_id: comn::GameId) -> Option<String> {
+        self.join_codes
+            .iter()
+            .find(|(_, other_id)| **other_id == game_id)
+            .map(|(join_code, _)| join_code.clone())
+    }
+
     fn get_non_full_game_to_join(
         &mut self,
         game_id: Option<comn::GameId>,
@@ -533,7 +1053,10 @@ impl Runner {
             }
         } else {
             // The player wants to join just any game.
-            let non_full_games = self.games.iter().filter(|(_, game)| !game.is_full());
+            let join_codes = &self.join_codes;
+            let non_full_games = self.games.iter().filter(|(game_id, game)| {
+                !game.is_full() && !join_codes.values().any(|other_id| other_id == *game_id)
+            });
 
             if let Some((game_id, _)) = non_full_games.choose(&mut rand::thread_rng()) {
                 Ok(*game_id)
@@ -559,16 +1082,61 @@ impl Runner {
         }
     }
 
+    fn game_settings_with_options(
+        &self,
+        options: &comn::GameOptions,
+    ) -> Result<comn::Settings, comn::JoinError> {
+        let mut settings = self.config.game_settings.clone();
+
+        if let Some(ticks_per_second) = options.ticks_per_second {
+            if ticks_per_second < MIN_TICKS_PER_SECOND || ticks_per_second > MAX_TICKS_PER_SECOND {
+                return Err(comn::JoinError::InvalidGameOptions);
+            }
+            settings.ticks_per_second = ticks_per_second;
+        }
+
+        if let Some(max_num_players) = options.max_num_players {
+            // Leave room for the bots and at least one more player.
+            if max_num_players <= self.config.num_bots
+                || max_num_players > self.config.game_settings.max_num_players
+            {
+                return Err(comn::JoinError::InvalidGameOptions);
+            }
+            settings.max_num_players = max_num_players;
+        }
+
+        Ok(settings)
+    }
+
     fn add_game(&mut self) -> comn::GameId {
+        self.add_game_with_settings(self.config.game_settings.clone())
+    }
+
+    fn add_game_with_settings(&mut self, settings: comn::Settings) -> comn::GameId {
         let game_id = comn::GameId(Uuid::new_v4());
-        let mut game = Game::new(Arc::new(self.config.game_settings.clone()));
+        let mut game = Game::new(Arc::new(settings), self.clock.now());
 
-        /*for i in 0..2 {
-            game.join(format!("random_bot{}", i), Some(Bot::random()));
-        }*/
+        for i in 0..self.config.num_bots {
+            let bot = Bot::new(
+                self.config.bot_behavior.clone(),
+                self.config.bot_config.clone(),
+            );
+            game.join(
+                format!("{}_bot{}", self.config.bot_behavior.name(), i),
+                Some(bot),
+            );
+        }
 
-        for i in 0..2 {
-            game.join(format!("left_right_bot{}", i), Some(Bot::left_right(2.0)));
+        if let Some(replay_dir) = self.config.replay_dir.as_ref() {
+            match ReplayWriter::create(replay_dir, game_id, &game.state) {
+                Ok(replay) => {
+                    info!("Recording replay of game {:?}", game_id);
+                    self.replays.insert(game_id, replay);
+                }
+                Err(err) => {
+                    warn!("Failed to create replay for game {:?}: {}", game_id, err);
+                }
+            }
         }
 
         assert!(!self.games.contains_key(&game_id));
@@ -577,21 +1145,99 @@ impl Runner {
         game_id
     }
 
+    fn publish_stats(&self) {
+        // Note that `coarse_prof` is thread-local, so the report needs to be
+        // written by the runner thread.
+        let mut profile = Vec::new();
+        coarse_prof::write(&mut profile).unwrap();
+
+        if self.shared_stats.lock().unwrap().reset_profile {
+            coarse_prof::reset();
+        }
+
+        let snapshot = StatsSnapshot {
+            stats: self.stats.clone(),
+            num_peers: self
+                .players
+                .values()
+                .filter(|player| player.peer.is_some())
+                .count(),
+            game_num_players: self
+                .games
+                .iter()
+                .map(|(game_id, game)| (*game_id, game.state.players.len()))
+                .collect(),
+            player_traffic: self
+                .players
+                .values()
+                .map(|player| (player.game_id, player.player_id, player.traffic))
+                .collect(),
+            profile: String::from_utf8_lossy(&profile).into_owned(),
+            reset_profile: false,
+        };
+
+        *self.shared_stats.lock().unwrap() = snapshot;
+    }
+
+    fn reload_map_if_modified(&mut self) {
+        let path = if let Some(path) = self.config.watch_map.as_ref() {
+            path
+        } else {
+            return;
+        };
+
+        let modified = map_modified_time(path);
+        if modified.is_none() || modified == self.map_modified {
+            return;
+        }
+
+        // Remember the time even if loading fails, so that we do not spam
+        // warnings until the file is changed again.
+        self.map_modified = modified;
+
+        match tiled::load_map(path) {
+            Ok(map) => {
+                info!(
+                    "Reloaded map from {}, using it for new games",
+                    path.display()
+                );
+                self.config.game_settings.map = map;
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to reload map from {}, keeping previous map: {:?}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+    }
+
     fn collect_player_inputs_for_tick(
         &mut self,
+        game_ids: &HashSet<comn::GameId>,
     ) -> HashMap<comn::GameId, Vec<(comn::PlayerId, comn::TickNum, comn::Input)>> {
-        let mut tick_inputs: HashMap<_, _> = self
-            .games
-            .keys()
+        let mut tick_inputs: HashMap<_, _> = game_ids
+            .iter()
             .map(|game_id| (*game_id, Vec::new()))
             .collect();
 
         for player in self.players.values_mut() {
+            if !game_ids.contains(&player.game_id) {
+                continue;
+            }
+
             let game = &self.games[&player.game_id].state;
 
             // We explicitly buffer player inputs for some time, so that we can
             // deal with jitter.
-            let lag = PLAYER_INPUT_BUFFER * game.settings.tick_period();
+            let lag = self.config.input_buffer.buffer(
+                game.settings.tick_period(),
+                player.recv_input_time.recv_delay_std_dev().unwrap_or(0.0),
+            );
+            self.stats
+                .input_buffer
+                .record(lag / game.settings.tick_period());
             let buffered_input_time = player
                 .recv_input_time
                 .estimate(game.game_time() - lag)
@@ -607,7 +1253,7 @@ impl Runner {
 
                 self.stats
                     .input_delay
-                    .record((game.tick_num.0 - oldest_tick_num.0) as f32);
+                    .record(game.tick_num.distance(oldest_tick_num) as f32);
 
                 player_tick_inputs.push((player.player_id, oldest_tick_num, oldest_input));
                 player.inputs.pop();
@@ -636,46 +1282,103 @@ impl Runner {
         tick_inputs
     }
 
-    fn prepare_tick_for_player(player: &mut Player, game: &Game) -> comn::Tick {
+    fn prepare_tick_for_player(
+        player: &mut Player,
+        game: &Game,
+        scoreboard: &comn::Scoreboard,
+        keyframe_interval: Option<u32>,
+    ) -> comn::Tick {
         let mut state = game.state.clone();
         game.prepare_state_for_player(player.player_id, &mut state);
 
         let mut events = vec![(game.state.tick_num, game.last_events.clone())];
 
-        // Attempt to do delta encoding w.r.t. a previous state if
-        // possible.
-        let ack_num_and_sent_state = player
+        let is_keyframe_due = keyframe_interval.map_or(false, |interval| {
+            player.ticks_since_keyframe + 1 >= interval
+        });
+
+        // Find the last state that the player has acknowledged, if we still
+        // have it.
+        let acked_num_and_sent_state = player
             .last_ack_tick
             .and_then(|ack_num| {
                 player
                     .last_sent
-                    .front()
-                    .as_ref()
-                    .map(|(_, sent_state)| (ack_num, sent_state))
+                    .base()
+                    .map(|sent_state| (ack_num, sent_state))
             })
             .filter(|(ack_num, sent_state)| {
-                *ack_num == sent_state.tick_num && ack_num.0 + MAX_DIFF_TICKS > state.tick_num.0
+                *ack_num == sent_state.tick_num
+                    && state.tick_num.distance(*ack_num) < player.diff_ticks as i32
             });
 
+        if let Some((ack_num, _)) = acked_num_and_sent_state {
+            // Re-send all the events that happened after the acknowledged
+            // tick. The events of the acknowledged tick itself have been
+            // received by the client together with that tick, so we can skip
+            // those. Keyframes need this as well, since they only replace the
+            // state, not the events that the client may have missed.
+            for sent_state in player.last_sent.iter() {
+                if !sent_state.events.is_empty() && sent_state.tick_num.is_after(ack_num) {
+                    events.push((sent_state.tick_num, sent_state.events.clone()));
+                }
+            }
+        }
+
+        // Attempt to do delta encoding w.r.t. the acknowledged state if
+        // possible.
+        let ack_num_and_sent_state = acked_num_and_sent_state.filter(|_| !is_keyframe_due);
+
+        // Only send the scoreboard if it has changed. Note that the client
+        // already knows the scoreboard of the base tick.
+        let base_scoreboard = ack_num_and_sent_state
+            .and_then(|(ack_num, _)| player.last_sent.get(ack_num))
+            .map(|sent_state| &sent_state.scoreboard);
+        let changed_scoreboard = if base_scoreboard == Some(scoreboard) {
+            None
+        } else {
+            Some(scoreboard.clone())
+        };
+
         let (diff_base, diff) = if let Some((ack_num, sent_state)) = ack_num_and_sent_state {
             // Okay, we know that the player has acknowledged a tick for which
             // we also still have the state. We can use this state as the basis
             // for delta encoding.
-
-            // Re-send all the events that happened since the base tick.
-            for (sent_events, sent_state) in player.last_sent.iter() {
-                if !sent_events.is_empty() {
-                    events.push((sent_state.tick_num, sent_events.clone()));
-                }
-            }
+            player.num_consecutive_scratch_ticks = 0;
 
             (Some(ack_num), sent_state.diff(&state))
+        } else if is_keyframe_due {
+            // Send a keyframe, even though we might be able to delta encode.
+            debug!(
+                "Sending keyframe {:?} to {:?}",
+                game.state.tick_num, player.player_id,
+            );
+            let base_state = comn::Game::new(game.state.settings.clone());
+            (None, base_state.diff(&state))
         } else {
             // We cannot do delta encoding.
-            info!(
-                "Sending tick {:?} from scratch to {:?} (last ack: {:?})",
-                game.state.tick_num, player.player_id, player.last_ack_tick,
-            );
+            player.num_consecutive_scratch_ticks += 1;
+            player.traffic.scratch_ticks += 1;
+
+            if player.num_consecutive_scratch_ticks == 1 {
+                info!(
+                    "Sending tick {:?} from scratch to {:?} (last ack: {:?})",
+                    game.state.tick_num, player.player_id, player.last_ack_tick,
+                );
+            }
+
+            if player.num_consecutive_scratch_ticks >= MAX_CONSECUTIVE_SCRATCH_TICKS
+                && player.diff_ticks < MAX_WIDENED_DIFF_TICKS
+            {
+                player.diff_ticks = (player.diff_ticks * 2).min(MAX_WIDENED_DIFF_TICKS);
+                player.num_consecutive_scratch_ticks = 0;
+
+                warn!(
+                    "Sent {} ticks from scratch in a row to {:?}, widening diff window to {}",
+                    MAX_CONSECUTIVE_SCRATCH_TICKS, player.player_id, player.diff_ticks,
+                );
+            }
+
             let base_state = comn::Game::new(game.state.settings.clone());
             (None, base_state.diff(&state))
         };
@@ -685,11 +1388,17 @@ impl Runner {
         // client's receival acknowledgement).
         player
             .last_sent
-            .push_back((game.last_events.clone(), state.clone()));
+            .push(game.last_events.clone(), scoreboard.clone(), state);
+
+        player.ticks_since_keyframe = if diff_base.is_none() {
+            0
+        } else {
+            player.ticks_since_keyframe + 1
+        };
 
         // Prune the state memory. This should be rarely necessary, since we
         // already prune states when we receive acknowledgements.
-        if player.last_sent.len() > MAX_DIFF_TICKS as usize {
+        if player.last_sent.len() > player.diff_ticks as usize {
             /*warn!(
                 "Player {:?}'s state memory grew too long ({}), pruning",
                 player.player_id,
@@ -704,6 +1413,405 @@ impl Runner {
             diff,
             events,
             your_last_input_num: player.last_input.clone().map(|(num, _)| num),
+            scoreboard: changed_scoreboard,
         }
     }
 }
+
+fn map_modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Returns a short code for inviting players to a private game. We leave out
+/// characters that are easily confused with each other.
+fn random_join_code() -> String {
+    const CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+    let mut rng = rand::thread_rng();
+    (0..JOIN_CODE_LEN)
+        .map(|_| CHARS[rng.gen_range(0, CHARS.len())] as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::clock::ManualClock;
+
+    fn test_runner(clock: &ManualClock) -> (Runner, webrtc::RecvMessageTx, webrtc::SendMessageRx) {
+        let map = comn::game::MapBuilder::new(comn::Vector::new(1000.0, 1000.0))
+            .spawn_point(comn::Point::new(500.0, 500.0))
+            .build();
+        let config = Config {
+            max_num_games: 1,
+            game_settings: comn::game::SettingsBuilder::new(map).build(),
+            replay_dir: None,
+            watch_map: None,
+            num_bots: 0,
+            bot_behavior: Behavior::Hunter,
+            bot_config: BotConfig::default(),
+            codec: comn::CodecKind::Bincode,
+            compress_threshold: None,
+            keyframe_interval: None,
+            allow_peer_migration: false,
+            ice_servers: Vec::new(),
+            webrtc_signaling_url: None,
+            input_buffer: InputBufferConfig::default(),
+            ping: PingConfig::default(),
+        };
+
+        let (recv_message_tx, recv_message_rx) = webrtc::recv_message_channel();
+        let (send_message_tx, send_message_rx) = mpsc::unbounded_channel();
+        let (_shutdown_tx, shutdown_rx) = oneshot::channel();
+        let runner = Runner::new(
+            config,
+            Arc::new(clock.clone()),
+            recv_message_rx,
+            send_message_tx,
+            shutdown_rx,
+        );
+
+        (runner, recv_message_tx, send_message_rx)
+    }
+
+    fn join(runner: &mut Runner) -> comn::PlayerToken {
+        let request = comn::JoinRequest {
+            protocol_version: comn::PROTOCOL_VERSION,
+            game_id: None,
+            player_name: "test".to_string(),
+            join_code: None,
+            create_private: false,
+            game_options: comn::GameOptions::default(),
+        };
+
+        runner.try_join_game(request).unwrap().your_token
+    }
+
+    fn set_tick_num(runner: &mut Runner, player_token: comn::PlayerToken, tick_num: comn::TickNum) {
+        let game_id = runner.players[&player_token].game_id;
+        runner.games.get_mut(&game_id).unwrap().state.tick_num = tick_num;
+    }
+
+    /// Sends messages with inputs that are too far ahead of the game.
+    fn send_inputs_ahead(
+        runner: &mut Runner,
+        player_token: comn::PlayerToken,
+        num_messages: usize,
+    ) {
+        let peer: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let game_id = runner.players[&player_token].game_id;
+        let tick_num = runner.games[&game_id].state.tick_num;
+        let inputs: Vec<_> = (0..comn::MAX_INPUTS_PER_MESSAGE as u32)
+            .map(|i| {
+                (
+                    comn::TickNum(tick_num.0.wrapping_add(MAX_INPUT_TICKS_AHEAD + 1 + i)),
+                    comn::Input::default(),
+                )
+            })
+            .collect();
+
+        for _ in 0..num_messages {
+            let message =
+                comn::SignedClientMessage(player_token, comn::ClientMessage::Input(inputs.clone()));
+            runner.handle_message(peer, runner.clock.now(), 0, message);
+        }
+    }
+
+    /// Runs the given tick of the player's game with the given events, and
+    /// returns the tick that we would send to the player.
+    fn prepare_tick(
+        runner: &mut Runner,
+        player_token: comn::PlayerToken,
+        tick_num: comn::TickNum,
+        events: Vec<comn::Event>,
+        keyframe_interval: Option<u32>,
+    ) -> comn::Tick {
+        set_tick_num(runner, player_token, tick_num);
+
+        let player = runner.players.get_mut(&player_token).unwrap();
+        let game = runner.games.get_mut(&player.game_id).unwrap();
+        game.last_events = events;
+
+        let scoreboard = comn::Scoreboard::from_game(&game.state);
+
+        Runner::prepare_tick_for_player(player, game, &scoreboard, keyframe_interval)
+    }
+
+    fn test_event() -> comn::Event {
+        comn::Event::NewCatcher {
+            player_id: comn::PlayerId(0),
+        }
+    }
+
+    fn event_tick_nums(tick: &comn::Tick) -> Vec<comn::TickNum> {
+        tick.events.iter().map(|(tick_num, _)| *tick_num).collect()
+    }
+
+    #[test]
+    fn manual_clock_runs_many_ticks() {
+        let clock = ManualClock::default();
+        let (mut runner, recv_message_tx, mut send_message_rx) = test_runner(&clock);
+        let token = join(&mut runner);
+        let codec = runner.config.codec;
+        let peer: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+
+        let send = |message| {
+            let data = comn::SignedClientMessage(token, message).serialize(&codec);
+            recv_message_tx
+                .send(webrtc::MessageIn {
+                    peer,
+                    data,
+                    recv_time: clock.now(),
+                })
+                .unwrap();
+        };
+
+        // The runner only learns the client's address once it receives a
+        // message.
+        send(comn::ClientMessage::Ping(comn::SequenceNum(0)));
+
+        // Run for much longer than the ping timeout, answering each ping one
+        // millisecond after it was sent.
+        let duration = 3 * runner.config.ping.timeout;
+        let start_time = clock.now();
+        while clock.now() - start_time < duration {
+            runner.run_update();
+            clock.advance(Duration::from_millis(1));
+
+            while let Ok(message_out) = send_message_rx.try_recv() {
+                if let Some(comn::ServerMessage::Ping(sequence_num)) =
+                    comn::ServerMessage::deserialize(&codec, &message_out.data)
+                {
+                    send(comn::ClientMessage::Pong(sequence_num));
+                }
+            }
+        }
+
+        let player = &runner.players[&token];
+        let game = &runner.games[&player.game_id];
+        let expected_ticks = duration.as_secs_f32() * game.settings().ticks_per_second as f32;
+        assert!(game.state.tick_num.0 as f32 >= 0.9 * expected_ticks);
+        assert!(player.ping.estimate() <= Duration::from_millis(2));
+    }
+
+    #[test]
+    fn token_is_bound_to_its_first_peer() {
+        let clock = ManualClock::default();
+        let (mut runner, _, mut send_message_rx) = test_runner(&clock);
+        let token = join(&mut runner);
+        let first_peer: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let second_peer: SocketAddr = "127.0.0.1:5678".parse().unwrap();
+
+        let mut send_ping = |runner: &mut Runner, peer| {
+            let message =
+                comn::SignedClientMessage(token, comn::ClientMessage::Ping(comn::SequenceNum(0)));
+            runner.handle_message(peer, clock.now(), 0, message);
+
+            // Returns the peer that we answered to, if any.
+            send_message_rx
+                .try_recv()
+                .ok()
+                .map(|message_out| message_out.peer)
+        };
+
+        assert_eq!(send_ping(&mut runner, first_peer), Some(first_peer));
+        assert_eq!(runner.players[&token].peer, Some(first_peer));
+
+        // Another peer using the token is ignored while the first one is
+        // still active.
+        clock.advance(RECONNECT_SILENCE / 2);
+        assert_eq!(send_ping(&mut runner, second_peer), None);
+        assert_eq!(runner.players[&token].peer, Some(first_peer));
+
+        // Once the first peer has been silent for long enough, we assume that
+        // the client has reconnected.
+        clock.advance(RECONNECT_SILENCE / 2);
+        assert_eq!(send_ping(&mut runner, second_peer), Some(second_peer));
+        assert_eq!(runner.players[&token].peer, Some(second_peer));
+
+        // Now the old peer is the one that is ignored.
+        assert_eq!(send_ping(&mut runner, first_peer), None);
+        assert_eq!(runner.players[&token].peer, Some(second_peer));
+    }
+
+    #[test]
+    fn input_ahead_check_handles_wrap() {
+        let (mut runner, _, _) = test_runner(&ManualClock::default());
+        let token = join(&mut runner);
+        set_tick_num(&mut runner, token, comn::TickNum(u32::MAX - 1));
+
+        runner.record_player_input(token, &[(comn::TickNum(1), comn::Input::default())]);
+        assert_eq!(runner.players[&token].num_rejected_inputs, 0);
+
+        runner.record_player_input(token, &[(comn::TickNum(2), comn::Input::default())]);
+        assert_eq!(runner.players[&token].num_rejected_inputs, 1);
+    }
+
+    #[test]
+    fn occasional_rejected_inputs_are_forgiven() {
+        let (mut runner, _, _) = test_runner(&ManualClock::default());
+        let token = join(&mut runner);
+        let ticks_per_second = runner.config.game_settings.ticks_per_second as u32;
+
+        for second in 0..4 * MAX_ABUSIVE_INPUT_SECONDS as u32 {
+            let tick_num = comn::TickNum(second * ticks_per_second);
+            set_tick_num(&mut runner, token, tick_num);
+
+            if second % 2 == 0 {
+                send_inputs_ahead(&mut runner, token, 20);
+            } else {
+                runner.record_player_input(token, &[(tick_num, comn::Input::default())]);
+            }
+        }
+
+        assert!(runner.players.contains_key(&token));
+    }
+
+    #[test]
+    fn sustained_rejected_inputs_disconnect() {
+        let (mut runner, _, mut send_message_rx) = test_runner(&ManualClock::default());
+        let token = join(&mut runner);
+        let ticks_per_second = runner.config.game_settings.ticks_per_second as u32;
+
+        for second in 0..=MAX_ABUSIVE_INPUT_SECONDS as u32 {
+            assert!(runner.players.contains_key(&token));
+
+            set_tick_num(&mut runner, token, comn::TickNum(second * ticks_per_second));
+            send_inputs_ahead(&mut runner, token, 20);
+        }
+
+        assert!(!runner.players.contains_key(&token));
+
+        let codec = runner.config.codec;
+        let mut received_disconnect = false;
+        while let Ok(message_out) = send_message_rx.try_recv() {
+            if let Some(comn::ServerMessage::Disconnect) =
+                comn::ServerMessage::deserialize(&codec, &message_out.data)
+            {
+                received_disconnect = true;
+            }
+        }
+        assert!(received_disconnect);
+    }
+
+    #[test]
+    fn sent_states_stay_bounded_without_acks() {
+        let (mut runner, _, _) = test_runner(&ManualClock::default());
+        let token = join(&mut runner);
+
+        for i in 0..3 * MAX_WIDENED_DIFF_TICKS {
+            prepare_tick(&mut runner, token, comn::TickNum(i), Vec::new(), None);
+
+            let player = &runner.players[&token];
+            assert!(player.last_sent.len() <= player.diff_ticks as usize);
+        }
+
+        assert!(runner.players[&token].diff_ticks <= MAX_WIDENED_DIFF_TICKS);
+    }
+
+    #[test]
+    fn acked_events_are_not_resent() {
+        let (mut runner, _, _) = test_runner(&ManualClock::default());
+        let token = join(&mut runner);
+
+        prepare_tick(
+            &mut runner,
+            token,
+            comn::TickNum(1),
+            vec![test_event()],
+            None,
+        );
+        runner.record_player_ack_tick(token, comn::TickNum(1), 0);
+
+        for i in 2..=5 {
+            let tick = prepare_tick(
+                &mut runner,
+                token,
+                comn::TickNum(i),
+                vec![test_event()],
+                None,
+            );
+
+            // Until the next ack, we keep re-sending the newer events.
+            assert_eq!(tick.events.len(), i as usize - 1);
+        }
+
+        runner.record_player_ack_tick(token, comn::TickNum(3), 0);
+        let tick = prepare_tick(
+            &mut runner,
+            token,
+            comn::TickNum(6),
+            vec![test_event()],
+            None,
+        );
+
+        assert_eq!(tick.diff_base, Some(comn::TickNum(3)));
+        assert_eq!(
+            event_tick_nums(&tick),
+            vec![comn::TickNum(6), comn::TickNum(4), comn::TickNum(5)]
+        );
+    }
+
+    #[test]
+    fn scoreboard_is_only_sent_when_changed() {
+        let (mut runner, _, _) = test_runner(&ManualClock::default());
+        let token = join(&mut runner);
+
+        let tick = prepare_tick(&mut runner, token, comn::TickNum(1), Vec::new(), None);
+        assert!(tick.scoreboard.is_some());
+
+        // The client knows the scoreboard of the acknowledged tick.
+        runner.record_player_ack_tick(token, comn::TickNum(1), 0);
+        let tick = prepare_tick(&mut runner, token, comn::TickNum(2), Vec::new(), None);
+        assert_eq!(tick.diff_base, Some(comn::TickNum(1)));
+        assert!(tick.scoreboard.is_none());
+
+        let player = &runner.players[&token];
+        let game = runner.games.get_mut(&player.game_id).unwrap();
+        game.state.players.get_mut(&player.player_id).unwrap().score += 1;
+
+        let tick = prepare_tick(&mut runner, token, comn::TickNum(3), Vec::new(), None);
+        assert_eq!(tick.diff_base, Some(comn::TickNum(1)));
+        assert_eq!(tick.scoreboard.unwrap().entries[0].score, 1);
+    }
+
+    #[test]
+    fn keyframe_resends_unacked_events() {
+        let (mut runner, _, _) = test_runner(&ManualClock::default());
+        let token = join(&mut runner);
+
+        prepare_tick(
+            &mut runner,
+            token,
+            comn::TickNum(1),
+            vec![test_event()],
+            None,
+        );
+        runner.record_player_ack_tick(token, comn::TickNum(1), 0);
+        prepare_tick(
+            &mut runner,
+            token,
+            comn::TickNum(2),
+            vec![test_event()],
+            None,
+        );
+
+        // With an interval of 1, a keyframe is due in every tick.
+        let tick = prepare_tick(
+            &mut runner,
+            token,
+            comn::TickNum(3),
+            vec![test_event()],
+            Some(1),
+        );
+
+        assert!(tick.diff_base.is_none());
+        assert_eq!(
+            event_tick_nums(&tick),
+            vec![comn::TickNum(3), comn::TickNum(2)]
+        );
+    }
+}
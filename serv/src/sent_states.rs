@@ -0,0 +1,211 @@
+use std::collections::VecDeque;
+
+use log::warn;
+
+use comn::util::diff::{Diff, Diffable};
+
+/// What we remember about each state that we have sent to a player.
+#[derive(Debug, Clone)]
+pub struct SentState {
+    pub tick_num: comn::TickNum,
+    pub events: Vec<comn::Event>,
+    pub scoreboard: comn::Scoreboard,
+    pub state_hash: u64,
+}
+
+/// The states that we have sent to a player, ordered by the tick number
+/// ascending, for delta encoding.
+///
+/// Under sustained loss, players may have many states in flight, so we keep
+/// only the oldest and the newest state in full. The states in between are
+/// stored as diffs against their predecessor. The oldest state is the only
+/// one that can serve as the basis for delta encoding, since we forget all
+/// states older than the last one that the player acknowledged.
+#[derive(Debug, Clone, Default)]
+pub struct SentStates {
+    /// The oldest state that we remember, in full.
+    base: Option<(SentState, comn::Game)>,
+
+    /// The newer states, each with its diff against the previous state.
+    newer: VecDeque<(SentState, comn::game::GameDiff)>,
+
+    /// The newest state, in full, so that we can diff the next state against
+    /// it. Only set if `newer` is not empty.
+    latest: Option<comn::Game>,
+}
+
+impl SentStates {
+    pub fn len(&self) -> usize {
+        self.base.iter().count() + self.newer.len()
+    }
+
+    /// Returns the oldest state that we remember.
+    pub fn base(&self) -> Option<&comn::Game> {
+        self.base.as_ref().map(|(_, state)| state)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SentState> {
+        self.base
+            .iter()
+            .map(|(sent, _)| sent)
+            .chain(self.newer.iter().map(|(sent, _)| sent))
+    }
+
+    pub fn get(&self, tick_num: comn::TickNum) -> Option<&SentState> {
+        self.iter().find(|sent| sent.tick_num == tick_num)
+    }
+
+    pub fn push(
+        &mut self,
+        events: Vec<comn::Event>,
+        scoreboard: comn::Scoreboard,
+        state: comn::Game,
+    ) {
+        let sent = SentState {
+            tick_num: state.tick_num,
+            events,
+            scoreboard,
+            state_hash: state.state_hash(),
+        };
+
+        if let Some((_, base_state)) = self.base.as_ref() {
+            let prev_state = self.latest.as_ref().unwrap_or(base_state);
+            let diff = prev_state.diff(&state);
+
+            self.newer.push_back((sent, diff));
+            self.latest = Some(state);
+        } else {
+            self.base = Some((sent, state));
+        }
+    }
+
+    /// Forgets the oldest state.
+    pub fn pop_front(&mut self) {
+        let (_, mut base_state) = match self.base.take() {
+            Some(base) => base,
+            None => return,
+        };
+
+        if let Some((sent, diff)) = self.newer.pop_front() {
+            if let Err(err) = diff.apply(&mut base_state) {
+                // This should not happen, since the diffs are our own. We can
+                // recover by sending the next tick from scratch.
+                warn!("Failed to apply sent state diff ({:?}), forgetting", err);
+                self.clear();
+                return;
+            }
+
+            self.base = Some((sent, base_state));
+        }
+
+        if self.newer.is_empty() {
+            self.latest = None;
+        }
+    }
+
+    /// Forgets all the states that are older than the given tick.
+    pub fn prune_before(&mut self, tick_num: comn::TickNum) {
+        while self
+            .base
+            .as_ref()
+            .map_or(false, |(sent, _)| sent.tick_num.is_before(tick_num))
+        {
+            self.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.base = None;
+        self.newer.clear();
+        self.latest = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn test_states(num_states: u32) -> Vec<comn::Game> {
+        let map = comn::game::MapBuilder::new(comn::Vector::new(1000.0, 1000.0)).build();
+        let settings = Arc::new(comn::game::SettingsBuilder::new(map).build());
+
+        (0..num_states)
+            .map(|i| {
+                let mut state = comn::Game::new(settings.clone());
+                state.tick_num = comn::TickNum(i);
+
+                // Make each state differ from the previous one, with entities
+                // being added, moved and removed.
+                for j in 0..i % 4 {
+                    state.entities.insert(
+                        comn::EntityId(j),
+                        comn::Entity::Player(comn::PlayerEntity::new(
+                            comn::PlayerId(j),
+                            comn::Point::new(10.0 * i as f32, 20.0 * j as f32),
+                        )),
+                    );
+                }
+
+                state
+            })
+            .collect()
+    }
+
+    fn assert_base_is(sent_states: &SentStates, state: &comn::Game) {
+        let base = sent_states.base().unwrap();
+
+        assert_eq!(base.tick_num, state.tick_num);
+        assert_eq!(base.entities, state.entities);
+        assert_eq!(base.state_hash(), state.state_hash());
+    }
+
+    #[test]
+    fn pop_front_rebuilds_the_sent_states() {
+        let states = test_states(10);
+        let mut sent_states = SentStates::default();
+
+        for state in states.iter() {
+            sent_states.push(Vec::new(), comn::Scoreboard::default(), state.clone());
+        }
+
+        for (i, state) in states.iter().enumerate() {
+            assert_eq!(sent_states.len(), states.len() - i);
+            assert_base_is(&sent_states, state);
+            assert_eq!(
+                sent_states.get(state.tick_num).unwrap().state_hash,
+                state.state_hash()
+            );
+
+            sent_states.pop_front();
+        }
+
+        assert_eq!(sent_states.len(), 0);
+        assert!(sent_states.base().is_none());
+    }
+
+    #[test]
+    fn prune_before_rebuilds_the_acked_state() {
+        let states = test_states(10);
+        let mut sent_states = SentStates::default();
+
+        for state in states.iter() {
+            sent_states.push(Vec::new(), comn::Scoreboard::default(), state.clone());
+        }
+
+        sent_states.prune_before(comn::TickNum(6));
+        assert_eq!(sent_states.len(), 4);
+        assert_base_is(&sent_states, &states[6]);
+
+        // Pushing after pruning continues from the newest state.
+        let mut next_state = states[9].clone();
+        next_state.tick_num = comn::TickNum(10);
+        next_state.entities.clear();
+        sent_states.push(Vec::new(), comn::Scoreboard::default(), next_state.clone());
+
+        sent_states.prune_before(comn::TickNum(10));
+        assert_eq!(sent_states.len(), 1);
+        assert_base_is(&sent_states, &next_state);
+    }
+}
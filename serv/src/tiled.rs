@@ -1,8 +1,10 @@
 use std::{convert::AsRef, path::Path};
 
+use log::warn;
+
 use comn::{
     game::entities::{DangerGuy, FoodSpawn, Turret, Wall},
-    geom::AaRect,
+    geom::{AaRect, Polygon, Shape},
 };
 
 pub const PLAYER_SPAWN_NAME: &str = "spawn";
@@ -11,13 +13,21 @@ pub const PLAYER_SPAWN_NAME: &str = "spawn";
 pub enum LoadError {
     Tiled(tiled::TiledError),
     UnknownEntityType(String),
-    MissingProperty(String),
-    WrongTypeProperty(String),
+    MissingProperty { object: String, key: String },
+    WrongTypeProperty { object: String, key: String },
+    UnsupportedShape { object: String },
+    NonConvexPolygon { object: String },
+    ZeroPassNormal { object: String },
+    InvalidMap(comn::game::MapError),
 }
 
 pub fn load_map<P: AsRef<Path>>(path: P) -> Result<comn::Map, LoadError> {
     let tiled_map = tiled::parse_file(path.as_ref()).map_err(LoadError::Tiled)?;
 
+    tiled_map_to_map(&tiled_map)
+}
+
+fn tiled_map_to_map(tiled_map: &tiled::Map) -> Result<comn::Map, LoadError> {
     let size = comn::Vector::new(
         (tiled_map.width * tiled_map.tile_width) as f32,
         (tiled_map.height * tiled_map.tile_height) as f32,
@@ -49,39 +59,124 @@ pub fn load_map<P: AsRef<Path>>(path: P) -> Result<comn::Map, LoadError> {
         })
         .collect();
 
-    Ok(comn::Map {
+    let map = comn::Map {
         spawn_points,
         entities: entities?,
         size,
-    })
+    };
+    map.validate().map_err(LoadError::InvalidMap)?;
+
+    Ok(map)
 }
 
 fn object_to_entity(object: &tiled::Object) -> Result<comn::Entity, LoadError> {
     let entity = match object_name(object) {
-        "turret" => comn::Entity::Turret(Turret::new(object_center(object))),
-        "wall" => comn::Entity::Wall(Wall {
-            rect: object_aa_rect(object),
-        }),
-        "food_spawn" => comn::Entity::FoodSpawn(FoodSpawn::new(object_center(object))),
-        "danger_guy" => comn::Entity::DangerGuy(DangerGuy {
-            start_pos: object_center(object),
-            end_pos: object_center(object)
-                + comn::Vector::new(
-                    read_property_f32(object, "delta_x")?,
-                    read_property_f32(object, "delta_y")?,
+        "turret" => {
+            check_properties(object, &["range"]);
+
+            let mut turret = Turret::new(object_center(object));
+            if let Some(range) = read_optional_property_f32(object, "range")? {
+                turret.range = range;
+            }
+
+            comn::Entity::Turret(turret)
+        }
+        "wall" => {
+            check_properties(object, &["pass_x", "pass_y"]);
+
+            let pass_x = read_optional_property_f32(object, "pass_x")?;
+            let pass_y = read_optional_property_f32(object, "pass_y")?;
+            let pass_normal = if pass_x.is_some() || pass_y.is_some() {
+                let normal = comn::Vector::new(pass_x.unwrap_or(0.0), pass_y.unwrap_or(0.0));
+                if normal.norm() == 0.0 {
+                    return Err(LoadError::ZeroPassNormal {
+                        object: object_description(object),
+                    });
+                }
+                Some(normal.normalize())
+            } else {
+                None
+            };
+
+            comn::Entity::Wall(Wall {
+                shape: object_wall_shape(object)?,
+                pass_normal,
+            })
+        }
+        "food_spawn" => {
+            check_properties(object, &[]);
+
+            comn::Entity::FoodSpawn(FoodSpawn::new(object_center(object)))
+        }
+        "danger_guy" => {
+            check_properties(
+                object,
+                &[
+                    "delta_x",
+                    "delta_y",
+                    "speed_go",
+                    "speed_back",
+                    "wait_go",
+                    "wait_back",
+                    "phase",
+                    "is_hot",
+                    "easing",
+                    "size_x",
+                    "size_y",
+                ],
+            );
+
+            let (path, size) = match &object.shape {
+                tiled::ObjectShape::Polyline { points } => {
+                    // The danger guy follows the polyline, so its size needs
+                    // to be given separately.
+                    let path: Vec<comn::Point> = points
+                        .iter()
+                        .map(|(x, y)| object_top_left(object) + comn::Vector::new(*x, *y))
+                        .collect();
+                    let size = comn::Vector::new(
+                        read_property_f32(object, "size_x")?,
+                        read_property_f32(object, "size_y")?,
+                    );
+
+                    (path, size)
+                }
+                _ => {
+                    let start_pos = object_center(object);
+                    let end_pos = start_pos
+                        + comn::Vector::new(
+                            read_property_f32(object, "delta_x")?,
+                            read_property_f32(object, "delta_y")?,
+                        );
+
+                    (vec![start_pos, end_pos], object_size(object))
+                }
+            };
+
+            if path.len() < 2 {
+                return Err(LoadError::UnsupportedShape {
+                    object: object_description(object),
+                });
+            }
+
+            comn::Entity::DangerGuy(DangerGuy {
+                start_pos: path[0],
+                end_pos: path[path.len() - 1],
+                waypoints: path[1..path.len() - 1].to_vec(),
+                easing: read_optional_property_bool(object, "easing")?.unwrap_or(false),
+                size,
+                speed: (
+                    read_property_f32(object, "speed_go")?,
+                    read_property_f32(object, "speed_back")?,
                 ),
-            size: object_size(object),
-            speed: (
-                read_property_f32(object, "speed_go")?,
-                read_property_f32(object, "speed_back")?,
-            ),
-            wait_time: (
-                read_property_f32(object, "wait_go")?,
-                read_property_f32(object, "wait_back")?,
-            ),
-            phase: read_property_f32(object, "phase")?,
-            is_hot: true,
-        }),
+                wait_time: (
+                    read_property_f32(object, "wait_go")?,
+                    read_property_f32(object, "wait_back")?,
+                ),
+                phase: read_property_f32(object, "phase")?,
+                is_hot: read_optional_property_bool(object, "is_hot")?.unwrap_or(true),
+            })
+        }
         name => {
             return Err(LoadError::UnknownEntityType(name.to_string()));
         }
@@ -90,18 +185,63 @@ fn object_to_entity(object: &tiled::Object) -> Result<comn::Entity, LoadError> {
     Ok(entity)
 }
 
-fn read_property_f32(object: &tiled::Object, prop_key: &str) -> Result<f32, LoadError> {
-    let prop_value = object
+/// Warns about properties that we do not know. These are most likely typos,
+/// but they may also be left over from editing the map, so we do not refuse
+/// to load the map.
+fn check_properties(object: &tiled::Object, allowed_keys: &[&str]) {
+    for key in object
         .properties
-        .get(prop_key)
-        .ok_or_else(|| LoadError::MissingProperty(prop_key.to_string()))?;
-    if let tiled::PropertyValue::FloatValue(result) = prop_value {
-        Ok(*result)
-    } else {
-        Err(LoadError::WrongTypeProperty(prop_key.to_string()))
+        .keys()
+        .filter(|key| !allowed_keys.contains(&key.as_str()))
+    {
+        warn!(
+            "Ignoring unknown property {:?} of {}",
+            key,
+            object_description(object)
+        );
     }
 }
 
+fn read_property_f32(object: &tiled::Object, prop_key: &str) -> Result<f32, LoadError> {
+    read_optional_property_f32(object, prop_key)?.ok_or_else(|| LoadError::MissingProperty {
+        object: object_description(object),
+        key: prop_key.to_string(),
+    })
+}
+
+fn read_optional_property_f32(
+    object: &tiled::Object,
+    prop_key: &str,
+) -> Result<Option<f32>, LoadError> {
+    match object.properties.get(prop_key) {
+        Some(tiled::PropertyValue::FloatValue(result)) => Ok(Some(*result)),
+        Some(tiled::PropertyValue::IntValue(result)) => Ok(Some(*result as f32)),
+        Some(_) => Err(LoadError::WrongTypeProperty {
+            object: object_description(object),
+            key: prop_key.to_string(),
+        }),
+        None => Ok(None),
+    }
+}
+
+fn read_optional_property_bool(
+    object: &tiled::Object,
+    prop_key: &str,
+) -> Result<Option<bool>, LoadError> {
+    match object.properties.get(prop_key) {
+        Some(tiled::PropertyValue::BoolValue(result)) => Ok(Some(*result)),
+        Some(_) => Err(LoadError::WrongTypeProperty {
+            object: object_description(object),
+            key: prop_key.to_string(),
+        }),
+        None => Ok(None),
+    }
+}
+
+fn object_description(object: &tiled::Object) -> String {
+    format!("{} (id {})", object_name(object), object.id)
+}
+
 fn object_name(object: &tiled::Object) -> &str {
     if object.obj_type.is_empty() {
         &object.name
@@ -114,6 +254,30 @@ fn object_aa_rect(object: &tiled::Object) -> AaRect {
     AaRect::new_top_left(object_top_left(object), object_size(object))
 }
 
+fn object_wall_shape(object: &tiled::Object) -> Result<Shape, LoadError> {
+    match &object.shape {
+        tiled::ObjectShape::Rect { .. } => Ok(Shape::AaRect(object_aa_rect(object))),
+        tiled::ObjectShape::Polygon { points } | tiled::ObjectShape::Polyline { points } => {
+            // Polylines are closed, so that they can be used in the same way
+            // as polygons. Tiled gives the points relative to the object
+            // position.
+            let points = points
+                .iter()
+                .map(|(x, y)| object_top_left(object) + comn::Vector::new(*x, *y))
+                .collect();
+
+            Polygon::new_convex(points)
+                .map(Shape::Polygon)
+                .ok_or_else(|| LoadError::NonConvexPolygon {
+                    object: object_description(object),
+                })
+        }
+        _ => Err(LoadError::UnsupportedShape {
+            object: object_description(object),
+        }),
+    }
+}
+
 fn object_center(object: &tiled::Object) -> comn::Point {
     object_top_left(object) + object_size(object) / 2.0
 }
@@ -125,3 +289,94 @@ fn object_top_left(object: &tiled::Object) -> comn::Point {
 fn object_size(object: &tiled::Object) -> comn::Vector {
     comn::Vector::new(object.width, object.height)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(tmx: &str) -> Result<comn::Map, LoadError> {
+        let tiled_map = tiled::parse(tmx.as_bytes()).map_err(LoadError::Tiled)?;
+        tiled_map_to_map(&tiled_map)
+    }
+
+    fn tmx_with_objects(objects: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<map version="1.4" orientation="orthogonal" renderorder="right-down" width="10" height="10" tilewidth="32" tileheight="32" infinite="0" nextlayerid="2" nextobjectid="10">
+ <objectgroup id="1" name="Map">
+  <object id="1" name="spawn" x="0" y="0" width="32" height="32"/>
+{}
+ </objectgroup>
+</map>"#,
+            objects
+        )
+    }
+
+    #[test]
+    fn test_map_loads() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../maps/test.tmx");
+        let map = load_map(path).unwrap();
+
+        assert!(!map.spawn_points.is_empty());
+        assert!(map.entities.iter().any(|entity| match entity {
+            comn::Entity::Wall(_) => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn danger_guy_properties_are_read() {
+        let map = parse(&tmx_with_objects(
+            r#"  <object id="2" type="danger_guy" x="100" y="100" width="20" height="30">
+   <properties>
+    <property name="delta_x" type="float" value="50"/>
+    <property name="delta_y" type="float" value="0"/>
+    <property name="speed_go" type="float" value="100"/>
+    <property name="speed_back" type="float" value="200"/>
+    <property name="wait_go" type="float" value="1"/>
+    <property name="wait_back" type="float" value="2"/>
+    <property name="phase" type="float" value="0.5"/>
+    <property name="is_hot" type="bool" value="false"/>
+   </properties>
+  </object>"#,
+        ))
+        .unwrap();
+
+        let danger_guy = match &map.entities[..] {
+            [comn::Entity::DangerGuy(danger_guy)] => danger_guy,
+            entities => panic!("unexpected entities: {:?}", entities),
+        };
+
+        assert!((danger_guy.start_pos - comn::Point::new(110.0, 115.0)).norm() < 1e-3);
+        assert!((danger_guy.end_pos - comn::Point::new(160.0, 115.0)).norm() < 1e-3);
+        assert!((danger_guy.speed.1 - 200.0).abs() < 1e-3);
+        assert!((danger_guy.wait_time.1 - 2.0).abs() < 1e-3);
+        assert!(!danger_guy.is_hot);
+    }
+
+    #[test]
+    fn unknown_properties_are_ignored() {
+        let map = parse(&tmx_with_objects(
+            r#"  <object id="2" type="wall" x="100" y="100" width="64" height="64">
+   <properties>
+    <property name="ASDF" type="float" value="0"/>
+   </properties>
+  </object>"#,
+        ))
+        .unwrap();
+
+        assert_eq!(map.entities.len(), 1);
+    }
+
+    #[test]
+    fn missing_property_is_an_error() {
+        let result = parse(&tmx_with_objects(
+            r#"  <object id="2" type="danger_guy" x="100" y="100" width="20" height="30"/>"#,
+        ));
+
+        match result {
+            Err(LoadError::MissingProperty { key, .. }) => assert_eq!(key, "delta_x"),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+}
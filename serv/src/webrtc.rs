@@ -1,16 +1,22 @@
-use std::{net::SocketAddr, time::Instant};
+use std::{net::SocketAddr, sync::Arc, time::Instant};
 
 use log::{info, warn};
 
 use futures::{select, FutureExt};
 use tokio::sync::{mpsc, oneshot};
 
+use crate::clock::Clock;
+
+#[derive(Clone)]
 pub struct MessageIn {
     pub peer: SocketAddr,
     pub data: Vec<u8>,
+
+    /// Time at which we received the message, taken from the runner's clock.
     pub recv_time: Instant,
 }
 
+#[derive(Clone)]
 pub struct MessageOut {
     pub peer: SocketAddr,
     pub data: Vec<u8>,
@@ -33,9 +39,14 @@ pub fn send_message_channel() -> (SendMessageTx, SendMessageRx) {
 #[derive(Debug, Clone)]
 pub struct Config {
     pub listen_addr: SocketAddr,
+
+    /// Address that clients send their WebRTC messages to, if it differs from
+    /// the listen address, e.g. when running behind NAT.
+    pub public_addr: Option<SocketAddr>,
 }
 
 pub struct Server {
+    clock: Arc<dyn Clock>,
     recv_message_tx: RecvMessageTx,
     send_message_rx: SendMessageRx,
 
@@ -45,6 +56,7 @@ pub struct Server {
 impl Server {
     pub async fn new(
         config: Config,
+        clock: Arc<dyn Clock>,
         recv_message_tx: RecvMessageTx,
         send_message_rx: SendMessageRx,
     ) -> Result<Self, std::io::Error> {
@@ -53,12 +65,19 @@ impl Server {
         // it seems that both addresses must listen on the same port:
         // <https://github.com/kyren/webrtc-unreliable/issues/3#issuecomment-532905616>
         //
-        // There might be some use in using a different IP for the two
-        // addresses, but for now we'll just use the exact same address.
-        let webrtc_server =
-            webrtc_unreliable::Server::new(config.listen_addr, config.listen_addr).await?;
+        // By default, we just use the exact same address for both.
+        let public_addr = config.public_addr.unwrap_or(config.listen_addr);
+        if public_addr.port() != config.listen_addr.port() {
+            warn!(
+                "WebRTC public address {} has a different port than listen address {}",
+                public_addr, config.listen_addr,
+            );
+        }
+
+        let webrtc_server = webrtc_unreliable::Server::new(config.listen_addr, public_addr).await?;
 
         Ok(Self {
+            clock,
             recv_message_tx,
             send_message_rx,
             webrtc_server,
@@ -106,12 +125,12 @@ impl Server {
                             let message_in = MessageIn {
                                 peer: message_result.remote_addr,
                                 data: message_buf[0..message_result.message_len].to_vec(),
-                                recv_time: Instant::now(),
+                                recv_time: self.clock.now(),
                             };
-                            if self.recv_message_tx.send(message_in).is_err() {
-                                info!("recv_message_tx closed, terminating");
-                                return;
-                            }
+                            // If the receiver has been dropped, the runner
+                            // is shutting down. We keep going until all of its
+                            // remaining messages have been sent, though.
+                            let _ = self.recv_message_tx.send(message_in);
                         }
                         Err(err) => {
                             warn!("Could not receive message: {}", err);